@@ -0,0 +1,270 @@
+use crate::operators::{self as OP, Activation, GatherError, OperatorError, RopeCache, RopeLayout};
+use crate::params::{EmbeddingTable, Weight};
+use crate::tensor::Tensor;
+#[cfg(test)]
+use crate::test_support::story_model;
+
+// The operators forward() runs through, so that an implementation other than the CPU one
+// (SIMD, GPU, quantized) can be swapped in without touching model.rs. The remaining
+// operators (attention score matmuls, rope re-rotation, softcap) still run on the CPU.
+pub trait Backend: Send + Sync {
+    // C = beta * C + alpha * A @ B^T
+    fn matmul_transb(
+        &self,
+        c: &mut Tensor<f32>,
+        beta: f32,
+        a: &Tensor<f32>,
+        b: &Weight<f32>,
+        alpha: f32,
+    ) -> Result<(), OperatorError>;
+
+    // rms_norm, or rms_norm_gemma with unit_offset
+    fn rms_norm(
+        &self,
+        y: &mut Tensor<f32>,
+        x: &Tensor<f32>,
+        w: &Tensor<f32>,
+        epsilon: f32,
+        unit_offset: bool,
+    ) -> Result<(), OperatorError>;
+
+    // C = alpha * rms_norm(X) @ B^T, a projection of the normalized hidden states. By default
+    // X is normalized into a temporary first, CpuBackend fuses the two for f32 weights
+    #[allow(clippy::too_many_arguments)]
+    fn rms_norm_matmul_transb(
+        &self,
+        c: &mut Tensor<f32>,
+        x: &Tensor<f32>,
+        norm_w: &Tensor<f32>,
+        b: &Weight<f32>,
+        epsilon: f32,
+        unit_offset: bool,
+        alpha: f32,
+    ) -> Result<(), OperatorError> {
+        rms_norm_then_matmul_transb(self, c, x, norm_w, b, epsilon, unit_offset, alpha)
+    }
+
+    fn rope(
+        &self,
+        y: &mut Tensor<f32>,
+        start_pos: usize,
+        cache: &RopeCache,
+        layout: RopeLayout,
+    ) -> Result<(), OperatorError>;
+
+    fn masked_softmax(&self, y: &mut Tensor<f32>);
+
+    // masked_softmax that only keeps the first n_sink and the last window keys of a query
+    fn masked_softmax_sink(&self, y: &mut Tensor<f32>, n_sink: usize, window: usize) {
+        OP::masked_softmax_sink(y, n_sink, window);
+    }
+
+    fn swiglu(&self, y: &mut Tensor<f32>, x: &Tensor<f32>);
+
+    // y = act(x) * y, swiglu for silu
+    fn gated_activation(&self, y: &mut Tensor<f32>, x: &Tensor<f32>, act: Activation) {
+        match act {
+            Activation::Silu => self.swiglu(y, x),
+            act => OP::gated_activation(y, x, act),
+        }
+    }
+
+    // embedding lookup with every row multiplied by scale
+    fn gather(
+        &self,
+        y: &mut Tensor<f32>,
+        indices: &Tensor<u32>,
+        table: &EmbeddingTable<f32>,
+        scale: f32,
+    ) -> Result<(), GatherError>;
+}
+
+#[allow(clippy::too_many_arguments)]
+fn rms_norm_then_matmul_transb<B: Backend + ?Sized>(
+    backend: &B,
+    c: &mut Tensor<f32>,
+    x: &Tensor<f32>,
+    norm_w: &Tensor<f32>,
+    b: &Weight<f32>,
+    epsilon: f32,
+    unit_offset: bool,
+    alpha: f32,
+) -> Result<(), OperatorError> {
+    let mut normed = Tensor::<f32>::default(x.shape());
+    backend.rms_norm(&mut normed, x, norm_w, epsilon, unit_offset)?;
+    backend.matmul_transb(c, 0., &normed, b, alpha)
+}
+
+// The operators in operators.rs
+pub struct CpuBackend;
+
+impl Backend for CpuBackend {
+    fn matmul_transb(
+        &self,
+        c: &mut Tensor<f32>,
+        beta: f32,
+        a: &Tensor<f32>,
+        b: &Weight<f32>,
+        alpha: f32,
+    ) -> Result<(), OperatorError> {
+        b.matmul_transb(c, beta, a, alpha)
+    }
+
+    fn rms_norm(
+        &self,
+        y: &mut Tensor<f32>,
+        x: &Tensor<f32>,
+        w: &Tensor<f32>,
+        epsilon: f32,
+        unit_offset: bool,
+    ) -> Result<(), OperatorError> {
+        OP::checked_rms_norm(y, x, w, epsilon, unit_offset)
+    }
+
+    fn rms_norm_matmul_transb(
+        &self,
+        c: &mut Tensor<f32>,
+        x: &Tensor<f32>,
+        norm_w: &Tensor<f32>,
+        b: &Weight<f32>,
+        epsilon: f32,
+        unit_offset: bool,
+        alpha: f32,
+    ) -> Result<(), OperatorError> {
+        match b {
+            Weight::Full(b) => {
+                OP::checked_rms_norm_matmul_transb(c, x, norm_w, b, epsilon, unit_offset, alpha)
+            }
+            b => rms_norm_then_matmul_transb(self, c, x, norm_w, b, epsilon, unit_offset, alpha),
+        }
+    }
+
+    fn rope(
+        &self,
+        y: &mut Tensor<f32>,
+        start_pos: usize,
+        cache: &RopeCache,
+        layout: RopeLayout,
+    ) -> Result<(), OperatorError> {
+        OP::checked_rope_cached(y, start_pos, cache, layout)
+    }
+
+    fn masked_softmax(&self, y: &mut Tensor<f32>) {
+        OP::masked_softmax(y);
+    }
+
+    fn swiglu(&self, y: &mut Tensor<f32>, x: &Tensor<f32>) {
+        OP::swiglu(y, x);
+    }
+
+    fn gather(
+        &self,
+        y: &mut Tensor<f32>,
+        indices: &Tensor<u32>,
+        table: &EmbeddingTable<f32>,
+        scale: f32,
+    ) -> Result<(), GatherError> {
+        table.gather_scaled(y, indices, scale)
+    }
+}
+
+// Counts the calls that reach CpuBackend, and the tokens of every embedding lookup
+#[cfg(test)]
+#[derive(Default)]
+pub struct CountingBackend {
+    pub matmuls: std::sync::atomic::AtomicUsize,
+    pub softmaxes: std::sync::atomic::AtomicUsize,
+    pub gathered: std::sync::Mutex<Vec<usize>>,
+}
+
+#[cfg(test)]
+impl Backend for CountingBackend {
+    fn matmul_transb(
+        &self,
+        c: &mut Tensor<f32>,
+        beta: f32,
+        a: &Tensor<f32>,
+        b: &Weight<f32>,
+        alpha: f32,
+    ) -> Result<(), OperatorError> {
+        self.matmuls
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        CpuBackend.matmul_transb(c, beta, a, b, alpha)
+    }
+
+    fn rms_norm(
+        &self,
+        y: &mut Tensor<f32>,
+        x: &Tensor<f32>,
+        w: &Tensor<f32>,
+        epsilon: f32,
+        unit_offset: bool,
+    ) -> Result<(), OperatorError> {
+        CpuBackend.rms_norm(y, x, w, epsilon, unit_offset)
+    }
+
+    fn rms_norm_matmul_transb(
+        &self,
+        c: &mut Tensor<f32>,
+        x: &Tensor<f32>,
+        norm_w: &Tensor<f32>,
+        b: &Weight<f32>,
+        epsilon: f32,
+        unit_offset: bool,
+        alpha: f32,
+    ) -> Result<(), OperatorError> {
+        self.matmuls
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        CpuBackend.rms_norm_matmul_transb(c, x, norm_w, b, epsilon, unit_offset, alpha)
+    }
+
+    fn rope(
+        &self,
+        y: &mut Tensor<f32>,
+        start_pos: usize,
+        cache: &RopeCache,
+        layout: RopeLayout,
+    ) -> Result<(), OperatorError> {
+        CpuBackend.rope(y, start_pos, cache, layout)
+    }
+
+    fn masked_softmax(&self, y: &mut Tensor<f32>) {
+        self.softmaxes
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        CpuBackend.masked_softmax(y);
+    }
+
+    fn swiglu(&self, y: &mut Tensor<f32>, x: &Tensor<f32>) {
+        CpuBackend.swiglu(y, x);
+    }
+
+    fn gather(
+        &self,
+        y: &mut Tensor<f32>,
+        indices: &Tensor<u32>,
+        table: &EmbeddingTable<f32>,
+        scale: f32,
+    ) -> Result<(), GatherError> {
+        self.gathered.lock().unwrap().push(indices.size());
+        CpuBackend.gather(y, indices, table, scale)
+    }
+}
+
+#[test]
+fn test_forward_through_backend() {
+    use std::sync::Arc;
+    let model = story_model();
+    let counting = Arc::new(CountingBackend::default());
+    let counted = story_model().with_backend(counting.clone());
+
+    let input = Tensor::<u32>::new(vec![1, 300, 25, 700, 40], &vec![5]);
+    let expected = model.forward(&input, &mut model.new_cache()).unwrap();
+    let logits = counted.forward(&input, &mut counted.new_cache()).unwrap();
+    assert_eq!(logits.data(), expected.data());
+    // 7 projections per layer for 2 layers and lm_head, one softmax per layer
+    let load = |n: &std::sync::atomic::AtomicUsize| n.load(std::sync::atomic::Ordering::Relaxed);
+    assert_eq!(
+        (load(&counting.matmuls), load(&counting.softmaxes)),
+        (15, 2)
+    );
+}
@@ -1,8 +1,16 @@
-use serde;
+use crate::operators::Activation;
+use serde::de::Error;
+use serde::Deserialize;
+use smallvec::{smallvec, SmallVec};
+
 #[derive(serde::Serialize, serde::Deserialize, Debug)]
 pub(crate) struct LlamaConfigJson {
+    #[serde(default)]
+    pub model_type: Option<String>,
     pub bos_token_id: u32,
-    pub eos_token_id: u32,
+    // a single id or, as in Llama-3, a list of terminators
+    #[serde(deserialize_with = "int_or_list")]
+    pub eos_token_id: SmallVec<[u32; 4]>,
     pub hidden_size: usize,
     pub intermediate_size: usize,
     pub max_position_embeddings: usize,
@@ -17,6 +25,119 @@ pub(crate) struct LlamaConfigJson {
     pub torch_dtype: String,
     #[serde(default = "default_tie_word_embeddings")]
     pub tie_word_embeddings: bool,
+    #[serde(default = "default_hidden_act")]
+    pub hidden_act: HiddenAct,
+    // Gemma-2 soft-capping of attention scores and final logits
+    #[serde(default)]
+    pub attn_logit_softcapping: Option<f32>,
+    #[serde(default)]
+    pub final_logit_softcapping: Option<f32>,
+    // partial rope, either as a fraction of the head size (Phi) or directly (GPT-NeoX/GPT-J)
+    #[serde(default)]
+    pub partial_rotary_factor: Option<f32>,
+    #[serde(default)]
+    pub rotary_dim: Option<usize>,
+    // pair element 2i with 2i + 1 in rope (GPT-J) instead of i with i + rotary_dim / 2
+    #[serde(default)]
+    pub rope_interleaved: bool,
+    #[serde(default)]
+    pub rope_scaling: Option<RopeScalingJson>,
+}
+
+// activation of the MLP gate, an unknown name fails to parse instead of running silu
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum HiddenAct {
+    Silu,
+    Gelu,
+    #[serde(alias = "gelu_new")]
+    GeluPytorchTanh,
+    Relu,
+    Relu2,
+}
+
+impl From<HiddenAct> for Activation {
+    fn from(act: HiddenAct) -> Self {
+        match act {
+            HiddenAct::Silu => Activation::Silu,
+            HiddenAct::Gelu => Activation::Gelu,
+            HiddenAct::GeluPytorchTanh => Activation::GeluTanh,
+            HiddenAct::Relu => Activation::Relu,
+            HiddenAct::Relu2 => Activation::Relu2,
+        }
+    }
+}
+
+// the "rope_scaling" section of config.json, null when the model uses plain rope
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub(crate) enum RopeScalingJson {
+    Linear {
+        factor: f32,
+    },
+    Dynamic {
+        factor: f32,
+    },
+    Yarn {
+        factor: f32,
+        // defaults to max_position_embeddings / factor
+        #[serde(default)]
+        original_max_position_embeddings: Option<usize>,
+        #[serde(default = "default_yarn_beta_fast")]
+        beta_fast: f32,
+        #[serde(default = "default_yarn_beta_slow")]
+        beta_slow: f32,
+        // defaults to 0.1 * ln(factor) + 1
+        #[serde(default)]
+        attention_factor: Option<f32>,
+    },
+}
+
+impl LlamaConfigJson {
+    // Gemma checkpoints store rms_norm weights as offsets from 1, see rms_norm_gemma
+    pub fn rms_norm_unit_offset(&self) -> bool {
+        self.model_type
+            .as_deref()
+            .is_some_and(|t| t.starts_with("gemma"))
+    }
+
+    // factor the looked up token embeddings are multiplied by, sqrt(hidden_size) for Gemma
+    pub fn embedding_scale(&self) -> f32 {
+        if self
+            .model_type
+            .as_deref()
+            .is_some_and(|t| t.starts_with("gemma"))
+        {
+            (self.hidden_size as f32).sqrt()
+        } else {
+            1.
+        }
+    }
+
+    // combinations of sizes that deserialize but that the model cannot run
+    pub fn check(&self) -> Result<(), String> {
+        let (n_heads, n_kv_heads) = (self.num_attention_heads, self.num_key_value_heads);
+        // every kv head serves the same number of query heads in grouped-query attention
+        if n_kv_heads == 0 || n_heads % n_kv_heads != 0 {
+            return Err(format!(
+                "num_attention_heads {n_heads} is not a multiple of num_key_value_heads {n_kv_heads}"
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn head_dim(&self) -> usize {
+        self.hidden_size / self.num_attention_heads
+    }
+
+    // number of elements of every head that rope rotates, the whole head by default
+    pub fn rotary_dim(&self) -> usize {
+        match (self.rotary_dim, self.partial_rotary_factor) {
+            (Some(rotary_dim), _) => rotary_dim,
+            (None, Some(factor)) => (self.head_dim() as f32 * factor) as usize,
+            (None, None) => self.head_dim(),
+        }
+    }
 }
 
 #[inline(always)]
@@ -29,7 +150,120 @@ const fn default_rope_theta() -> f32 {
     1e4
 }
 
+#[inline(always)]
+const fn default_yarn_beta_fast() -> f32 {
+    32.
+}
+
+#[inline(always)]
+const fn default_yarn_beta_slow() -> f32 {
+    1.
+}
+
 #[inline(always)]
 const fn default_tie_word_embeddings() -> bool {
     false
 }
+
+fn int_or_list<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<SmallVec<[u32; 4]>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum IntOrList {
+        Int(u32),
+        List(Vec<u32>),
+    }
+    match IntOrList::deserialize(deserializer)? {
+        IntOrList::Int(id) => Ok(smallvec![id]),
+        IntOrList::List(ids) if ids.is_empty() => {
+            Err(D::Error::custom("no eos_token_id in the list"))
+        }
+        IntOrList::List(ids) => Ok(ids.into()),
+    }
+}
+
+#[inline(always)]
+const fn default_hidden_act() -> HiddenAct {
+    HiddenAct::Silu
+}
+
+#[cfg(test)]
+fn parse_hidden_act(act: &str) -> Result<HiddenAct, serde_json::Error> {
+    let config = format!(
+        r#"{{"bos_token_id": 1, "eos_token_id": 2, "hidden_size": 8, "intermediate_size": 16,
+        "max_position_embeddings": 32, "num_attention_heads": 2, "num_hidden_layers": 1,
+        "num_key_value_heads": 1, "vocab_size": 10, "torch_dtype": "float32",
+        "hidden_act": "{act}"}}"#
+    );
+    serde_json::from_str::<LlamaConfigJson>(&config).map(|c| c.hidden_act)
+}
+
+#[test]
+fn test_hidden_act() {
+    assert_eq!(parse_hidden_act("silu").unwrap(), HiddenAct::Silu);
+    assert_eq!(parse_hidden_act("gelu").unwrap(), HiddenAct::Gelu);
+    assert_eq!(
+        parse_hidden_act("gelu_pytorch_tanh").unwrap(),
+        HiddenAct::GeluPytorchTanh
+    );
+    assert_eq!(
+        parse_hidden_act("gelu_new").unwrap(),
+        HiddenAct::GeluPytorchTanh
+    );
+    assert_eq!(parse_hidden_act("relu").unwrap(), HiddenAct::Relu);
+    assert_eq!(parse_hidden_act("relu2").unwrap(), HiddenAct::Relu2);
+    assert_eq!(
+        Activation::from(HiddenAct::GeluPytorchTanh),
+        Activation::GeluTanh
+    );
+}
+
+#[test]
+fn test_check_kv_heads() {
+    let config = |n_heads: usize, n_kv_heads: usize| {
+        let config = format!(
+            r#"{{"bos_token_id": 1, "eos_token_id": 2, "hidden_size": 48, "intermediate_size": 16,
+            "max_position_embeddings": 32, "num_attention_heads": {n_heads}, "num_hidden_layers": 1,
+            "num_key_value_heads": {n_kv_heads}, "vocab_size": 10, "torch_dtype": "float32"}}"#
+        );
+        serde_json::from_str::<LlamaConfigJson>(&config).unwrap()
+    };
+    assert!(config(4, 2).check().is_ok());
+    assert!(config(4, 4).check().is_ok());
+    assert_eq!(
+        config(6, 4).check().unwrap_err(),
+        "num_attention_heads 6 is not a multiple of num_key_value_heads 4"
+    );
+    assert!(config(4, 0).check().is_err());
+}
+
+#[test]
+fn test_hidden_act_unknown() {
+    let err = parse_hidden_act("swish2").unwrap_err();
+    assert!(
+        err.to_string().contains("unknown variant `swish2`"),
+        "{err}"
+    );
+}
+
+#[test]
+fn test_eos_token_id() {
+    let parse = |eos: &str| {
+        let config = format!(
+            r#"{{"bos_token_id": 1, "eos_token_id": {eos}, "hidden_size": 8, "intermediate_size": 16,
+            "max_position_embeddings": 32, "num_attention_heads": 2, "num_hidden_layers": 1,
+            "num_key_value_heads": 1, "vocab_size": 10, "torch_dtype": "float32"}}"#
+        );
+        serde_json::from_str::<LlamaConfigJson>(&config).map(|c| c.eos_token_id.to_vec())
+    };
+    assert_eq!(parse("2").unwrap(), [2]);
+    assert_eq!(
+        parse("[128001, 128008, 128009]").unwrap(),
+        [128001, 128008, 128009]
+    );
+    assert_eq!(parse("[7]").unwrap(), [7]);
+    assert!(parse("[]").is_err());
+    assert!(parse("\"2\"").is_err());
+    assert!(parse("-1").is_err());
+}
@@ -0,0 +1,466 @@
+#[cfg(test)]
+use crate::test_support::{story_model, story_tokenizer};
+use tokenizers::Tokenizer;
+
+// restricts which tokens generation may pick next
+pub trait Constraint {
+    // sets allowed[t] for every token t that may follow the generated ones, where remaining
+    // counts the tokens left for the output including the next one
+    fn allowed_tokens(&mut self, generated: &[u32], remaining: usize, allowed: &mut [bool]);
+    // whether the generated tokens form a whole output, so that generation can stop
+    fn is_complete(&mut self, generated: &[u32]) -> bool;
+}
+
+// the bytes every token decodes to, with "▁" as a space and "<0xNN>" as a single byte;
+// special tokens get no bytes, which no constraint accepts
+pub fn token_pieces(tokenizer: &Tokenizer) -> Vec<Vec<u8>> {
+    let special = tokenizer.get_added_tokens_decoder();
+    (0..tokenizer.get_vocab_size(true) as u32)
+        .map(|id| match tokenizer.id_to_token(id) {
+            Some(_) if special.get(&id).is_some_and(|t| t.special) => Vec::new(),
+            Some(piece) => piece_bytes(&piece),
+            None => Vec::new(),
+        })
+        .collect()
+}
+
+fn piece_bytes(piece: &str) -> Vec<u8> {
+    if let Some(hex) = piece.strip_prefix("<0x").and_then(|p| p.strip_suffix('>')) {
+        if let Ok(byte) = u8::from_str_radix(hex, 16) {
+            return vec![byte];
+        }
+    }
+    piece.replace('\u{2581}', " ").into_bytes()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Escape {
+    None,
+    Backslash,
+    Hex(u8), // hex digits left of a \u escape
+    // continuation bytes left of a multi-byte UTF-8 character and the range of the next
+    // one, which after some leading bytes is narrower than 0x80..=0xbf
+    Utf8(u8, u8, u8),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Number {
+    Minus,
+    Zero,
+    Int,
+    Dot,
+    Frac,
+    Exp,
+    ExpSign,
+    ExpDigits,
+}
+
+impl Number {
+    // whether the number may end here
+    fn terminal(self) -> bool {
+        matches!(
+            self,
+            Number::Zero | Number::Int | Number::Frac | Number::ExpDigits
+        )
+    }
+
+    fn step(self, b: u8) -> Option<Number> {
+        match (self, b) {
+            (Number::Minus, b'0') => Some(Number::Zero),
+            (Number::Minus | Number::Int, b'0'..=b'9') => Some(Number::Int),
+            (Number::Zero | Number::Int, b'.') => Some(Number::Dot),
+            (Number::Dot | Number::Frac, b'0'..=b'9') => Some(Number::Frac),
+            (Number::Zero | Number::Int | Number::Frac, b'e' | b'E') => Some(Number::Exp),
+            (Number::Exp, b'+' | b'-') => Some(Number::ExpSign),
+            (Number::Exp | Number::ExpSign | Number::ExpDigits, b'0'..=b'9') => {
+                Some(Number::ExpDigits)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Mode {
+    Value,       // a value has to start
+    ArrayFirst,  // right after '[', a value or ']'
+    ObjectFirst, // right after '{', a key or '}'
+    Key,         // after ',' in an object
+    Colon,       // after a key
+    After,       // after a value inside a container, ',' or the closing bracket
+    Str { key: bool, escape: Escape },
+    Number(Number),
+    Literal(&'static [u8]), // the bytes left of true, false or null
+    Done,
+}
+
+// a pushdown automaton over the bytes of one JSON value
+#[derive(Clone, Debug)]
+struct JsonState {
+    stack: Vec<u8>, // the open brackets
+    mode: Mode,
+}
+
+fn is_whitespace(b: u8) -> bool {
+    matches!(b, b' ' | b'\t' | b'\n' | b'\r')
+}
+
+impl JsonState {
+    fn new() -> Self {
+        JsonState {
+            stack: Vec::new(),
+            mode: Mode::Value,
+        }
+    }
+
+    fn end_value(&mut self) {
+        self.mode = if self.stack.is_empty() {
+            Mode::Done
+        } else {
+            Mode::After
+        };
+    }
+
+    fn start_value(&mut self, b: u8) -> bool {
+        self.mode = match b {
+            b'{' => {
+                self.stack.push(b'{');
+                Mode::ObjectFirst
+            }
+            b'[' => {
+                self.stack.push(b'[');
+                Mode::ArrayFirst
+            }
+            b'"' => Mode::Str {
+                key: false,
+                escape: Escape::None,
+            },
+            b'-' => Mode::Number(Number::Minus),
+            b'0' => Mode::Number(Number::Zero),
+            b'1'..=b'9' => Mode::Number(Number::Int),
+            b't' => Mode::Literal(b"rue"),
+            b'f' => Mode::Literal(b"alse"),
+            b'n' => Mode::Literal(b"ull"),
+            _ => return false,
+        };
+        true
+    }
+
+    fn close(&mut self, b: u8) -> bool {
+        let open = if b == b'}' { b'{' } else { b'[' };
+        if self.stack.last() != Some(&open) {
+            return false;
+        }
+        self.stack.pop();
+        self.end_value();
+        true
+    }
+
+    // feeds one byte, false if no JSON value continues with it
+    fn step(&mut self, b: u8) -> bool {
+        match self.mode {
+            Mode::Str { key, escape } => {
+                let escape = match (escape, b) {
+                    (Escape::None, b'"') => {
+                        if key {
+                            self.mode = Mode::Colon;
+                        } else {
+                            self.end_value();
+                        }
+                        return true;
+                    }
+                    (Escape::None, b'\\') => Escape::Backslash,
+                    (Escape::None, 0x20..=0x7f) => Escape::None,
+                    // the leading bytes of well-formed UTF-8, without overlong encodings
+                    // or surrogates
+                    (Escape::None, 0xc2..=0xdf) => Escape::Utf8(1, 0x80, 0xbf),
+                    (Escape::None, 0xe0) => Escape::Utf8(2, 0xa0, 0xbf),
+                    (Escape::None, 0xed) => Escape::Utf8(2, 0x80, 0x9f),
+                    (Escape::None, 0xe1..=0xef) => Escape::Utf8(2, 0x80, 0xbf),
+                    (Escape::None, 0xf0) => Escape::Utf8(3, 0x90, 0xbf),
+                    (Escape::None, 0xf1..=0xf3) => Escape::Utf8(3, 0x80, 0xbf),
+                    (Escape::None, 0xf4) => Escape::Utf8(3, 0x80, 0x8f),
+                    (Escape::Utf8(n, lo, hi), _) if (lo..=hi).contains(&b) => {
+                        if n == 1 {
+                            Escape::None
+                        } else {
+                            Escape::Utf8(n - 1, 0x80, 0xbf)
+                        }
+                    }
+                    (Escape::Backslash, b'"' | b'\\' | b'/' | b'b' | b'f' | b'n' | b'r' | b't') => {
+                        Escape::None
+                    }
+                    (Escape::Backslash, b'u') => Escape::Hex(4),
+                    (Escape::Hex(n), _) if b.is_ascii_hexdigit() => {
+                        if n == 1 {
+                            Escape::None
+                        } else {
+                            Escape::Hex(n - 1)
+                        }
+                    }
+                    _ => return false,
+                };
+                self.mode = Mode::Str { key, escape };
+                true
+            }
+            Mode::Number(number) => match number.step(b) {
+                Some(number) => {
+                    self.mode = Mode::Number(number);
+                    true
+                }
+                // the byte ends the number and is read again after it
+                None if number.terminal() => {
+                    self.end_value();
+                    self.step(b)
+                }
+                None => false,
+            },
+            Mode::Literal(rest) => {
+                if rest[0] != b {
+                    return false;
+                }
+                if rest.len() == 1 {
+                    self.end_value();
+                } else {
+                    self.mode = Mode::Literal(&rest[1..]);
+                }
+                true
+            }
+            _ if is_whitespace(b) => true,
+            Mode::Value => self.start_value(b),
+            Mode::ArrayFirst if b == b']' => self.close(b),
+            Mode::ArrayFirst => self.start_value(b),
+            Mode::ObjectFirst | Mode::Key if b == b'"' => {
+                self.mode = Mode::Str {
+                    key: true,
+                    escape: Escape::None,
+                };
+                true
+            }
+            Mode::ObjectFirst if b == b'}' => self.close(b),
+            Mode::Colon if b == b':' => {
+                self.mode = Mode::Value;
+                true
+            }
+            Mode::After if b == b',' => {
+                self.mode = if self.stack.last() == Some(&b'{') {
+                    Mode::Key
+                } else {
+                    Mode::Value
+                };
+                true
+            }
+            Mode::After if b == b'}' || b == b']' => self.close(b),
+            _ => false,
+        }
+    }
+
+    fn is_complete(&self) -> bool {
+        match self.mode {
+            Mode::Done => true,
+            Mode::Number(number) => self.stack.is_empty() && number.terminal(),
+            _ => false,
+        }
+    }
+
+    // the fewest bytes that still complete the value, such as `":0}` inside a key
+    fn distance(&self) -> usize {
+        let mode = match self.mode {
+            Mode::Value => 1,
+            Mode::Key => 4,
+            Mode::Colon => 2,
+            Mode::ArrayFirst | Mode::ObjectFirst | Mode::After | Mode::Done => 0,
+            Mode::Str { key, escape } => {
+                let escape = match escape {
+                    Escape::None => 0,
+                    Escape::Backslash => 1,
+                    Escape::Hex(n) | Escape::Utf8(n, _, _) => n as usize,
+                };
+                escape + 1 + if key { 2 } else { 0 }
+            }
+            Mode::Number(number) => (!number.terminal()) as usize,
+            Mode::Literal(rest) => rest.len(),
+        };
+        mode + self.stack.len()
+    }
+}
+
+// generates exactly one JSON value, with any whitespace around it; the closing distance
+// assumes that every byte the shortest completion needs is a token of its own
+#[allow(unused)]
+pub struct JsonConstraint {
+    pieces: Vec<Vec<u8>>,
+    state: JsonState,
+    consumed: usize, // how many generated tokens the state has read
+}
+
+#[allow(unused)]
+impl JsonConstraint {
+    // pieces holds the bytes of every token, see token_pieces
+    pub fn new(pieces: Vec<Vec<u8>>) -> Self {
+        JsonConstraint {
+            pieces,
+            state: JsonState::new(),
+            consumed: 0,
+        }
+    }
+
+    fn feed(state: &mut JsonState, piece: &[u8]) -> bool {
+        !piece.is_empty() && piece.iter().all(|&b| state.step(b))
+    }
+
+    // catches the state up with the generated tokens, starting over for a shorter history
+    fn advance(&mut self, generated: &[u32]) {
+        if generated.len() < self.consumed {
+            self.state = JsonState::new();
+            self.consumed = 0;
+        }
+        for &token in &generated[self.consumed..] {
+            let piece = self.pieces.get(token as usize).map_or(&[][..], |p| &p[..]);
+            assert!(
+                Self::feed(&mut self.state, piece),
+                "token {token} does not continue the JSON value"
+            );
+        }
+        self.consumed = generated.len();
+    }
+}
+
+impl Constraint for JsonConstraint {
+    fn allowed_tokens(&mut self, generated: &[u32], remaining: usize, allowed: &mut [bool]) {
+        self.advance(generated);
+        for (allowed, piece) in allowed.iter_mut().zip(&self.pieces) {
+            let mut state = self.state.clone();
+            *allowed = Self::feed(&mut state, piece)
+                && (state.is_complete() || state.distance() < remaining);
+        }
+    }
+
+    fn is_complete(&mut self, generated: &[u32]) -> bool {
+        self.advance(generated);
+        self.state.is_complete()
+    }
+}
+
+#[cfg(test)]
+fn json_accepts(text: &str) -> Option<bool> {
+    let mut state = JsonState::new();
+    text.bytes()
+        .all(|b| state.step(b))
+        .then(|| state.is_complete())
+}
+
+#[test]
+fn test_json_state() {
+    for text in [
+        r#"{"a": [1, -2.5e+3, true, null, {}], "b\"\u00e9": "x y"}"#,
+        "[]",
+        " 0 ",
+        "-0.5E2",
+        "\"hi\"\n",
+        "\"caf\u{e9} \u{65e5}\u{1f600}\"",
+        "false",
+    ] {
+        assert_eq!(json_accepts(text), Some(true), "{text}");
+    }
+    // valid so far but not finished
+    for text in [
+        "{", r#"{"a""#, "[1,", "\"\\u00", "tr", "-", "1.", "\"\u{e9}",
+    ] {
+        assert_eq!(json_accepts(text), Some(false), "{text}");
+    }
+    for text in [
+        "}",
+        "[1,]",
+        r#"{"a" 1}"#,
+        "{1: 2}",
+        "01",
+        "[1}",
+        "\"\\x\"",
+        "1 2",
+        "nul!",
+    ] {
+        assert_eq!(json_accepts(text), None, "{text}");
+    }
+}
+
+#[test]
+fn test_json_distance() {
+    let mut state = JsonState::new();
+    for (text, distance) in [
+        ("[{", 2),
+        ("\"k", 5),
+        ("\"", 4),
+        (":", 3),
+        ("t", 5),
+        ("rue", 2),
+    ] {
+        assert!(text.bytes().all(|b| state.step(b)), "{text}");
+        assert_eq!(state.distance(), distance, "{text}");
+    }
+    // closing it exactly that many bytes later
+    assert!("}]".bytes().all(|b| state.step(b)));
+    assert!(state.is_complete());
+}
+
+#[test]
+fn test_json_constraint_utf8() {
+    // "é" is 0xc3 0xa9, which byte-fallback tokens split in two
+    let pieces: Vec<Vec<u8>> = ["\"", "<0xC3>", "<0xA9>", "a", "\\"]
+        .iter()
+        .map(|p| piece_bytes(p))
+        .collect();
+    let mut constraint = JsonConstraint::new(pieces);
+    let mut allowed = [false; 5];
+    constraint.allowed_tokens(&[0], 8, &mut allowed);
+    assert_eq!(allowed, [true, true, false, true, true]);
+    // inside the character only its continuation goes, not even the closing quote
+    constraint.allowed_tokens(&[0, 1], 8, &mut allowed);
+    assert_eq!(allowed, [false, false, true, false, false]);
+    constraint.allowed_tokens(&[0, 1, 2], 8, &mut allowed);
+    assert_eq!(allowed, [true, true, false, true, true]);
+    assert!(constraint.is_complete(&[0, 1, 2, 0]));
+    // and with one token left there is no room for the continuation and the quote
+    constraint.allowed_tokens(&[0], 1, &mut allowed);
+    assert_eq!(allowed, [true, false, false, false, false]);
+}
+
+#[test]
+fn test_json_constraint() {
+    use crate::generation::GenerationConfig;
+    let model = story_model();
+    let tokenizer = story_tokenizer();
+    let pieces = token_pieces(&tokenizer);
+    // the story vocabulary has no brackets, so only strings, numbers and literals come out here
+    assert!(!pieces
+        .iter()
+        .any(|p| p.contains(&b'{') || p.contains(&b'[')));
+    let config = |seed| GenerationConfig {
+        max_len: 12,
+        top_p: 0.9,
+        top_k: 50,
+        seed: Some(seed),
+        ..Default::default()
+    };
+    let prompt = tokenizer
+        .encode("Tim said", true)
+        .unwrap()
+        .get_ids()
+        .to_vec();
+    for seed in 0..100 {
+        let mut constraint = JsonConstraint::new(pieces.clone());
+        let output = model
+            .generate_with_constraint(&prompt, &config(seed), Some(&mut constraint))
+            .unwrap();
+        assert!(output.len() <= 12);
+        let bytes: Vec<u8> = output
+            .iter()
+            .flat_map(|&t| pieces[t as usize].clone())
+            .collect();
+        let text = String::from_utf8(bytes).unwrap();
+        assert!(
+            serde_json::from_str::<serde_json::Value>(&text).is_ok(),
+            "seed {seed}: {text:?}"
+        );
+    }
+}
@@ -0,0 +1,662 @@
+use crate::operators::{GatherError, RopeCache, RopeLayout};
+use crate::params::LLamaParams;
+use crate::tensor::Tensor;
+#[cfg(test)]
+use crate::test_support::story_model;
+use cudarc::cublas::result::CublasError;
+use cudarc::cublas::sys::{cublasOperation_t, cublasStatus_t};
+use cudarc::cublas::{CudaBlas, Gemm, GemmConfig};
+use cudarc::driver::sys::CUresult;
+use cudarc::driver::{
+    CudaContext, CudaFunction, CudaSlice, CudaStream, CudaView, CudaViewMut, DevicePtr,
+    DevicePtrMut, DriverError, LaunchConfig, PushKernelArg,
+};
+use cudarc::nvrtc::CompileError;
+use std::sync::Arc;
+
+// Runs the whole forward pass on a CUDA device. Unlike WgpuBackend this is not a Backend,
+// which works on host tensors: the weights and the kv cache stay in device memory and the
+// only transfers of a step are the token ids going in and the logits coming out.
+// matmul_transb goes through cuBLAS, every other operator is a kernel compiled by NVRTC.
+pub struct CudaBackend {
+    stream: Arc<CudaStream>,
+    blas: CudaBlas,
+    kernels: Kernels,
+    config: CudaConfig,
+    embedding_table: CudaSlice<f32>, // (vocab, d)
+    layers: Vec<LayerWeights>,
+    rms_out_w: CudaSlice<f32>, // (d, )
+    lm_head: CudaSlice<f32>,   // (vocab, d)
+    rope_sin: CudaSlice<f32>,  // (max_seq_len, rotary_dim / 2)
+    rope_cos: CudaSlice<f32>,  // (max_seq_len, rotary_dim / 2)
+}
+
+// Sizes of the model, see Llama
+pub struct CudaConfig {
+    pub vocab: usize,
+    pub n_layers: usize,
+    pub n_q_h: usize,
+    pub n_kv_h: usize,
+    pub d: usize,
+    pub dqkv: usize,
+    pub di: usize,
+    pub eps: f32,
+    pub embedding_scale: f32,
+    pub max_seq_len: usize,
+    pub attn_scale: f32,
+    pub rope_layout: RopeLayout,
+}
+
+struct LayerWeights {
+    rms_att_w: CudaSlice<f32>,
+    wq: CudaSlice<f32>,
+    wk: CudaSlice<f32>,
+    wv: CudaSlice<f32>,
+    wo: CudaSlice<f32>,
+    rms_ffn_w: CudaSlice<f32>,
+    w_up: CudaSlice<f32>,
+    w_gate: CudaSlice<f32>,
+    w_down: CudaSlice<f32>,
+}
+
+struct Kernels {
+    gather: CudaFunction,
+    rms_norm: CudaFunction,
+    rope: CudaFunction,
+    attention_scores: CudaFunction,
+    masked_softmax: CudaFunction,
+    attention_values: CudaFunction,
+    swiglu: CudaFunction,
+}
+
+// The kv cache of a CudaBackend, in device memory
+pub struct CudaCache {
+    k: Vec<CudaSlice<f32>>, // (max_seq_len, n_kv_h * dqkv) x layers
+    v: Vec<CudaSlice<f32>>, // (max_seq_len, n_kv_h * dqkv) x layers
+    len: usize,
+}
+
+impl CudaCache {
+    #[allow(unused)]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+}
+
+#[derive(Debug)]
+pub enum CudaError {
+    NoDevice,
+    OutOfMemory,
+    Driver(DriverError),
+    Blas(CublasError),
+    Compile(CompileError),
+    // a feature of the model that the kernels do not implement
+    Unsupported(&'static str),
+    Gather(GatherError),
+    // the tokens of a step do not fit the kv cache
+    ContextFull { len: usize, max: usize },
+}
+
+impl std::fmt::Display for CudaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CudaError::NoDevice => write!(f, "no CUDA device"),
+            CudaError::OutOfMemory => write!(f, "out of device memory"),
+            CudaError::Driver(e) => write!(f, "CUDA driver: {e}"),
+            CudaError::Blas(e) => write!(f, "cuBLAS: {e}"),
+            CudaError::Compile(e) => write!(f, "NVRTC: {e}"),
+            CudaError::Unsupported(what) => write!(f, "not supported on CUDA: {what}"),
+            CudaError::Gather(e) => write!(f, "{e}"),
+            CudaError::ContextFull { len, max } => {
+                write!(f, "{len} tokens do not fit the kv cache of {max}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for CudaError {}
+
+impl From<DriverError> for CudaError {
+    fn from(e: DriverError) -> Self {
+        match e.0 {
+            CUresult::CUDA_ERROR_OUT_OF_MEMORY => CudaError::OutOfMemory,
+            CUresult::CUDA_ERROR_NO_DEVICE => CudaError::NoDevice,
+            _ => CudaError::Driver(e),
+        }
+    }
+}
+
+impl From<CublasError> for CudaError {
+    fn from(e: CublasError) -> Self {
+        match e.0 {
+            cublasStatus_t::CUBLAS_STATUS_ALLOC_FAILED => CudaError::OutOfMemory,
+            _ => CudaError::Blas(e),
+        }
+    }
+}
+
+impl From<CompileError> for CudaError {
+    fn from(e: CompileError) -> Self {
+        CudaError::Compile(e)
+    }
+}
+
+impl From<GatherError> for CudaError {
+    fn from(e: GatherError) -> Self {
+        CudaError::Gather(e)
+    }
+}
+
+// threads of the kernels that reduce one row per block, a power of two
+const ROW_THREADS: u32 = 256;
+
+const KERNELS: &str = r#"
+#define ROW_THREADS 256
+
+// y[i] = table[ids[i / d] * d + i % d] * scale
+extern "C" __global__ void gather(float *y, const float *table, const unsigned int *ids,
+                                  int d, int n, float scale) {
+    int i = blockIdx.x * blockDim.x + threadIdx.x;
+    if (i < n) y[i] = table[(size_t)ids[i / d] * d + i % d] * scale;
+}
+
+// sum of v over the threads of a block
+__device__ float block_sum(float v, float *partial) {
+    partial[threadIdx.x] = v;
+    __syncthreads();
+    for (int s = ROW_THREADS / 2; s > 0; s /= 2) {
+        if (threadIdx.x < s) partial[threadIdx.x] += partial[threadIdx.x + s];
+        __syncthreads();
+    }
+    float sum = partial[0];
+    __syncthreads();
+    return sum;
+}
+
+__device__ float block_max(float v, float *partial) {
+    partial[threadIdx.x] = v;
+    __syncthreads();
+    for (int s = ROW_THREADS / 2; s > 0; s /= 2) {
+        if (threadIdx.x < s) partial[threadIdx.x] = fmaxf(partial[threadIdx.x], partial[threadIdx.x + s]);
+        __syncthreads();
+    }
+    float max = partial[0];
+    __syncthreads();
+    return max;
+}
+
+// one block per row of d elements
+extern "C" __global__ void rms_norm(float *y, const float *x, const float *w, int d, float eps) {
+    __shared__ float partial[ROW_THREADS];
+    const float *row = x + (size_t)blockIdx.x * d;
+    float *out = y + (size_t)blockIdx.x * d;
+    float sum = 0.0f;
+    for (int i = threadIdx.x; i < d; i += ROW_THREADS) sum += row[i] * row[i];
+    float rms = sqrtf(block_sum(sum, partial) / d + eps);
+    for (int i = threadIdx.x; i < d; i += ROW_THREADS) out[i] = w[i] * row[i] / rms;
+}
+
+// y is (seq_len, n_heads, head_dim), one thread per rotated pair
+extern "C" __global__ void rope(float *y, const float *sin_table, const float *cos_table,
+                                int start_pos, int seq_len, int n_heads, int head_dim,
+                                int half, int interleaved) {
+    int t = blockIdx.x * blockDim.x + threadIdx.x;
+    if (t >= seq_len * n_heads * half) return;
+    int i = t % half;
+    int head = t / half;
+    int tok = head / n_heads;
+    float *h = y + (size_t)head * head_dim;
+    int ia = interleaved ? 2 * i : i;
+    int ib = interleaved ? 2 * i + 1 : i + half;
+    float s = sin_table[(size_t)(start_pos + tok) * half + i];
+    float c = cos_table[(size_t)(start_pos + tok) * half + i];
+    float a = h[ia], b = h[ib];
+    h[ia] = a * c - b * s;
+    h[ib] = b * c + a * s;
+}
+
+// scores (n_q_h, seq_len, total) = q @ k.T * scale, query head h reads kv head h / n_groups
+// q is (seq_len, n_q_h * dqkv), k is (total, n_kv_h * dqkv)
+extern "C" __global__ void attention_scores(float *scores, const float *q, const float *k,
+                                            int n_q_h, int n_groups, int seq_len, int total,
+                                            int dqkv, float scale) {
+    int t = blockIdx.x * blockDim.x + threadIdx.x;
+    if (t >= n_q_h * seq_len * total) return;
+    int j = t % total;
+    int i = (t / total) % seq_len;
+    int h = t / (total * seq_len);
+    int n_kv_h = n_q_h / n_groups;
+    const float *qh = q + ((size_t)i * n_q_h + h) * dqkv;
+    const float *kh = k + ((size_t)j * n_kv_h + h / n_groups) * dqkv;
+    float dot = 0.0f;
+    for (int e = 0; e < dqkv; e++) dot += qh[e] * kh[e];
+    scores[t] = dot * scale;
+}
+
+// one block per row of total scores, query i of the seq_len new ones sees keys up to
+// total - seq_len + i
+extern "C" __global__ void masked_softmax(float *y, int seq_len, int total) {
+    __shared__ float partial[ROW_THREADS];
+    float *row = y + (size_t)blockIdx.x * total;
+    int boundary = total - seq_len + blockIdx.x % seq_len + 1;
+    float max = -INFINITY;
+    for (int j = threadIdx.x; j < boundary; j += ROW_THREADS) max = fmaxf(max, row[j]);
+    max = block_max(max, partial);
+    float sum = 0.0f;
+    for (int j = threadIdx.x; j < boundary; j += ROW_THREADS) {
+        row[j] = expf(row[j] - max);
+        sum += row[j];
+    }
+    sum = block_sum(sum, partial);
+    for (int j = threadIdx.x; j < total; j += ROW_THREADS) row[j] = j < boundary ? row[j] / sum : 0.0f;
+}
+
+// out (seq_len, n_q_h * dqkv) = scores @ v, v is (total, n_kv_h * dqkv)
+extern "C" __global__ void attention_values(float *out, const float *scores, const float *v,
+                                            int n_q_h, int n_groups, int seq_len, int total,
+                                            int dqkv) {
+    int t = blockIdx.x * blockDim.x + threadIdx.x;
+    if (t >= seq_len * n_q_h * dqkv) return;
+    int e = t % dqkv;
+    int h = (t / dqkv) % n_q_h;
+    int i = t / (dqkv * n_q_h);
+    int n_kv_h = n_q_h / n_groups;
+    const float *s = scores + ((size_t)h * seq_len + i) * total;
+    const float *vh = v + (size_t)(h / n_groups) * dqkv + e;
+    float sum = 0.0f;
+    for (int j = 0; j < total; j++) sum += s[j] * vh[(size_t)j * n_kv_h * dqkv];
+    out[t] = sum;
+}
+
+// y = silu(x) * y
+extern "C" __global__ void swiglu(float *y, const float *x, int n) {
+    int i = blockIdx.x * blockDim.x + threadIdx.x;
+    if (i < n) y[i] *= x[i] / (1.0f + expf(-x[i]));
+}
+"#;
+
+fn elems(n: usize) -> LaunchConfig {
+    LaunchConfig::for_num_elems(n as u32)
+}
+
+fn rows(n: usize) -> LaunchConfig {
+    LaunchConfig {
+        grid_dim: (n as u32, 1, 1),
+        block_dim: (ROW_THREADS, 1, 1),
+        shared_mem_bytes: 0,
+    }
+}
+
+impl CudaBackend {
+    // Uploads params to the first CUDA device, quantized and half precision weights are
+    // dequantized to f32 on the way. NoDevice if the CUDA libraries or a device are missing.
+    pub fn new(
+        config: CudaConfig,
+        params: &LLamaParams<f32>,
+        rope: &RopeCache,
+    ) -> Result<Self, CudaError> {
+        // with dynamic loading a missing library would only panic at the first call
+        let present = unsafe {
+            cudarc::driver::sys::is_culib_present()
+                && cudarc::nvrtc::sys::is_culib_present()
+                && cudarc::cublas::sys::is_culib_present()
+        };
+        if !present {
+            return Err(CudaError::NoDevice);
+        }
+        let ctx = CudaContext::new(0)?;
+        let stream = ctx.default_stream();
+        let blas = CudaBlas::new(stream.clone())?;
+        let module = ctx.load_module(cudarc::nvrtc::compile_ptx(KERNELS)?)?;
+        let kernels = Kernels {
+            gather: module.load_function("gather")?,
+            rms_norm: module.load_function("rms_norm")?,
+            rope: module.load_function("rope")?,
+            attention_scores: module.load_function("attention_scores")?,
+            masked_softmax: module.load_function("masked_softmax")?,
+            attention_values: module.load_function("attention_values")?,
+            swiglu: module.load_function("swiglu")?,
+        };
+
+        let upload = |t: &Tensor<f32>| stream.clone_htod(t.data());
+        let layers = (0..config.n_layers)
+            .map(|l| {
+                Ok(LayerWeights {
+                    rms_att_w: upload(&params.rms_att_w[l])?,
+                    wq: upload(&params.wq[l].to_f32())?,
+                    wk: upload(&params.wk[l].to_f32())?,
+                    wv: upload(&params.wv[l].to_f32())?,
+                    wo: upload(&params.wo[l].to_f32())?,
+                    rms_ffn_w: upload(&params.rms_ffn_w[l])?,
+                    w_up: upload(&params.w_up[l].to_f32())?,
+                    w_gate: upload(&params.w_gate[l].to_f32())?,
+                    w_down: upload(&params.w_down[l].to_f32())?,
+                })
+            })
+            .collect::<Result<Vec<_>, DriverError>>()?;
+        let lm_head = match &params.lm_head_quantized {
+            Some(lm_head) => upload(&lm_head.to_f32())?,
+            None => upload(&params.lm_head)?,
+        };
+        let (sin, cos) = rope.tables(config.max_seq_len);
+        Ok(CudaBackend {
+            embedding_table: upload(&params.embedding_table.to_f32())?,
+            layers,
+            rms_out_w: upload(&params.rms_out_w)?,
+            lm_head,
+            rope_sin: stream.clone_htod(&sin)?,
+            rope_cos: stream.clone_htod(&cos)?,
+            stream,
+            blas,
+            kernels,
+            config,
+        })
+    }
+
+    #[allow(unused)]
+    pub fn new_cache(&self) -> Result<CudaCache, CudaError> {
+        let c = &self.config;
+        let size = c.max_seq_len * c.n_kv_h * c.dqkv;
+        let alloc = || self.stream.alloc_zeros::<f32>(size);
+        Ok(CudaCache {
+            k: (0..c.n_layers).map(|_| alloc()).collect::<Result<_, _>>()?,
+            v: (0..c.n_layers).map(|_| alloc()).collect::<Result<_, _>>()?,
+            len: 0,
+        })
+    }
+
+    // Llama::forward on the device, (1, vocab) logits of the last token.
+    // Fails on token ids outside the vocab and on steps past max_seq_len, leaving the
+    // cache untouched.
+    #[allow(unused)]
+    pub fn forward(&self, input: &[u32], cache: &mut CudaCache) -> Result<Tensor<f32>, CudaError> {
+        let c = &self.config;
+        let seq_len = input.len();
+        let past_seq_len = cache.len;
+        let total_seq_len = past_seq_len + seq_len;
+        if total_seq_len > c.max_seq_len {
+            return Err(CudaError::ContextFull {
+                len: total_seq_len,
+                max: c.max_seq_len,
+            });
+        }
+        if let Some((pos, &index)) = input
+            .iter()
+            .enumerate()
+            .find(|(_, &i)| i as usize >= c.vocab)
+        {
+            return Err(GatherError::IndexOutOfRange {
+                pos,
+                index,
+                rows: c.vocab,
+            }
+            .into());
+        }
+        let n_groups = c.n_q_h / c.n_kv_h;
+        let kv_dim = c.n_kv_h * c.dqkv;
+        let q_dim = c.n_q_h * c.dqkv;
+
+        let ids = self.stream.clone_htod(input)?;
+        let mut residual = self.stream.alloc_zeros::<f32>(seq_len * c.d)?;
+        let mut hidden_states = self.stream.alloc_zeros::<f32>(seq_len * c.d)?;
+        let mut q = self.stream.alloc_zeros::<f32>(seq_len * q_dim)?;
+        let mut att_scores = self
+            .stream
+            .alloc_zeros::<f32>(c.n_q_h * seq_len * total_seq_len)?;
+        let mut gate_buf = self.stream.alloc_zeros::<f32>(seq_len * c.di)?;
+        let mut up_buf = self.stream.alloc_zeros::<f32>(seq_len * c.di)?;
+
+        self.gather(&mut residual, &ids, seq_len)?;
+        for (layer, w) in self.layers.iter().enumerate() {
+            self.rms_norm(
+                &mut hidden_states.slice_mut(..),
+                &residual.slice(..),
+                &w.rms_att_w,
+            )?;
+            let new_kv = past_seq_len * kv_dim..total_seq_len * kv_dim;
+            let mut k = cache.k[layer].slice_mut(new_kv.clone());
+            let mut v = cache.v[layer].slice_mut(new_kv);
+            self.matmul_transb(&mut q, 0., &hidden_states, &w.wq, seq_len, q_dim, c.d, 1.)?;
+            self.matmul_transb(&mut k, 0., &hidden_states, &w.wk, seq_len, kv_dim, c.d, 1.)?;
+            self.matmul_transb(&mut v, 0., &hidden_states, &w.wv, seq_len, kv_dim, c.d, 1.)?;
+            self.rope(&mut q.slice_mut(..), past_seq_len, seq_len, c.n_q_h)?;
+            self.rope(&mut k, past_seq_len, seq_len, c.n_kv_h)?;
+
+            let full_k = cache.k[layer].slice(..total_seq_len * kv_dim);
+            let full_v = cache.v[layer].slice(..total_seq_len * kv_dim);
+            let (n_q_h, n_groups, seq, total, dqkv) = (
+                c.n_q_h as i32,
+                n_groups as i32,
+                seq_len as i32,
+                total_seq_len as i32,
+                c.dqkv as i32,
+            );
+            let mut launch = self.stream.launch_builder(&self.kernels.attention_scores);
+            launch.arg(&mut att_scores).arg(&q).arg(&full_k);
+            launch
+                .arg(&n_q_h)
+                .arg(&n_groups)
+                .arg(&seq)
+                .arg(&total)
+                .arg(&dqkv);
+            launch.arg(&c.attn_scale);
+            unsafe { launch.launch(elems(c.n_q_h * seq_len * total_seq_len)) }?;
+            let mut launch = self.stream.launch_builder(&self.kernels.masked_softmax);
+            launch.arg(&mut att_scores).arg(&seq).arg(&total);
+            unsafe { launch.launch(rows(c.n_q_h * seq_len)) }?;
+            let mut launch = self.stream.launch_builder(&self.kernels.attention_values);
+            launch.arg(&mut hidden_states).arg(&att_scores).arg(&full_v);
+            launch
+                .arg(&n_q_h)
+                .arg(&n_groups)
+                .arg(&seq)
+                .arg(&total)
+                .arg(&dqkv);
+            unsafe { launch.launch(elems(seq_len * q_dim)) }?;
+            // out = attn_V @ O_weight.T, added onto the residual through beta
+            self.matmul_transb(
+                &mut residual,
+                1.,
+                &hidden_states,
+                &w.wo,
+                seq_len,
+                c.d,
+                q_dim,
+                1.,
+            )?;
+
+            self.rms_norm(
+                &mut hidden_states.slice_mut(..),
+                &residual.slice(..),
+                &w.rms_ffn_w,
+            )?;
+            self.matmul_transb(
+                &mut gate_buf,
+                0.,
+                &hidden_states,
+                &w.w_gate,
+                seq_len,
+                c.di,
+                c.d,
+                1.,
+            )?;
+            self.matmul_transb(
+                &mut up_buf,
+                0.,
+                &hidden_states,
+                &w.w_up,
+                seq_len,
+                c.di,
+                c.d,
+                1.,
+            )?;
+            let n = (seq_len * c.di) as i32;
+            let mut launch = self.stream.launch_builder(&self.kernels.swiglu);
+            launch.arg(&mut up_buf).arg(&gate_buf).arg(&n);
+            unsafe { launch.launch(elems(seq_len * c.di)) }?;
+            self.matmul_transb(
+                &mut residual,
+                1.,
+                &up_buf,
+                &w.w_down,
+                seq_len,
+                c.d,
+                c.di,
+                1.,
+            )?;
+        }
+
+        let last = (seq_len - 1) * c.d..seq_len * c.d;
+        self.rms_norm(
+            &mut hidden_states.slice_mut(..c.d),
+            &residual.slice(last),
+            &self.rms_out_w,
+        )?;
+        let mut logits = self.stream.alloc_zeros::<f32>(c.vocab)?;
+        let hidden_states = hidden_states.slice(..c.d);
+        self.matmul_transb(
+            &mut logits,
+            0.,
+            &hidden_states,
+            &self.lm_head,
+            1,
+            c.vocab,
+            c.d,
+            1.,
+        )?;
+        let logits = self.stream.clone_dtoh(&logits)?;
+        cache.len = total_seq_len;
+        Ok(Tensor::new(logits, &vec![1, c.vocab]))
+    }
+
+    fn gather(
+        &self,
+        y: &mut CudaSlice<f32>,
+        ids: &CudaSlice<u32>,
+        seq_len: usize,
+    ) -> Result<(), CudaError> {
+        let (d, n) = (self.config.d as i32, (seq_len * self.config.d) as i32);
+        let mut launch = self.stream.launch_builder(&self.kernels.gather);
+        launch
+            .arg(y)
+            .arg(&self.embedding_table)
+            .arg(ids)
+            .arg(&d)
+            .arg(&n)
+            .arg(&self.config.embedding_scale);
+        unsafe { launch.launch(elems(seq_len * self.config.d)) }?;
+        Ok(())
+    }
+
+    // every row of d elements of x normalized into y
+    fn rms_norm(
+        &self,
+        y: &mut CudaViewMut<f32>,
+        x: &CudaView<f32>,
+        w: &CudaSlice<f32>,
+    ) -> Result<(), CudaError> {
+        let d = self.config.d as i32;
+        let mut launch = self.stream.launch_builder(&self.kernels.rms_norm);
+        launch.arg(y).arg(x).arg(w).arg(&d).arg(&self.config.eps);
+        unsafe { launch.launch(rows(x.len() / self.config.d)) }?;
+        Ok(())
+    }
+
+    // y is (seq_len, n_heads, dqkv)
+    fn rope(
+        &self,
+        y: &mut CudaViewMut<f32>,
+        start_pos: usize,
+        seq_len: usize,
+        n_heads: usize,
+    ) -> Result<(), CudaError> {
+        let half = self.rope_sin.len() / self.config.max_seq_len;
+        let interleaved = match self.config.rope_layout {
+            RopeLayout::Neox => 0i32,
+            RopeLayout::Interleaved => 1,
+        };
+        let (start_pos, seq, heads, head_dim, half_i) = (
+            start_pos as i32,
+            seq_len as i32,
+            n_heads as i32,
+            self.config.dqkv as i32,
+            half as i32,
+        );
+        let mut launch = self.stream.launch_builder(&self.kernels.rope);
+        launch.arg(y).arg(&self.rope_sin).arg(&self.rope_cos);
+        launch
+            .arg(&start_pos)
+            .arg(&seq)
+            .arg(&heads)
+            .arg(&head_dim)
+            .arg(&half_i);
+        launch.arg(&interleaved);
+        unsafe { launch.launch(elems(seq_len * n_heads * half)) }?;
+        Ok(())
+    }
+
+    // C (m, n) = beta * C + alpha * A (m, k) @ B (n, k).T, all row-major.
+    // Read column-major, that is C.T = B @ A.T, which is how cuBLAS is asked for it.
+    #[allow(clippy::too_many_arguments)]
+    fn matmul_transb(
+        &self,
+        c: &mut impl DevicePtrMut<f32>,
+        beta: f32,
+        a: &impl DevicePtr<f32>,
+        b: &CudaSlice<f32>,
+        m: usize,
+        n: usize,
+        k: usize,
+        alpha: f32,
+    ) -> Result<(), CudaError> {
+        let config = GemmConfig {
+            transa: cublasOperation_t::CUBLAS_OP_T,
+            transb: cublasOperation_t::CUBLAS_OP_N,
+            m: n as i32,
+            n: m as i32,
+            k: k as i32,
+            alpha,
+            lda: k as i32,
+            ldb: k as i32,
+            beta,
+            ldc: n as i32,
+        };
+        unsafe { self.blas.gemm(config, b, a, c) }?;
+        Ok(())
+    }
+}
+
+#[test]
+fn test_cuda_decode_step() {
+    let model = story_model();
+    let cuda = match model.to_cuda() {
+        Ok(cuda) => cuda,
+        Err(CudaError::NoDevice) => {
+            eprintln!("no CUDA device, skipping");
+            return;
+        }
+        Err(e) => panic!("{e}"),
+    };
+    let mut cache = model.new_cache();
+    let mut cuda_cache = cuda.new_cache().unwrap();
+    let prompt = Tensor::<u32>::new(vec![1, 300, 25, 700, 40], &vec![5]);
+    let expected = model.forward(&prompt, &mut cache).unwrap();
+    let logits = cuda.forward(prompt.data(), &mut cuda_cache).unwrap();
+    assert!(logits.max_abs_diff(&expected) < 1e-3);
+    // one decode step reading the device kv cache
+    let expected = model
+        .forward(&Tensor::new(vec![99], &vec![1]), &mut cache)
+        .unwrap();
+    let logits = cuda.forward(&[99], &mut cuda_cache).unwrap();
+    assert!(logits.max_abs_diff(&expected) < 1e-3);
+    assert_eq!(cuda_cache.len(), 6);
+    assert!(matches!(
+        cuda.forward(&[3, 2048], &mut cuda_cache),
+        Err(CudaError::Gather(GatherError::IndexOutOfRange {
+            pos: 1,
+            index: 2048,
+            rows: 2048
+        }))
+    ));
+    assert_eq!(cuda_cache.len(), 6);
+}
@@ -0,0 +1,969 @@
+use crate::model::AttentionSink;
+use crate::operators as OP;
+use crate::tensor::Tensor;
+use std::collections::HashMap;
+use std::time::Duration;
+
+// sampling and stopping settings of Llama::generate, see OP::random_sample. It reads from
+// JSON with every field optional, the missing ones taking the defaults, and
+// GenerationConfig::builder() sets them one by one.
+#[derive(Clone, Debug, PartialEq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GenerationConfig {
+    pub max_len: usize,
+    pub top_p: f32,
+    pub top_k: u32,
+    pub temperature: f32,
+    pub min_p: f32,     // 0 disables it
+    pub typical_p: f32, // 1 disables it
+    // CTRL repetition penalty of the tokens among the last penalty_last_n of the prompt
+    // and the output, 1 disables it
+    pub repetition_penalty: f32,
+    pub penalty_last_n: usize,
+    // OpenAI style penalties of the tokens generated so far, applied after the repetition
+    // penalty, 0 disables them
+    pub frequency_penalty: f32,
+    pub presence_penalty: f32,
+    // no n-gram of this size occurs twice in the prompt and the output, 0 disables it
+    pub no_repeat_ngram_size: usize,
+    // penalizes the tokens that would extend a repeat of earlier text, see processor::Dry
+    pub dry: Option<DryParams>,
+    // added onto the logits of these tokens every step, -100 and below bans the token
+    pub logit_bias: HashMap<u32, f32>,
+    // never generated, suppress_special also bans the bos token
+    pub banned_tokens: Vec<u32>,
+    pub suppress_special: bool,
+    // replaces top_k, top_p, min_p and typical_p with Mirostat v2 when set
+    pub mirostat: Option<OP::MirostatParams>,
+    // replaces the sampling, mirostat included, with contrastive search when set
+    pub contrastive: Option<ContrastiveSearch>,
+    // None fails a generation that would go past the cache with ContextOverflow, unless
+    // stop_at_context_limit
+    pub attention_sink: Option<AttentionSink>,
+    // the cache holds the first n_keep tokens and the last window_len, the tokens keeping
+    // their positions in the text; an attention sink of absolute positions, see sink()
+    pub sliding_window: Option<SlidingWindow>,
+    // drafts the tokens that followed an earlier occurrence of the last n-gram and checks
+    // them in one forward, only when decoding greedily without sinks, whose output it
+    // leaves as it is
+    pub prompt_lookup: Option<PromptLookup>,
+    // the generation finishes once this much time has passed since it started, checked
+    // after every token and before every forward; seconds in JSON
+    #[serde(deserialize_with = "seconds")]
+    pub max_time: Option<Duration>,
+    // the prompt and the output together, which never go past max_seq_len anyway
+    pub max_total_tokens: Option<usize>,
+    // a generation that fills the cache finishes with ContextFull instead of failing
+    pub stop_at_context_limit: bool,
+    // generate_in keeps the entries of the longest common prefix of its cache and the
+    // prompt and only feeds the rest, false feeds the whole prompt again
+    pub prefix_cache: bool,
+    // generate_text and generate_with_callback end the text right before the first of
+    // these; the wrappers that return tokens have no text to look for them in and reject
+    // them, see validate_tokens
+    pub stop: Vec<String>,
+    // this many of the most likely alternatives come with every token of generate_logprobs,
+    // and generate_text returns its tokens with them when set
+    pub logprobs: Option<usize>,
+    // the same seed, prompt and settings always give the same tokens, None seeds from entropy
+    pub seed: Option<u64>,
+}
+
+// what the binary has always sampled with, every penalty and extra filter off
+impl Default for GenerationConfig {
+    fn default() -> Self {
+        GenerationConfig {
+            max_len: 500,
+            top_p: 0.8,
+            top_k: 30,
+            temperature: 1.,
+            min_p: 0.,
+            typical_p: 1.,
+            repetition_penalty: 1.,
+            penalty_last_n: 64,
+            frequency_penalty: 0.,
+            presence_penalty: 0.,
+            no_repeat_ngram_size: 0,
+            dry: None,
+            logit_bias: HashMap::new(),
+            banned_tokens: vec![],
+            suppress_special: false,
+            mirostat: None,
+            contrastive: None,
+            attention_sink: None,
+            sliding_window: None,
+            prompt_lookup: None,
+            max_time: None,
+            max_total_tokens: None,
+            stop_at_context_limit: false,
+            prefix_cache: true,
+            stop: vec![],
+            logprobs: None,
+            seed: None,
+        }
+    }
+}
+
+fn seconds<'de, D: serde::Deserializer<'de>>(d: D) -> Result<Option<Duration>, D::Error> {
+    let seconds: Option<f64> = serde::Deserialize::deserialize(d)?;
+    seconds
+        .map(|s| Duration::try_from_secs_f64(s).map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+// Contrastive search (Su et al.) picks, among the k most likely tokens, the one with the
+// best (1 - alpha) * p - alpha * the largest cosine similarity of its hidden state with one
+// of the context, which keeps greedy decoding from going round in circles
+#[allow(unused)]
+#[derive(Clone, Copy, Debug, PartialEq, serde::Deserialize)]
+pub struct ContrastiveSearch {
+    pub k: usize,
+    pub alpha: f32,
+}
+
+// evicts the oldest tokens but the first n_keep, such as a system prompt, once the cache
+// holds window_len more
+#[allow(unused)]
+#[derive(Clone, Copy, Debug, PartialEq, serde::Deserialize)]
+pub struct SlidingWindow {
+    pub window_len: usize,
+    #[serde(default)]
+    pub n_keep: usize,
+}
+
+// the index of the candidate contrastive search goes for, given the probabilities of the
+// candidates, their hidden states and those of the context, d values each
+pub fn contrastive_pick(
+    probs: &[f32],
+    hidden: &[f32],
+    context: &[f32],
+    d: usize,
+    alpha: f32,
+) -> usize {
+    assert!(
+        !probs.is_empty() && hidden.len() == probs.len() * d && context.len().is_multiple_of(d)
+    );
+    let norm = |v: &[f32]| {
+        v.iter()
+            .map(|x| x * x)
+            .sum::<f32>()
+            .sqrt()
+            .max(f32::MIN_POSITIVE)
+    };
+    let context_norms: Vec<f32> = context.chunks(d).map(norm).collect();
+    let score = |(p, h): (&f32, &[f32])| {
+        let h_norm = norm(h);
+        let degeneration = context
+            .chunks(d)
+            .zip(&context_norms)
+            .map(|(c, c_norm)| c.iter().zip(h).map(|(a, b)| a * b).sum::<f32>() / (c_norm * h_norm))
+            .fold(f32::NEG_INFINITY, f32::max);
+        // no context, nothing to be similar to
+        let degeneration = if context.is_empty() { 0. } else { degeneration };
+        (1. - alpha) * p - alpha * degeneration
+    };
+    // the first of equal scores, which come most likely first
+    let mut best = (0, f32::NEG_INFINITY);
+    for (i, s) in probs.iter().zip(hidden.chunks(d)).map(score).enumerate() {
+        if s > best.1 {
+            best = (i, s);
+        }
+    }
+    best.0
+}
+
+// DRY takes multiplier * base^(n - allowed_length) off the logit of a token that would
+// make a repeat of n >= allowed_length tokens longer. No repeat goes across one of the
+// sequence breakers.
+#[allow(unused)]
+#[derive(Clone, Debug, PartialEq, serde::Deserialize)]
+pub struct DryParams {
+    pub multiplier: f32,
+    pub base: f32,
+    pub allowed_length: usize,
+    #[serde(default)]
+    pub sequence_breakers: Vec<u32>,
+}
+
+// prompt lookup decoding matches the last ngram tokens and drafts up to max_draft
+#[allow(unused)]
+#[derive(Clone, Copy, Debug, PartialEq, serde::Deserialize)]
+pub struct PromptLookup {
+    pub ngram: usize,
+    pub max_draft: usize,
+}
+
+// The n-grams of a growing sequence by a rolling hash, each mapped to where its latest
+// occurrence with a token after it ends.
+#[derive(Clone)]
+pub struct NgramIndex {
+    n: usize,
+    ends: HashMap<u64, usize>,
+    hash: u64, // of the last n tokens indexed
+    pow: u64,  // BASE^n, which a token leaving the window was multiplied by
+    len: usize,
+}
+
+impl NgramIndex {
+    const BASE: u64 = 0x100_0000_01b3;
+
+    pub fn new(n: usize) -> Self {
+        assert!(n > 0);
+        NgramIndex {
+            n,
+            ends: HashMap::new(),
+            hash: 0,
+            pow: Self::BASE.wrapping_pow(n as u32),
+            len: 0,
+        }
+    }
+
+    // indexes the tokens appended to the sequence since the last call
+    pub fn update(&mut self, tokens: &[u32]) {
+        for i in self.len..tokens.len() {
+            if i >= self.n {
+                // tokens[i] follows the n-gram ending here
+                self.ends.insert(self.hash, i);
+            }
+            self.hash = self
+                .hash
+                .wrapping_mul(Self::BASE)
+                .wrapping_add(tokens[i] as u64 + 1);
+            if i >= self.n {
+                let out = (tokens[i - self.n] as u64 + 1).wrapping_mul(self.pow);
+                self.hash = self.hash.wrapping_sub(out);
+            }
+        }
+        self.len = tokens.len();
+    }
+
+    // up to max tokens that followed the last n tokens of the indexed sequence before
+    pub fn propose<'t>(&self, tokens: &'t [u32], max: usize) -> &'t [u32] {
+        debug_assert_eq!(tokens.len(), self.len);
+        let len = tokens.len();
+        match self.ends.get(&self.hash) {
+            // not a collision of the hashes
+            Some(&end) if len >= self.n && tokens[end - self.n..end] == tokens[len - self.n..] => {
+                &tokens[end..(end + max).min(len)]
+            }
+            _ => &[],
+        }
+    }
+}
+
+// why a generation ended
+#[allow(unused)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize)]
+pub enum FinishReason {
+    Eos,
+    MaxLen,
+    ContextFull, // max_seq_len without an attention sink, under stop_at_context_limit
+    Constraint,  // complete, or at a dead end
+    Stop,        // a stop string, or on_token
+    Cancelled,
+    TimeLimit,  // max_time
+    TokenLimit, // max_total_tokens
+    Error,      // an operator failed
+}
+
+// why Llama::generate and friends could not generate
+#[derive(Clone, Debug, PartialEq)]
+pub enum GenerateError {
+    // found before any forward
+    PromptTooLong {
+        len: usize,
+        max_position_embeddings: usize,
+    },
+    // the prompt and the output would take needed positions of a cache of max, found before
+    // feeding them
+    ContextOverflow {
+        needed: usize,
+        max: usize,
+    },
+    Operator(OP::OperatorError),
+}
+
+impl std::fmt::Display for GenerateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GenerateError::PromptTooLong {
+                len,
+                max_position_embeddings,
+            } => write!(
+                f,
+                "the prompt is {len} tokens, longer than max_position_embeddings {max_position_embeddings}"
+            ),
+            GenerateError::ContextOverflow { needed, max } => write!(
+                f,
+                "the generation needs a context of {needed} tokens, more than the {max} of the cache"
+            ),
+            GenerateError::Operator(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for GenerateError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            GenerateError::Operator(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<OP::OperatorError> for GenerateError {
+    fn from(e: OP::OperatorError) -> Self {
+        match e {
+            OP::OperatorError::ContextOverflow { needed, max } => {
+                GenerateError::ContextOverflow { needed, max }
+            }
+            e => GenerateError::Operator(e),
+        }
+    }
+}
+
+// what Llama::generate_with_callback reports, in this order
+#[allow(unused)]
+#[derive(Clone, Debug, PartialEq)]
+pub enum GenerationEvent {
+    PrefillDone { n_tokens: usize, ms: f64 },
+    TokenGenerated { id: u32, text_piece: String },
+    Finished { reason: FinishReason, rest: String },
+}
+
+// a generated token with its log probability under the distribution it was sampled from,
+// after the penalties, biases and bans but before the temperature and truncation
+#[derive(Clone, Debug, PartialEq, serde::Serialize)]
+pub struct GeneratedToken {
+    pub id: u32,
+    pub logprob: f32,
+    pub top: Vec<(u32, f32)>, // the most likely tokens there, most likely first
+}
+
+// how a speculative generation went, or one with prompt lookup
+#[allow(unused)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct GenerationStats {
+    pub tokens: usize,         // generated
+    pub prefill_tokens: usize, // prompt tokens fed through the model, shared ones not included
+    pub forwards: usize,       // forward calls of the model generating them, the prefill included
+    pub drafted: usize,        // draft tokens proposed
+    pub accepted: usize,       // and kept
+    // prompt tokens whose entries the cache of generate_in held already, and were not fed
+    pub cached_prompt_tokens: usize,
+}
+
+#[allow(unused)]
+impl GenerationStats {
+    // 1 when nothing was drafted
+    pub fn acceptance_rate(&self) -> f32 {
+        if self.drafted == 0 {
+            1.
+        } else {
+            self.accepted as f32 / self.drafted as f32
+        }
+    }
+
+    pub fn tokens_per_forward(&self) -> f32 {
+        self.tokens as f32 / self.forwards.max(1) as f32
+    }
+}
+
+// one of the samples of Llama::generate_n
+#[allow(unused)]
+#[derive(Clone, Debug, PartialEq)]
+pub struct GenerationResult {
+    pub tokens: Vec<u32>,
+    pub finish_reason: FinishReason,
+    pub stats: GenerationStats,
+}
+
+// a sequence beam search came up with, score being logprob / len^length_penalty
+#[allow(unused)]
+#[derive(Clone, Debug, PartialEq)]
+pub struct Hypothesis {
+    pub tokens: Vec<u32>, // ending in an eos token unless max_new_tokens cut it off
+    pub logprob: f32,     // the sum of the log probabilities of the tokens
+    pub score: f32,
+}
+
+// The bookkeeping of beam search, apart from the model: every step extends the live
+// hypotheses by the width most likely tokens of each, keeps the width best extensions by
+// logprob and retires those ending in eos that rank among the best width, which are never
+// extended again. It is done once width hypotheses are retired or none is live.
+pub struct BeamSearch {
+    width: usize,
+    length_penalty: f32,
+    eos: Vec<u32>,
+    live: Vec<Hypothesis>,
+    finished: Vec<Hypothesis>,
+}
+
+#[allow(unused)]
+impl BeamSearch {
+    pub fn new(width: usize, length_penalty: f32, eos: &[u32]) -> Self {
+        assert!(width > 0);
+        BeamSearch {
+            width,
+            length_penalty,
+            eos: eos.to_vec(),
+            live: vec![Hypothesis {
+                tokens: vec![],
+                logprob: 0.,
+                score: 0.,
+            }],
+            finished: vec![],
+        }
+    }
+
+    pub fn live(&self) -> &[Hypothesis] {
+        &self.live
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.live.is_empty() || self.finished.len() >= self.width
+    }
+
+    fn score(&self, logprob: f32, len: usize) -> f32 {
+        if len == 0 {
+            logprob
+        } else {
+            logprob / (len as f32).powf(self.length_penalty)
+        }
+    }
+
+    // logits[i] is what follows live()[i]; returns the index of the hypothesis each one
+    // live afterwards extends
+    pub fn step(&mut self, logits: &[Tensor<f32>]) -> Vec<usize> {
+        assert_eq!(logits.len(), self.live.len());
+        let mut candidates = Vec::with_capacity(self.live.len() * self.width);
+        for (i, (beam, logits)) in self.live.iter().zip(logits).enumerate() {
+            let (_, top) = OP::logprobs(logits, 0, self.width);
+            for (token, logprob) in top {
+                candidates.push((i, token, beam.logprob + logprob));
+            }
+        }
+        // stable, so ties go to the earlier beam and the more likely token
+        candidates.sort_by(|a, b| b.2.total_cmp(&a.2));
+        let mut live = Vec::with_capacity(self.width);
+        let mut parents = Vec::with_capacity(self.width);
+        for (rank, (i, token, logprob)) in candidates.into_iter().enumerate() {
+            if live.len() == self.width {
+                break;
+            }
+            let is_eos = self.eos.contains(&token);
+            if is_eos && rank >= self.width {
+                continue;
+            }
+            let mut tokens = self.live[i].tokens.clone();
+            tokens.push(token);
+            let score = self.score(logprob, tokens.len());
+            let hypothesis = Hypothesis {
+                tokens,
+                logprob,
+                score,
+            };
+            if is_eos {
+                self.finished.push(hypothesis);
+            } else {
+                live.push(hypothesis);
+                parents.push(i);
+            }
+        }
+        self.live = live;
+        parents
+    }
+
+    // every retired hypothesis and the live ones, best score first
+    pub fn finish(self) -> Vec<Hypothesis> {
+        let mut all = self.finished;
+        all.extend(self.live);
+        all.sort_by(|a, b| b.score.total_cmp(&a.score));
+        all
+    }
+}
+
+impl GenerationConfig {
+    #[allow(unused)]
+    pub fn builder() -> GenerationConfigBuilder {
+        GenerationConfigBuilder {
+            config: GenerationConfig::default(),
+        }
+    }
+
+    // the attention sink that generate evicts the cache with, if any
+    pub fn sink(&self) -> Option<AttentionSink> {
+        let window = self.sliding_window.map(|w| AttentionSink {
+            n_sink: w.n_keep,
+            window: w.window_len,
+            absolute_positions: true,
+        });
+        self.attention_sink.or(window)
+    }
+
+    // settings that make no sense whatever the model
+    pub fn validate(&self) -> Result<(), String> {
+        // NaN fails every one of these
+        let in_unit = |name: &str, v: f32| {
+            if (0. ..=1.).contains(&v) {
+                Ok(())
+            } else {
+                Err(format!("{name} {v} is not within [0, 1]"))
+            }
+        };
+        if self.temperature.is_nan() || self.temperature < 0. {
+            return Err(format!("temperature {} is negative", self.temperature));
+        }
+        in_unit("top_p", self.top_p)?;
+        in_unit("min_p", self.min_p)?;
+        in_unit("typical_p", self.typical_p)?;
+        if self.typical_p == 0. {
+            return Err("typical_p 0 keeps no token".to_string());
+        }
+        if self.repetition_penalty.is_nan() || self.repetition_penalty <= 0. {
+            return Err(format!(
+                "repetition_penalty {} is not positive",
+                self.repetition_penalty
+            ));
+        }
+        if !self.frequency_penalty.is_finite() || !self.presence_penalty.is_finite() {
+            return Err("frequency_penalty and presence_penalty have to be finite".to_string());
+        }
+        if let Some(bias) = self.logit_bias.values().find(|b| b.is_nan()) {
+            return Err(format!("logit_bias {bias} is not a number"));
+        }
+        if let Some(params) = self.mirostat {
+            if params.tau.is_nan() || params.tau <= 0. || params.eta.is_nan() || params.eta < 0. {
+                return Err(format!("mirostat {params:?} needs tau > 0 and eta >= 0"));
+            }
+        }
+        if let Some(dry) = &self.dry {
+            if !(dry.multiplier >= 0. && dry.base >= 1. && dry.multiplier.is_finite())
+                || dry.base.is_infinite()
+            {
+                return Err(format!("dry {dry:?} needs multiplier >= 0 and base >= 1"));
+            }
+        }
+        if let Some(contrastive) = self.contrastive {
+            if contrastive.k == 0
+                || contrastive.alpha.is_nan()
+                || !(0. ..=1.).contains(&contrastive.alpha)
+            {
+                return Err(format!(
+                    "contrastive {contrastive:?} needs k > 0 and alpha within [0, 1]"
+                ));
+            }
+        }
+        if let Some(lookup) = self.prompt_lookup {
+            if lookup.ngram == 0 || lookup.max_draft == 0 {
+                return Err(format!(
+                    "prompt_lookup {lookup:?} needs an ngram and a max_draft"
+                ));
+            }
+        }
+        if let Some(window) = self.sliding_window {
+            if window.window_len == 0 {
+                return Err(format!("sliding_window {window:?} needs a window_len"));
+            }
+            if self.attention_sink.is_some() {
+                return Err("attention_sink and sliding_window are both set".to_string());
+            }
+        }
+        if self.stop.iter().any(|s| s.is_empty()) {
+            return Err("empty stop string".to_string());
+        }
+        Ok(())
+    }
+
+    // validate, for a generation of tokens rather than text, which cannot stop at a string
+    pub fn validate_tokens(&self) -> Result<(), String> {
+        self.validate()?;
+        if !self.stop.is_empty() {
+            return Err(format!(
+                "stop {:?} needs the text of the tokens, see generate_text",
+                self.stop
+            ));
+        }
+        Ok(())
+    }
+
+    // settings that cannot apply to a model with a vocab of vocab tokens
+    pub fn check(&self, vocab: usize) -> Result<(), String> {
+        if let Some(id) = self.logit_bias.keys().find(|&&id| id as usize >= vocab) {
+            return Err(format!(
+                "logit_bias token {id} is out of range for a vocab of {vocab}"
+            ));
+        }
+        if let Some(id) = self.banned_tokens.iter().find(|&&id| id as usize >= vocab) {
+            return Err(format!(
+                "banned token {id} is out of range for a vocab of {vocab}"
+            ));
+        }
+        Ok(())
+    }
+}
+
+// GenerationConfig::default() with the settings changed one call at a time, validated by build
+pub struct GenerationConfigBuilder {
+    config: GenerationConfig,
+}
+
+#[allow(unused)]
+impl GenerationConfigBuilder {
+    pub fn max_len(mut self, max_len: usize) -> Self {
+        self.config.max_len = max_len;
+        self
+    }
+
+    pub fn top_p(mut self, top_p: f32) -> Self {
+        self.config.top_p = top_p;
+        self
+    }
+
+    pub fn top_k(mut self, top_k: u32) -> Self {
+        self.config.top_k = top_k;
+        self
+    }
+
+    pub fn temperature(mut self, temperature: f32) -> Self {
+        self.config.temperature = temperature;
+        self
+    }
+
+    pub fn min_p(mut self, min_p: f32) -> Self {
+        self.config.min_p = min_p;
+        self
+    }
+
+    pub fn typical_p(mut self, typical_p: f32) -> Self {
+        self.config.typical_p = typical_p;
+        self
+    }
+
+    pub fn repetition_penalty(mut self, penalty: f32, last_n: usize) -> Self {
+        self.config.repetition_penalty = penalty;
+        self.config.penalty_last_n = last_n;
+        self
+    }
+
+    pub fn frequency_penalty(mut self, penalty: f32) -> Self {
+        self.config.frequency_penalty = penalty;
+        self
+    }
+
+    pub fn presence_penalty(mut self, penalty: f32) -> Self {
+        self.config.presence_penalty = penalty;
+        self
+    }
+
+    pub fn no_repeat_ngram_size(mut self, n: usize) -> Self {
+        self.config.no_repeat_ngram_size = n;
+        self
+    }
+
+    // adds onto the bias of the token
+    pub fn logit_bias(mut self, id: u32, bias: f32) -> Self {
+        *self.config.logit_bias.entry(id).or_default() += bias;
+        self
+    }
+
+    pub fn ban(mut self, id: u32) -> Self {
+        self.config.banned_tokens.push(id);
+        self
+    }
+
+    pub fn suppress_special(mut self, suppress: bool) -> Self {
+        self.config.suppress_special = suppress;
+        self
+    }
+
+    pub fn mirostat(mut self, tau: f32, eta: f32) -> Self {
+        self.config.mirostat = Some(OP::MirostatParams { tau, eta });
+        self
+    }
+
+    pub fn attention_sink(mut self, n_sink: usize, window: usize) -> Self {
+        self.config.attention_sink = Some(AttentionSink {
+            n_sink,
+            window,
+            absolute_positions: false,
+        });
+        self
+    }
+
+    pub fn sliding_window(mut self, window_len: usize, n_keep: usize) -> Self {
+        self.config.sliding_window = Some(SlidingWindow { window_len, n_keep });
+        self
+    }
+
+    pub fn dry(mut self, multiplier: f32, base: f32, allowed_length: usize) -> Self {
+        self.config.dry = Some(DryParams {
+            multiplier,
+            base,
+            allowed_length,
+            sequence_breakers: vec![],
+        });
+        self
+    }
+
+    // tokens no DRY repeat goes across, for after dry
+    pub fn dry_sequence_breakers(mut self, breakers: &[u32]) -> Self {
+        if let Some(dry) = &mut self.config.dry {
+            dry.sequence_breakers = breakers.to_vec();
+        }
+        self
+    }
+
+    pub fn contrastive(mut self, k: usize, alpha: f32) -> Self {
+        self.config.contrastive = Some(ContrastiveSearch { k, alpha });
+        self
+    }
+
+    pub fn prompt_lookup(mut self, ngram: usize, max_draft: usize) -> Self {
+        self.config.prompt_lookup = Some(PromptLookup { ngram, max_draft });
+        self
+    }
+
+    pub fn max_time(mut self, max_time: Duration) -> Self {
+        self.config.max_time = Some(max_time);
+        self
+    }
+
+    pub fn max_total_tokens(mut self, max_total_tokens: usize) -> Self {
+        self.config.max_total_tokens = Some(max_total_tokens);
+        self
+    }
+
+    pub fn stop_at_context_limit(mut self, stop: bool) -> Self {
+        self.config.stop_at_context_limit = stop;
+        self
+    }
+
+    pub fn prefix_cache(mut self, enabled: bool) -> Self {
+        self.config.prefix_cache = enabled;
+        self
+    }
+
+    pub fn stop(mut self, stop: impl Into<String>) -> Self {
+        self.config.stop.push(stop.into());
+        self
+    }
+
+    pub fn logprobs(mut self, n: usize) -> Self {
+        self.config.logprobs = Some(n);
+        self
+    }
+
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.config.seed = Some(seed);
+        self
+    }
+
+    pub fn build(self) -> Result<GenerationConfig, String> {
+        self.config.validate()?;
+        Ok(self.config)
+    }
+}
+
+#[test]
+fn test_generation_config_default() {
+    let config = GenerationConfig::builder().build().unwrap();
+    assert_eq!(config, GenerationConfig::default());
+    // the sampling main.rs hardcoded before there was a config
+    assert_eq!(
+        (
+            config.max_len,
+            config.top_p,
+            config.top_k,
+            config.temperature
+        ),
+        (500, 0.8, 30, 1.)
+    );
+    // and everything added since is off
+    assert_eq!((config.min_p, config.typical_p), (0., 1.));
+    assert_eq!(
+        (
+            config.repetition_penalty,
+            config.frequency_penalty,
+            config.presence_penalty
+        ),
+        (1., 0., 0.)
+    );
+    assert_eq!(config.no_repeat_ngram_size, 0);
+    assert!(
+        config.logit_bias.is_empty() && config.banned_tokens.is_empty() && config.stop.is_empty()
+    );
+    assert!(!config.suppress_special);
+    assert!(config.mirostat.is_none() && config.attention_sink.is_none());
+    assert!(config.sliding_window.is_none() && config.sink().is_none());
+    assert!(config.logprobs.is_none() && config.seed.is_none());
+    assert_eq!(
+        serde_json::from_str::<GenerationConfig>("{}").unwrap(),
+        config
+    );
+}
+
+#[test]
+fn test_generation_config_json() {
+    let config: GenerationConfig = serde_json::from_str(
+        r#"{"top_k": 50, "temperature": 0.7, "logit_bias": {"3": -2.5}, "stop": ["\nUser:"],
+        "mirostat": {"tau": 3, "eta": 0.1}, "attention_sink": {"n_sink": 4, "window": 60},
+        "max_time": 0.25}"#,
+    )
+    .unwrap();
+    let built = GenerationConfig::builder()
+        .top_k(50)
+        .temperature(0.7)
+        .logit_bias(3, -2.5)
+        .stop("\nUser:")
+        .mirostat(3., 0.1)
+        .attention_sink(4, 60)
+        .max_time(Duration::from_millis(250))
+        .build()
+        .unwrap();
+    assert_eq!(config, built);
+    assert!(serde_json::from_str::<GenerationConfig>(r#"{"max_time": -1}"#).is_err());
+    let err = serde_json::from_str::<GenerationConfig>(r#"{"top_q": 0.5}"#).unwrap_err();
+    assert!(err.to_string().contains("unknown field `top_q`"), "{err}");
+}
+
+#[test]
+fn test_generation_config_validate() {
+    let err = |builder: GenerationConfigBuilder| builder.build().unwrap_err();
+    let builder = GenerationConfig::builder;
+    assert_eq!(
+        err(builder().temperature(-0.5)),
+        "temperature -0.5 is negative"
+    );
+    assert_eq!(
+        err(builder().temperature(f32::NAN)),
+        "temperature NaN is negative"
+    );
+    assert_eq!(err(builder().top_p(1.5)), "top_p 1.5 is not within [0, 1]");
+    assert_eq!(
+        err(builder().min_p(-0.1)),
+        "min_p -0.1 is not within [0, 1]"
+    );
+    assert_eq!(err(builder().typical_p(0.)), "typical_p 0 keeps no token");
+    assert_eq!(
+        err(builder().repetition_penalty(0., 64)),
+        "repetition_penalty 0 is not positive"
+    );
+    assert!(builder().frequency_penalty(f32::INFINITY).build().is_err());
+    assert!(builder().logit_bias(1, f32::NAN).build().is_err());
+    assert!(builder().mirostat(0., 0.1).build().is_err());
+    assert!(builder().prompt_lookup(0, 8).build().is_err());
+    assert!(builder().contrastive(4, 1.5).build().is_err());
+    assert!(builder().dry(0.8, 0.5, 2).build().is_err());
+    assert!(builder().sliding_window(0, 4).build().is_err());
+    assert_eq!(
+        err(builder().sliding_window(64, 4).attention_sink(4, 60)),
+        "attention_sink and sliding_window are both set"
+    );
+    assert_eq!(err(builder().stop("User:").stop("")), "empty stop string");
+    let config = builder().stop("User:").build().unwrap();
+    assert!(config
+        .validate_tokens()
+        .unwrap_err()
+        .contains("generate_text"));
+    assert_eq!(builder().build().unwrap().validate_tokens(), Ok(()));
+    // the edges are fine, as is greedy decoding at temperature 0
+    assert!(builder()
+        .top_p(1.)
+        .min_p(1.)
+        .temperature(0.)
+        .build()
+        .is_ok());
+    // out of range token ids only show against a vocab
+    let config = builder().ban(2048).build().unwrap();
+    assert!(config.check(4096).is_ok());
+    assert_eq!(
+        config.check(2048).unwrap_err(),
+        "banned token 2048 is out of range for a vocab of 2048"
+    );
+}
+
+#[test]
+fn test_beam_search_bookkeeping() {
+    let logits = |p: [f32; 4]| Tensor::new(p.iter().map(|p| p.ln()).collect(), &vec![4]);
+    let tokens = |search: &BeamSearch| {
+        search
+            .live()
+            .iter()
+            .map(|h| h.tokens.clone())
+            .collect::<Vec<_>>()
+    };
+    let close = |a: f32, b: f32| (a - b).abs() < 1e-5;
+    // token 3 is eos
+    let mut search = BeamSearch::new(2, 1., &[3]);
+    assert_eq!(search.step(&[logits([0.5, 0.3, 0.15, 0.05])]), [0, 0]);
+    assert_eq!(tokens(&search), [vec![0], vec![1]]);
+    assert!(close(search.live()[1].logprob, 0.3f32.ln()));
+    // [0, 3] is the best of all and retires, the next two stay live, [1, 0] first
+    let parents = search.step(&[logits([0.1, 0.1, 0.2, 0.6]), logits([0.7, 0.1, 0.1, 0.1])]);
+    assert_eq!(parents, [1, 0]);
+    assert_eq!(tokens(&search), [vec![1, 0], vec![0, 2]]);
+    assert!(close(search.live()[0].logprob, 0.21f32.ln()));
+    assert!(close(search.live()[1].logprob, 0.1f32.ln()));
+    assert!(!search.is_done());
+    // [0, 2, 3] retires as well, which makes two
+    let parents = search.step(&[logits([0.25; 4]), logits([0.05, 0.05, 0.1, 0.8])]);
+    assert_eq!(parents, [0, 0]);
+    assert_eq!(tokens(&search), [vec![1, 0, 0], vec![1, 0, 1]]);
+    assert!(search.is_done());
+    let all = search.finish();
+    let expected: [(&[u32], f32); 4] = [
+        (&[0, 3], 0.3),
+        (&[0, 2, 3], 0.08),
+        (&[1, 0, 0], 0.0525),
+        (&[1, 0, 1], 0.0525),
+    ];
+    assert_eq!(all.len(), 4);
+    for (h, (tokens, p)) in all.iter().zip(expected) {
+        assert_eq!(h.tokens, tokens);
+        assert!(close(h.logprob, p.ln()));
+        assert!(close(h.score, p.ln() / tokens.len() as f32));
+    }
+    // the length penalty decides between a short and a longer, less likely hypothesis
+    let best = |length_penalty| {
+        let mut search = BeamSearch::new(2, length_penalty, &[3]);
+        search.step(&[logits([0.6, 0.05, 0.05, 0.3])]);
+        search.step(&[logits([0.45, 0.45, 0.05, 0.05])]);
+        search.finish()[0].tokens.clone()
+    };
+    assert_eq!(best(0.), [3]);
+    assert_eq!(best(1.), [0, 0]);
+}
+
+#[test]
+fn test_ngram_index() {
+    let tokens = [5, 6, 7, 8, 5, 6, 9, 5, 6];
+    let mut index = NgramIndex::new(2);
+    index.update(&tokens[..4]);
+    assert_eq!(index.propose(&tokens[..4], 3), &[] as &[u32]);
+    // the latest earlier occurrence of 5 6 counts, and the draft ends with the sequence
+    index.update(&tokens);
+    assert_eq!(index.propose(&tokens, 3), &[9, 5, 6]);
+    assert_eq!(index.propose(&tokens, 1), &[9]);
+    let mut index = NgramIndex::new(3);
+    index.update(&tokens);
+    assert_eq!(index.propose(&tokens, 3), &[] as &[u32]);
+}
+
+#[test]
+fn test_contrastive_pick() {
+    let probs = [0.5, 0.3, 0.2];
+    // candidate 0 along x, 1 at 45 degrees and 2 along y
+    let hidden = [1., 0., 1., 1., 0., 3.];
+    let context = [1., 0., 1., 0.];
+    assert_eq!(contrastive_pick(&probs, &hidden, &context, 2, 0.), 0);
+    // alpha 0.2: 0.4 - 0.2, 0.24 - 0.2 * 0.707 and 0.16 - 0
+    assert_eq!(contrastive_pick(&probs, &hidden, &context, 2, 0.2), 0);
+    // alpha 0.5: 0.25 - 0.5, 0.15 - 0.5 * 0.707 and 0.1 - 0
+    assert_eq!(contrastive_pick(&probs, &hidden, &context, 2, 0.5), 2);
+    // the largest similarity counts, along y too: 0.25 - 0.5, 0.15 - 0.354 and 0.1 - 0.5
+    let context = [2., 0., 0., 1.];
+    assert_eq!(contrastive_pick(&probs, &hidden, &context, 2, 0.5), 1);
+    // without context it is the most likely one
+    assert_eq!(contrastive_pick(&probs, &hidden, &[], 2, 0.9), 0);
+}
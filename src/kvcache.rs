@@ -1,43 +1,1476 @@
-use std::{usize, vec};
-
+use crate::paged::{PagePool, PageRows, PagedLayer};
 use crate::tensor::Tensor;
+use half::f16;
+use half::slice::HalfFloatSliceExt;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::ops::Range;
+use std::path::Path;
+use std::sync::Arc;
+
+// what the cache stores K and V as, forward computing in f32 either way
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum KvDtype {
+    #[default]
+    F32,
+    F16, // half the memory, rounded to the nearest f16 on write
+    // a quarter of the memory and a scale per head and position, every head vector x
+    // stored as round(x / s) with s = max |x| / 127
+    I8,
+}
+
+impl std::str::FromStr for KvDtype {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "f32" => Ok(KvDtype::F32),
+            "f16" => Ok(KvDtype::F16),
+            "i8" => Ok(KvDtype::I8),
+            _ => Err(format!(
+                "unknown kv cache dtype {s}, expected f32, f16 or i8"
+            )),
+        }
+    }
+}
+
+// how an f32 cache orders the entries of a layer: token-major holds every position as
+// one row of all heads, head-major every head as one matrix of all positions, which is what
+// attention reads, so that scanning the history of a head is one contiguous read
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum KvLayout {
+    #[default]
+    TokenMajor, // (max_seq_len, n_kv_heads, head_dim)
+    HeadMajor, // (n_kv_heads, max_seq_len, head_dim)
+}
+
+impl std::str::FromStr for KvLayout {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "token-major" => Ok(KvLayout::TokenMajor),
+            "head-major" => Ok(KvLayout::HeadMajor),
+            _ => Err(format!(
+                "unknown kv cache layout {s}, expected token-major or head-major"
+            )),
+        }
+    }
+}
+
+// The shape of the caches of a model, along with a hash of its config, which a cache file
+// records and must match to be loaded, see Llama::cache_spec
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CacheSpec {
+    pub n_layers: usize,
+    pub n_kv_heads: usize,
+    pub head_dim: usize,
+    pub max_seq_len: usize,
+    pub config_hash: u64,
+}
+
+#[derive(Debug)]
+pub enum CacheFileError {
+    Io(io::Error),
+    NotACacheFile,
+    // a header field of the file differs from the model it is loaded for
+    Mismatch {
+        field: &'static str,
+        expected: u64,
+        found: u64,
+    },
+}
+
+impl fmt::Display for CacheFileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CacheFileError::Io(e) => write!(f, "cannot read the kv cache file: {e}"),
+            CacheFileError::NotACacheFile => write!(f, "not a kv cache file"),
+            CacheFileError::Mismatch {
+                field,
+                expected,
+                found,
+            } => write!(
+                f,
+                "kv cache file of another model: {field} is {found} instead of {expected}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CacheFileError {}
+
+impl From<io::Error> for CacheFileError {
+    fn from(e: io::Error) -> Self {
+        CacheFileError::Io(e)
+    }
+}
+
+const CACHE_MAGIC: &[u8; 8] = b"LMKVC003";
+
+fn write_u64(w: &mut impl Write, x: u64) -> io::Result<()> {
+    w.write_all(&x.to_le_bytes())
+}
+
+fn read_u64(r: &mut impl Read) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    r.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+// K or V of a layer in a narrower type than the f32 of forward
+#[derive(Clone)]
+enum Packed {
+    F16(Vec<f16>), // (max_seq_len, dim)
+    I8 {
+        values: Vec<i8>,  // (max_seq_len, dim)
+        scales: Vec<f32>, // (max_seq_len, dim / head_dim)
+        head_dim: usize,
+    },
+}
+
+impl Packed {
+    fn new(dtype: KvDtype, max_seq_len: usize, dim: usize, head_dim: usize) -> Self {
+        match dtype {
+            KvDtype::F16 => Packed::F16(vec![f16::ZERO; max_seq_len * dim]),
+            KvDtype::I8 => {
+                assert!(dim.is_multiple_of(head_dim));
+                Packed::I8 {
+                    values: vec![0; max_seq_len * dim],
+                    scales: vec![0.; max_seq_len * dim / head_dim],
+                    head_dim,
+                }
+            }
+            KvDtype::F32 => unreachable!("f32 is stored as tensors"),
+        }
+    }
+
+    // rows of (rows.len(), dim) values
+    fn write(&mut self, rows: Range<usize>, dim: usize, src: &[f32]) {
+        let values = rows.start * dim..rows.end * dim;
+        match self {
+            Packed::F16(data) => data[values].convert_from_f32_slice(src),
+            Packed::I8 {
+                values: data,
+                scales,
+                head_dim,
+            } => {
+                let n = *head_dim;
+                let heads = data[values.clone()].chunks_mut(n);
+                let scales = &mut scales[values.start / n..values.end / n];
+                for ((q, x), s) in heads.zip(src.chunks(n)).zip(scales) {
+                    *s = x.iter().fold(0f32, |m, x| m.max(x.abs())) / 127.;
+                    let inv = if *s > 0. { 1. / *s } else { 0. };
+                    for (q, x) in q.iter_mut().zip(x) {
+                        *q = (x * inv).round() as i8;
+                    }
+                }
+            }
+        }
+    }
+
+    fn read(&self, rows: Range<usize>, dim: usize, dst: &mut [f32]) {
+        let values = rows.start * dim..rows.end * dim;
+        match self {
+            Packed::F16(data) => data[values].convert_to_f32_slice(dst),
+            Packed::I8 {
+                values: data,
+                scales,
+                head_dim,
+            } => {
+                let n = *head_dim;
+                let heads = data[values.clone()].chunks(n);
+                let scales = &scales[values.start / n..values.end / n];
+                for ((q, x), s) in heads.zip(dst.chunks_mut(n)).zip(scales) {
+                    for (q, x) in q.iter().zip(x) {
+                        *x = *q as f32 * s;
+                    }
+                }
+            }
+        }
+    }
+
+    // zeros of the same dtype with room for rows
+    fn empty_like(&self, rows: usize, dim: usize) -> Self {
+        match self {
+            Packed::F16(_) => Packed::new(KvDtype::F16, rows, dim, dim),
+            Packed::I8 { head_dim, .. } => Packed::new(KvDtype::I8, rows, dim, *head_dim),
+        }
+    }
+
+    fn capacity(&self, dim: usize) -> usize {
+        match self {
+            Packed::F16(data) => data.len() / dim,
+            Packed::I8 { values, .. } => values.len() / dim,
+        }
+    }
+
+    fn bytes(&self) -> usize {
+        match self {
+            Packed::F16(data) => data.len() * size_of::<f16>(),
+            Packed::I8 { values, scales, .. } => values.len() + scales.len() * size_of::<f32>(),
+        }
+    }
+
+    // copies the rows of src from src_start on to the ones from dst on, returning the bytes
+    fn copy_rows(
+        &mut self,
+        src: &Packed,
+        src_start: usize,
+        rows: usize,
+        dst: usize,
+        dim: usize,
+    ) -> usize {
+        let (from, to) = (src_start * dim..(src_start + rows) * dim, dst * dim);
+        match (self, src) {
+            (Packed::F16(data), Packed::F16(src)) => {
+                data[to..to + from.len()].copy_from_slice(&src[from]);
+                rows * dim * size_of::<f16>()
+            }
+            (
+                Packed::I8 {
+                    values,
+                    scales,
+                    head_dim,
+                },
+                Packed::I8 {
+                    values: src_values,
+                    scales: src_scales,
+                    ..
+                },
+            ) => {
+                let n = *head_dim;
+                values[to..to + from.len()].copy_from_slice(&src_values[from.clone()]);
+                scales[to / n..(to + from.len()) / n]
+                    .copy_from_slice(&src_scales[from.start / n..from.end / n]);
+                rows * (dim + dim / n * size_of::<f32>())
+            }
+            _ => unreachable!("packed rows of another dtype"),
+        }
+    }
+
+    // the first rows in little-endian bytes, values and then scales
+    fn save(&self, w: &mut impl Write, rows: usize, dim: usize) -> io::Result<()> {
+        match self {
+            Packed::F16(data) => data[..rows * dim]
+                .iter()
+                .try_for_each(|x| w.write_all(&x.to_le_bytes())),
+            Packed::I8 {
+                values,
+                scales,
+                head_dim,
+            } => {
+                let bytes: Vec<u8> = values[..rows * dim].iter().map(|&q| q as u8).collect();
+                w.write_all(&bytes)?;
+                scales[..rows * dim / head_dim]
+                    .iter()
+                    .try_for_each(|s| w.write_all(&s.to_le_bytes()))
+            }
+        }
+    }
+
+    fn read_from(&mut self, r: &mut impl Read, rows: usize, dim: usize) -> io::Result<()> {
+        match self {
+            Packed::F16(data) => {
+                let mut bytes = vec![0; rows * dim * 2];
+                r.read_exact(&mut bytes)?;
+                for (x, b) in data.iter_mut().zip(bytes.chunks_exact(2)) {
+                    *x = f16::from_le_bytes([b[0], b[1]]);
+                }
+            }
+            Packed::I8 {
+                values,
+                scales,
+                head_dim,
+            } => {
+                let mut bytes = vec![0; rows * dim];
+                r.read_exact(&mut bytes)?;
+                values
+                    .iter_mut()
+                    .zip(&bytes)
+                    .for_each(|(q, &b)| *q = b as i8);
+                read_f32s(r, &mut scales[..rows * dim / *head_dim])?;
+            }
+        }
+        Ok(())
+    }
+
+    // returns the bytes moved
+    fn copy_within(&mut self, src: Range<usize>, dst: usize, dim: usize) -> usize {
+        match self {
+            Packed::F16(data) => {
+                data.copy_within(src.start * dim..src.end * dim, dst * dim);
+                src.len() * dim * size_of::<f16>()
+            }
+            Packed::I8 {
+                values,
+                scales,
+                head_dim,
+            } => {
+                let heads = dim / *head_dim;
+                values.copy_within(src.start * dim..src.end * dim, dst * dim);
+                scales.copy_within(src.start * heads..src.end * heads, dst * heads);
+                src.len() * (dim + heads * size_of::<f32>())
+            }
+        }
+    }
+}
+
+// The storage of every layer holds max_seq_len positions from the start, so appending
+// entries writes into place and never copies the ones already cached. A cache made
+// with_prefix reads its first entries from a SharedPrefix, its storage holding the rest.
 pub struct KVCache<T> {
-    k_cache: Vec<Tensor<T>>, // (max_seq_len, n_kv_head * dqkv) x layers
-    v_cache: Vec<Tensor<T>>, // (max_seq_len, n_kv_head * dqkv) x layers
-    #[allow(unused)]
+    layers: Vec<Arc<Layer<T>>>, // which forks share until they write to them
+    prefix: Option<Arc<KVCache<T>>>, // the entries before those of layers, read-only
     max_seq_len: usize,
     dim: usize,
     length: usize, // length of the current sequence
+    // the ids of the first entries, as far as forward recorded them, which generations
+    // going on from the cache compare their prompt with
+    tokens: Vec<u32>,
+    // (at, n): the entries from at on are n positions further into the text than their
+    // index, after skip_positions
+    gap: (usize, usize),
+    // bytes of cached entries copied within the storage by evict, and out of the layers
+    // shared with a fork on the first write to them
+    copied: usize,
+}
+
+// K and V of a layer
+enum Layer<T> {
+    Full([Tensor<T>; 2]),      // (max_seq_len, n_kv_head * dqkv) each
+    Packed([Packed; 2]),       // of a dtype other than f32
+    Paged(PagedLayer),         // f32 in pages of a PagePool, as many as the entries need
+    HeadMajor([Tensor<T>; 2]), // (n_kv_head, max_seq_len, dqkv) each, see KvLayout
+}
+
+impl<T: Default + Copy> Layer<T> {
+    // zeros of the same dtype with room for rows entries
+    fn empty_like(&self, rows: usize, dim: usize) -> Self {
+        match self {
+            Layer::Full(_) => {
+                let tensor = || Tensor::default(&vec![rows, dim]);
+                Layer::Full([tensor(), tensor()])
+            }
+            Layer::Packed([k, v]) => {
+                Layer::Packed([k.empty_like(rows, dim), v.empty_like(rows, dim)])
+            }
+            Layer::Paged(paged) => Layer::Paged(paged.empty_like()),
+            Layer::HeadMajor([k, _]) => {
+                let (heads, head_dim) = (k.shape()[0], k.shape()[2]);
+                let tensor = || Tensor::default(&vec![heads, rows, head_dim]);
+                Layer::HeadMajor([tensor(), tensor()])
+            }
+        }
+    }
+
+    fn capacity(&self, dim: usize) -> usize {
+        match self {
+            Layer::Full(kv) => kv[0].shape()[0],
+            Layer::Packed(kv) => kv[0].capacity(dim),
+            Layer::Paged(paged) => paged.capacity(),
+            Layer::HeadMajor(kv) => kv[0].shape()[1],
+        }
+    }
+
+    fn bytes(&self) -> usize {
+        match self {
+            Layer::Full(kv) | Layer::HeadMajor(kv) => 2 * kv[0].size() * size_of::<T>(),
+            Layer::Packed([k, v]) => k.bytes() + v.bytes(),
+            Layer::Paged(paged) => paged.bytes(),
+        }
+    }
+
+    // copies the entries of src from src_start on to the ones from dst on, returning the
+    // bytes copied
+    fn copy_rows(
+        &mut self,
+        src: &Layer<T>,
+        src_start: usize,
+        rows: usize,
+        dst: usize,
+        dim: usize,
+    ) -> usize {
+        match (self, src) {
+            (Layer::Full(kv), Layer::Full(src)) => {
+                for (t, s) in kv.iter_mut().zip(src) {
+                    let from = &s.data()[src_start * dim..(src_start + rows) * dim];
+                    unsafe { t.data_mut()[dst * dim..][..from.len()].copy_from_slice(from) };
+                }
+                2 * rows * dim * size_of::<T>()
+            }
+            (Layer::Packed(kv), Layer::Packed(src)) => kv
+                .iter_mut()
+                .zip(src)
+                .map(|(p, s)| p.copy_rows(s, src_start, rows, dst, dim))
+                .sum(),
+            (Layer::Paged(paged), Layer::Paged(src)) => paged.copy_rows(src, src_start, rows, dst),
+            (Layer::HeadMajor(kv), Layer::HeadMajor(src)) => {
+                for (t, s) in kv.iter_mut().zip(src) {
+                    let (head_dim, capacity) = (s.shape()[2], s.shape()[1]);
+                    let room = t.shape()[1] * head_dim;
+                    let data = unsafe { t.data_mut() };
+                    for (h, from) in s.data().chunks(capacity * head_dim).enumerate() {
+                        let from = &from[src_start * head_dim..(src_start + rows) * head_dim];
+                        data[h * room + dst * head_dim..][..from.len()].copy_from_slice(from);
+                    }
+                }
+                2 * rows * dim * size_of::<T>()
+            }
+            _ => unreachable!("rows of another dtype"),
+        }
+    }
+
+    // a layer of the same room holding only a copy of the first rows entries, and the
+    // bytes copied
+    fn prefix(&self, rows: usize, dim: usize) -> (Self, usize) {
+        let mut copy = self.empty_like(self.capacity(dim), dim);
+        let bytes = copy.copy_rows(self, 0, rows, 0, dim);
+        (copy, bytes)
+    }
+}
+
+impl Layer<f32> {
+    // K and V of the entries of rows as f32
+    fn read_rows(&self, rows: Range<usize>, dim: usize, k: &mut [f32], v: &mut [f32]) {
+        let values = rows.start * dim..rows.end * dim;
+        match self {
+            Layer::Full([tk, tv]) => {
+                k.copy_from_slice(&tk.data()[values.clone()]);
+                v.copy_from_slice(&tv.data()[values]);
+            }
+            Layer::Packed([pk, pv]) => {
+                pk.read(rows.clone(), dim, k);
+                pv.read(rows, dim, v);
+            }
+            Layer::Paged(paged) => paged.read_rows(rows, k, v),
+            Layer::HeadMajor(kv) => {
+                for (t, out) in kv.iter().zip([k, v]) {
+                    let (capacity, head_dim) = (t.shape()[1], t.shape()[2]);
+                    for (h, head) in t.data().chunks(capacity * head_dim).enumerate() {
+                        for (i, row) in rows.clone().enumerate() {
+                            out[i * dim + h * head_dim..][..head_dim]
+                                .copy_from_slice(&head[row * head_dim..][..head_dim]);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn write_rows(&mut self, rows: Range<usize>, dim: usize, k: &[f32], v: &[f32]) {
+        let values = rows.start * dim..rows.end * dim;
+        match self {
+            Layer::Full([tk, tv]) => unsafe {
+                tk.data_mut()[values.clone()].copy_from_slice(k);
+                tv.data_mut()[values].copy_from_slice(v);
+            },
+            Layer::Packed([pk, pv]) => {
+                pk.write(rows.clone(), dim, k);
+                pv.write(rows, dim, v);
+            }
+            Layer::Paged(paged) => paged.write_rows(rows, k, v),
+            Layer::HeadMajor(kv) => {
+                for (t, src) in kv.iter_mut().zip([k, v]) {
+                    let (capacity, head_dim) = (t.shape()[1], t.shape()[2]);
+                    let data = unsafe { t.data_mut() };
+                    for (h, head) in data.chunks_mut(capacity * head_dim).enumerate() {
+                        for (i, row) in rows.clone().enumerate() {
+                            head[row * head_dim..][..head_dim]
+                                .copy_from_slice(&src[i * dim + h * head_dim..][..head_dim]);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Entries made once, such as those of a long system prompt, along with their tokens. Every
+// cache made with_prefix of it reads them as its first entries rather than a copy of them.
+#[derive(Clone)]
+pub struct SharedPrefix {
+    tokens: Arc<[u32]>,
+    cache: Arc<KVCache<f32>>,
+}
+
+#[allow(unused)]
+impl SharedPrefix {
+    // cache holding the entries of tokens, which best has no room for more
+    pub fn new(tokens: &[u32], mut cache: KVCache<f32>) -> Self {
+        assert!(
+            cache.len() == tokens.len() && cache.prefix.is_none() && cache.gap == (0, 0),
+            "a shared prefix is the plain entries of its tokens"
+        );
+        cache.tokens = tokens.to_vec();
+        SharedPrefix {
+            tokens: tokens.into(),
+            cache: Arc::new(cache),
+        }
+    }
+
+    pub fn tokens(&self) -> &[u32] {
+        &self.tokens
+    }
+
+    pub fn len(&self) -> usize {
+        self.tokens.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+
+    pub fn memory_bytes(&self) -> usize {
+        self.cache.memory_bytes()
+    }
 }
 
 impl<T: Default + Copy> KVCache<T> {
     pub fn new(n_layers: usize, max_seq_len: usize, dim: usize, init_len: usize) -> Self {
+        let tensor = || Tensor::default(&vec![max_seq_len, dim]);
         KVCache {
-            k_cache: (0..n_layers)
-                .map(|_| Tensor::default(&vec![max_seq_len, dim]))
-                .collect(),
-            v_cache: (0..n_layers)
-                .map(|_| Tensor::default(&vec![max_seq_len, dim]))
+            layers: (0..n_layers)
+                .map(|_| Arc::new(Layer::Full([tensor(), tensor()])))
                 .collect(),
-            max_seq_len: max_seq_len,
-            dim: dim,
+            prefix: None,
+            max_seq_len,
+            dim,
             length: init_len,
+            tokens: Vec::new(),
+            gap: (0, 0),
+            copied: 0,
+        }
+    }
+
+    // a cache of n_layers of K and V laid out head-major, see KvLayout
+    #[allow(unused)]
+    pub fn head_major(
+        n_layers: usize,
+        max_seq_len: usize,
+        n_kv_heads: usize,
+        head_dim: usize,
+    ) -> Self {
+        let tensor = || Tensor::default(&vec![n_kv_heads, max_seq_len, head_dim]);
+        KVCache {
+            layers: (0..n_layers)
+                .map(|_| Arc::new(Layer::HeadMajor([tensor(), tensor()])))
+                .collect(),
+            prefix: None,
+            max_seq_len,
+            dim: n_kv_heads * head_dim,
+            length: 0,
+            tokens: Vec::new(),
+            gap: (0, 0),
+            copied: 0,
+        }
+    }
+
+    // K and V of a head-major cache, (n_kv_head, max_seq_len, dqkv) each, whose entries
+    // attention reads in place; None for the other layouts and with a shared prefix, which
+    // go through load_layer
+    pub fn head_major_layer(&mut self, layer: usize) -> Option<(Tensor<T>, Tensor<T>)> {
+        if self.prefix.is_some() || !matches!(&*self.layers[layer], Layer::HeadMajor(_)) {
+            return None;
+        }
+        match self.layer_mut(layer) {
+            Layer::HeadMajor([k, v]) => Some((k.slice(0, k.shape()), v.slice(0, v.shape()))),
+            _ => unreachable!(),
+        }
+    }
+
+    // how many of the entries the shared prefix holds, which come before those of layers
+    fn prefix_len(&self) -> usize {
+        self.prefix.as_ref().map_or(0, |prefix| prefix.length)
+    }
+
+    // Copy the entries of the shared prefix into storage of this cache, which then holds
+    // all of its entries, before writing to those entries.
+    fn unshare(&mut self) {
+        let Some(prefix) = self.prefix.take() else {
+            return;
+        };
+        let (p, dim) = (prefix.length, self.dim);
+        let mut copied = 0;
+        for (layer, shared) in self.layers.iter_mut().zip(&prefix.layers) {
+            let mut own = shared.empty_like(self.max_seq_len, dim);
+            copied += own.copy_rows(shared, 0, p, 0, dim);
+            copied += own.copy_rows(layer, 0, self.length - p, p, dim);
+            *layer = Arc::new(own);
+        }
+        self.copied += copied;
+    }
+
+    // the layer to write to, copied first if a fork shares it
+    fn layer_mut(&mut self, layer: usize) -> &mut Layer<T> {
+        let rows = self.length - self.prefix_len();
+        let shared = &mut self.layers[layer];
+        if Arc::get_mut(shared).is_none() {
+            let (copy, bytes) = shared.prefix(rows, self.dim);
+            self.copied += bytes;
+            *shared = Arc::new(copy);
+        }
+        Arc::get_mut(shared).unwrap()
+    }
+
+    // K or V of the entries from start on; packed caches have no f32 storage and a shared
+    // prefix is not part of it, see load_layer
+    fn entries(&mut self, layer: usize, start: usize, i: usize) -> Tensor<T> {
+        assert!(
+            self.prefix.is_none(),
+            "f32 entries of a cache with a prefix"
+        );
+        let shape = [self.length - start, self.dim];
+        let offset = start * self.dim;
+        match self.layer_mut(layer) {
+            Layer::Full(kv) => kv[i].slice(offset, &shape),
+            Layer::Packed(_) => panic!("f32 entries of a packed cache"),
+            Layer::Paged(_) => panic!("f32 entries of a paged cache"),
+            Layer::HeadMajor(_) => panic!("token-major entries of a head-major cache"),
         }
     }
 
     pub fn k_cache(&mut self, layer: usize, start: usize) -> Tensor<T> {
-        self.k_cache[layer].slice(start * self.dim, &vec![self.length - start, self.dim])
+        self.entries(layer, start, 0)
     }
 
     pub fn v_cache(&mut self, layer: usize, start: usize) -> Tensor<T> {
-        self.v_cache[layer].slice(start * self.dim, &vec![self.length - start, self.dim])
+        self.entries(layer, start, 1)
     }
 
-    pub fn increment(&mut self, seq_len: usize){
+    pub fn increment(&mut self, seq_len: usize) {
         self.length += seq_len;
     }
 
+    // notes that the entries from at on are those of ids, which only counts when the ids
+    // of every entry before them are known
+    pub fn record_tokens(&mut self, at: usize, ids: &[u32]) {
+        if self.tokens.len() == at {
+            // room for a whole context at once, so that decoding does not grow it token by token
+            self.tokens
+                .reserve(self.max_seq_len.saturating_sub(self.tokens.len()));
+            self.tokens.extend_from_slice(ids);
+        }
+    }
+
+    // the ids of the first entries, see record_tokens
+    #[allow(unused)]
+    pub fn tokens(&self) -> &[u32] {
+        &self.tokens
+    }
+
+    // how many of the first entries are those of token_ids, a text from position 0 on
+    pub fn common_prefix(&self, token_ids: &[u32]) -> usize {
+        self.tokens
+            .iter()
+            .enumerate()
+            .take_while(|&(i, id)| token_ids.get(self.position(i)) == Some(id))
+            .count()
+    }
+
+    #[allow(unused)]
+    pub fn copied_bytes(&self) -> usize {
+        self.copied
+    }
+
+    // the bytes of storage of the layers, at max_seq_len whatever the length unless paged;
+    // a shared prefix does not count, while the layers shared with a fork count for both
+    #[allow(unused)]
+    pub fn memory_bytes(&self) -> usize {
+        self.layers.iter().map(|layer| layer.bytes()).sum()
+    }
+
     pub fn len(&self) -> usize {
         self.length
     }
+
+    // how many entries fit, past which forward fails with ContextOverflow
+    pub fn max_len(&self) -> usize {
+        self.max_seq_len
+    }
+
+    // how many entries the storage has room for as it is: max_len for the contiguous
+    // layouts, which allocate all of it from the start, the positions of the pages taken so
+    // far for paged caches
+    #[allow(unused)]
+    pub fn capacity(&self) -> usize {
+        let rows = self
+            .layers
+            .iter()
+            .map(|layer| layer.capacity(self.dim))
+            .min();
+        self.prefix_len() + rows.unwrap_or(0)
+    }
+
+    // Make room for n entries after the current ones at once, such as ahead of a long
+    // prompt, rather than as forward writes them. Only paged caches grow, taking the pages
+    // from the pool now.
+    #[allow(unused)]
+    pub fn reserve(&mut self, n: usize) {
+        let rows = (self.length + n).min(self.max_seq_len) - self.prefix_len();
+        for layer in 0..self.layers.len() {
+            if !matches!(&*self.layers[layer], Layer::Paged(_)) {
+                continue;
+            }
+            if let Layer::Paged(paged) = self.layer_mut(layer) {
+                paged.reserve(rows);
+            }
+        }
+    }
+
+    // Forget every entry, such as between unrelated prompts. Unlike truncate(0) the storage
+    // stays as it is, pages included, so the next generation allocates nothing; a cache with
+    // a shared prefix moves to storage of its own first.
+    #[allow(unused)]
+    pub fn clear(&mut self) {
+        self.unshare();
+        self.length = 0;
+        self.tokens.clear();
+        self.gap = (0, 0);
+    }
+
+    pub fn layers(&self) -> usize {
+        self.layers.len()
+    }
+
+    // the size of the key, and the value, of one position in each layer
+    pub fn dim(&self) -> usize {
+        self.dim
+    }
+
+    // Forget everything after the first len entries, such as rejected draft tokens or the
+    // answer to regenerate. The storage stays, so this only moves the length.
+    #[allow(unused)]
+    pub fn truncate(&mut self, len: usize) {
+        assert!(len <= self.length);
+        if len < self.prefix_len() {
+            self.unshare();
+        }
+        // pages past the entries go back to the pool, unless a fork still reads them
+        let rows = len - self.prefix_len();
+        for layer in &mut self.layers {
+            if let Some(Layer::Paged(paged)) = Arc::get_mut(layer) {
+                paged.truncate(rows);
+            }
+        }
+        self.length = len;
+        self.tokens.truncate(len);
+        if len <= self.gap.0 {
+            self.gap = (0, 0);
+        }
+    }
+
+    // the position in the text of the entry at index i, which is i unless skip_positions;
+    // position(len()) is where the next token goes
+    pub fn position(&self, i: usize) -> usize {
+        let (at, n) = self.gap;
+        if i >= at {
+            i + n
+        } else {
+            i
+        }
+    }
+
+    pub fn gap(&self) -> (usize, usize) {
+        self.gap
+    }
+
+    // truncate to the entries of the positions before pos, and to those before the gap when
+    // pos is one of the skipped ones
+    pub fn truncate_positions(&mut self, pos: usize) {
+        let (at, n) = self.gap;
+        let len = if pos >= at + n { pos - n } else { pos.min(at) };
+        self.truncate(len.min(self.length));
+    }
+
+    // Mark the entries from index at on as n positions further into the text, such as
+    // after evict dropped n entries there without repositioning the keys of later ones.
+    // There is just one gap, which grows when at stays the same.
+    #[allow(unused)]
+    pub fn skip_positions(&mut self, at: usize, n: usize) {
+        assert!(
+            self.gap.1 == 0 || self.gap.0 == at,
+            "positions already skipped at {} rather than {at}",
+            self.gap.0
+        );
+        self.gap = (at, self.gap.1 + n);
+    }
+
+    // A copy for a branch of the generation such as a beam, which shares every layer with
+    // this cache until one of the two writes to it. Writing copies the layer's entries
+    // for the writer and leaves the other one alone, so forking only copies the token ids.
+    #[allow(unused)]
+    pub fn fork(&self) -> Self {
+        KVCache {
+            layers: self.layers.clone(),
+            prefix: self.prefix.clone(),
+            max_seq_len: self.max_seq_len,
+            dim: self.dim,
+            length: self.length,
+            tokens: self.tokens.clone(),
+            gap: self.gap,
+            copied: 0,
+        }
+    }
+
+    // Drop the n entries after the first keep of every layer and move the later ones down.
+    // Keys keep the rotation of their old positions, see OP::rope_reposition.
+    pub fn evict(&mut self, keep: usize, n: usize) {
+        assert!(keep + n <= self.length);
+        if keep < self.prefix_len() {
+            self.unshare();
+        }
+        // the indices within the storage, after the prefix
+        let p = self.prefix_len();
+        let (dim, keep, length) = (self.dim, keep - p, self.length - p);
+        let mut copied = 0;
+        for layer in 0..self.layers.len() {
+            match self.layer_mut(layer) {
+                Layer::Full(kv) => {
+                    for t in kv {
+                        let data = unsafe { t.data_mut() };
+                        data.copy_within((keep + n) * dim..length * dim, keep * dim);
+                        copied += (length - keep - n) * dim * size_of::<T>();
+                    }
+                }
+                Layer::Packed(kv) => {
+                    for packed in kv {
+                        copied += packed.copy_within(keep + n..length, keep, dim);
+                    }
+                }
+                Layer::Paged(paged) => {
+                    copied += paged.copy_within(keep + n..length, keep);
+                    paged.truncate(length - n);
+                }
+                Layer::HeadMajor(kv) => {
+                    for t in kv {
+                        let (capacity, head_dim) = (t.shape()[1], t.shape()[2]);
+                        let data = unsafe { t.data_mut() };
+                        for head in data.chunks_mut(capacity * head_dim) {
+                            head.copy_within(
+                                (keep + n) * head_dim..length * head_dim,
+                                keep * head_dim,
+                            );
+                        }
+                        copied += (length - keep - n) * dim * size_of::<T>();
+                    }
+                }
+            }
+        }
+        self.copied += copied;
+        self.length -= n;
+        let known = self.tokens.len();
+        self.tokens
+            .drain(known.min(keep + p)..known.min(keep + p + n));
+    }
+}
+
+fn read_f32s(r: &mut impl Read, dst: &mut [f32]) -> io::Result<()> {
+    let mut bytes = vec![0; dst.len() * 4];
+    r.read_exact(&mut bytes)?;
+    for (x, b) in dst.iter_mut().zip(bytes.chunks_exact(4)) {
+        *x = f32::from_le_bytes(b.try_into().unwrap());
+    }
+    Ok(())
+}
+
+impl KVCache<f32> {
+    // a cache of n_layers of (max_seq_len, dim), dim being heads of head_dim
+    pub fn with_dtype(
+        n_layers: usize,
+        max_seq_len: usize,
+        dim: usize,
+        head_dim: usize,
+        dtype: KvDtype,
+    ) -> Self {
+        if dtype == KvDtype::F32 {
+            return KVCache::new(n_layers, max_seq_len, dim, 0);
+        }
+        let packed = || Packed::new(dtype, max_seq_len, dim, head_dim);
+        KVCache {
+            layers: (0..n_layers)
+                .map(|_| Arc::new(Layer::Packed([packed(), packed()])))
+                .collect(),
+            prefix: None,
+            max_seq_len,
+            dim,
+            length: 0,
+            tokens: Vec::new(),
+            gap: (0, 0),
+            copied: 0,
+        }
+    }
+
+    // a cache of up to max_seq_len entries in pages of pool, which it takes as the entries
+    // grow and gives back when they shrink or it drops
+    #[allow(unused)]
+    pub fn paged(n_layers: usize, max_seq_len: usize, pool: &Arc<PagePool>) -> Self {
+        KVCache {
+            layers: (0..n_layers)
+                .map(|_| Arc::new(Layer::Paged(PagedLayer::new(pool.clone()))))
+                .collect(),
+            prefix: None,
+            max_seq_len,
+            dim: pool.dim(),
+            length: 0,
+            tokens: Vec::new(),
+            gap: (0, 0),
+            copied: 0,
+        }
+    }
+
+    // a cache of the entries of prefix and then room for the rest of max_seq_len
+    #[allow(unused)]
+    pub fn with_prefix(prefix: &SharedPrefix, max_seq_len: usize) -> Self {
+        let shared = &prefix.cache;
+        assert!(shared.len() <= max_seq_len);
+        let rows = max_seq_len - shared.len();
+        KVCache {
+            layers: shared
+                .layers
+                .iter()
+                .map(|layer| Arc::new(layer.empty_like(rows, shared.dim)))
+                .collect(),
+            prefix: Some(shared.clone()),
+            max_seq_len,
+            dim: shared.dim,
+            length: shared.len(),
+            tokens: prefix.tokens.to_vec(),
+            gap: (0, 0),
+            copied: 0,
+        }
+    }
+
+    #[allow(unused)]
+    pub fn dtype(&self) -> KvDtype {
+        match self.layers.first().map(|layer| &**layer) {
+            Some(Layer::Packed([Packed::F16(_), _])) => KvDtype::F16,
+            Some(Layer::Packed([Packed::I8 { .. }, _])) => KvDtype::I8,
+            _ => KvDtype::F32,
+        }
+    }
+
+    // K and V of all entries of a layer as f32, (len, dim) each: the storage itself of an
+    // f32 cache, a copy of the others that store_layer writes back
+    pub fn load_layer(&mut self, layer: usize) -> (Tensor<f32>, Tensor<f32>) {
+        if self.prefix.is_none() && matches!(&*self.layers[layer], Layer::Full(_)) {
+            return (self.k_cache(layer, 0), self.v_cache(layer, 0));
+        }
+        let (p, dim) = (self.prefix_len(), self.dim);
+        let shape = vec![self.length, dim];
+        let (mut k, mut v) = (Tensor::default(&shape), Tensor::default(&shape));
+        let (k_data, v_data) = unsafe { (k.data_mut(), v.data_mut()) };
+        let ((prefix_k, k_data), (prefix_v, v_data)) =
+            (k_data.split_at_mut(p * dim), v_data.split_at_mut(p * dim));
+        if let Some(prefix) = &self.prefix {
+            prefix.layers[layer].read_rows(0..p, dim, prefix_k, prefix_v);
+        }
+        self.layers[layer].read_rows(0..self.length - p, dim, k_data, v_data);
+        (k, v)
+    }
+
+    // K and V of all entries of a paged layer where the pages hold them, as attention reads
+    // them rather than through load_layer; None for the other layouts and with a shared
+    // prefix
+    pub fn layer_pages(&self, layer: usize) -> Option<PageRows<'_>> {
+        match &*self.layers[layer] {
+            Layer::Paged(paged) if self.in_pages(layer) => Some(paged.pages(self.length)),
+            _ => None,
+        }
+    }
+
+    // whether layer_pages has the entries of layer once they are written
+    pub fn in_pages(&self, layer: usize) -> bool {
+        self.prefix.is_none() && matches!(&*self.layers[layer], Layer::Paged(_))
+    }
+
+    // writes the entries from start on of what load_layer returned and forward changed
+    pub fn store_layer(&mut self, layer: usize, start: usize, k: &Tensor<f32>, v: &Tensor<f32>) {
+        if self.prefix.is_none() && matches!(&*self.layers[layer], Layer::Full(_)) {
+            return;
+        }
+        // entries of the shared prefix change in a copy of it
+        if start < self.prefix_len() {
+            self.unshare();
+        }
+        let (p, dim, length) = (self.prefix_len(), self.dim, self.length);
+        let values = start * dim..length * dim;
+        self.layer_mut(layer).write_rows(
+            start - p..length - p,
+            dim,
+            &k.data()[values.clone()],
+            &v.data()[values],
+        );
+    }
+
+    // writes the entries from start on, the rows of k and v, (len - start, dim) each
+    pub fn write_entries(&mut self, layer: usize, start: usize, k: &Tensor<f32>, v: &Tensor<f32>) {
+        let (p, dim, length) = (self.prefix_len(), self.dim, self.length);
+        assert!(start >= p && k.size() == (length - start) * dim);
+        self.layer_mut(layer)
+            .write_rows(start - p..length - p, dim, k.data(), v.data());
+    }
+
+    // Writes the header, the spec and then the dtype, length and gap, all u64, the count of
+    // known token ids as a u64 and the u32 ids, then K and V of the entries of every layer,
+    // all little-endian
+    pub fn write_to(&self, w: &mut impl Write, spec: &CacheSpec) -> io::Result<()> {
+        if self.prefix.is_some() {
+            let mut copy = self.fork();
+            copy.unshare();
+            return copy.write_to(w, spec);
+        }
+        assert!(
+            spec.n_layers == self.layers() && spec.n_kv_heads * spec.head_dim == self.dim,
+            "{spec:?} is not the spec of this cache"
+        );
+        w.write_all(CACHE_MAGIC)?;
+        let dtype = match self.dtype() {
+            KvDtype::F32 => 0,
+            KvDtype::F16 => 1,
+            KvDtype::I8 => 2,
+        };
+        for x in [
+            spec.n_layers as u64,
+            spec.n_kv_heads as u64,
+            spec.head_dim as u64,
+            spec.max_seq_len as u64,
+            spec.config_hash,
+            dtype,
+            self.length as u64,
+            self.gap.0 as u64,
+            self.gap.1 as u64,
+            self.tokens.len() as u64,
+        ] {
+            write_u64(w, x)?;
+        }
+        for id in &self.tokens {
+            w.write_all(&id.to_le_bytes())?;
+        }
+        let rows = self.length * self.dim;
+        let (mut k, mut v) = (vec![0.; rows], vec![0.; rows]);
+        for layer in &self.layers {
+            match &**layer {
+                Layer::Full(kv) => kv.iter().try_for_each(|t| {
+                    let data = &t.data()[..rows];
+                    data.iter().try_for_each(|x| w.write_all(&x.to_le_bytes()))
+                })?,
+                Layer::Packed(kv) => kv
+                    .iter()
+                    .try_for_each(|p| p.save(w, self.length, self.dim))?,
+                Layer::Paged(_) | Layer::HeadMajor(_) => {
+                    layer.read_rows(0..self.length, self.dim, &mut k, &mut v);
+                    [&k, &v].iter().try_for_each(|data| {
+                        data.iter().try_for_each(|x| w.write_all(&x.to_le_bytes()))
+                    })?
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // a cache that write_to wrote for a model of spec
+    pub fn read_from(r: &mut impl Read, spec: &CacheSpec) -> Result<Self, CacheFileError> {
+        let mut magic = [0; 8];
+        match r.read_exact(&mut magic) {
+            Ok(()) if &magic == CACHE_MAGIC => {}
+            Ok(()) => return Err(CacheFileError::NotACacheFile),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                return Err(CacheFileError::NotACacheFile)
+            }
+            Err(e) => return Err(e.into()),
+        }
+        for (field, expected) in [
+            ("n_layers", spec.n_layers as u64),
+            ("n_kv_heads", spec.n_kv_heads as u64),
+            ("head_dim", spec.head_dim as u64),
+            ("max_seq_len", spec.max_seq_len as u64),
+            ("config_hash", spec.config_hash),
+        ] {
+            let found = read_u64(r)?;
+            if found != expected {
+                return Err(CacheFileError::Mismatch {
+                    field,
+                    expected,
+                    found,
+                });
+            }
+        }
+        let dtype = match read_u64(r)? {
+            0 => KvDtype::F32,
+            1 => KvDtype::F16,
+            2 => KvDtype::I8,
+            _ => return Err(CacheFileError::NotACacheFile),
+        };
+        let length = read_u64(r)? as usize;
+        let gap = (read_u64(r)? as usize, read_u64(r)? as usize);
+        let known = read_u64(r)? as usize;
+        if length > spec.max_seq_len || gap.0 > length || known > length {
+            return Err(CacheFileError::NotACacheFile);
+        }
+        let mut ids = vec![0; known * 4];
+        r.read_exact(&mut ids)?;
+        let dim = spec.n_kv_heads * spec.head_dim;
+        let mut cache =
+            KVCache::with_dtype(spec.n_layers, spec.max_seq_len, dim, spec.head_dim, dtype);
+        cache.length = length;
+        cache.tokens = ids
+            .chunks_exact(4)
+            .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+            .collect();
+        cache.gap = gap;
+        for layer in 0..spec.n_layers {
+            match cache.layer_mut(layer) {
+                Layer::Full(kv) => {
+                    for t in kv {
+                        read_f32s(r, unsafe { &mut t.data_mut()[..length * dim] })?;
+                    }
+                }
+                Layer::Packed(kv) => {
+                    for p in kv {
+                        p.read_from(r, length, dim)?;
+                    }
+                }
+                Layer::Paged(_) | Layer::HeadMajor(_) => {
+                    unreachable!("with_dtype makes token-major caches")
+                }
+            }
+        }
+        Ok(cache)
+    }
+
+    #[allow(unused)]
+    pub fn save(&self, path: impl AsRef<Path>, spec: &CacheSpec) -> io::Result<()> {
+        let mut w = io::BufWriter::new(std::fs::File::create(path)?);
+        self.write_to(&mut w, spec)?;
+        w.flush()
+    }
+
+    #[allow(unused)]
+    pub fn load(path: impl AsRef<Path>, spec: &CacheSpec) -> Result<Self, CacheFileError> {
+        let mut r = io::BufReader::new(std::fs::File::open(path)?);
+        KVCache::read_from(&mut r, spec)
+    }
+}
+
+#[test]
+fn test_evict() {
+    let mut cache = KVCache::<f32>::new(1, 6, 2, 0);
+    let rows = Tensor::<f32>::new((0..10).map(|x| x as f32).collect(), &vec![5, 2]);
+    cache.increment(5);
+    cache.record_tokens(0, &[10, 11, 12, 13, 14]);
+    let mut k = cache.k_cache(0, 0);
+    unsafe { k.data_mut() }.copy_from_slice(rows.data());
+    cache.evict(1, 2);
+    assert_eq!((cache.len(), cache.tokens()), (3, &[10, 13, 14][..]));
+    assert_eq!(cache.k_cache(0, 0).data(), &[0., 1., 6., 7., 8., 9.]);
+    assert_eq!(cache.v_cache(0, 0).size(), 6);
+    assert_eq!(cache.copied_bytes(), 2 * 2 * 2 * 4);
+}
+
+#[test]
+fn test_f16_roundtrip() {
+    let mut cache = KVCache::with_dtype(2, 8, 4, 2, KvDtype::F16);
+    assert_eq!(cache.dtype(), KvDtype::F16);
+    cache.increment(6);
+    let (mut k, mut v) = cache.load_layer(1);
+    assert!(k.data().iter().chain(v.data()).all(|&x| x == 0.));
+    let values: Vec<f32> = (0..24).map(|i| (i as f32 - 11.3) * 0.37).collect();
+    unsafe { k.data_mut() }.copy_from_slice(&values);
+    unsafe { v.data_mut() }.copy_from_slice(&values);
+    cache.store_layer(1, 0, &k, &v);
+    // f16 keeps 11 significant bits, so the relative error is at most 2^-11
+    let (mut k, v) = cache.load_layer(1);
+    for (x, y) in values.iter().zip(k.data()) {
+        assert!((x - y).abs() <= x.abs() / 2048., "{x} => {y}");
+    }
+    assert_eq!(k.data(), v.data());
+    // store_layer writes only from start on, and evict moves the packed entries
+    unsafe { k.data_mut() }.fill(1.);
+    cache.store_layer(1, 4, &k, &k);
+    cache.evict(1, 3);
+    let (k, _) = cache.load_layer(1);
+    assert_eq!(
+        k.data()[..4],
+        values[..4]
+            .iter()
+            .map(|&x| f16::from_f32(x).to_f32())
+            .collect::<Vec<_>>()[..]
+    );
+    assert_eq!(k.data()[4..], [1.; 8]);
+    assert!(cache.load_layer(0).0.data().iter().all(|&x| x == 0.));
+}
+
+#[test]
+fn test_i8_roundtrip() {
+    let mut cache = KVCache::with_dtype(1, 8, 6, 3, KvDtype::I8);
+    assert_eq!(cache.dtype(), KvDtype::I8);
+    cache.increment(4);
+    // heads of very different magnitudes, a zero one among them
+    let values: Vec<f32> = (0..24)
+        .map(|i| match i / 3 {
+            2 => 0.,
+            h => (i as f32 - 7.7).sin() * 10f32.powi(h % 4 - 2),
+        })
+        .collect();
+    let (mut k, mut v) = cache.load_layer(0);
+    unsafe { k.data_mut() }.copy_from_slice(&values);
+    unsafe { v.data_mut() }.copy_from_slice(&values);
+    cache.store_layer(0, 0, &k, &v);
+    // every value is within half a step of its head's scale
+    let (k, v) = cache.load_layer(0);
+    for (head, x) in values.chunks(3).zip(k.data().chunks(3)) {
+        let scale = head.iter().fold(0f32, |m, x| m.max(x.abs())) / 127.;
+        for (a, b) in head.iter().zip(x) {
+            assert!((a - b).abs() <= scale / 2. * 1.0001, "{a} => {b}");
+        }
+    }
+    assert_eq!(k.data()[6..9], [0.; 3]);
+    assert_eq!(k.data(), v.data());
+    // truncating and writing again replaces what came after
+    cache.truncate(2);
+    cache.increment(1);
+    let (mut k, v) = cache.load_layer(0);
+    unsafe { k.data_mut()[12..].fill(-4.) };
+    cache.store_layer(0, 2, &k, &v);
+    let (k, _) = cache.load_layer(0);
+    assert_eq!(k.data()[12..], [-4.; 6]);
+    let (before, _) = cache.load_layer(0);
+    cache.increment(1);
+    assert_eq!(cache.load_layer(0).0.data()[..18], before.data()[..]);
+}
+
+#[test]
+fn test_fork_copy_on_write() {
+    let dim = 4;
+    let mut cache = KVCache::<f32>::new(2, 64, dim, 0);
+    cache.increment(50);
+    for layer in 0..2 {
+        let mut k = cache.k_cache(layer, 0);
+        let data = unsafe { k.data_mut() };
+        data.iter_mut().enumerate().for_each(|(i, x)| *x = i as f32);
+    }
+    let prefix = cache.k_cache(0, 0).data().to_vec();
+    // forking shares the layers instead of copying the 50 entries
+    let mut fork = cache.fork();
+    assert_eq!((cache.copied_bytes(), fork.copied_bytes()), (0, 0));
+    // the first write to layer 0 copies its entries, 53 by now, for the writer only
+    cache.increment(3);
+    unsafe { cache.k_cache(0, 50).data_mut() }.fill(-1.);
+    assert_eq!(cache.copied_bytes(), 2 * 53 * dim * 4);
+    fork.increment(5);
+    unsafe { fork.k_cache(0, 50).data_mut() }.fill(-2.);
+    assert_eq!(fork.copied_bytes(), 0);
+    assert_eq!(cache.k_cache(0, 0).data()[..50 * dim], prefix);
+    assert_eq!(fork.k_cache(0, 0).data()[..50 * dim], prefix);
+    assert_eq!(cache.k_cache(0, 50).data(), [-1.; 3 * 4]);
+    assert_eq!(fork.k_cache(0, 50).data(), [-2.; 5 * 4]);
+    assert_eq!(cache.copied_bytes(), 2 * 53 * dim * 4);
+    // layer 1 is still shared, until the fork evicts from it
+    fork.evict(10, 20);
+    assert_eq!(fork.copied_bytes(), 2 * 55 * dim * 4 + 2 * 2 * 25 * dim * 4);
+    assert_eq!(cache.k_cache(1, 0).data()[..50 * dim], prefix);
+    assert_eq!(fork.k_cache(1, 10).data()[0], (30 * dim) as f32);
+}
+
+#[test]
+fn test_save_load() {
+    let spec = CacheSpec {
+        n_layers: 2,
+        n_kv_heads: 2,
+        head_dim: 3,
+        max_seq_len: 8,
+        config_hash: 0x1234,
+    };
+    let path = std::env::temp_dir().join(format!("kvcache-{}", std::process::id()));
+    for dtype in [KvDtype::F32, KvDtype::F16, KvDtype::I8] {
+        let mut cache = KVCache::with_dtype(2, 8, 6, 3, dtype);
+        cache.increment(5);
+        for layer in 0..2 {
+            let (mut k, mut v) = cache.load_layer(layer);
+            let data = (0..30).map(|i| (i * (layer + 2)) as f32 / 7. - 1.);
+            unsafe { k.data_mut() }
+                .iter_mut()
+                .zip(data)
+                .for_each(|(x, y)| *x = y);
+            unsafe { v.data_mut() }.fill(layer as f32 - 0.5);
+            cache.store_layer(layer, 0, &k, &v);
+        }
+        cache.skip_positions(1, 40);
+        cache.record_tokens(0, &[1, 2, 3, 4, 5]);
+        cache.save(&path, &spec).unwrap();
+        let mut loaded = KVCache::load(&path, &spec).unwrap();
+        assert_eq!((loaded.dtype(), loaded.len()), (dtype, 5));
+        assert_eq!(
+            (loaded.gap(), loaded.tokens()),
+            ((1, 40), &[1, 2, 3, 4, 5][..])
+        );
+        for layer in 0..2 {
+            let ((k, v), (k2, v2)) = (cache.load_layer(layer), loaded.load_layer(layer));
+            assert_eq!((k.data(), v.data()), (k2.data(), v2.data()));
+        }
+    }
+    // other models and other files are refused
+    let other = CacheSpec {
+        config_hash: 0x4321,
+        ..spec
+    };
+    assert!(matches!(
+        KVCache::load(&path, &other),
+        Err(CacheFileError::Mismatch {
+            field: "config_hash",
+            ..
+        })
+    ));
+    let fewer = CacheSpec {
+        n_kv_heads: 1,
+        ..spec
+    };
+    let e = KVCache::load(&path, &fewer).err().unwrap();
+    assert_eq!(
+        e.to_string(),
+        "kv cache file of another model: n_kv_heads is 2 instead of 1"
+    );
+    std::fs::write(&path, b"not a cache").unwrap();
+    assert!(matches!(
+        KVCache::load(&path, &spec),
+        Err(CacheFileError::NotACacheFile)
+    ));
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_fork() {
+    let mut cache = KVCache::<f32>::new(2, 4, 2, 0);
+    cache.increment(1);
+    unsafe { cache.k_cache(1, 0).data_mut() }.copy_from_slice(&[1., 2.]);
+    let mut fork = cache.fork();
+    fork.increment(1);
+    unsafe { fork.k_cache(1, 1).data_mut() }.copy_from_slice(&[3., 4.]);
+    unsafe { fork.k_cache(1, 0).data_mut()[0] = 5. };
+    // writes to the fork leave the original alone
+    assert_eq!(cache.len(), 1);
+    assert_eq!(cache.k_cache(1, 0).data(), &[1., 2.]);
+    assert_eq!(fork.k_cache(1, 0).data(), &[5., 2., 3., 4.]);
+}
+
+#[test]
+fn test_skip_positions() {
+    let mut cache = KVCache::<f32>::new(1, 16, 2, 10);
+    cache.evict(2, 3);
+    cache.skip_positions(2, 3);
+    cache.evict(2, 1);
+    cache.skip_positions(2, 1);
+    // 2 kept entries, then the entries of positions 6 to 9
+    assert_eq!((cache.len(), cache.gap()), (6, (2, 4)));
+    assert_eq!((cache.position(1), cache.position(2)), (1, 6));
+    assert_eq!(cache.position(cache.len()), 10);
+    assert_eq!(cache.fork().position(5), 9);
+    cache.truncate_positions(8);
+    assert_eq!(cache.len(), 4);
+    // a position that was skipped goes back to the kept entries, which forget the gap
+    cache.truncate_positions(4);
+    assert_eq!((cache.len(), cache.gap()), (2, (0, 0)));
+    assert_eq!(cache.position(cache.len()), 2);
+}
+
+#[test]
+fn test_shared_prefix() {
+    let mut cache = KVCache::<f32>::new(1, 3, 2, 3);
+    let (mut k, mut v) = cache.load_layer(0);
+    unsafe { k.data_mut() }.copy_from_slice(&[1., 2., 3., 4., 5., 6.]);
+    unsafe { v.data_mut() }.fill(-1.);
+    let prefix = SharedPrefix::new(&[7, 8, 9], cache);
+    let (mut a, mut b) = (
+        KVCache::with_prefix(&prefix, 8),
+        KVCache::with_prefix(&prefix, 8),
+    );
+    // the storage of either only has room for the tail
+    assert_eq!((prefix.memory_bytes(), a.memory_bytes()), (48, 80));
+    for (cache, x) in [(&mut a, 10.), (&mut b, 20.)] {
+        cache.increment(2);
+        let (mut k, v) = cache.load_layer(0);
+        assert_eq!(&k.data()[..6], &[1., 2., 3., 4., 5., 6.]);
+        unsafe { k.data_mut()[6..].fill(x) };
+        cache.store_layer(0, 3, &k, &v);
+    }
+    assert_eq!(a.load_layer(0).0.data()[5..], [6., 10., 10., 10., 10.]);
+    assert_eq!(b.load_layer(0).0.data()[5..], [6., 20., 20., 20., 20.]);
+    assert_eq!(a.copied_bytes(), 0);
+    // going back into the prefix takes a copy of it, which leaves the prefix alone
+    a.truncate(2);
+    assert_eq!(a.memory_bytes(), 128);
+    let (mut k, v) = a.load_layer(0);
+    unsafe { k.data_mut()[0] = 0. };
+    a.store_layer(0, 0, &k, &v);
+    assert_eq!(a.k_cache(0, 0).data(), &[0., 2., 3., 4.]);
+    assert_eq!(b.load_layer(0).0.data()[..2], [1., 2.]);
+}
+
+#[test]
+fn test_head_major() {
+    let mut cache = KVCache::<f32>::head_major(1, 6, 2, 2);
+    cache.increment(5);
+    let (mut k, mut v) = cache.load_layer(0);
+    unsafe { k.data_mut() }
+        .iter_mut()
+        .enumerate()
+        .for_each(|(i, x)| *x = i as f32);
+    unsafe { v.data_mut() }.fill(-1.);
+    cache.store_layer(0, 0, &k, &v);
+    // head 0 holds the first two values of every position, head 1 the last two
+    let (k_heads, _) = cache.head_major_layer(0).unwrap();
+    let expected = [0., 1., 4., 5., 8., 9., 12., 13., 16., 17.];
+    assert_eq!(k_heads.data()[..10], expected);
+    assert_eq!(k_heads.data()[12..22], expected.map(|x| x + 2.));
+    // writing and evicting keep the positions of both heads together
+    let row = Tensor::<f32>::new(vec![20., 21., 22., 23.], &vec![1, 4]);
+    cache.increment(1);
+    cache.write_entries(0, 5, &row, &row);
+    cache.evict(1, 2);
+    let (k, v) = cache.load_layer(0);
+    let kept = [0, 3, 4, 5].map(|i| [0., 1., 2., 3.].map(|x| x + 4. * i as f32));
+    assert_eq!(k.data(), kept.concat());
+    assert_eq!(v.data()[12..], [20., 21., 22., 23.]);
+    // a cache file of it loads token-major
+    let spec = CacheSpec {
+        n_layers: 1,
+        n_kv_heads: 2,
+        head_dim: 2,
+        max_seq_len: 6,
+        config_hash: 0,
+    };
+    let mut bytes = vec![];
+    cache.write_to(&mut bytes, &spec).unwrap();
+    let mut loaded = KVCache::read_from(&mut &bytes[..], &spec).unwrap();
+    assert_eq!(loaded.k_cache(0, 0).data(), k.data());
+}
+
+#[test]
+fn test_clear() {
+    let mut cache = KVCache::<f32>::new(2, 8, 4, 0);
+    cache.increment(3);
+    cache.record_tokens(0, &[1, 2, 3]);
+    cache.skip_positions(3, 2);
+    let (data, capacity) = (cache.k_cache(0, 0).data().as_ptr(), cache.capacity());
+    cache.clear();
+    // nothing is left of the text, but the storage is the same
+    assert_eq!(
+        (cache.len(), cache.position(0), cache.tokens()),
+        (0, 0, &[][..])
+    );
+    assert_eq!(cache.k_cache(0, 0).data().as_ptr(), data);
+    assert_eq!((cache.capacity(), capacity), (8, 8));
+    // a paged cache keeps its pages, which reserve takes up front
+    let pool = PagePool::new(4, 4);
+    let mut paged = KVCache::paged(2, 32, &pool);
+    assert_eq!(paged.capacity(), 0);
+    paged.reserve(10);
+    assert_eq!((paged.capacity(), pool.in_use()), (12, 6));
+    paged.increment(10);
+    paged.clear();
+    assert_eq!((paged.len(), paged.capacity(), pool.in_use()), (0, 12, 6));
+    // and never past max_len
+    paged.reserve(100);
+    assert_eq!(paged.capacity(), 32);
 }
@@ -1,28 +1,207 @@
+mod backend;
 mod config;
+mod constraint;
+#[cfg(feature = "cuda")]
+mod cuda_backend;
+mod generation;
 mod kvcache;
 mod model;
 mod operators;
+mod paged;
 mod params;
+mod processor;
+mod session;
+mod speculative;
+mod stop;
 mod tensor;
+#[cfg(test)]
+mod test_support;
+#[cfg(feature = "wgpu")]
+mod wgpu_backend;
 
+use generation::GenerationConfig;
+use std::io::Write;
 use std::path::PathBuf;
 use tokenizers::Tokenizer;
 
+// the value following flag, parsed
+fn flag_value<T: std::str::FromStr>(args: &mut impl Iterator<Item = String>, flag: &str) -> T {
+    let value = args
+        .next()
+        .unwrap_or_else(|| panic!("{flag} needs a value"));
+    value
+        .parse()
+        .unwrap_or_else(|_| panic!("invalid value {value} for {flag}"))
+}
+
+// MemAvailable of /proc/meminfo, where there is one
+fn available_memory() -> Option<usize> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+    let line = meminfo.lines().find(|l| l.starts_with("MemAvailable:"))?;
+    let kib: usize = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kib * 1024)
+}
+
 fn main() {
+    let mut config = GenerationConfig::default();
+    // strings passed to --ban, banned once the tokenizer is loaded
+    let mut banned = vec![];
+    let mut json = false;
+    let mut kv_dtype = kvcache::KvDtype::F32;
+    let mut kv_layout = kvcache::KvLayout::TokenMajor;
+    let mut max_seq_len = None;
+    let mut prompts: Vec<String> = vec![];
+    let mut verbose = false;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            // a JSON file of settings, which the flags after it change
+            "--config" => {
+                let path: PathBuf = flag_value(&mut args, "--config");
+                let file = std::fs::File::open(&path)
+                    .unwrap_or_else(|e| panic!("cannot open {}: {e}", path.display()));
+                config = serde_json::from_reader(file)
+                    .unwrap_or_else(|e| panic!("invalid config {}: {e}", path.display()));
+            }
+            "--max-len" => config.max_len = flag_value(&mut args, "--max-len"),
+            "--top-p" => config.top_p = flag_value(&mut args, "--top-p"),
+            "--top-k" => config.top_k = flag_value(&mut args, "--top-k"),
+            "--temperature" => config.temperature = flag_value(&mut args, "--temperature"),
+            // makes the sampled story reproducible
+            "--seed" => config.seed = Some(flag_value(&mut args, "--seed")),
+            "--min-p" => config.min_p = flag_value(&mut args, "--min-p"),
+            "--typical-p" => config.typical_p = flag_value(&mut args, "--typical-p"),
+            "--repeat-penalty" => {
+                config.repetition_penalty = flag_value(&mut args, "--repeat-penalty")
+            }
+            "--repeat-last-n" => config.penalty_last_n = flag_value(&mut args, "--repeat-last-n"),
+            "--frequency-penalty" => {
+                config.frequency_penalty = flag_value(&mut args, "--frequency-penalty")
+            }
+            "--presence-penalty" => {
+                config.presence_penalty = flag_value(&mut args, "--presence-penalty")
+            }
+            "--ban" => banned.push(flag_value::<String>(&mut args, "--ban")),
+            "--suppress-special" => config.suppress_special = true,
+            "--stop" => config.stop.push(flag_value(&mut args, "--stop")),
+            "--logprobs" => config.logprobs = Some(flag_value(&mut args, "--logprobs")),
+            "--json" => json = true,
+            "--no-repeat-ngram-size" => {
+                config.no_repeat_ngram_size = flag_value(&mut args, "--no-repeat-ngram-size")
+            }
+            // the n-gram size, drafting up to 8 tokens, see --config for other draft lengths
+            "--prompt-lookup" => {
+                config.prompt_lookup = Some(generation::PromptLookup {
+                    ngram: flag_value(&mut args, "--prompt-lookup"),
+                    max_draft: 8,
+                })
+            }
+            "--max-time" => {
+                let seconds = flag_value(&mut args, "--max-time");
+                config.max_time = Some(std::time::Duration::from_secs_f64(seconds))
+            }
+            "--max-total-tokens" => {
+                config.max_total_tokens = Some(flag_value(&mut args, "--max-total-tokens"))
+            }
+            "--kv-dtype" => kv_dtype = flag_value(&mut args, "--kv-dtype"),
+            "--kv-layout" => kv_layout = flag_value(&mut args, "--kv-layout"),
+            "--stop-at-context-limit" => config.stop_at_context_limit = true,
+            "--max-seq-len" => max_seq_len = Some(flag_value(&mut args, "--max-seq-len")),
+            // one story per prompt, one after another in the same cache
+            "--prompt" => prompts.push(flag_value(&mut args, "--prompt")),
+            // prints where the memory goes at startup
+            "--verbose" => verbose = true,
+            _ => panic!("unknown argument {arg}"),
+        }
+    }
+    if let Err(e) = config.validate() {
+        eprintln!("{e}");
+        std::process::exit(2);
+    }
     let project_dir = env!("CARGO_MANIFEST_DIR");
     let model_dir = PathBuf::from(project_dir).join("models").join("story");
-    let llama = model::Llama::<f32>::from_safetensors(&model_dir);
+    let mut llama = model::Llama::<f32>::from_safetensors(&model_dir)
+        .with_kv_dtype(kv_dtype)
+        .with_kv_layout(kv_layout);
+    if let Some(max_seq_len) = max_seq_len {
+        llama = llama.with_max_seq_len(max_seq_len);
+    }
+    let report = llama.memory_report(llama.max_seq_len());
+    if verbose {
+        eprintln!("{report}");
+    }
+    // the weights are loaded already, the cache and the buffers still to come
+    let needed = report.cache_max + report.forward_buffers;
+    if let Some(available) = available_memory().filter(|&available| needed > available) {
+        eprintln!(
+            "a max_seq_len of {} needs {} MiB for the kv cache and forward, only {} MiB are available",
+            llama.max_seq_len(),
+            needed >> 20,
+            available >> 20
+        );
+        std::process::exit(2);
+    }
+    #[cfg(feature = "wgpu")]
+    let llama = llama.with_backend(wgpu_backend::default_backend());
     let tokenizer = Tokenizer::from_file(model_dir.join("tokenizer.json")).unwrap();
-    let input = "Once upon a time";
-    let binding = tokenizer.encode(input, true).unwrap();
-    let input_ids = binding.get_ids();
-    print!("\n{}", input);
-    let output_ids = llama.generate(
-        input_ids,
-        500,
-        0.8,
-        30,
-        1.,
-    );
-    println!("{}", tokenizer.decode(&output_ids, true).unwrap());
+    for s in banned {
+        let encoding = tokenizer.encode(s.as_str(), false).unwrap();
+        match encoding.get_ids() {
+            &[id] => config.banned_tokens.push(id),
+            ids => eprintln!(
+                "not banning {s:?}, it is {} tokens rather than one",
+                ids.len()
+            ),
+        }
+    }
+    if prompts.is_empty() {
+        prompts.push("Once upon a time".to_string());
+    }
+    let pieces = constraint::token_pieces(&tokenizer);
+    if json {
+        // an object per prompt, with the log probabilities of the tokens under --logprobs
+        for input in &prompts {
+            let binding = tokenizer.encode(input.as_str(), true).unwrap();
+            let (text, tokens) = llama
+                .generate_text(binding.get_ids(), &config, &pieces, |_| {})
+                .unwrap();
+            let mut output = serde_json::json!({ "prompt": input, "text": text });
+            if config.logprobs.is_some() {
+                output["tokens"] = serde_json::to_value(tokens).unwrap();
+            }
+            println!("{output}");
+        }
+        return;
+    }
+    // allocated once, each story starting over in the storage the one before used
+    let mut cache = llama.new_cache();
+    // the stop strings are looked for in the text below, the stream only gives tokens
+    let stream_config = GenerationConfig {
+        stop: Vec::new(),
+        ..config.clone()
+    };
+    for input in &prompts {
+        let binding = tokenizer.encode(input.as_str(), true).unwrap();
+        cache.clear();
+        print!("\n{}", input);
+        // streamed as generated, holding back what may begin a stop string or a character
+        let mut stop = stop::StopMatcher::new(&config.stop);
+        let mut utf8 = stop::Utf8Stream::default();
+        let stream = llama.generate_stream_in(&mut cache, binding.get_ids(), &stream_config);
+        for token in stream.unwrap() {
+            // such as a story that outgrows --max-seq-len
+            let token = token.unwrap_or_else(|e| {
+                eprintln!("\n{e}");
+                std::process::exit(1)
+            });
+            let piece = pieces.get(token as usize).map_or(&[][..], |p| &p[..]);
+            print!("{}", utf8.push(&stop.push(piece)));
+            std::io::stdout().flush().unwrap();
+            if stop.stopped() {
+                break;
+            }
+        }
+        print!("{}", utf8.push(&stop.finish()));
+        println!("{}", utf8.finish());
+    }
 }
@@ -1,36 +1,147 @@
 use std::fs::File;
-use std::vec;
 
-use crate::config::LlamaConfigJson;
-use crate::kvcache::KVCache;
+use crate::backend::{Backend, CpuBackend};
+use crate::config::{LlamaConfigJson, RopeScalingJson};
+use crate::constraint::Constraint;
+#[cfg(feature = "cuda")]
+use crate::cuda_backend::{CudaBackend, CudaConfig, CudaError};
+use crate::generation::{
+    contrastive_pick, BeamSearch, ContrastiveSearch, FinishReason, GenerateError, GeneratedToken,
+    GenerationConfig, GenerationEvent, GenerationResult, GenerationStats, Hypothesis, NgramIndex,
+    PromptLookup,
+};
+use crate::kvcache::{CacheSpec, KVCache, KvDtype, KvLayout, SharedPrefix};
 use crate::operators as OP;
-use crate::params::LLamaParams;
+use crate::paged::{PagePool, PageRows};
+use crate::params::{LLamaParams, LoadOptions, Weight};
+use crate::processor::{GenerationContext, LogitsPipeline};
+use crate::stop::{StopMatcher, Utf8Stream};
 use crate::tensor::Tensor;
+#[cfg(test)]
+use crate::test_support::{story_dir, story_model, story_tokenizer};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
 use safetensors::SafeTensors;
+use smallvec::SmallVec;
+use std::ops::{ControlFlow, Deref, DerefMut};
 use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+// StreamingLLM: the first n_sink tokens stay attendable along with the last window ones and
+// the kv cache evicts everything in between, so it never holds more than n_sink + window
+#[allow(unused)]
+#[derive(Clone, Copy, Debug, PartialEq, serde::Deserialize)]
+pub struct AttentionSink {
+    pub n_sink: usize,
+    pub window: usize,
+    // the tokens keep their positions within the whole text rather than being moved to
+    // the positions within the cache, see evict_for_sink
+    #[serde(default)]
+    pub absolute_positions: bool,
+}
+
+// what a model takes in memory, in bytes, see Llama::memory_report
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MemoryReport {
+    pub weights: usize,
+    pub cache: usize,     // K and V of seq_len tokens
+    pub cache_max: usize, // of max_seq_len tokens, which new_cache allocates up front
+    // an estimate of the buffers of a forward of seq_len tokens, the largest of them the
+    // attention scores of every head and token with room for max_seq_len keys
+    pub forward_buffers: usize,
+}
+
+impl MemoryReport {
+    // the weights, a new cache and one forward
+    pub fn total(&self) -> usize {
+        self.weights + self.cache_max + self.forward_buffers
+    }
+}
+
+impl std::fmt::Display for MemoryReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let mib = |bytes: usize| bytes as f64 / (1 << 20) as f64;
+        write!(
+            f,
+            "weights {:.1} MiB, kv cache {:.1} MiB ({:.1} MiB at max_seq_len), \
+            forward buffers {:.1} MiB, total {:.1} MiB",
+            mib(self.weights),
+            mib(self.cache),
+            mib(self.cache_max),
+            mib(self.forward_buffers),
+            mib(self.total())
+        )
+    }
+}
+
 pub struct Llama<T> {
-    vocab: usize,           // vocab size
-    n_layers: usize,        // number of layers
-    n_q_h: usize,           // number of heads for q
-    n_kv_h: usize,          // number of heads for k and v
-    d: usize,               // dimension of hidden states
-    dqkv: usize,            // length of a single q, k, or v vector
-    di: usize,              // dimension of intermediate states
-    eps: f32,               // epsilon for RMS normalization
-    rope_theta: f32,        // rope theta for rope initialization
-    max_seq_len: usize,     // maximum sequence length
+    vocab: usize,                       // vocab size
+    n_layers: usize,                    // number of layers
+    n_q_h: usize,                       // number of heads for q
+    n_kv_h: usize,                      // number of heads for k and v
+    d: usize,                           // dimension of hidden states
+    dqkv: usize,                        // length of a single q, k, or v vector
+    di: usize,                          // dimension of intermediate states
+    activation: OP::Activation,         // activation of the gate projection in the MLP
+    eps: f32,                           // epsilon for RMS normalization
+    norm_unit_offset: bool,             // rms_norm scales by 1 + w (Gemma) instead of w
+    embedding_scale: f32,               // looked up embeddings are multiplied by it, 1 unless Gemma
+    rope: OP::RopeCache,                // precomputed rope sin/cos tables
+    rope_layout: OP::RopeLayout,        // which elements of a head rope rotates together
+    max_seq_len: usize,                 // maximum sequence length
+    attn_scale: f32, // scale of q @ k.T, 1 / sqrt(dqkv) unless rope scaling changes it
+    attn_softcap: Option<f32>, // soft-capping of attention scores
+    final_softcap: Option<f32>, // soft-capping of the output logits
+    kv_dtype: KvDtype, // what new_cache stores K and V as
+    kv_layout: KvLayout, // and how it orders them when they are f32
+    page_pool: Option<Arc<PagePool>>, // which the caches of new_cache take pages from, if any
+    workspace: Mutex<ForwardWorkspace>, // the buffers of forward, see forward_in
     params: LLamaParams<T>, // trained weights of this model
-    bos_token_id: u32,      // start token id
-    eos_token_id: u32,      // end token id
+    backend: Arc<dyn Backend>, // runs the operators of forward, CpuBackend by default
+    bos_token_id: u32, // start token id
+    eos_token_ids: SmallVec<[u32; 4]>, // any of them ends a generation
 }
 
 impl Llama<f32> {
     pub fn from_safetensors(model_dir: impl AsRef<Path>) -> Self {
+        Self::from_safetensors_with(model_dir, LoadOptions::default())
+    }
+
+    pub fn from_safetensors_with(model_dir: impl AsRef<Path>, options: LoadOptions) -> Self {
         let config = File::open(model_dir.as_ref().join("config.json")).unwrap();
-        let config: LlamaConfigJson = serde_json::from_reader(config).unwrap();
+        let config: LlamaConfigJson = serde_json::from_reader(config)
+            .unwrap_or_else(|e| panic!("unsupported config.json: {e}"));
+        config
+            .check()
+            .unwrap_or_else(|e| panic!("unsupported config.json: {e}"));
         let model_file = std::fs::read(model_dir.as_ref().join("model.safetensors")).unwrap();
         let safetensor = SafeTensors::deserialize(&model_file).unwrap();
-        let params = LLamaParams::from_safetensors(&safetensor, &config);
+        let params = LLamaParams::from_safetensors_with(&safetensor, &config, options);
+        let rope_scaling = match config.rope_scaling {
+            None => OP::RopeScaling::None,
+            Some(RopeScalingJson::Linear { factor }) => OP::RopeScaling::Linear { factor },
+            Some(RopeScalingJson::Dynamic { factor }) => OP::RopeScaling::DynamicNtk {
+                factor,
+                max_position_embeddings: config.max_position_embeddings,
+            },
+            Some(RopeScalingJson::Yarn {
+                factor,
+                original_max_position_embeddings,
+                beta_fast,
+                beta_slow,
+                attention_factor,
+            }) => OP::RopeScaling::Yarn {
+                factor,
+                original_max_position_embeddings: original_max_position_embeddings
+                    .unwrap_or((config.max_position_embeddings as f32 / factor) as usize),
+                beta_fast,
+                beta_slow,
+                attention_factor: attention_factor.unwrap_or(0.1 * factor.ln() + 1.),
+            },
+        };
+        // scaled rope stretches the usable context beyond the trained one
+        let max_seq_len = rope_scaling.max_positions(config.max_position_embeddings);
 
         Self {
             vocab: config.vocab_size,
@@ -38,230 +149,4022 @@ impl Llama<f32> {
             n_q_h: config.num_attention_heads,
             n_kv_h: config.num_key_value_heads,
             d: config.hidden_size,
-            dqkv: config.hidden_size / config.num_attention_heads,
+            dqkv: config.head_dim(),
             di: config.intermediate_size,
+            activation: config.hidden_act.into(),
             eps: config.rms_norm_eps,
-            rope_theta: config.rope_theta,
-            max_seq_len: config.max_position_embeddings,
-            params: params,
+            norm_unit_offset: config.rms_norm_unit_offset(),
+            embedding_scale: config.embedding_scale(),
+            rope: OP::RopeCache::new(
+                max_seq_len,
+                config.rotary_dim(),
+                config.rope_theta,
+                rope_scaling,
+            ),
+            rope_layout: if config.rope_interleaved {
+                OP::RopeLayout::Interleaved
+            } else {
+                OP::RopeLayout::Neox
+            },
+            max_seq_len,
+            attn_scale: rope_scaling.attention_factor().powi(2) / (config.head_dim() as f32).sqrt(),
+            attn_softcap: config.attn_logit_softcapping,
+            final_softcap: config.final_logit_softcapping,
+            params,
+            backend: Arc::new(CpuBackend),
             bos_token_id: config.bos_token_id,
-            eos_token_id: config.eos_token_id,
+            eos_token_ids: config.eos_token_id,
+            kv_dtype: KvDtype::F32,
+            kv_layout: KvLayout::TokenMajor,
+            page_pool: None,
+            workspace: Mutex::default(),
+        }
+    }
+
+    #[allow(unused)]
+    pub fn bos_token_id(&self) -> u32 {
+        self.bos_token_id
+    }
+
+    #[allow(unused)]
+    pub fn vocab(&self) -> usize {
+        self.vocab
+    }
+
+    #[allow(unused)]
+    pub fn max_seq_len(&self) -> usize {
+        self.max_seq_len
+    }
+
+    #[allow(unused)]
+    pub fn eos_token_ids(&self) -> &[u32] {
+        &self.eos_token_ids
+    }
+
+    // what a chat template appends after an assistant turn: configs list the end of text
+    // first and turn terminators such as Llama-3's <|eot_id|> after it, so the last one
+    #[allow(unused)]
+    pub fn turn_terminator(&self) -> u32 {
+        *self.eos_token_ids.last().unwrap()
+    }
+
+    // run forward on another backend
+    #[allow(unused)]
+    pub fn with_backend(mut self, backend: Arc<dyn Backend>) -> Self {
+        self.backend = backend;
+        self
+    }
+
+    // caches of new_cache, and thus of the generations, store K and V as dtype
+    #[allow(unused)]
+    pub fn with_kv_dtype(mut self, dtype: KvDtype) -> Self {
+        self.kv_dtype = dtype;
+        self
+    }
+
+    // f32 caches of new_cache lay K and V out as layout, those of other dtypes and paged
+    // ones stay token-major
+    #[allow(unused)]
+    pub fn with_kv_layout(mut self, layout: KvLayout) -> Self {
+        self.kv_layout = layout;
+        self
+    }
+
+    // Caches of new_cache take pages of pool as their entries grow rather than holding
+    // max_seq_len of them from the start, so that many sequences share the memory of the
+    // ones they actually have. They are f32 whatever with_kv_dtype.
+    #[allow(unused)]
+    pub fn with_page_pool(mut self, pool: Arc<PagePool>) -> Self {
+        assert_eq!(pool.dim(), self.n_kv_h * self.dqkv, "pages of another dim");
+        self.page_pool = Some(pool);
+        self
+    }
+
+    // Caches of new_cache hold max_seq_len tokens, and generations stop there. A limit
+    // past the positions the model was trained for works, though the output degrades.
+    #[allow(unused)]
+    pub fn with_max_seq_len(mut self, max_seq_len: usize) -> Self {
+        assert!(max_seq_len > 0);
+        self.max_seq_len = max_seq_len;
+        self
+    }
+
+    #[allow(unused)]
+    pub fn weights_memory_bytes(&self) -> usize {
+        self.params.size_in_bytes()
+    }
+
+    // the memory of the weights, of the cache and of a forward of seq_len tokens
+    #[allow(unused)]
+    pub fn memory_report(&self, seq_len: usize) -> MemoryReport {
+        let dim = self.n_kv_h * self.dqkv;
+        // paged caches are f32 and, like head-major ones, take the new entries from a buffer of
+        // the size of the copy that a cache of another dtype is read into
+        let (dtype, copied) = match self.page_pool {
+            Some(_) => (KvDtype::F32, true),
+            None => (
+                self.kv_dtype,
+                self.kv_dtype != KvDtype::F32 || self.kv_layout == KvLayout::HeadMajor,
+            ),
+        };
+        let per_token = KVCache::with_dtype(self.n_layers, 1, dim, self.dqkv, dtype).memory_bytes();
+        // the workspace of forward, whose scores and head-major copies of k and v take room
+        // for max_seq_len keys, the f32 copy of the layer that a cache of another dtype is read
+        // into, and the logits of the last token
+        let floats = seq_len * (2 * self.d + 3 * self.n_q_h * self.dqkv + 2 * self.di)
+            + self.n_q_h * seq_len * self.max_seq_len
+            + 2 * self.max_seq_len * dim
+            + if copied { 2 * seq_len * dim } else { 0 }
+            + self.vocab;
+        MemoryReport {
+            weights: self.weights_memory_bytes(),
+            cache: per_token * seq_len,
+            cache_max: per_token * self.max_seq_len,
+            forward_buffers: floats * size_of::<f32>(),
+        }
+    }
+
+    // compute the logits with a 4-bit lm_head, see LLamaParams::quantize_lm_head_q4
+    #[allow(unused)]
+    pub fn quantize_lm_head_q4(&mut self) {
+        self.params.quantize_lm_head_q4();
+    }
+
+    // a copy of this model on the first CUDA device, see CudaBackend
+    #[cfg(feature = "cuda")]
+    #[allow(unused)]
+    pub fn to_cuda(&self) -> Result<CudaBackend, CudaError> {
+        if self.activation != OP::Activation::Silu {
+            return Err(CudaError::Unsupported("activations other than silu"));
+        }
+        if self.norm_unit_offset {
+            return Err(CudaError::Unsupported("rms_norm with a unit offset"));
         }
+        if self.params.q_norm.is_some() || self.params.k_norm.is_some() {
+            return Err(CudaError::Unsupported("q and k norms"));
+        }
+        if self.attn_softcap.is_some() || self.final_softcap.is_some() {
+            return Err(CudaError::Unsupported("soft-capping"));
+        }
+        if self.rope.for_seq_len(self.max_seq_len).is_some() {
+            return Err(CudaError::Unsupported("dynamic NTK rope scaling"));
+        }
+        let config = CudaConfig {
+            vocab: self.vocab,
+            n_layers: self.n_layers,
+            n_q_h: self.n_q_h,
+            n_kv_h: self.n_kv_h,
+            d: self.d,
+            dqkv: self.dqkv,
+            di: self.di,
+            eps: self.eps,
+            embedding_scale: self.embedding_scale,
+            max_seq_len: self.max_seq_len,
+            attn_scale: self.attn_scale,
+            rope_layout: self.rope_layout,
+        };
+        CudaBackend::new(config, &self.params, &self.rope)
     }
 
     pub fn new_cache(&self) -> KVCache<f32> {
-        KVCache::new(self.n_layers, self.max_seq_len, self.n_kv_h * self.dqkv, 0)
+        if let Some(pool) = &self.page_pool {
+            return KVCache::paged(self.n_layers, self.max_seq_len, pool);
+        }
+        if self.kv_dtype == KvDtype::F32 && self.kv_layout == KvLayout::HeadMajor {
+            return KVCache::head_major(self.n_layers, self.max_seq_len, self.n_kv_h, self.dqkv);
+        }
+        let dim = self.n_kv_h * self.dqkv;
+        KVCache::with_dtype(
+            self.n_layers,
+            self.max_seq_len,
+            dim,
+            self.dqkv,
+            self.kv_dtype,
+        )
+    }
+
+    // tokens prefilled once, such as a system prompt that many chats start with, into
+    // entries that the caches of cache_with_prefix all read rather than copy
+    #[allow(unused)]
+    pub fn shared_prefix(&self, tokens: &[u32]) -> Result<SharedPrefix, GenerateError> {
+        if tokens.len() > self.max_seq_len {
+            return Err(GenerateError::PromptTooLong {
+                len: tokens.len(),
+                max_position_embeddings: self.max_seq_len,
+            });
+        }
+        let dim = self.n_kv_h * self.dqkv;
+        let mut cache =
+            KVCache::with_dtype(self.n_layers, tokens.len(), dim, self.dqkv, self.kv_dtype);
+        if !tokens.is_empty() {
+            self.forward(
+                &Tensor::new(tokens.to_vec(), &vec![tokens.len()]),
+                &mut cache,
+            )?;
+        }
+        Ok(SharedPrefix::new(tokens, cache))
+    }
+
+    // a cache holding the entries of prefix, with room for max_seq_len in all
+    #[allow(unused)]
+    pub fn cache_with_prefix(&self, prefix: &SharedPrefix) -> KVCache<f32> {
+        KVCache::with_prefix(prefix, self.max_seq_len)
+    }
+
+    // what the cache files of the model record, config_hash being FNV-1a of its config
+    #[allow(unused)]
+    pub fn cache_spec(&self) -> CacheSpec {
+        let dims = [
+            self.vocab,
+            self.n_layers,
+            self.n_q_h,
+            self.n_kv_h,
+            self.d,
+            self.dqkv,
+            self.di,
+            self.max_seq_len,
+        ];
+        let floats = [
+            self.eps,
+            self.rope.theta(),
+            self.attn_scale,
+            self.embedding_scale,
+        ];
+        let fields = dims.map(|x| x as u64).into_iter();
+        let mut hash = 0xcbf29ce484222325u64;
+        for x in fields.chain(floats.map(|x| x.to_bits() as u64)) {
+            for b in x.to_le_bytes() {
+                hash = (hash ^ b as u64).wrapping_mul(0x100000001b3);
+            }
+        }
+        CacheSpec {
+            n_layers: self.n_layers,
+            n_kv_heads: self.n_kv_h,
+            head_dim: self.dqkv,
+            max_seq_len: self.max_seq_len,
+            config_hash: hash,
+        }
     }
 
     // 前向传播
-    pub fn forward(&self, input: &Tensor<u32>, cache: &mut KVCache<f32>) -> Tensor<f32> {
+    // Fails on token ids outside the vocab, leaving the cache untouched, and on weights whose
+    // shapes do not fit the config, naming the layer
+    #[allow(unused)]
+    pub fn forward(
+        &self,
+        input: &Tensor<u32>,
+        cache: &mut KVCache<f32>,
+    ) -> Result<Tensor<f32>, OP::OperatorError> {
+        self.forward_with_sink(input, cache, None)
+    }
+
+    // forward where every query only attends to the sink and window keys of sink,
+    // which matters for prompts longer than n_sink + window, see evict_for_sink
+    pub fn forward_with_sink(
+        &self,
+        input: &Tensor<u32>,
+        cache: &mut KVCache<f32>,
+        sink: Option<AttentionSink>,
+    ) -> Result<Tensor<f32>, OP::OperatorError> {
+        let mut logits = Tensor::<f32>::default(&vec![1, self.vocab]);
+        self.forward_into(input, cache, sink, &mut logits)?;
+        Ok(logits)
+    }
+
+    // forward, but with the logits after every one of the input tokens, (seq_len, vocab)
+    #[allow(unused)]
+    pub fn forward_all(
+        &self,
+        input: &Tensor<u32>,
+        cache: &mut KVCache<f32>,
+    ) -> Result<Tensor<f32>, OP::OperatorError> {
+        let mut logits = Tensor::<f32>::default(&vec![input.size(), self.vocab]);
+        self.forward_into(input, cache, None, &mut logits)?;
+        Ok(logits)
+    }
+
+    // forward, along with the hidden states after the last norm for every input token,
+    // (seq_len, d), which the lm_head turns into logits
+    #[allow(unused)]
+    pub fn forward_with_hidden(
+        &self,
+        input: &Tensor<u32>,
+        cache: &mut KVCache<f32>,
+    ) -> Result<(Tensor<f32>, Tensor<f32>), OP::OperatorError> {
+        let mut logits = Tensor::<f32>::default(&vec![1, self.vocab]);
+        let mut hidden = Tensor::<f32>::default(&vec![input.size(), self.d]);
+        self.forward_hidden_into(input, cache, None, &mut logits, Some(&mut hidden))?;
+        Ok((logits, hidden))
+    }
+
+    // Forward of several sequences at once, each with its own cache, giving the logits after
+    // the last token of each, (1, vocab). The tokens of all of them are packed into one
+    // input for the projections and the mlp, so the weights are read once for the batch,
+    // while rope and attention are per sequence, every one at its own positions and
+    // attending to its own cache only. Rope tables that depend on the sequence length go
+    // through forward one sequence after another instead.
+    #[allow(unused)]
+    pub fn forward_batch(
+        &self,
+        inputs: &[&[u32]],
+        caches: &mut [&mut KVCache<f32>],
+    ) -> Result<Vec<Tensor<f32>>, OP::OperatorError> {
+        self.forward_batch_hidden(inputs, caches, None)
+    }
+
+    // forward_batch, copying the hidden state after the last norm of the last token of every
+    // sequence into hidden if given, (batch, d)
+    fn forward_batch_hidden(
+        &self,
+        inputs: &[&[u32]],
+        caches: &mut [&mut KVCache<f32>],
+        mut hidden: Option<&mut Tensor<f32>>,
+    ) -> Result<Vec<Tensor<f32>>, OP::OperatorError> {
+        assert_eq!(inputs.len(), caches.len(), "a cache per sequence");
+        assert!(
+            inputs.iter().all(|input| !input.is_empty()),
+            "an empty sequence in the batch"
+        );
+        // every sequence is checked before any cache moves on, the fallback below included
+        let kv_dim = self.n_kv_h * self.dqkv;
+        for cache in caches.iter() {
+            assert!(
+                cache.layers() == self.n_layers && cache.dim() == kv_dim,
+                "cache of {} layers of dim {} for a model of {} layers of dim {kv_dim}",
+                cache.layers(),
+                cache.dim(),
+                self.n_layers
+            );
+        }
+        for (input, cache) in inputs.iter().zip(caches.iter()) {
+            if cache.len() + input.len() > cache.max_len() {
+                return Err(OP::OperatorError::ContextOverflow {
+                    needed: cache.len() + input.len(),
+                    max: cache.max_len(),
+                });
+            }
+        }
+        let dynamic = inputs.iter().zip(caches.iter()).any(|(input, cache)| {
+            let start = cache.position(cache.len());
+            self.rope.for_seq_len(start).is_some()
+                || self.rope.for_seq_len(start + input.len()).is_some()
+        });
+        if dynamic {
+            let mut outputs = Vec::with_capacity(inputs.len());
+            for (i, (input, cache)) in inputs.iter().zip(caches.iter_mut()).enumerate() {
+                let input = Tensor::new(input.to_vec(), &[input.len()]);
+                let mut logits = Tensor::<f32>::default(&[1, self.vocab]);
+                let mut h = Tensor::<f32>::default(&[input.size(), self.d]);
+                self.forward_hidden_into(&input, cache, None, &mut logits, Some(&mut h))?;
+                if let Some(hidden) = hidden.as_deref_mut() {
+                    let (rows, last) = (unsafe { hidden.data_mut() }, h.data());
+                    rows[i * self.d..][..self.d].copy_from_slice(&last[last.len() - self.d..]);
+                }
+                outputs.push(logits);
+            }
+            return Ok(outputs);
+        }
+        self.with_workspace(|workspace| self.forward_batch_in(inputs, caches, hidden, workspace))
+    }
+
+    // forward_batch_hidden of sequences that all rotate with the tables of self.rope,
+    // working in workspace like forward_in
+    fn forward_batch_in(
+        &self,
+        inputs: &[&[u32]],
+        caches: &mut [&mut KVCache<f32>],
+        hidden: Option<&mut Tensor<f32>>,
+        workspace: &mut ForwardWorkspace,
+    ) -> Result<Vec<Tensor<f32>>, OP::OperatorError> {
+        let ws = workspace;
+        let kv_dim = self.n_kv_h * self.dqkv;
+        // the rows of sequence i are offsets[i]..offsets[i + 1] of the packed input
+        let mut offsets = vec![0];
+        offsets.extend(inputs.iter().scan(0, |end, input| {
+            *end += input.len();
+            Some(*end)
+        }));
+        let n = offsets[inputs.len()];
+        let packed = Tensor::<u32>::new(inputs.concat(), &[n]);
+        let mut residual = ws.residual.view(&[n, self.d], 0);
+        let backend = self.backend.as_ref();
+        backend.gather(
+            &mut residual,
+            &packed,
+            &self.params.embedding_table,
+            self.embedding_scale,
+        )?;
+        // where the input of every sequence goes, as forward finds it
+        let past: Vec<(usize, usize)> = caches
+            .iter_mut()
+            .zip(inputs)
+            .map(|(cache, input)| {
+                let past_seq_len = cache.len();
+                let start_pos = cache.position(past_seq_len);
+                assert!(
+                    past_seq_len > 0 || (start_pos == 0 && cache.tokens().is_empty()),
+                    "an empty cache starting at position {start_pos}"
+                );
+                cache.increment(input.len());
+                cache.record_tokens(past_seq_len, input);
+                (past_seq_len, start_pos)
+            })
+            .collect();
+        let n_groups = self.n_q_h / self.n_kv_h;
+        let q_dim = self.n_q_h * self.dqkv;
+        let hidden_states = ws.hidden.view(&[n, self.d], 0);
+        let mut q_buf = ws.q.view(&[n, q_dim], 0);
+        let mut k_buf = ws.k.view(&[n, kv_dim], 0);
+        let mut v_buf = ws.v.view(&[n, kv_dim], 0);
+        let mut gate_buf = ws.gate.view(&[n, self.di], 0);
+        let mut up_buf = ws.up.view(&[n, self.di], 0);
+        // the scores of every sequence in turn, with room for the longest of them
+        let longest = inputs.iter().map(|input| input.len()).max().unwrap();
+        let max_len = caches.iter().map(|cache| cache.max_len()).max().unwrap();
+        let scores_room = self.n_q_h * longest * max_len;
+
+        for layer in 0..self.n_layers {
+            let in_layer = |e: OP::OperatorError| e.in_layer(layer);
+            let rms_w = &self.params.rms_att_w[layer];
+            for (y, w) in [
+                (&mut q_buf, &self.params.wq[layer]),
+                (&mut k_buf, &self.params.wk[layer]),
+                (&mut v_buf, &self.params.wv[layer]),
+            ] {
+                backend
+                    .rms_norm_matmul_transb(
+                        y,
+                        &residual,
+                        rms_w,
+                        w,
+                        self.eps,
+                        self.norm_unit_offset,
+                        1.0,
+                    )
+                    .map_err(in_layer)?;
+            }
+            for (i, cache) in caches.iter_mut().enumerate() {
+                let (start, seq_len) = (offsets[i], offsets[i + 1] - offsets[i]);
+                let (past_seq_len, start_pos) = past[i];
+                let q = &mut q_buf.slice(start * q_dim, &[seq_len, self.n_q_h, self.dqkv]);
+                let k = &mut k_buf.slice(start * kv_dim, &[seq_len, self.n_kv_h, self.dqkv]);
+                let v = v_buf.slice(start * kv_dim, &[seq_len, kv_dim]);
+                if let Some(q_norm) = &self.params.q_norm {
+                    OP::checked_qk_rms_norm(q, &q_norm[layer], self.eps).map_err(in_layer)?;
+                }
+                if let Some(k_norm) = &self.params.k_norm {
+                    OP::checked_qk_rms_norm(k, &k_norm[layer], self.eps).map_err(in_layer)?;
+                }
+                backend
+                    .rope(q, start_pos, &self.rope, self.rope_layout)
+                    .map_err(in_layer)?;
+                backend
+                    .rope(k, start_pos, &self.rope, self.rope_layout)
+                    .map_err(in_layer)?;
+                cache.write_entries(layer, past_seq_len, k, &v);
+                let heads = cache.head_major_layer(layer);
+                let rows = match (&heads, cache.layer_pages(layer)) {
+                    (None, None) => Some(cache.load_layer(layer)),
+                    _ => None,
+                };
+                let total_seq_len = past_seq_len + seq_len;
+                let scores_shape = [self.n_kv_h, n_groups, seq_len, total_seq_len];
+                self_attention_on(
+                    backend,
+                    &mut ws.heads,
+                    &mut hidden_states.slice(start * q_dim, &[seq_len, q_dim]),
+                    &mut ws.scores.view(&scores_shape, scores_room),
+                    q,
+                    match (&heads, &rows) {
+                        (Some((k, v)), _) => KvSource::HeadMajor(k, v),
+                        (None, Some((k, v))) => KvSource::Rows(k, v),
+                        (None, None) => KvSource::Pages(cache.layer_pages(layer).unwrap()),
+                    },
+                    self.n_kv_h,
+                    n_groups,
+                    seq_len,
+                    total_seq_len,
+                    cache.max_len(),
+                    self.dqkv,
+                    self.attn_scale,
+                    self.attn_softcap,
+                    None,
+                );
+            }
+            backend
+                .matmul_transb(
+                    &mut residual,
+                    1.,
+                    &hidden_states,
+                    &self.params.wo[layer],
+                    1.0,
+                )
+                .map_err(in_layer)?;
+            mlp_on(
+                backend,
+                &mut residual,
+                &mut gate_buf,
+                &mut up_buf,
+                &self.params.w_up[layer],
+                &self.params.w_down[layer],
+                &self.params.w_gate[layer],
+                &self.params.rms_ffn_w[layer],
+                self.eps,
+                self.norm_unit_offset,
+                self.activation,
+            )
+            .map_err(in_layer)?;
+        }
+
+        // the last row of every sequence, through the lm_head together
+        let batch = inputs.len();
+        let mut last = Tensor::<f32>::default(&[batch, self.d]);
+        {
+            let (rows, data) = (unsafe { last.data_mut() }, residual.data());
+            for (i, &end) in offsets[1..].iter().enumerate() {
+                rows[i * self.d..][..self.d].copy_from_slice(&data[(end - 1) * self.d..][..self.d]);
+            }
+        }
+        let mut normed = Tensor::<f32>::default(&[batch, self.d]);
+        backend.rms_norm(
+            &mut normed,
+            &last,
+            &self.params.rms_out_w,
+            self.eps,
+            self.norm_unit_offset,
+        )?;
+        if let Some(hidden) = hidden {
+            assert!(hidden.size() == batch * self.d);
+            unsafe { hidden.data_mut() }.copy_from_slice(normed.data());
+        }
+        let lm_head = match &self.params.lm_head_quantized {
+            Some(lm_head) => lm_head,
+            None => &Weight::Full(self.params.lm_head.slice(0, self.params.lm_head.shape())),
+        };
+        let mut logits = Tensor::<f32>::default(&[batch, self.vocab]);
+        backend.matmul_transb(&mut logits, 0., &normed, lm_head, 1.0)?;
+        if let Some(cap) = self.final_softcap {
+            OP::softcap(&mut logits, cap);
+        }
+        Ok(logits
+            .data()
+            .chunks(self.vocab)
+            .map(|row| Tensor::new(row.to_vec(), &[1, self.vocab]))
+            .collect())
+    }
+
+    // forward_with_sink writing the logits into a tensor of the caller's, which holds either
+    // (1, vocab) for the last input token or (seq_len, vocab) for all of them
+    pub fn forward_into(
+        &self,
+        input: &Tensor<u32>,
+        cache: &mut KVCache<f32>,
+        sink: Option<AttentionSink>,
+        logits: &mut Tensor<f32>,
+    ) -> Result<(), OP::OperatorError> {
+        self.forward_hidden_into(input, cache, sink, logits, None)
+    }
+
+    // forward_into, copying the hidden states of every input token into hidden if given
+    fn forward_hidden_into(
+        &self,
+        input: &Tensor<u32>,
+        cache: &mut KVCache<f32>,
+        sink: Option<AttentionSink>,
+        logits: &mut Tensor<f32>,
+        hidden: Option<&mut Tensor<f32>>,
+    ) -> Result<(), OP::OperatorError> {
+        self.with_workspace(|workspace| {
+            self.forward_in(input, cache, sink, logits, hidden, workspace)
+        })
+    }
+
+    // calls f with the workspace of the model, or one of its own while another thread has it
+    fn with_workspace<R>(&self, f: impl FnOnce(&mut ForwardWorkspace) -> R) -> R {
+        match self.workspace.try_lock() {
+            Ok(mut workspace) => f(&mut workspace),
+            Err(_) => f(&mut ForwardWorkspace::default()),
+        }
+    }
+
+    // forward_hidden_into working in workspace, whose buffers it takes views of rather than
+    // allocating its own
+    fn forward_in(
+        &self,
+        input: &Tensor<u32>,
+        cache: &mut KVCache<f32>,
+        sink: Option<AttentionSink>,
+        logits: &mut Tensor<f32>,
+        hidden: Option<&mut Tensor<f32>>,
+        workspace: &mut ForwardWorkspace,
+    ) -> Result<(), OP::OperatorError> {
+        let rows = logits.size() / self.vocab;
+        assert!(logits.size() == rows * self.vocab && (rows == 1 || rows == input.size()));
         // 1. 获取输入序列的长度，以及缓存中已有的序列长度
         let seq_len = input.size();
+        // the input goes to the positions after the cached ones, which must come from a
+        // cache of this model that has room for it
         let past_seq_len = cache.len();
+        assert!(
+            cache.layers() == self.n_layers && cache.dim() == self.n_kv_h * self.dqkv,
+            "cache of {} layers of dim {} for a model of {} layers of dim {}",
+            cache.layers(),
+            cache.dim(),
+            self.n_layers,
+            self.n_kv_h * self.dqkv
+        );
+        if past_seq_len + seq_len > cache.max_len() {
+            return Err(OP::OperatorError::ContextOverflow {
+                needed: past_seq_len + seq_len,
+                max: cache.max_len(),
+            });
+        }
+        // Embedding lookup 执行嵌入查找，将输入序列转换为嵌入向量, before touching the cache
+        let ws = workspace;
+        let mut residual = ws.residual.view(&[seq_len, self.d], 0);
+        let backend = self.backend.as_ref();
+        backend.gather(
+            &mut residual,
+            input,
+            &self.params.embedding_table,
+            self.embedding_scale,
+        )?;
+        // the position in the text of the first input token, which is past_seq_len unless
+        // the cache skipped positions, and the entries from at on that are skipped ones
+        let start_pos = cache.position(past_seq_len);
+        // a cleared cache, or a new one, has nothing left of the text it held before
+        assert!(
+            past_seq_len > 0 || (start_pos == 0 && cache.tokens().is_empty()),
+            "an empty cache starting at position {start_pos}"
+        );
+        let (at, skipped) = cache.gap();
+        let at = at.min(past_seq_len);
         // 2. 更新缓存中的序列长度
         cache.increment(seq_len);
+        cache.record_tokens(past_seq_len, input.data());
         let total_seq_len = past_seq_len + seq_len;
         let n_groups = self.n_q_h / self.n_kv_h;
 
-        // Some pre-allocated buffers that will be reused 预分配一些缓冲区，用于存储中间结果
-        let mut residual = Tensor::<f32>::default(&vec![seq_len, self.d]);
-        let mut hidden_states = Tensor::<f32>::default(&vec![seq_len, self.d]);
-        let mut q_buf = Tensor::<f32>::default(&vec![seq_len, self.n_q_h * self.dqkv]);
-        let mut att_scores =
-            Tensor::<f32>::default(&vec![self.n_kv_h, n_groups, seq_len, total_seq_len]);
-        let mut gate_buf = Tensor::<f32>::default(&vec![seq_len, self.di]);
-        let mut up_buf = Tensor::<f32>::default(&vec![seq_len, self.di]);
+        // Some pre-allocated buffers that will be reused 预分配一些缓冲区，用于存储中间结果, the
+        // scores taking room for max_len keys at once so that decoding does not grow them
+        let max_len = cache.max_len();
+        let mut hidden_states = ws.hidden.view(&[seq_len, self.d], 0);
+        let mut q_buf = ws.q.view(&[seq_len, self.n_q_h * self.dqkv], 0);
+        let scores_shape = [self.n_kv_h, n_groups, seq_len, total_seq_len];
+        let mut att_scores = ws
+            .scores
+            .view(&scores_shape, self.n_q_h * seq_len * max_len);
+        let mut gate_buf = ws.gate.view(&[seq_len, self.di], 0);
+        let mut up_buf = ws.up.view(&[seq_len, self.di], 0);
+        let kv_shape = [seq_len, self.n_kv_h * self.dqkv];
+        // the new entries of a head-major or paged cache, which go into it once they are
+        // rotated
+        let (k_buf, v_buf) = (ws.k.view(&kv_shape, 0), ws.v.view(&kv_shape, 0));
+        // with dynamic NTK the rope tables depend on the sequence length, the cached keys
+        // were rotated for past_seq_len and are re-rotated whenever that changes the tables.
+        // Cached values and the keys of later layers still come from hidden states computed
+        // with the older tables, so decoding past the trained context is close to but not
+        // the same as prefilling the whole sequence at once.
+        let rope_past = self.rope.for_seq_len(start_pos);
+        let rope_total = self.rope.for_seq_len(start_pos + seq_len);
+        let rope_past = rope_past.as_ref().unwrap_or(&self.rope);
+        let rope = rope_total.as_ref().unwrap_or(&self.rope);
+        let rerotate = past_seq_len > 0 && rope_past.theta() != rope.theta();
 
         // Computation Starts Here
-        // Embedding lookup 执行嵌入查找，将输入序列转换为嵌入向量
-        OP::gather(&mut residual, input, &self.params.embedding_table);
         // 对每一层执行RMS normalization归一化
         for layer in 0..self.n_layers {
-            OP::rms_norm(
+            let in_layer = |e: OP::OperatorError| e.in_layer(layer);
+            // 计算自注意力, q, k and v project the rms_norm of the residual without storing it
+            // (seq, n_h * dqkv)
+            let q = q_buf.reshape(&[seq_len, self.n_q_h * self.dqkv]);
+            // (total_seq, n_kv_h * dqkv), or just the new rows of a head-major or paged cache,
+            // whose storage attention reads, the pages of one that has keys to rerotate
+            // excepted
+            let heads = cache.head_major_layer(layer);
+            let paged = heads.is_none() && !rerotate && cache.in_pages(layer);
+            let (full_k, full_v, new_rows) = if heads.is_some() || paged {
+                (k_buf.slice(0, &kv_shape), v_buf.slice(0, &kv_shape), 0)
+            } else {
+                let (full_k, full_v) = cache.load_layer(layer);
+                (full_k, full_v, past_seq_len)
+            };
+            let k = &mut full_k.slice(new_rows * self.n_kv_h * self.dqkv, &kv_shape);
+            let v = &mut full_v.slice(new_rows * self.n_kv_h * self.dqkv, &kv_shape);
+            let rms_w = &self.params.rms_att_w[layer];
+            for (y, w) in [
+                (&mut *q, &self.params.wq[layer]),
+                (&mut *k, &self.params.wk[layer]),
+                (&mut *v, &self.params.wv[layer]),
+            ] {
+                backend
+                    .rms_norm_matmul_transb(
+                        y,
+                        &residual,
+                        rms_w,
+                        w,
+                        self.eps,
+                        self.norm_unit_offset,
+                        1.0,
+                    )
+                    .map_err(in_layer)?;
+            }
+            q.reshape(&[seq_len, self.n_q_h, self.dqkv]);
+            k.reshape(&[seq_len, self.n_kv_h, self.dqkv]);
+            if let Some(q_norm) = &self.params.q_norm {
+                OP::checked_qk_rms_norm(q, &q_norm[layer], self.eps).map_err(in_layer)?;
+            }
+            if let Some(k_norm) = &self.params.k_norm {
+                OP::checked_qk_rms_norm(k, &k_norm[layer], self.eps).map_err(in_layer)?;
+            }
+            backend
+                .rope(q, start_pos, rope, self.rope_layout)
+                .map_err(in_layer)?;
+            backend
+                .rope(k, start_pos, rope, self.rope_layout)
+                .map_err(in_layer)?;
+            if rerotate {
+                // the entries before the skipped positions and the ones after them
+                let row = self.n_kv_h * self.dqkv;
+                for (start, rows, pos) in [(0, at, 0), (at, past_seq_len - at, at + skipped)] {
+                    let past_k = match &heads {
+                        None => {
+                            vec![full_k.slice(start * row, &vec![rows, self.n_kv_h, self.dqkv])]
+                        }
+                        Some((k_heads, _)) => (0..self.n_kv_h)
+                            .map(|h| {
+                                let offset = (h * k_heads.shape()[1] + start) * self.dqkv;
+                                k_heads.slice(offset, &vec![rows, 1, self.dqkv])
+                            })
+                            .collect(),
+                    };
+                    for mut past_k in past_k {
+                        OP::rope_rerotate(&mut past_k, pos, rope_past, rope, self.rope_layout);
+                    }
+                }
+            }
+            // a cache that is no f32 storage gets the new entries, and the rerotated ones
+            if heads.is_some() || paged {
+                cache.write_entries(layer, past_seq_len, &full_k, &full_v);
+            } else {
+                let start = if rerotate { 0 } else { past_seq_len };
+                cache.store_layer(layer, start, &full_k, &full_v);
+            }
+
+            let kv = match (&heads, paged) {
+                (Some((k, v)), _) => KvSource::HeadMajor(k, v),
+                (None, true) => KvSource::Pages(cache.layer_pages(layer).unwrap()),
+                (None, false) => KvSource::Rows(&full_k, &full_v),
+            };
+            self_attention_on(
+                backend,
+                &mut ws.heads,
                 &mut hidden_states,
-                &residual,
-                &self.params.rms_att_w[layer],
-                self.eps,
-            );
-            // 计算自注意力
-            let q = (&mut q_buf).reshape(&vec![seq_len, self.n_q_h * self.dqkv]); // (seq, n_h * dqkv)
-            let k = &mut cache.k_cache(layer, past_seq_len); // (seq, n_kv_h * dqkv)
-            let v = &mut cache.v_cache(layer, past_seq_len); // (seq, n_kv_h * dqkv)
-            OP::matmul_transb(q, 0., &hidden_states, &self.params.wq[layer], 1.0);
-            OP::matmul_transb(k, 0., &hidden_states, &self.params.wk[layer], 1.0);
-            OP::matmul_transb(v, 0., &hidden_states, &self.params.wv[layer], 1.0);
-            OP::rope(
-                q.reshape(&vec![seq_len, self.n_q_h, self.dqkv]),
-                past_seq_len,
-                self.rope_theta,
-            );
-            OP::rope(
-                k.reshape(&vec![seq_len, self.n_kv_h, self.dqkv]),
-                past_seq_len,
-                self.rope_theta,
+                &mut att_scores,
+                q,
+                kv,
+                self.n_kv_h,
+                n_groups,
+                seq_len,
+                total_seq_len,
+                max_len,
+                self.dqkv,
+                self.attn_scale,
+                self.attn_softcap,
+                sink,
             );
+            // out = attn_V @ O_weight.T, added onto the residual through beta
+            backend
+                .matmul_transb(
+                    &mut residual,
+                    1.,
+                    &hidden_states,
+                    &self.params.wo[layer],
+                    1.0,
+                )
+                .map_err(in_layer)?;
 
-            let full_k = &mut cache.k_cache(layer, 0); // (total_seq, n_kv_h * dqkv)
-            let full_v = &mut cache.v_cache(layer, 0); // (total_seq, n_kv_h * dqkv)
-
-            todo!("self_attention(...)");
-            todo!("down_proj matmul and add residual");
-
-            todo!("mlp(...)");
+            mlp_on(
+                backend,
+                &mut residual,
+                &mut gate_buf,
+                &mut up_buf,
+                &self.params.w_up[layer],
+                &self.params.w_down[layer],
+                &self.params.w_gate[layer],
+                &self.params.rms_ffn_w[layer],
+                self.eps,
+                self.norm_unit_offset,
+                self.activation,
+            )
+            .map_err(in_layer)?;
         }
 
         // No matter what seq_len, the output is always a 1D vector of length vocab,
         // which contains the probabilities for the next token.
-        let mut logits = Tensor::<f32>::default(&vec![1, self.vocab]);
-        let mut hidden_states = hidden_states.slice((seq_len - 1) * self.d, &vec![1, self.d]);
-        let residual = residual.slice((seq_len - 1) * self.d, &vec![self.d]);
+        let normed = if hidden.is_some() { seq_len } else { rows };
+        let mut hidden_states = hidden_states.slice((seq_len - normed) * self.d, &[normed, self.d]);
+        let residual = residual.slice((seq_len - normed) * self.d, &[normed, self.d]);
 
-        OP::rms_norm(
+        backend.rms_norm(
             &mut hidden_states,
             &residual,
             &self.params.rms_out_w,
             self.eps,
-        );
+            self.norm_unit_offset,
+        )?;
+        if let Some(hidden) = hidden {
+            assert!(hidden.size() == seq_len * self.d);
+            unsafe { hidden.data_mut() }.copy_from_slice(hidden_states.data());
+        }
+        // the rows of the logits, found in the whole of hidden_states if it was normed
+        let hidden_states = if normed == rows {
+            hidden_states
+        } else {
+            hidden_states.slice((seq_len - rows) * self.d, &[rows, self.d])
+        };
+
+        let lm_head = match &self.params.lm_head_quantized {
+            Some(lm_head) => lm_head,
+            None => &Weight::Full(self.params.lm_head.slice(0, self.params.lm_head.shape())),
+        };
+        // beta 0 still multiplies what logits held, and 0 times a banned -inf is NaN
+        unsafe { logits.data_mut() }.fill(0.);
+        backend.matmul_transb(logits, 0., &hidden_states, lm_head, 1.0)?;
+        if let Some(cap) = self.final_softcap {
+            OP::softcap(logits, cap);
+        }
+
+        Ok(())
+    }
+
+    // The token contrastive search picks after logits, feeding the k most likely through
+    // the model in one forward_batch for their hidden states, each after a fork of the
+    // cache. The cache goes on with the fork of the pick, its logits and hidden state
+    // coming along.
+    #[allow(clippy::type_complexity)]
+    fn contrastive_step(
+        &self,
+        params: ContrastiveSearch,
+        sink: Option<AttentionSink>,
+        cache: &mut KVCache<f32>,
+        logits: &Tensor<f32>,
+        context: &[f32],
+        stats: &mut GenerationStats,
+    ) -> Result<(u32, Option<(Tensor<f32>, Vec<f32>)>), OP::OperatorError> {
+        let data = logits.data();
+        let mut candidates: Vec<u32> = (0..self.vocab as u32)
+            .filter(|&i| data[i as usize] > f32::NEG_INFINITY)
+            .collect();
+        candidates.sort_by(|&a, &b| {
+            data[b as usize]
+                .total_cmp(&data[a as usize])
+                .then(a.cmp(&b))
+        });
+        candidates.truncate(params.k);
+        if let Some(sink) = sink {
+            self.evict_for_sink(cache, sink, 1);
+        }
+        // nothing to weigh up, or no room for it
+        if candidates.len() < 2 || cache.len() >= self.max_seq_len {
+            return Ok((OP::argmax_row(data), None));
+        }
+        let max = data[candidates[0] as usize];
+        let total: f32 = data.iter().map(|&l| (l - max).exp()).sum();
+        let probs: Vec<f32> = candidates
+            .iter()
+            .map(|&c| (data[c as usize] - max).exp() / total)
+            .collect();
+        // the likeliest one goes on in cache itself, the sink made room for one token
+        let mut forks: Vec<KVCache<f32>> = candidates[1..].iter().map(|_| cache.fork()).collect();
+        let mut caches: Vec<&mut KVCache<f32>> =
+            std::iter::once(&mut *cache).chain(&mut forks).collect();
+        let inputs: Vec<&[u32]> = candidates.iter().map(std::slice::from_ref).collect();
+        let mut hidden = Tensor::<f32>::default(&[candidates.len(), self.d]);
+        let mut logits = self.forward_batch_hidden(&inputs, &mut caches, Some(&mut hidden))?;
+        stats.forwards += 1;
+        let pick = contrastive_pick(&probs, hidden.data(), context, self.d, params.alpha);
+        if pick > 0 {
+            std::mem::swap(cache, &mut forks[pick - 1]);
+        }
+        let h = hidden.data()[pick * self.d..][..self.d].to_vec();
+        Ok((candidates[pick], Some((logits.swap_remove(pick), h))))
+    }
 
-        OP::matmul_transb(&mut logits, 0., &hidden_states, &self.params.lm_head, 1.0);
+    // Evict the middle of cache so that incoming new tokens fit into n_sink + window.
+    // The keys that stay are moved to contiguous positions after the sinks, so new tokens
+    // get the positions within the cache rather than within the whole text, as StreamingLLM
+    // does. With dynamic NTK the keys are moved with the tables of the current length.
+    // Under absolute_positions nothing moves and the cache skips the evicted positions.
+    pub fn evict_for_sink(&self, cache: &mut KVCache<f32>, sink: AttentionSink, incoming: usize) {
+        let len = cache.len();
+        let keep = sink.n_sink.min(len);
+        let n = (len + incoming)
+            .saturating_sub(sink.n_sink + sink.window)
+            .min(len - keep);
+        if n == 0 {
+            return;
+        }
+        if sink.absolute_positions {
+            cache.evict(keep, n);
+            cache.skip_positions(keep, n);
+            return;
+        }
+        let rope = self.rope.for_seq_len(len);
+        let rope = rope.as_ref().unwrap_or(&self.rope);
+        for layer in 0..self.n_layers {
+            let (full_k, full_v) = cache.load_layer(layer);
+            let mut k = full_k.slice(
+                (keep + n) * self.n_kv_h * self.dqkv,
+                &vec![len - keep - n, self.n_kv_h, self.dqkv],
+            );
+            OP::rope_reposition(&mut k, keep + n, keep, rope, self.rope_layout);
+            cache.store_layer(layer, keep + n, &full_k, &full_v);
+        }
+        cache.evict(keep, n);
+    }
 
-        logits
+    // Beam search over up to max_new_tokens tokens after the prompt, every hypothesis with a
+    // cache of its own. Returns the retired hypotheses and those max_new_tokens or a full
+    // cache cut off, best length normalized score first, see BeamSearch.
+    #[allow(unused)]
+    pub fn generate_beam(
+        &self,
+        prompt_ids: &[u32],
+        beam_width: usize,
+        max_new_tokens: usize,
+        length_penalty: f32,
+    ) -> Result<Vec<Hypothesis>, OP::OperatorError> {
+        let mut search = BeamSearch::new(beam_width, length_penalty, &self.eos_token_ids);
+        let mut caches = vec![self.new_cache()];
+        let prompt = Tensor::<u32>::new(prompt_ids.to_vec(), &vec![prompt_ids.len()]);
+        let mut logits = vec![self.forward(&prompt, &mut caches[0])?];
+        for step in 0..max_new_tokens {
+            let parents = search.step(&logits);
+            if step + 1 == max_new_tokens
+                || search.is_done()
+                || caches[0].len() + 1 > self.max_seq_len
+            {
+                break;
+            }
+            logits.clear();
+            let mut next = Vec::with_capacity(parents.len());
+            for (hypothesis, &parent) in search.live().iter().zip(&parents) {
+                let mut cache = caches[parent].fork();
+                let token = *hypothesis.tokens.last().unwrap();
+                logits.push(self.forward(&Tensor::<u32>::new(vec![token], &vec![1]), &mut cache)?);
+                next.push(cache);
+            }
+            caches = next;
+        }
+        Ok(search.finish())
     }
 
+    // generates up to config.max_len tokens after the prompt, or past max_seq_len with an
+    // attention sink
+    #[allow(unused)]
     pub fn generate(
         &self,
         token_ids: &[u32],
-        max_len: usize,
-        top_p: f32,
-        top_k: u32,
-        temperature: f32,
-    ) -> Vec<u32>{
-        let mut result = Vec::<u32>::new();
-        
-        todo!("实现文本生成");
-        
-        result
+        config: &GenerationConfig,
+    ) -> Result<Vec<u32>, GenerateError> {
+        self.generate_with_constraint(token_ids, config, None)
     }
-}
 
-fn self_attention(
-    hidden_states: &mut Tensor<f32>, // (seq, n_kv_h * n_groups * dqkv)
-    att_scores: &mut Tensor<f32>,    // (n_kv_h, n_groups, seq, total_seq)
-    q: &Tensor<f32>,                 // (seq, n_kv_h * n_groups * dqkv)
-    k: &Tensor<f32>,                 // (total_seq, n_kv_h * dqkv)
-    v: &Tensor<f32>,                 // (total_seq, n_kv_h * dqkv)
-    n_kv_h: usize,
-    n_groups: usize,
-    seq_len: usize,
-    total_seq_len: usize,
-    dqkv: usize,
-) {
-    todo!("Implement self_attention");
-}
+    // generate, picking only tokens the constraint allows and stopping once it is complete
+    #[allow(unused)]
+    pub fn generate_with_constraint(
+        &self,
+        token_ids: &[u32],
+        config: &GenerationConfig,
+        constraint: Option<&mut dyn Constraint>,
+    ) -> Result<Vec<u32>, GenerateError> {
+        check_tokens(config);
+        self.generate_inner(token_ids, config, constraint, &mut |_, _| true)
+            .map(|(tokens, _)| tokens)
+    }
 
-fn mlp(
-    residual: &mut Tensor<f32>,     // 残差张量
-    hidden_states: &mut Tensor<f32>,// 隐藏状态张量
-    gate: &mut Tensor<f32>,         // 门控张量
-    up: &mut Tensor<f32>,       // 上投影张量
-    w_up: &Tensor<f32>,         // 上投影权重
-    w_down: &Tensor<f32>,    // 下投影权重
-    w_gate: &Tensor<f32>,    // 门控权重
-    rms_w: &Tensor<f32>,     // RMS归一化权重
-    eps: f32,                // RMS归一化的epsilon值
-) {
-    // todo!("Implement mlp");
-    // 1. 计算残差张量的RMS归一化
-    OP::rms_norm(hidden_states, residual, rms_w, eps);
-    // 2. 计算门控张量和上投影张量
-    OP::matmul_transb(gate, 0., hidden_states, w_gate, 1.0);
-    OP::matmul_transb(up, 0., hidden_states, w_up, 1.0);
-    // 3. SwiGLU激活
-    for i in 0..gate.size() {
-        unsafe {
-            gate.data_mut()[i] = gate.data()[i] * (1. / (1. + (-gate.data()[i]).exp()));
+    // generate, processing the logits with pipeline instead of the processors of the config,
+    // which LogitsPipeline::from_config makes for adding to
+    #[allow(unused)]
+    pub fn generate_with_pipeline(
+        &self,
+        token_ids: &[u32],
+        config: &GenerationConfig,
+        pipeline: LogitsPipeline,
+    ) -> Result<Vec<u32>, GenerateError> {
+        check_tokens(config);
+        let mut generation = Generation::new(self, token_ids, config)?;
+        generation.decoder.pipeline = pipeline;
+        while generation.finished.is_none() {
+            generation.round(None, &mut |_, _| true)?;
+        }
+        Ok(generation.result)
+    }
+
+    // generate, counting the forward calls and the tokens config.prompt_lookup drafted
+    #[allow(unused)]
+    pub fn generate_with_stats(
+        &self,
+        token_ids: &[u32],
+        config: &GenerationConfig,
+    ) -> Result<(Vec<u32>, GenerationStats), GenerateError> {
+        check_tokens(config);
+        self.generate_inner(token_ids, config, None, &mut |_, _| true)
+    }
+
+    // generate, along with the log probabilities of the tokens
+    #[allow(unused)]
+    pub fn generate_logprobs(
+        &self,
+        token_ids: &[u32],
+        config: &GenerationConfig,
+    ) -> Result<Vec<GeneratedToken>, GenerateError> {
+        check_tokens(config);
+        let n = config.logprobs.unwrap_or(0);
+        let mut tokens = Vec::new();
+        self.generate_inner(token_ids, config, None, &mut |id, logits| {
+            tokens.push(generated_token(logits, id, n));
+            true
+        })?;
+        Ok(tokens)
+    }
+
+    // the text of the generated tokens, where pieces holds the bytes of every token (see
+    // constraint::token_pieces); on_text gets it piece by piece as it is generated, holding
+    // back what may turn out to begin a stop string. The tokens come along only when
+    // config.logprobs is set.
+    pub fn generate_text(
+        &self,
+        token_ids: &[u32],
+        config: &GenerationConfig,
+        pieces: &[Vec<u8>],
+        mut on_text: impl FnMut(&str),
+    ) -> Result<(String, Vec<GeneratedToken>), GenerateError> {
+        let mut tokens = Vec::new();
+        let mut stop = StopMatcher::new(&config.stop);
+        let mut utf8 = Utf8Stream::default();
+        let mut text = String::new();
+        let mut emit = |s: String| {
+            if !s.is_empty() {
+                on_text(&s);
+                text.push_str(&s);
+            }
+        };
+        self.generate_inner(token_ids, config, None, &mut |token, logits| {
+            if let Some(n) = config.logprobs {
+                tokens.push(generated_token(logits, token, n));
+            }
+            let piece = pieces.get(token as usize).map_or(&[][..], |p| &p[..]);
+            emit(utf8.push(&stop.push(piece)));
+            !stop.stopped()
+        })?;
+        emit(utf8.push(&stop.finish()));
+        emit(utf8.finish());
+        Ok((text, tokens))
+    }
+
+    // generate_text pushing events instead: PrefillDone before the first token, then
+    // TokenGenerated for every token, eos included, with the text it completes, and last
+    // Finished. Breaking from on_event stops the generation before the next token.
+    #[allow(unused)]
+    pub fn generate_with_callback(
+        &self,
+        token_ids: &[u32],
+        config: &GenerationConfig,
+        pieces: &[Vec<u8>],
+        mut on_event: impl FnMut(GenerationEvent) -> ControlFlow<()>,
+    ) -> Result<FinishReason, GenerateError> {
+        let start = Instant::now();
+        let mut generation = Generation::new(self, token_ids, config)?;
+        let mut stop = StopMatcher::new(&config.stop);
+        let mut utf8 = Utf8Stream::default();
+        let (mut prefilled, mut cancelled) = (false, false);
+        let mut on_token = |token: u32, _: &Tensor<f32>| {
+            if !prefilled {
+                prefilled = true;
+                let ms = start.elapsed().as_secs_f64() * 1000.;
+                let n_tokens = token_ids.len();
+                cancelled = on_event(GenerationEvent::PrefillDone { n_tokens, ms }).is_break();
+            }
+            let piece = pieces.get(token as usize).map_or(&[][..], |p| &p[..]);
+            let text_piece = utf8.push(&stop.push(piece));
+            let id = token;
+            cancelled = cancelled
+                || on_event(GenerationEvent::TokenGenerated { id, text_piece }).is_break();
+            !cancelled && !stop.stopped()
+        };
+        while generation.finished.is_none() {
+            generation.round(None, &mut on_token)?;
         }
+        // eos, which rounds keep to themselves
+        if generation.finished == Some(FinishReason::Eos) {
+            on_token(*generation.result.last().unwrap(), &generation.logits);
+        }
+        let reason = match generation.finished {
+            _ if cancelled => FinishReason::Cancelled,
+            reason => reason.unwrap(),
+        };
+        // what was held back for a stop string or a character that never completed
+        let rest = utf8.push(&stop.finish()) + &utf8.finish();
+        let _ = on_event(GenerationEvent::Finished { reason, rest });
+        Ok(reason)
+    }
+
+    // generate going on from cache, such as the one of the previous request of a chat, so
+    // that of token_ids only what comes after the entries they have in common with it is
+    // fed. The cache is left holding the entries of token_ids and the generated tokens but
+    // the last, even when forward fails or the output ends within the drafts of a round.
+    #[allow(unused)]
+    pub fn generate_in(
+        &self,
+        cache: &mut KVCache<f32>,
+        token_ids: &[u32],
+        config: &GenerationConfig,
+    ) -> Result<Vec<u32>, GenerateError> {
+        self.generate_in_with_stats(cache, token_ids, config)
+            .map(|(tokens, _)| tokens)
+    }
+
+    // generate_in, along with how it went, cached_prompt_tokens among it
+    #[allow(unused)]
+    pub fn generate_in_with_stats(
+        &self,
+        cache: &mut KVCache<f32>,
+        token_ids: &[u32],
+        config: &GenerationConfig,
+    ) -> Result<(Vec<u32>, GenerationStats), GenerateError> {
+        check_tokens(config);
+        Generation::check(self, token_ids, config)?;
+        let cache = GenerationCache::Borrowed(cache);
+        let mut generation = Generation::with_cache(self, token_ids, config, cache);
+        generation.resume(token_ids);
+        while generation.finished.is_none() {
+            generation.round(None, &mut |_, _| true)?;
+        }
+        generation.stats.tokens = generation.result.len();
+        Ok((generation.result, generation.stats))
     }
-    for i in 0..up.size() {
-        unsafe {
-            up.data_mut()[i] *= up.data()[i];
+
+    // on_token sees every generated token but eos, with the logits it was sampled from, and
+    // returns whether to go on
+    fn generate_inner(
+        &self,
+        token_ids: &[u32],
+        config: &GenerationConfig,
+        mut constraint: Option<&mut dyn Constraint>,
+        on_token: &mut dyn FnMut(u32, &Tensor<f32>) -> bool,
+    ) -> Result<(Vec<u32>, GenerationStats), GenerateError> {
+        let mut generation = Generation::new(self, token_ids, config)?;
+        while generation.finished.is_none() {
+            generation.round(constraint.as_deref_mut(), on_token)?;
         }
+        generation.stats.tokens = generation.result.len();
+        Ok((generation.result, generation.stats))
     }
-    // 4. 计算输出
-    let mut output = Tensor::<f32>::default(&residual.shape());
-    OP::matmul_transb(&mut output, 0., up, w_down, 1.0);
-    // 5. 更新residual
-    for i in 0..residual.size() {
-        unsafe {
-            residual.data_mut()[i] += output.data()[i];
+
+    // n generations after one prefill of the prompt, each going on from a fork of its cache.
+    // Sample i is seeded with config.seed + i, so the first one is what generate gives, and
+    // its stats include the prefill the others share.
+    #[allow(unused)]
+    pub fn generate_n(
+        &self,
+        token_ids: &[u32],
+        n: usize,
+        config: &GenerationConfig,
+    ) -> Result<Vec<GenerationResult>, GenerateError> {
+        check_tokens(config);
+        let mut prefill = Generation::new(self, token_ids, config)?;
+        if prefill.finished.is_none() {
+            prefill.forward()?;
         }
+        let mut results = Vec::with_capacity(n);
+        for i in 0..n {
+            let mut generation = prefill.fork(config.seed.map(|seed| seed.wrapping_add(i as u64)));
+            if i == 0 {
+                generation.stats = prefill.stats;
+            }
+            if generation.finished.is_none() {
+                generation.sample(None, &mut |_, _| true)?;
+            }
+            while generation.finished.is_none() {
+                generation.round(None, &mut |_, _| true)?;
+            }
+            results.push(GenerationResult {
+                stats: GenerationStats {
+                    tokens: generation.result.len(),
+                    ..generation.stats
+                },
+                tokens: generation.result,
+                finish_reason: generation.finished.unwrap(),
+            });
+        }
+        Ok(results)
+    }
+
+    // generate one token at a time: the prompt is fed on the first call of next, every call
+    // after it runs one forward at most, and dropping the stream early cancels the rest
+    #[allow(unused)]
+    pub fn generate_stream<'a>(
+        &'a self,
+        token_ids: &[u32],
+        config: &'a GenerationConfig,
+    ) -> Result<TokenStream<'a>, GenerateError> {
+        check_tokens(config);
+        Ok(TokenStream {
+            generation: Generation::new(self, token_ids, config)?,
+            yielded: 0,
+        })
+    }
+
+    // generate_stream going on from cache as generate_in does, which the stream holds
+    // until it is dropped
+    #[allow(unused)]
+    pub fn generate_stream_in<'a>(
+        &'a self,
+        cache: &'a mut KVCache<f32>,
+        token_ids: &[u32],
+        config: &'a GenerationConfig,
+    ) -> Result<TokenStream<'a>, GenerateError> {
+        check_tokens(config);
+        Generation::check(self, token_ids, config)?;
+        let cache = GenerationCache::Borrowed(cache);
+        let mut generation = Generation::with_cache(self, token_ids, config, cache);
+        generation.resume(token_ids);
+        Ok(TokenStream {
+            generation,
+            yielded: 0,
+        })
     }
 }
 
-#[test]
-pub fn test_mlp() {
-    let seq_len = 4;
-    let d = 2;
-    let di = 3;
-    let mut residual = Tensor::<f32>::new(vec![1., 1., 1., 1., 1., 1., 1., 1.], &vec![seq_len, d]);
-    let mut hidden_states = Tensor::<f32>::default(&vec![seq_len, d]);
-    let mut gate_buf = Tensor::<f32>::default(&vec![seq_len, di]);
-    let mut up_buf = Tensor::<f32>::default(&vec![seq_len, di]);
-    let w_up = Tensor::<f32>::new(vec![0.1, 0.2, 0.3, 0.4, 0.5, 0.6], &vec![di, d]);
-    let w_down = Tensor::<f32>::new(vec![0.1, 0.2, 0.3, 0.4, 0.5, 0.6], &vec![d, di]);
-    let w_gate = Tensor::<f32>::new(vec![0.1, 0.2, 0.3, 0.4, 0.5, 0.6], &vec![di, d]);
-    let rms_w = Tensor::<f32>::new(vec![1., 1.], &vec![d]);
-    let eps = 1e-6;
-    mlp(
-        &mut residual,
-        &mut hidden_states,
-        &mut gate_buf,
-        &mut up_buf,
-        &w_up,
-        &w_down,
-        &w_gate,
-        &rms_w,
-        eps,
-    );
+// the wrappers that return tokens take no stop strings, which generate_text looks for
+fn check_tokens(config: &GenerationConfig) {
+    config
+        .validate_tokens()
+        .unwrap_or_else(|e| panic!("invalid generation config: {e}"));
+}
 
-    assert!(residual.close_to(
-        &Tensor::<f32>::new(
-            vec![
-                1.3429964, 1.7290739, 1.3429964, 1.7290739, 1.3429964, 1.7290739, 1.3429964,
-                1.7290739
-            ],
-            &vec![seq_len, d]
-        ),
-        1e-3
-    ))
+// the cache a generation writes to, a new one or that of the caller of generate_in
+enum GenerationCache<'a> {
+    Owned(KVCache<f32>),
+    Borrowed(&'a mut KVCache<f32>),
 }
 
-#[test]
-pub fn test_load_safetensors() {
-    use std::path::PathBuf;
-    use crate::tensor::float_eq;
-    let project_dir = env!("CARGO_MANIFEST_DIR");
-    let model_dir = PathBuf::from(project_dir).join("models").join("story");
-    let model = Llama::from_safetensors(model_dir);
-    assert_eq!(model.vocab, 2048);
-    assert_eq!(model.n_layers, 2);
-    assert_eq!(model.n_q_h, 8);
-    assert_eq!(model.n_kv_h, 4);
-    assert_eq!(model.d, 128);
-    assert_eq!(model.dqkv, 16);
-    assert_eq!(model.di, 384);
+impl Deref for GenerationCache<'_> {
+    type Target = KVCache<f32>;
 
-    assert!(float_eq(&model.params.embedding_table.data()[50], &0.14453125, 1e-6));
-    assert_eq!(model.params.lm_head.data()[10], model.params.embedding_table.data()[10]);
-    assert!(float_eq(&model.params.rms_att_w[0].data()[10], &0.18652344, 1e-6));
-    assert!(float_eq(&model.params.rms_ffn_w[1].data()[10], &0.32421875, 1e-6));
-    assert!(float_eq(&model.params.rms_out_w.data()[100], &0.73046875, 1e-6));
-    assert!(float_eq(&model.params.w_down[0].data()[100], &-0.0625, 1e-6));
-    assert!(float_eq(&model.params.w_up[0].data()[100], &1.46875, 1e-6));
-    assert!(float_eq(&model.params.w_gate[1].data()[100], &0.296875, 1e-6));
-    assert!(float_eq(&model.params.wq[1].data()[100], &0.032226563, 1e-6));
-    assert!(float_eq(&model.params.wk[1].data()[100], &-0.21386719, 1e-6));
-    assert!(float_eq(&model.params.wv[0].data()[100], &0.041015625, 1e-6));
-    assert!(float_eq(&model.params.wo[0].data()[100], &0.01965332, 1e-6));
+    fn deref(&self) -> &KVCache<f32> {
+        match self {
+            GenerationCache::Owned(cache) => cache,
+            GenerationCache::Borrowed(cache) => cache,
+        }
+    }
+}
+
+impl DerefMut for GenerationCache<'_> {
+    fn deref_mut(&mut self) -> &mut KVCache<f32> {
+        match self {
+            GenerationCache::Owned(cache) => cache,
+            GenerationCache::Borrowed(cache) => cache,
+        }
+    }
+}
 
+// The state of a generation between its forward calls, which generate runs to the end and
+// TokenStream a round at a time.
+struct Generation<'a> {
+    model: &'a Llama<f32>,
+    config: &'a GenerationConfig,
+    decoder: Decoder<'a>,
+    result: Vec<u32>,
+    allowed: Vec<bool>,
+    cache: GenerationCache<'a>,
+    stats: GenerationStats,
+    lookup: Option<PromptLookup>,
+    index: Option<NgramIndex>,
+    drafts: Vec<u32>,
+    // the whole prompt is fed in the first round, then one token per round through the
+    // same input and logits, unless there are drafts to go with it
+    input: Tensor<u32>,
+    logits: Tensor<f32>,
+    rows: Option<Tensor<f32>>, // the logits after the drafts too, when there were any
+    // for contrastive search, the hidden states of the tokens fed so far, and the logits and
+    // hidden state of the last token when the cache holds it already
+    hidden: Vec<f32>,
+    evaluated: Option<(Tensor<f32>, Vec<f32>)>,
+    finished: Option<FinishReason>,
+    deadline: Option<Instant>, // max_time from the start
+}
+
+impl<'a> Generation<'a> {
+    fn new(
+        model: &'a Llama<f32>,
+        token_ids: &[u32],
+        config: &'a GenerationConfig,
+    ) -> Result<Self, GenerateError> {
+        Self::check(model, token_ids, config)?;
+        let cache = GenerationCache::Owned(model.new_cache());
+        Ok(Self::with_cache(model, token_ids, config, cache))
+    }
+
+    // panics on a config that is invalid for the model, and fails on a prompt it has no
+    // room for
+    fn check(
+        model: &Llama<f32>,
+        token_ids: &[u32],
+        config: &GenerationConfig,
+    ) -> Result<(), GenerateError> {
+        config
+            .validate()
+            .and_then(|_| config.check(model.vocab))
+            .unwrap_or_else(|e| panic!("invalid generation config: {e}"));
+        if let Some(sink) = config.sink() {
+            assert!(
+                sink.window > 0 && sink.n_sink + sink.window <= model.max_seq_len,
+                "attention sink {sink:?} does not fit into max_seq_len {}",
+                model.max_seq_len
+            );
+        }
+        if token_ids.len() > model.max_seq_len {
+            return Err(GenerateError::PromptTooLong {
+                len: token_ids.len(),
+                max_position_embeddings: model.max_seq_len,
+            });
+        }
+        Ok(())
+    }
+
+    // a generation of a prompt check accepted, writing to cache
+    fn with_cache(
+        model: &'a Llama<f32>,
+        token_ids: &[u32],
+        config: &'a GenerationConfig,
+        cache: GenerationCache<'a>,
+    ) -> Self {
+        let decoder = Decoder::new(config, token_ids, model.bos_token_id);
+        // the greedy output stays the same when the drafts are checked against it
+        let lookup = config
+            .prompt_lookup
+            .filter(|_| decoder.greedy && config.sink().is_none() && config.contrastive.is_none());
+        let finished = if config.max_len == 0 {
+            Some(FinishReason::MaxLen)
+        } else if config
+            .max_total_tokens
+            .is_some_and(|n| token_ids.len() >= n)
+        {
+            Some(FinishReason::TokenLimit)
+        } else {
+            None
+        };
+        Generation {
+            model,
+            config,
+            decoder,
+            result: Vec::with_capacity(config.max_len),
+            allowed: vec![false; model.vocab],
+            cache,
+            stats: GenerationStats::default(),
+            lookup,
+            index: lookup.map(|lookup| NgramIndex::new(lookup.ngram)),
+            drafts: Vec::new(),
+            input: Tensor::<u32>::new(token_ids.to_vec(), &vec![token_ids.len()]),
+            logits: Tensor::<f32>::default(&vec![1, model.vocab]),
+            rows: None,
+            hidden: Vec::new(),
+            evaluated: None,
+            finished,
+            deadline: config.max_time.map(|t| Instant::now() + t),
+        }
+    }
+
+    // Go on from the entries the cache holds already, keeping those of the longest prefix
+    // of the token_ids of with_cache among them and forgetting the rest. At least the last
+    // token is fed again, for its logits, and all of them under contrastive search, which
+    // needs their hidden states, or without prefix_cache.
+    fn resume(&mut self, token_ids: &[u32]) {
+        let cache = &mut self.cache;
+        // the positions up to the last of the common entries, those a sliding window skipped
+        // before it included
+        let fed = match cache.common_prefix(token_ids) {
+            0 => 0,
+            common => cache.position(common - 1) + 1,
+        };
+        let cached = if self.config.contrastive.is_some() || !self.config.prefix_cache {
+            0
+        } else {
+            fed.min(token_ids.len().saturating_sub(1))
+        };
+        // a sliding window may have evicted some of them, which are not fed again
+        cache.truncate_positions(cached);
+        let fed = cache.position(cache.len());
+        self.stats.cached_prompt_tokens = fed;
+        let rest = &token_ids[fed..];
+        self.input = Tensor::new(rest.to_vec(), &vec![rest.len()]);
+    }
+
+    // one forward and the tokens it gives, setting finished once the generation is over
+    fn round<'c>(
+        &mut self,
+        constraint: Option<&mut (dyn Constraint + 'c)>,
+        on_token: &mut dyn FnMut(u32, &Tensor<f32>) -> bool,
+    ) -> Result<(), OP::OperatorError> {
+        self.forward()?;
+        if self.finished.is_none() {
+            self.sample(constraint, on_token)?;
+        }
+        Ok(())
+    }
+
+    // feeds the input and the drafts, leaving the logits in logits or rows
+    fn forward(&mut self) -> Result<(), OP::OperatorError> {
+        let (model, config) = (self.model, self.config);
+        // contrastive search fed the token already, weighing it up
+        if let Some((logits, hidden)) = self.evaluated.take() {
+            self.logits = logits;
+            self.hidden.extend_from_slice(&hidden);
+            self.rows = None;
+            return Ok(());
+        }
+        // no forward starts past the deadline, so one that has passed already yields nothing
+        if self.deadline.is_some_and(|d| Instant::now() >= d) {
+            self.finished = Some(FinishReason::TimeLimit);
+            return Ok(());
+        }
+        let cache = &mut self.cache;
+        if let Some(sink) = config.sink() {
+            model.evict_for_sink(cache, sink, self.input.size());
+        }
+        // only a prompt longer than max_seq_len fills the cache of a generation with sinks
+        let needed = cache.len() + self.input.size();
+        if needed > cache.max_len() {
+            if !config.stop_at_context_limit {
+                return Err(OP::OperatorError::ContextOverflow {
+                    needed,
+                    max: cache.max_len(),
+                });
+            }
+            self.finished = Some(FinishReason::ContextFull);
+            return Ok(());
+        }
+        let budget = config.max_total_tokens.unwrap_or(usize::MAX);
+        let drafts = &mut self.drafts;
+        drafts.clear();
+        if let (Some(lookup), Some(index)) = (self.lookup, self.index.as_mut()) {
+            index.update(&self.decoder.history);
+            let max = lookup
+                .max_draft
+                .min(config.max_len - self.result.len() - 1)
+                .min(cache.max_len() - cache.len() - self.input.size())
+                .min(budget - self.decoder.history.len() - 1);
+            drafts.extend_from_slice(index.propose(&self.decoder.history, max));
+        }
+        if self.result.is_empty() {
+            self.stats.prefill_tokens = self.input.size();
+        }
+        self.stats.forwards += 1;
+        self.stats.drafted += drafts.len();
+        // the logits after the input and after every draft, the last of them unused when
+        // a draft turns out wrong
+        self.rows = if config.contrastive.is_some() {
+            let mut hidden = Tensor::<f32>::default(&vec![self.input.size(), model.d]);
+            let (sink, logits) = (config.sink(), &mut self.logits);
+            model.forward_hidden_into(&self.input, cache, sink, logits, Some(&mut hidden))?;
+            self.hidden.extend_from_slice(hidden.data());
+            None
+        } else if drafts.is_empty() {
+            model.forward_into(&self.input, cache, config.sink(), &mut self.logits)?;
+            None
+        } else {
+            let mut chunk = self.input.data().to_vec();
+            chunk.extend_from_slice(drafts);
+            let chunk = Tensor::new(chunk, &vec![self.input.size() + drafts.len()]);
+            let mut rows = Tensor::<f32>::default(&vec![drafts.len() + 1, model.vocab]);
+            model.forward_into(&chunk, cache, None, &mut rows)?;
+            Some(rows)
+        };
+        Ok(())
+    }
+
+    // the tokens after a forward, as many as the drafts allow
+    fn sample<'c>(
+        &mut self,
+        mut constraint: Option<&mut (dyn Constraint + 'c)>,
+        on_token: &mut dyn FnMut(u32, &Tensor<f32>) -> bool,
+    ) -> Result<(), OP::OperatorError> {
+        let (model, config) = (self.model, self.config);
+        let budget = config.max_total_tokens.unwrap_or(usize::MAX);
+        let (cache, logits, drafts) = (&mut *self.cache, &mut self.logits, &self.drafts);
+        let mut next = 0;
+        for i in 0..=drafts.len() {
+            if let Some(all) = &self.rows {
+                let row = &all.data()[i * model.vocab..][..model.vocab];
+                unsafe { logits.data_mut() }.copy_from_slice(row);
+            }
+            self.decoder.process(logits);
+            if let Some(constraint) = constraint.as_deref_mut() {
+                let remaining = config.max_len - self.result.len();
+                constraint.allowed_tokens(&self.result, remaining, &mut self.allowed);
+                // a dead end, which no token continues
+                if !self.allowed.contains(&true) {
+                    self.finished = Some(FinishReason::Constraint);
+                    cache.truncate_positions(self.decoder.history.len() - 1);
+                    return Ok(());
+                }
+                OP::mask_tokens(logits, &self.allowed);
+            }
+            next = match config.contrastive {
+                Some(params) => {
+                    let (sink, stats) = (config.sink(), &mut self.stats);
+                    let (token, evaluated) =
+                        model.contrastive_step(params, sink, cache, logits, &self.hidden, stats)?;
+                    self.evaluated = evaluated;
+                    token
+                }
+                None => self.decoder.sample(logits),
+            };
+            self.result.push(next);
+            self.decoder.push(next);
+            let accepted = drafts.get(i) == Some(&next);
+            self.stats.accepted += accepted as usize;
+            self.finished = if model.eos_token_ids.contains(&next) {
+                Some(FinishReason::Eos)
+            } else if !on_token(next, logits) {
+                Some(FinishReason::Stop)
+            } else if self.result.len() == config.max_len {
+                Some(FinishReason::MaxLen)
+            } else if self.decoder.history.len() >= budget {
+                Some(FinishReason::TokenLimit)
+            } else if self.deadline.is_some_and(|d| Instant::now() >= d) {
+                Some(FinishReason::TimeLimit)
+            } else if constraint
+                .as_deref_mut()
+                .is_some_and(|c| c.is_complete(&self.result))
+            {
+                Some(FinishReason::Constraint)
+            } else {
+                None
+            };
+            if self.finished.is_some() {
+                // the entries of the prompt and the output but its last token, not those of
+                // the drafts after it or of what contrastive search weighed up
+                cache.truncate_positions(self.decoder.history.len() - 1);
+                return Ok(());
+            }
+            if !accepted {
+                // the cache goes as far as the drafts that came true
+                cache.truncate(cache.len() - (drafts.len() - i));
+                break;
+            }
+        }
+        if self.input.size() != 1 {
+            self.input = Tensor::<u32>::default(&vec![1]);
+        }
+        unsafe { self.input.data_mut()[0] = next };
+        Ok(())
+    }
+
+    // an independent copy going on from here, sampling with its own seed and counting only
+    // its own work in stats
+    fn fork(&self, seed: Option<u64>) -> Self {
+        let copy = |t: &Tensor<f32>| Tensor::new(t.data().to_vec(), t.shape());
+        Generation {
+            model: self.model,
+            config: self.config,
+            decoder: self.decoder.fork(seed),
+            result: self.result.clone(),
+            allowed: self.allowed.clone(),
+            cache: GenerationCache::Owned(self.cache.fork()),
+            stats: GenerationStats::default(),
+            lookup: self.lookup,
+            index: self.index.clone(),
+            drafts: self.drafts.clone(),
+            input: Tensor::new(self.input.data().to_vec(), self.input.shape()),
+            logits: copy(&self.logits),
+            rows: self.rows.as_ref().map(copy),
+            hidden: self.hidden.clone(),
+            evaluated: (self.evaluated.as_ref()).map(|(logits, h)| (copy(logits), h.clone())),
+            finished: self.finished,
+            deadline: self.deadline,
+        }
+    }
+}
+
+// The tokens of Llama::generate_stream as they are generated, eos included. The cache and
+// the sampling state live in here, so the model is free for other generations meanwhile.
+pub struct TokenStream<'a> {
+    generation: Generation<'a>,
+    yielded: usize, // how many of generation.result
+}
+
+#[allow(unused)]
+impl TokenStream<'_> {
+    // None until the last token is out
+    pub fn finish_reason(&self) -> Option<FinishReason> {
+        self.generation
+            .finished
+            .filter(|_| self.yielded == self.generation.result.len())
+    }
+
+    // the forward calls and drafts so far
+    pub fn stats(&self) -> GenerationStats {
+        GenerationStats {
+            tokens: self.generation.result.len(),
+            ..self.generation.stats
+        }
+    }
+}
+
+impl Iterator for TokenStream<'_> {
+    type Item = Result<u32, GenerateError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // prompt lookup may leave several tokens from one round
+        while self.yielded == self.generation.result.len() {
+            if self.generation.finished.is_some() {
+                return None;
+            }
+            if let Err(e) = self.generation.round(None, &mut |_, _| true) {
+                self.generation.finished = Some(FinishReason::Error);
+                return Some(Err(e.into()));
+            }
+        }
+        self.yielded += 1;
+        Some(Ok(self.generation.result[self.yielded - 1]))
+    }
+}
+
+// What generate keeps from one token to the next besides the cache: processing the logits
+// and sampling from them reuses its buffers, so nothing allocates per token with the
+// default settings, and greedy decoding is a plain argmax.
+struct Decoder<'a> {
+    config: &'a GenerationConfig,
+    bos_token_id: u32,
+    greedy: bool,
+    rng: StdRng,
+    scratch: OP::SampleScratch,
+    mirostat: Option<OP::MirostatState>,
+    // the prompt and the output, which the processors look back into
+    history: Vec<u32>,
+    prompt_len: usize,
+    // the penalties, biases and bans
+    pipeline: LogitsPipeline,
+}
+
+impl<'a> Decoder<'a> {
+    fn new(config: &'a GenerationConfig, prompt: &[u32], bos_token_id: u32) -> Self {
+        let mut history = Vec::with_capacity(prompt.len() + config.max_len);
+        history.extend_from_slice(prompt);
+        Decoder {
+            config,
+            bos_token_id,
+            greedy: config.mirostat.is_none()
+                && (config.temperature <= 0. || config.top_k < 2 || config.top_p <= 0.),
+            rng: match config.seed {
+                Some(seed) => StdRng::seed_from_u64(seed),
+                None => StdRng::from_entropy(),
+            },
+            scratch: OP::SampleScratch::default(),
+            mirostat: config.mirostat.map(OP::MirostatState::new),
+            history,
+            prompt_len: prompt.len(),
+            pipeline: LogitsPipeline::from_config(config, bos_token_id),
+        }
+    }
+
+    // the same state, sampling on from a new seed or entropy, its processors those of the
+    // config again
+    fn fork(&self, seed: Option<u64>) -> Self {
+        Decoder {
+            config: self.config,
+            bos_token_id: self.bos_token_id,
+            greedy: self.greedy,
+            rng: match seed {
+                Some(seed) => StdRng::seed_from_u64(seed),
+                None => StdRng::from_entropy(),
+            },
+            scratch: OP::SampleScratch::default(),
+            mirostat: self.mirostat,
+            history: self.history.clone(),
+            prompt_len: self.prompt_len,
+            pipeline: LogitsPipeline::from_config(self.config, self.bos_token_id),
+        }
+    }
+
+    // the processors, in place
+    fn process(&mut self, logits: &mut Tensor<f32>) {
+        let ctx = GenerationContext {
+            tokens: &self.history,
+            prompt_len: self.prompt_len,
+        };
+        self.pipeline.process(&ctx, logits);
+    }
+
+    fn sample(&mut self, logits: &Tensor<f32>) -> u32 {
+        let config = self.config;
+        if self.greedy {
+            return OP::argmax_row(logits.data());
+        }
+        match (config.mirostat, &mut self.mirostat) {
+            (Some(params), Some(state)) => OP::mirostat_sample(
+                logits,
+                config.temperature,
+                params,
+                state,
+                &mut self.rng,
+                &mut self.scratch,
+            ),
+            _ => OP::random_sample_with(
+                logits,
+                config.top_p,
+                config.top_k,
+                config.temperature,
+                config.min_p,
+                config.typical_p,
+                &mut self.rng,
+                &mut self.scratch,
+            ),
+        }
+    }
+
+    fn push(&mut self, token: u32) {
+        self.history.push(token);
+    }
+}
+
+fn generated_token(logits: &Tensor<f32>, id: u32, n: usize) -> GeneratedToken {
+    let (logprob, top) = OP::logprobs(logits, id, n);
+    GeneratedToken { id, logprob, top }
+}
+
+// The buffers forward works in, kept by the model from one call to the next so that once
+// they have grown to the longest input and the longest cache, decoding allocates nothing.
+// Every call takes views of them of the shapes it needs, and writes them before reading.
+#[derive(Default)]
+struct ForwardWorkspace {
+    residual: Buffer,
+    hidden: Buffer,
+    q: Buffer,
+    k: Buffer, // k and v take the new entries of a head-major cache
+    v: Buffer,
+    scores: Buffer,
+    gate: Buffer,
+    up: Buffer,
+    heads: AttentionBuffers,
+}
+
+// q, k, v and the output of self_attention_on laid out head-major
+#[derive(Default)]
+struct AttentionBuffers {
+    q: Buffer,
+    k: Buffer,
+    v: Buffer,
+    out: Buffer,
+}
+
+// A buffer of the workspace, which only ever grows
+struct Buffer(Tensor<f32>);
+
+impl Default for Buffer {
+    fn default() -> Self {
+        Buffer(Tensor::default(&[0]))
+    }
+}
+
+impl Buffer {
+    // a view of shape at the start of the buffer, which first grows to room values, or as
+    // many as shape needs if that is more, when it is too small
+    fn view(&mut self, shape: &[usize], room: usize) -> Tensor<f32> {
+        let size = shape.iter().product::<usize>();
+        if self.0.size() < size {
+            self.0 = Tensor::default(&[size.max(room)]);
+        }
+        self.0.slice(0, shape)
+    }
+}
+
+#[allow(unused)]
+#[allow(clippy::too_many_arguments)]
+fn self_attention(
+    hidden_states: &mut Tensor<f32>,
+    att_scores: &mut Tensor<f32>,
+    q: &Tensor<f32>,
+    k: &Tensor<f32>,
+    v: &Tensor<f32>,
+    n_kv_h: usize,
+    n_groups: usize,
+    seq_len: usize,
+    total_seq_len: usize,
+    dqkv: usize,
+    scale: f32,
+    softcap: Option<f32>,
+) {
+    self_attention_on(
+        &CpuBackend,
+        &mut AttentionBuffers::default(),
+        hidden_states,
+        att_scores,
+        q,
+        KvSource::Rows(k, v),
+        n_kv_h,
+        n_groups,
+        seq_len,
+        total_seq_len,
+        total_seq_len,
+        dqkv,
+        scale,
+        softcap,
+        None,
+    );
+}
+
+// K and V of the total_seq keys self_attention_on attends to
+enum KvSource<'a> {
+    Rows(&'a Tensor<f32>, &'a Tensor<f32>), // (total_seq, n_kv_h * dqkv) each
+    // (n_kv_h, max_seq, dqkv) each with the first total_seq rows of every head cached
+    HeadMajor(&'a Tensor<f32>, &'a Tensor<f32>),
+    Pages(PageRows<'a>), // the rows of a paged cache, page by page
+}
+
+// self_attention with the softmax run by backend. Head-major K and V are read in place
+// rather than copied out, and the other layouts are laid out head-major straight from where
+// they are, the pages of a paged cache included.
+#[allow(clippy::too_many_arguments)]
+fn self_attention_on(
+    backend: &dyn Backend,
+    buffers: &mut AttentionBuffers,
+    hidden_states: &mut Tensor<f32>, // (seq, n_kv_h * n_groups * dqkv)
+    att_scores: &mut Tensor<f32>,    // (n_kv_h, n_groups, seq, total_seq)
+    q: &Tensor<f32>,                 // (seq, n_kv_h * n_groups * dqkv)
+    kv: KvSource,
+    n_kv_h: usize,
+    n_groups: usize,
+    seq_len: usize,
+    total_seq_len: usize,
+    max_seq_len: usize, // the most keys there will be, which the copies of k and v take room for
+    dqkv: usize,
+    scale: f32,
+    softcap: Option<f32>,
+    sink: Option<AttentionSink>,
+) {
+    let n_q_h = n_kv_h * n_groups;
+    // lay q out head-major so that every head is one contiguous matrix
+    let mut q_heads = buffers.q.view(&[n_q_h, seq_len, dqkv], 0);
+    {
+        let _q = q.data();
+        let qh = unsafe { q_heads.data_mut() };
+        for i in 0..seq_len {
+            for h in 0..n_q_h {
+                qh[(h * seq_len + i) * dqkv..][..dqkv]
+                    .copy_from_slice(&_q[(i * n_q_h + h) * dqkv..][..dqkv]);
+            }
+        }
+    }
+    // and k and v too unless they are already, v then transposed since attn @ V is written as
+    // a matmul_transb
+    let head_major = matches!(kv, KvSource::HeadMajor(..));
+    let (k_heads, v_heads) = match kv {
+        KvSource::HeadMajor(k, v) => (k.slice(0, k.shape()), v.slice(0, v.shape())),
+        KvSource::Rows(k, v) => {
+            let rows = std::iter::once((k.data(), v.data()));
+            kv_heads(buffers, rows, n_kv_h, total_seq_len, max_seq_len, dqkv)
+        }
+        KvSource::Pages(pages) => {
+            kv_heads(buffers, pages, n_kv_h, total_seq_len, max_seq_len, dqkv)
+        }
+    };
+    let mut out_heads = buffers.out.view(&[n_q_h, seq_len, dqkv], 0);
+    attend_heads(
+        backend,
+        att_scores,
+        &mut out_heads,
+        &q_heads,
+        &k_heads,
+        &v_heads,
+        head_major,
+        n_groups,
+        dqkv,
+        scale,
+        softcap,
+        sink,
+        cfg!(feature = "parallel"),
+    );
+
+    let oh = out_heads.data();
+    let out = unsafe { hidden_states.data_mut() };
+    for i in 0..seq_len {
+        for h in 0..n_q_h {
+            out[(i * n_q_h + h) * dqkv..][..dqkv]
+                .copy_from_slice(&oh[(h * seq_len + i) * dqkv..][..dqkv]);
+        }
+    }
+}
+
+#[allow(unused)]
+#[allow(clippy::too_many_arguments)]
+fn mlp(
+    residual: &mut Tensor<f32>,       // 残差张量
+    _hidden_states: &mut Tensor<f32>, // 隐藏状态张量, no longer written since rms_norm is fused
+    gate: &mut Tensor<f32>,           // 门控张量
+    up: &mut Tensor<f32>,             // 上投影张量
+    w_up: &Tensor<f32>,               // 上投影权重
+    w_down: &Tensor<f32>,             // 下投影权重
+    w_gate: &Tensor<f32>,             // 门控权重
+    rms_w: &Tensor<f32>,              // RMS归一化权重
+    eps: f32,                         // RMS归一化的epsilon值
+) {
+    let weight = |w: &Tensor<f32>| Weight::Full(w.slice(0, w.shape()));
+    mlp_with_activation(
+        residual,
+        gate,
+        up,
+        &weight(w_up),
+        &weight(w_down),
+        &weight(w_gate),
+        rms_w,
+        eps,
+        false,
+        OP::Activation::Silu,
+    )
+    .unwrap_or_else(|e| panic!("{e}"));
+}
+
+// mlp with the gate activation chosen by the model, SwiGLU for Llama
+#[allow(clippy::too_many_arguments)]
+fn mlp_with_activation(
+    residual: &mut Tensor<f32>,
+    gate: &mut Tensor<f32>,
+    up: &mut Tensor<f32>,
+    w_up: &Weight<f32>,
+    w_down: &Weight<f32>,
+    w_gate: &Weight<f32>,
+    rms_w: &Tensor<f32>,
+    eps: f32,
+    norm_unit_offset: bool,
+    activation: OP::Activation,
+) -> Result<(), OP::OperatorError> {
+    mlp_on(
+        &CpuBackend,
+        residual,
+        gate,
+        up,
+        w_up,
+        w_down,
+        w_gate,
+        rms_w,
+        eps,
+        norm_unit_offset,
+        activation,
+    )
+}
+
+// the first total_seq rows of token-major K and V, given in chunks of whole rows, as the
+// head-major K and transposed V of attend_heads in buffers
+fn kv_heads<'a>(
+    buffers: &mut AttentionBuffers,
+    rows: impl Iterator<Item = (&'a [f32], &'a [f32])>,
+    n_kv_h: usize,
+    total_seq_len: usize,
+    max_seq_len: usize,
+    dqkv: usize,
+) -> (Tensor<f32>, Tensor<f32>) {
+    let room = n_kv_h * max_seq_len * dqkv;
+    let mut k_heads = buffers.k.view(&[n_kv_h, total_seq_len, dqkv], room);
+    let mut v_heads = buffers.v.view(&[n_kv_h, dqkv, total_seq_len], room);
+    let kh = unsafe { k_heads.data_mut() };
+    let vh = unsafe { v_heads.data_mut() };
+    let mut j = 0;
+    for (_k, _v) in rows {
+        let n = (_k.len() / (n_kv_h * dqkv)).min(total_seq_len - j);
+        for (i, j) in (j..j + n).enumerate() {
+            for h in 0..n_kv_h {
+                let src = (i * n_kv_h + h) * dqkv;
+                kh[(h * total_seq_len + j) * dqkv..][..dqkv].copy_from_slice(&_k[src..][..dqkv]);
+                for d in 0..dqkv {
+                    vh[(h * dqkv + d) * total_seq_len + j] = _v[src + d];
+                }
+            }
+        }
+        j += n;
+    }
+    assert!(j == total_seq_len, "{j} keys rather than {total_seq_len}");
+    (k_heads, v_heads)
+}
+
+// The heads of self_attention_on, query head h writing only to its own scores in
+// att_scores, (n_q_h, seq, total_seq) whatever its shape, and its own rows of out_heads,
+// (n_q_h, seq, dqkv), while the query heads of a group read the same kv head. The heads go
+// to the rayon pool when parallel, each one doing the same arithmetic as on its own, with
+// one softmax over all of them in between for the backend.
+#[allow(clippy::too_many_arguments)]
+fn attend_heads(
+    backend: &dyn Backend,
+    att_scores: &mut Tensor<f32>,
+    out_heads: &mut Tensor<f32>,
+    q_heads: &Tensor<f32>,
+    k_heads: &Tensor<f32>,
+    v_heads: &Tensor<f32>, // transposed, (n_kv_h, dqkv, total_seq), unless head_major
+    head_major: bool,
+    n_groups: usize,
+    dqkv: usize,
+    scale: f32,
+    softcap: Option<f32>,
+    sink: Option<AttentionSink>,
+    #[cfg_attr(not(feature = "parallel"), allow(unused))] parallel: bool,
+) {
+    let (n_q_h, seq_len) = (q_heads.shape()[0], q_heads.shape()[1]);
+    let total_seq_len = att_scores.size() / (n_q_h * seq_len);
+    // the first total_seq rows of kv head h
+    let stride = k_heads.shape()[1] * dqkv;
+    let head = |t: &Tensor<f32>, h: usize, shape: &[usize]| t.slice(h * stride, shape);
+    let scores_of = |att_scores: &Tensor<f32>, h: usize| {
+        att_scores.slice(h * seq_len * total_seq_len, &[1, seq_len, total_seq_len])
+    };
+    let for_each_head = |f: &(dyn Fn(usize) + Sync)| {
+        #[cfg(feature = "parallel")]
+        if parallel {
+            use rayon::prelude::*;
+            (0..n_q_h).into_par_iter().for_each(f);
+            return;
+        }
+        (0..n_q_h).for_each(f);
+    };
+    // score = Q @ K.T * scale, query head h reading kv head h / n_groups
+    let scores = &*att_scores;
+    for_each_head(&|h| {
+        let mut scores = scores_of(scores, h);
+        OP::matmul_transb_batched(
+            &mut scores,
+            0.,
+            &q_heads.slice(h * seq_len * dqkv, &[1, seq_len, dqkv]),
+            &head(k_heads, h / n_groups, &[1, total_seq_len, dqkv]),
+            1.,
+        );
+        OP::scale(&mut scores, scale);
+        if let Some(cap) = softcap {
+            OP::softcap(&mut scores, cap);
+        }
+    });
+    // attn = softmax(score)
+    match sink {
+        None => backend.masked_softmax(att_scores),
+        Some(sink) => backend.masked_softmax_sink(att_scores, sink.n_sink, sink.window),
+    }
+    // attn_V = attn @ V
+    let (scores, out_heads) = (&*att_scores, &*out_heads);
+    for_each_head(&|h| {
+        let (kv, mut scores) = (h / n_groups, scores_of(scores, h));
+        let mut out = out_heads.slice(h * seq_len * dqkv, &[1, seq_len, dqkv]);
+        if head_major {
+            OP::matmul(
+                out.reshape(&[seq_len, dqkv]),
+                0.,
+                scores.reshape(&[seq_len, total_seq_len]),
+                &head(v_heads, kv, &[total_seq_len, dqkv]),
+                1.,
+            );
+        } else {
+            let v = head(v_heads, kv, &[1, dqkv, total_seq_len]);
+            OP::matmul_transb_batched(&mut out, 0., &scores, &v, 1.);
+        }
+    });
+}
+
+// mlp_with_activation on backend
+#[allow(clippy::too_many_arguments)]
+fn mlp_on(
+    backend: &dyn Backend,
+    residual: &mut Tensor<f32>,
+    gate: &mut Tensor<f32>,
+    up: &mut Tensor<f32>,
+    w_up: &Weight<f32>,
+    w_down: &Weight<f32>,
+    w_gate: &Weight<f32>,
+    rms_w: &Tensor<f32>,
+    eps: f32,
+    norm_unit_offset: bool,
+    activation: OP::Activation,
+) -> Result<(), OP::OperatorError> {
+    // 1. 计算残差张量的RMS归一化, rms_norm_gemma for checkpoints storing the weights as deltas,
+    // 2. 计算门控张量和上投影张量, fused so that the normalized residual is never stored
+    backend.rms_norm_matmul_transb(gate, residual, rms_w, w_gate, eps, norm_unit_offset, 1.0)?;
+    backend.rms_norm_matmul_transb(up, residual, rms_w, w_up, eps, norm_unit_offset, 1.0)?;
+    // 3. 门控激活: up = act(gate) * up
+    backend.gated_activation(up, gate, activation);
+    // 4. 计算输出并累加到residual上
+    backend.matmul_transb(residual, 1., up, w_down, 1.0)
+}
+
+// greedy decoding of up to max_len tokens
+#[cfg(test)]
+fn greedy(max_len: usize) -> GenerationConfig {
+    GenerationConfig::builder()
+        .max_len(max_len)
+        .top_k(1)
+        .build()
+        .unwrap()
+}
+
+#[test]
+pub fn test_mlp() {
+    let seq_len = 4;
+    let d = 2;
+    let di = 3;
+    let mut residual = Tensor::<f32>::new(vec![1., 1., 1., 1., 1., 1., 1., 1.], &vec![seq_len, d]);
+    let mut hidden_states = Tensor::<f32>::default(&vec![seq_len, d]);
+    let mut gate_buf = Tensor::<f32>::default(&vec![seq_len, di]);
+    let mut up_buf = Tensor::<f32>::default(&vec![seq_len, di]);
+    let w_up = Tensor::<f32>::new(vec![0.1, 0.2, 0.3, 0.4, 0.5, 0.6], &vec![di, d]);
+    let w_down = Tensor::<f32>::new(vec![0.1, 0.2, 0.3, 0.4, 0.5, 0.6], &vec![d, di]);
+    let w_gate = Tensor::<f32>::new(vec![0.1, 0.2, 0.3, 0.4, 0.5, 0.6], &vec![di, d]);
+    let rms_w = Tensor::<f32>::new(vec![1., 1.], &vec![d]);
+    let eps = 1e-6;
+    mlp(
+        &mut residual,
+        &mut hidden_states,
+        &mut gate_buf,
+        &mut up_buf,
+        &w_up,
+        &w_down,
+        &w_gate,
+        &rms_w,
+        eps,
+    );
+
+    assert!(residual.close_to(
+        &Tensor::<f32>::new(
+            vec![
+                1.3429964, 1.7290739, 1.3429964, 1.7290739, 1.3429964, 1.7290739, 1.3429964,
+                1.7290739
+            ],
+            &vec![seq_len, d]
+        ),
+        1e-3
+    ))
+}
+
+#[test]
+fn test_mlp_relu2() {
+    let mut residual = Tensor::<f32>::new(vec![1., 1.], &vec![1, 2]);
+    let mut gate_buf = Tensor::<f32>::default(&vec![1, 3]);
+    let mut up_buf = Tensor::<f32>::default(&vec![1, 3]);
+    let w_up = Weight::Full(Tensor::new(vec![0.1, 0.2, 0.3, 0.4, 0.5, 0.6], &vec![3, 2]));
+    let w_down = Weight::Full(Tensor::new(vec![0.1, 0.2, 0.3, 0.4, 0.5, 0.6], &vec![2, 3]));
+    let w_gate = Weight::Full(Tensor::new(
+        vec![-0.1, -0.2, 0.3, 0.4, 0.5, 0.6],
+        &vec![3, 2],
+    ));
+    let rms_w = Tensor::<f32>::new(vec![1., 1.], &vec![2]);
+    mlp_with_activation(
+        &mut residual,
+        &mut gate_buf,
+        &mut up_buf,
+        &w_up,
+        &w_down,
+        &w_gate,
+        &rms_w,
+        1e-6,
+        false,
+        OP::Activation::Relu2,
+    )
+    .unwrap();
+    // normalized input (1, 1): gate (-0.3, 0.7, 1.1), up (0.3, 0.7, 1.1),
+    // relu2(gate) * up = (0, 0.343, 1.331), then down and the residual
+    assert!(residual.close_to(&Tensor::<f32>::new(vec![1.4679, 1.9701], &vec![1, 2]), 1e-4));
+}
+
+#[test]
+fn test_forward_out_of_vocab() {
+    let model = story_model();
+    let mut cache = model.new_cache();
+    let input = Tensor::<u32>::new(vec![1, model.vocab as u32], &vec![2]);
+    assert_eq!(
+        model.forward(&input, &mut cache).err(),
+        Some(OP::OperatorError::Gather(
+            OP::GatherError::IndexOutOfRange {
+                pos: 1,
+                index: 2048,
+                rows: 2048
+            }
+        ))
+    );
+    assert_eq!(cache.len(), 0);
+}
+
+#[test]
+fn test_cache_truncate() {
+    use rand::SeedableRng;
+    let model = story_model();
+    let mut cache = model.new_cache();
+    let sample = |cache: &mut KVCache<f32>, seed| {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
+        let mut token = 300;
+        for _ in 0..20 {
+            let logits = model
+                .forward(&Tensor::new(vec![token], &vec![1]), cache)
+                .unwrap();
+            token = OP::random_sample(&logits, 0.9, 50, 1., 0., 1., &mut rng);
+        }
+    };
+    let prefix = |cache: &mut KVCache<f32>| -> Vec<Vec<f32>> {
+        let dim = cache.dim();
+        (0..model.n_layers)
+            .flat_map(|layer| [cache.k_cache(layer, 0), cache.v_cache(layer, 0)])
+            .map(|t| t.data()[..10 * dim].to_vec())
+            .collect()
+    };
+    sample(&mut cache, 1);
+    assert_eq!((cache.len(), cache.max_len()), (20, model.max_seq_len()));
+    let before = prefix(&mut cache);
+    cache.truncate(10);
+    assert_eq!(cache.len(), 10);
+    sample(&mut cache, 2);
+    assert_eq!(cache.len(), 30);
+    let after = prefix(&mut cache);
+    assert!(before
+        .iter()
+        .zip(&after)
+        .all(|(a, b)| a.iter().zip(b).all(|(x, y)| x.to_bits() == y.to_bits())));
+}
+
+#[test]
+fn test_cache_growth_never_copies() {
+    let mut model = story_model();
+    // linear rope scaling stretches the context of 512 to 2048
+    let scaling = OP::RopeScaling::Linear { factor: 4. };
+    model.max_seq_len = scaling.max_positions(512);
+    model.rope = OP::RopeCache::new(model.max_seq_len, model.dqkv, 10000., scaling);
+    let mut cache = model.new_cache();
+    let storage = |cache: &mut KVCache<f32>| -> Vec<*const f32> {
+        (0..model.n_layers)
+            .flat_map(|layer| [cache.k_cache(layer, 0), cache.v_cache(layer, 0)])
+            .map(|t| t.data().as_ptr())
+            .collect()
+    };
+    let before = storage(&mut cache);
+    let mut logits = Tensor::default(&vec![1, model.vocab]);
+    let mut input = Tensor::new(vec![1, 300, 25, 700, 40], &vec![5]);
+    while cache.len() < 1030 {
+        model
+            .forward_into(&input, &mut cache, None, &mut logits)
+            .unwrap();
+        input = Tensor::new(vec![OP::argmax_row(logits.data())], &vec![1]);
+    }
+    assert_eq!(cache.copied_bytes(), 0);
+    assert_eq!(storage(&mut cache), before);
+}
+
+#[test]
+fn test_generate_f16_cache() {
+    let model = story_model();
+    let half = story_model().with_kv_dtype(KvDtype::F16);
+    assert_eq!(half.new_cache().dtype(), KvDtype::F16);
+    let config = GenerationConfig {
+        max_len: 32,
+        seed: Some(7),
+        ..Default::default()
+    };
+    let prompt = [1, 300, 25, 700, 40];
+    let full = model.generate(&prompt, &config).unwrap();
+    let packed = half.generate(&prompt, &config).unwrap();
+    assert_eq!(full[..16], packed[..16]);
+    // and the logits of a prefill stay close
+    let input = Tensor::new(full.clone(), &vec![full.len()]);
+    let a = model.forward(&input, &mut model.new_cache()).unwrap();
+    let b = half.forward(&input, &mut half.new_cache()).unwrap();
+    assert!(a.close_to(&b, 1e-2));
+}
+
+#[test]
+fn test_i8_cache_perplexity() {
+    let tokenizer = story_tokenizer();
+    let text = "Once upon a time, there was a little boy named Tim. Tim liked to play with his \
+        red ball in the park. One day, the ball rolled into the pond and Tim was sad. His \
+        mom helped him get the ball out, and they went home to eat some cake together.";
+    let tokens = tokenizer.encode(text, true).unwrap().get_ids().to_vec();
+    // the log perplexity of feeding the text token by token, so that every token attends
+    // to keys and values read back from the cache
+    let log_perplexity = |model: &Llama<f32>| {
+        let mut cache = model.new_cache();
+        let nll: f32 = tokens
+            .windows(2)
+            .map(|pair| {
+                let input = Tensor::new(vec![pair[0]], &vec![1]);
+                let logits = model.forward(&input, &mut cache).unwrap();
+                OP::cross_entropy(&logits, &Tensor::new(vec![pair[1]], &vec![1]))
+            })
+            .sum();
+        nll / (tokens.len() - 1) as f32
+    };
+    let full = log_perplexity(&story_model());
+    let int8 = log_perplexity(&story_model().with_kv_dtype(KvDtype::I8));
+    assert!(full < 4., "{full}");
+    // perplexity within 2% of the f32 cache
+    assert!((int8 - full).abs() < 0.02, "{full} {int8}");
+}
+
+#[test]
+fn test_context_overflow() {
+    let model = story_model().with_max_seq_len(16);
+    let prompt: Vec<u32> = (0..10).map(|i| i * 37 + 3).collect();
+    let builder = || {
+        GenerationConfig::builder()
+            .temperature(0.)
+            .max_len(20)
+            .ban(model.eos_token_ids[0])
+    };
+    // after 7 tokens the cache holds 16 entries and there is no room for the 7th
+    let overflow = GenerateError::ContextOverflow {
+        needed: 17,
+        max: 16,
+    };
+    let config = builder().build().unwrap();
+    assert_eq!(model.generate(&prompt, &config), Err(overflow.clone()));
+    let stream: Vec<_> = model.generate_stream(&prompt, &config).unwrap().collect();
+    assert_eq!(stream.len(), 8);
+    assert_eq!(stream[7], Err(overflow));
+    // or it stops there, with the tokens so far
+    let config = builder().stop_at_context_limit(true).build().unwrap();
+    let tokens = model.generate(&prompt, &config).unwrap();
+    let streamed: Vec<u32> = stream[..7].iter().map(|t| *t.as_ref().unwrap()).collect();
+    assert_eq!(tokens, streamed);
+    let mut finished = None;
+    let pieces = vec![vec![]; model.vocab];
+    let reason = model
+        .generate_with_callback(&prompt, &config, &pieces, |event| {
+            if let GenerationEvent::Finished { reason, .. } = event {
+                finished = Some(reason);
+            }
+            ControlFlow::Continue(())
+        })
+        .unwrap();
+    assert_eq!(
+        (reason, finished),
+        (FinishReason::ContextFull, Some(reason))
+    );
+    // a prompt that does not fit fails before any forward
+    let long = vec![3; 17];
+    assert_eq!(
+        model.generate(&long, &config),
+        Err(GenerateError::PromptTooLong {
+            len: 17,
+            max_position_embeddings: 16
+        })
+    );
+}
+
+#[test]
+fn test_forward_cache_full() {
+    let model = story_model();
+    let mut cache = KVCache::new(model.n_layers, 4, model.n_kv_h * model.dqkv, 0);
+    model
+        .forward(&Tensor::new(vec![1, 300, 25], &vec![3]), &mut cache)
+        .unwrap();
+    let before = cache.k_cache(1, 0).data().to_vec();
+    let result = model.forward(&Tensor::new(vec![700, 40], &vec![2]), &mut cache);
+    assert_eq!(
+        result.err(),
+        Some(OP::OperatorError::ContextOverflow { needed: 5, max: 4 })
+    );
+    // failing before writing anything
+    assert_eq!(cache.len(), 3);
+    assert_eq!(cache.k_cache(1, 0).data(), before);
+}
+
+#[test]
+fn test_memory_report() {
+    let model_dir = story_dir();
+    let model = story_model();
+    let file = std::fs::read(model_dir.join("model.safetensors")).unwrap();
+    let safetensor = SafeTensors::deserialize(&file).unwrap();
+    let stored: usize = safetensor
+        .tensors()
+        .iter()
+        .map(|(_, view)| view.data().len())
+        .sum();
+    // the story model ties its embeddings, which load lm_head.weight a second time
+    let lm_head = safetensor.tensor("lm_head.weight").unwrap().data().len();
+    assert_eq!(model.weights_memory_bytes(), stored + lm_head);
+    let report = model.memory_report(100);
+    assert_eq!(report.weights, model.weights_memory_bytes());
+    assert_eq!(report.cache_max, model.new_cache().memory_bytes());
+    assert_eq!(report.cache * model.max_seq_len, report.cache_max * 100);
+    // caches of narrower dtypes take less, the forward an f32 copy of a layer more
+    let model = story_model()
+        .with_kv_dtype(KvDtype::I8)
+        .with_max_seq_len(1024);
+    let i8 = model.memory_report(100);
+    assert_eq!(i8.cache_max, model.new_cache().memory_bytes());
+    assert!(i8.cache < report.cache / 2 && i8.forward_buffers > report.forward_buffers);
+}
+
+#[test]
+fn test_q8_weight_memory() {
+    use crate::tensor::quantize_q8;
+    let model = story_model();
+    let p = &model.params;
+    let matrices = [&p.wq, &p.wk, &p.wv, &p.wo, &p.w_up, &p.w_gate, &p.w_down]
+        .into_iter()
+        .flatten()
+        .map(|w| w.to_f32())
+        .chain([p.lm_head.slice(0, p.lm_head.shape())]);
+    let (mut f32_bytes, mut q8_bytes) = (0, 0);
+    for w in matrices {
+        f32_bytes += w.size() * std::mem::size_of::<f32>();
+        q8_bytes += quantize_q8(&w).size_in_bytes();
+    }
+    // 32 bytes of values + 4 of scale per 32 f32s, 36 / 128 of the memory
+    assert_eq!(q8_bytes * 128, f32_bytes * 36);
+}
+
+#[test]
+fn test_generate_q4_lm_head() {
+    let mut model = story_model();
+    let prompt = [1, 300, 25, 700, 40];
+    let input = Tensor::<u32>::new(prompt.to_vec(), &vec![prompt.len()]);
+    let expected = model.forward(&input, &mut model.new_cache()).unwrap();
+
+    model.quantize_lm_head_q4();
+    let logits = model.forward(&input, &mut model.new_cache()).unwrap();
+    let range = expected.data().iter().fold(0f32, |m, x| m.max(x.abs()));
+    assert!(logits.max_abs_diff(&expected) < 0.1 * range);
+    let tokens = model.generate(&prompt, &greedy(20)).unwrap();
+    assert!(!tokens.is_empty() && tokens.iter().all(|&t| (t as usize) < model.vocab));
+}
+
+#[test]
+fn test_forward_i8() {
+    let model_dir = story_dir();
+    let model = story_model();
+    let options = LoadOptions {
+        i8_attention: true,
+        i8_mlp: true,
+        ..Default::default()
+    };
+    let quantized = Llama::from_safetensors_with(&model_dir, options);
+    assert!(matches!(quantized.params.w_down[1], Weight::I8(_)));
+
+    let input = Tensor::<u32>::new(vec![1, 300, 25, 700, 40], &vec![5]);
+    let expected = model.forward(&input, &mut model.new_cache()).unwrap();
+    let logits = quantized
+        .forward(&input, &mut quantized.new_cache())
+        .unwrap();
+    let range = expected.data().iter().fold(0f32, |m, x| m.max(x.abs()));
+    assert!(logits.max_abs_diff(&expected) < 0.05 * range);
+    assert_eq!(OP::argmax(&logits).data(), OP::argmax(&expected).data());
+}
+
+#[test]
+fn test_generate_f16_weights() {
+    let model_dir = story_dir();
+    let model = story_model();
+    let options = LoadOptions {
+        f16: true,
+        ..Default::default()
+    };
+    let half = Llama::from_safetensors_with(&model_dir, options);
+    assert!(matches!(half.params.wq[0], Weight::F16(_)));
+    let weight_bytes = |m: &Llama<f32>| -> usize {
+        let p = &m.params;
+        [&p.wq, &p.wk, &p.wv, &p.wo, &p.w_up, &p.w_gate, &p.w_down]
+            .into_iter()
+            .flatten()
+            .map(|w| w.size_in_bytes())
+            .sum()
+    };
+    assert_eq!(weight_bytes(&half) * 2, weight_bytes(&model));
+
+    // greedy decoding picks the same tokens
+    let prompt = [1, 300, 25, 700, 40];
+    assert_eq!(
+        half.generate(&prompt, &greedy(32)).unwrap(),
+        model.generate(&prompt, &greedy(32)).unwrap()
+    );
+}
+
+#[test]
+fn test_load_bf16_fixture() {
+    use crate::params::EmbeddingTable;
+    use std::path::PathBuf;
+    let project_dir = env!("CARGO_MANIFEST_DIR");
+    let model_dir = PathBuf::from(project_dir)
+        .join("tests")
+        .join("fixtures")
+        .join("tiny-bf16");
+    let model = Llama::from_safetensors(model_dir);
+    assert!(matches!(
+        model.params.embedding_table,
+        EmbeddingTable::BF16(_)
+    ));
+    let Weight::BF16(wq) = &model.params.wq[0] else {
+        panic!("bf16 weights should stay bf16");
+    };
+    let wq: Vec<f32> = wq.data()[..6].iter().map(|x| x.to_f32()).collect();
+    assert_eq!(wq, [-0.6875, -0.1875, 0.3125, 0.8125, -0.5, 0.]);
+    // norm weights are upcast at load
+    assert_eq!(model.params.rms_out_w.data(), &[1.; 8]);
+
+    let input = Tensor::<u32>::new(vec![1, 5, 9], &vec![3]);
+    let logits = model.forward(&input, &mut model.new_cache()).unwrap();
+    assert_eq!(logits.shape(), &vec![1, 16]);
+    assert!(logits.data().iter().all(|x| x.is_finite()));
+    let tokens = model.generate(&[1, 5, 9], &greedy(8)).unwrap();
+    assert!(tokens.iter().all(|&t| t < 16));
+}
+
+#[test]
+pub fn test_load_safetensors() {
+    use crate::tensor::float_eq;
+    let model = story_model();
+    assert_eq!(model.vocab, 2048);
+    assert_eq!(model.n_layers, 2);
+    assert_eq!(model.n_q_h, 8);
+    assert_eq!(model.n_kv_h, 4);
+    assert_eq!(model.d, 128);
+    assert_eq!(model.dqkv, 16);
+    assert_eq!(model.di, 384);
+
+    let embedding_table = model.params.embedding_table.to_f32();
+    assert!(float_eq(&embedding_table.data()[50], &0.14453125, 1e-6));
+    assert_eq!(model.params.lm_head.data()[10], embedding_table.data()[10]);
+    assert!(float_eq(
+        &model.params.rms_att_w[0].data()[10],
+        &0.18652344,
+        1e-6
+    ));
+    assert!(float_eq(
+        &model.params.rms_ffn_w[1].data()[10],
+        &0.32421875,
+        1e-6
+    ));
+    assert!(float_eq(
+        &model.params.rms_out_w.data()[100],
+        &0.73046875,
+        1e-6
+    ));
+    assert!(float_eq(
+        &model.params.w_down[0].to_f32().data()[100],
+        &-0.0625,
+        1e-6
+    ));
+    assert!(float_eq(
+        &model.params.w_up[0].to_f32().data()[100],
+        &1.46875,
+        1e-6
+    ));
+    assert!(float_eq(
+        &model.params.w_gate[1].to_f32().data()[100],
+        &0.296875,
+        1e-6
+    ));
+    assert!(float_eq(
+        &model.params.wq[1].to_f32().data()[100],
+        &0.032226563,
+        1e-6
+    ));
+    assert!(float_eq(
+        &model.params.wk[1].to_f32().data()[100],
+        &-0.21386719,
+        1e-6
+    ));
+    assert!(float_eq(
+        &model.params.wv[0].to_f32().data()[100],
+        &0.041015625,
+        1e-6
+    ));
+    assert!(float_eq(
+        &model.params.wo[0].to_f32().data()[100],
+        &0.01965332,
+        1e-6
+    ));
+}
+
+#[test]
+fn test_embedding_scale() {
+    let mut model = story_model();
+    assert_eq!(model.embedding_scale, 1.);
+    // the logits of a decode step as they were before the scale existed
+    let mut cache = model.new_cache();
+    let prompt = Tensor::<u32>::new(vec![1, 300, 25, 700, 40], &vec![5]);
+    model.forward(&prompt, &mut cache).unwrap();
+    let logits = model
+        .forward(&Tensor::new(vec![99], &vec![1]), &mut cache)
+        .unwrap();
+    let expected = Tensor::new(
+        vec![0.79586166, -0.053252697, -9.414634, 5.881403],
+        &vec![4],
+    );
+    assert!(logits.slice(0, &vec![4]).max_abs_diff(&expected) < 1e-3);
+
+    // a Gemma-style scale changes them
+    model.embedding_scale = (model.d as f32).sqrt();
+    let mut cache = model.new_cache();
+    model.forward(&prompt, &mut cache).unwrap();
+    let scaled = model
+        .forward(&Tensor::new(vec![99], &vec![1]), &mut cache)
+        .unwrap();
+    assert!(scaled.max_abs_diff(&logits) > 1e-2);
+}
+
+#[test]
+fn test_generate_seed() {
+    let model = story_model();
+    let config = |seed| GenerationConfig {
+        max_len: 64,
+        top_p: 0.9,
+        top_k: 50,
+        penalty_last_n: 0,
+        seed: Some(seed),
+        ..Default::default()
+    };
+    let prompt = [1, 300, 25, 700, 40];
+    let first = model.generate(&prompt, &config(42)).unwrap();
+    let second = model.generate(&prompt, &config(42)).unwrap();
+    assert_eq!(first, second);
+    // other seeds sample other stories
+    assert!((43..48).any(|seed| model.generate(&prompt, &config(seed)).unwrap() != first));
+    // so does mirostat, whose mu lives in the generation loop
+    let mirostat = GenerationConfig {
+        mirostat: Some(OP::MirostatParams { tau: 3., eta: 0.1 }),
+        ..config(42)
+    };
+    assert_eq!(
+        model.generate(&prompt, &mirostat).unwrap(),
+        model.generate(&prompt, &mirostat).unwrap()
+    );
+}
+
+#[test]
+fn test_generate_text_stop() {
+    let model = story_model();
+    let tokenizer = story_tokenizer();
+    let pieces = crate::constraint::token_pieces(&tokenizer);
+    let config = GenerationConfig {
+        max_len: 32,
+        top_p: 0.9,
+        top_k: 50,
+        penalty_last_n: 0,
+        seed: Some(7),
+        ..Default::default()
+    };
+    let prompt = [1, 300, 25, 700, 40];
+    let tokens = model.generate(&prompt, &config).unwrap();
+    let (full, _) = model
+        .generate_text(&prompt, &config, &pieces, |_| {})
+        .unwrap();
+    let bytes: Vec<u8> = tokens
+        .iter()
+        .flat_map(|&t| pieces[t as usize].clone())
+        .collect();
+    assert_eq!(full.as_bytes(), bytes);
+    // a stop string across the boundary of the 10th and the 11th token
+    let boundary: usize = tokens[..10].iter().map(|&t| pieces[t as usize].len()).sum();
+    let stop = full[boundary - 2..boundary + 2].to_string();
+    let expected = &full[..full.find(&stop).unwrap()];
+    let mut streamed = Vec::new();
+    let config = GenerationConfig {
+        stop: vec![stop.clone()],
+        logprobs: None,
+        ..config
+    };
+    let (text, _) = model
+        .generate_text(&prompt, &config, &pieces, |s| streamed.push(s.to_string()))
+        .unwrap();
+    assert_eq!(text, expected);
+    assert_eq!(streamed.concat(), text);
+    assert!(streamed.len() > 1);
+}
+
+#[test]
+#[should_panic(expected = "needs the text of the tokens")]
+fn test_generate_rejects_stop() {
+    let model = story_model();
+    // generate would return more tokens than the text generate_text cuts at the stop string
+    let config = GenerationConfig::builder().stop("\nUser:").build().unwrap();
+    let _ = model.generate(&[1, 300, 25], &config);
+}
+
+#[test]
+fn test_generate_logprobs() {
+    let model = story_model();
+    let greedy = GenerationConfig {
+        max_len: 24,
+        top_p: 1.,
+        top_k: 1,
+        repetition_penalty: 1.3,
+        logprobs: Some(5),
+        ..Default::default()
+    };
+    let prompt = [1, 300, 25, 700, 40];
+    let tokens = model.generate_logprobs(&prompt, &greedy).unwrap();
+    assert_eq!(
+        tokens.iter().map(|t| t.id).collect::<Vec<_>>(),
+        model.generate(&prompt, &greedy).unwrap()
+    );
+    for token in &tokens {
+        // the pick of greedy decoding is the most likely token after the penalty
+        assert_eq!(token.top.len(), 5);
+        assert_eq!(token.top[0], (token.id, token.logprob));
+        assert!(token.top.windows(2).all(|w| w[0].1 >= w[1].1));
+        assert!(
+            token.logprob <= 0. && token.top.iter().map(|t| t.1.exp()).sum::<f32>() < 1. + 1e-5
+        );
+    }
+}
+
+#[test]
+fn test_generate_beam() {
+    let model = story_model();
+    let prompt = [1, 300, 25, 700, 40];
+    // a single beam is greedy decoding
+    let beams = model.generate_beam(&prompt, 1, 24, 1.).unwrap();
+    assert_eq!(beams.len(), 1);
+    assert_eq!(
+        beams[0].tokens,
+        model.generate(&prompt, &greedy(24)).unwrap()
+    );
+    let beams = model.generate_beam(&prompt, 3, 16, 1.).unwrap();
+    assert!(beams.len() >= 3);
+    assert!(beams.windows(2).all(|w| w[0].score >= w[1].score));
+    // finding at least as likely a sequence as greedy decoding
+    let greedy_logprob: f32 = model
+        .generate_logprobs(&prompt, &greedy(16))
+        .unwrap()
+        .iter()
+        .map(|t| t.logprob)
+        .sum();
+    let best = beams
+        .iter()
+        .map(|h| h.logprob)
+        .fold(f32::NEG_INFINITY, f32::max);
+    assert!(best >= greedy_logprob - 1e-4, "{best} {greedy_logprob}");
+}
+
+// counts the allocations of every thread on its own, so that tests running at the same
+// time do not disturb each other
+#[cfg(test)]
+struct CountingAlloc;
+
+#[cfg(test)]
+thread_local! {
+    static ALLOCATIONS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+#[cfg(test)]
+unsafe impl std::alloc::GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: std::alloc::Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|n| n.set(n.get() + 1));
+        std::alloc::System.alloc(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: std::alloc::Layout, size: usize) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|n| n.set(n.get() + 1));
+        std::alloc::System.realloc(ptr, layout, size)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: std::alloc::Layout) {
+        std::alloc::System.dealloc(ptr, layout)
+    }
+}
+
+#[cfg(test)]
+#[global_allocator]
+static COUNTING_ALLOC: CountingAlloc = CountingAlloc;
+
+#[test]
+fn test_decode_allocations() {
+    let allocations = || ALLOCATIONS.with(|n| n.get());
+    let steps = 64;
+    for config in [
+        greedy(steps),
+        GenerationConfig {
+            seed: Some(1),
+            max_len: steps,
+            ..Default::default()
+        },
+    ] {
+        let mut decoder = Decoder::new(&config, &[1, 300, 25], 1);
+        let mut logits = Tensor::<f32>::random(&vec![1, 2048]);
+        // the first sample sizes the scratch buffers
+        decoder.process(&mut logits);
+        let next = decoder.sample(&logits);
+        decoder.push(next);
+        let before = allocations();
+        for _ in 1..steps {
+            decoder.process(&mut logits);
+            let next = decoder.sample(&logits);
+            decoder.push(next);
+            unsafe { logits.data_mut()[next as usize] -= 0.5 };
+        }
+        assert_eq!(allocations() - before, 0, "{config:?}");
+    }
+    assert!(allocations() > 0);
+}
+
+#[test]
+fn test_prompt_lookup() {
+    let model = story_model();
+    let tokenizer = story_tokenizer();
+    let text = "Tom has a red ball. Tom has a red ball. Tom has a red ball. Tom has a red ball.";
+    let prompt = tokenizer.encode(text, true).unwrap().get_ids().to_vec();
+    let config = greedy(96);
+    let (expected, plain) = model.generate_with_stats(&prompt, &config).unwrap();
+    assert_eq!(plain.forwards, expected.len());
+    let config = GenerationConfig {
+        prompt_lookup: Some(PromptLookup {
+            ngram: 3,
+            max_draft: 6,
+        }),
+        ..config
+    };
+    let (tokens, stats) = model.generate_with_stats(&prompt, &config).unwrap();
+    assert_eq!(tokens, expected);
+    assert!(stats.accepted > 0 && stats.accepted <= stats.drafted);
+    assert!(stats.tokens_per_forward() > 1., "{stats:?}");
+    // sampling is left alone
+    let config = GenerationConfig {
+        top_k: 30,
+        seed: Some(3),
+        ..config
+    };
+    let (_, stats) = model.generate_with_stats(&prompt, &config).unwrap();
+    assert_eq!(stats.drafted, 0);
+}
+
+#[test]
+fn test_prompt_lookup_eos_in_draft() {
+    let mut model = story_model();
+    let tokenizer = story_tokenizer();
+    let text = "Tom has a red ball. Tom has a red ball. Tom has a red ball. Tom has a red ball.";
+    let prompt = tokenizer.encode(text, true).unwrap().get_ids().to_vec();
+    let config = GenerationConfig {
+        prompt_lookup: Some(PromptLookup {
+            ngram: 3,
+            max_draft: 6,
+        }),
+        ..greedy(96)
+    };
+    // the stats after every token, the forwards the same for the tokens of one round
+    let mut stream = model.generate_stream(&prompt, &config).unwrap();
+    let (mut full, mut stats) = (Vec::new(), Vec::new());
+    while let Some(token) = stream.next() {
+        full.push(token.unwrap());
+        stats.push(stream.stats());
+    }
+    drop(stream);
+    // a token that stops the generation where it first shows up, before the last of the
+    // drafts of its round
+    let at = (0..full.len())
+        .find(|&i| {
+            let in_round = (0..i)
+                .rev()
+                .take_while(|&j| stats[j].forwards == stats[i].forwards)
+                .count();
+            let before = (i - in_round)
+                .checked_sub(1)
+                .map_or(0, |j| stats[j].drafted);
+            in_round < stats[i].drafted - before && !full[..i].contains(&full[i])
+        })
+        .unwrap();
+    model.eos_token_ids.clear();
+    model.eos_token_ids.push(full[at]);
+    let mut cache = model.new_cache();
+    let tokens = model.generate_in(&mut cache, &prompt, &config).unwrap();
+    assert_eq!(tokens, full[..=at]);
+    assert_eq!(cache.len(), prompt.len() + tokens.len() - 1);
+}
+
+#[test]
+fn test_generate_stream() {
+    let model = story_model();
+    let prompt = [1, 300, 25, 700, 40];
+    let config = GenerationConfig::builder()
+        .max_len(40)
+        .top_k(50)
+        .seed(11)
+        .build()
+        .unwrap();
+    let expected = model.generate(&prompt, &config).unwrap();
+    let streamed: Result<Vec<u32>, _> = model.generate_stream(&prompt, &config).unwrap().collect();
+    assert_eq!(streamed.unwrap(), expected);
+    // with several tokens from one forward
+    let lookup = GenerationConfig {
+        prompt_lookup: Some(PromptLookup {
+            ngram: 2,
+            max_draft: 4,
+        }),
+        ..greedy(40)
+    };
+    let mut stream = model.generate_stream(&prompt, &lookup).unwrap();
+    let streamed: Vec<u32> = stream.by_ref().map(Result::unwrap).collect();
+    assert_eq!(streamed, model.generate(&prompt, &greedy(40)).unwrap());
+    assert_eq!(stream.stats().tokens, streamed.len());
+    assert!(stream.next().is_none());
+    // nothing is left behind by a stream dropped early
+    let mut stream = model.generate_stream(&prompt, &config).unwrap();
+    let first: Vec<u32> = stream.by_ref().take(3).map(Result::unwrap).collect();
+    assert_eq!(first, expected[..3]);
+    assert_eq!(stream.stats().forwards, 3);
+    drop(stream);
+    assert_eq!(model.generate(&prompt, &config).unwrap(), expected);
+}
+
+#[test]
+fn test_generate_with_callback() {
+    let model = story_model();
+    let tokenizer = story_tokenizer();
+    let pieces = crate::constraint::token_pieces(&tokenizer);
+    let prompt = [1, 300, 25, 700, 40];
+    let config = GenerationConfig::builder()
+        .max_len(30)
+        .seed(4)
+        .build()
+        .unwrap();
+    let mut events = Vec::new();
+    let reason = model
+        .generate_with_callback(&prompt, &config, &pieces, |event| {
+            let generated = matches!(event, GenerationEvent::TokenGenerated { .. });
+            events.push(event);
+            let n = events.len() - 1; // after PrefillDone
+            if generated && n == 5 {
+                ControlFlow::Break(())
+            } else {
+                ControlFlow::Continue(())
+            }
+        })
+        .unwrap();
+    assert_eq!(reason, FinishReason::Cancelled);
+    assert_eq!(events.len(), 7);
+    assert!(matches!(
+        events[0],
+        GenerationEvent::PrefillDone { n_tokens: 5, .. }
+    ));
+    let expected = model.generate(&prompt, &config).unwrap();
+    let mut text = String::new();
+    for (event, &token) in events[1..6].iter().zip(&expected) {
+        let GenerationEvent::TokenGenerated { id, text_piece } = event else {
+            panic!("{event:?}")
+        };
+        assert_eq!(*id, token);
+        text.push_str(text_piece);
+    }
+    let GenerationEvent::Finished { reason, rest } = &events[6] else {
+        panic!("{:?}", events[6])
+    };
+    assert_eq!(*reason, FinishReason::Cancelled);
+    text.push_str(rest);
+    let bytes: Vec<u8> = expected[..5]
+        .iter()
+        .flat_map(|&t| pieces[t as usize].clone())
+        .collect();
+    assert_eq!(text, String::from_utf8_lossy(&bytes));
+    // run to the end, the reason agreeing with the stream
+    let mut n = 0;
+    let reason = model
+        .generate_with_callback(&prompt, &config, &pieces, |event| {
+            n += matches!(event, GenerationEvent::TokenGenerated { .. }) as usize;
+            ControlFlow::Continue(())
+        })
+        .unwrap();
+    let mut stream = model.generate_stream(&prompt, &config).unwrap();
+    assert_eq!(stream.by_ref().count(), n);
+    assert_eq!(stream.finish_reason(), Some(reason));
+    assert_eq!(n, expected.len());
+}
+
+#[test]
+fn test_generation_limits() {
+    use std::time::Duration;
+    let model = story_model();
+    let prompt = [1, 300, 25, 700, 40];
+    let config = GenerationConfig::builder()
+        .max_len(200)
+        .max_time(Duration::ZERO)
+        .seed(2)
+        .build()
+        .unwrap();
+    let mut stream = model.generate_stream(&prompt, &config).unwrap();
+    assert_eq!(stream.by_ref().count(), 0);
+    assert_eq!(stream.finish_reason(), Some(FinishReason::TimeLimit));
+    let (tokens, stats) = model.generate_with_stats(&prompt, &config).unwrap();
+    assert_eq!((tokens.len(), stats.forwards), (0, 0));
+    // the prompt counts towards max_total_tokens
+    let config = GenerationConfig {
+        max_total_tokens: Some(12),
+        max_time: None,
+        ..config
+    };
+    let mut stream = model.generate_stream(&prompt, &config).unwrap();
+    assert_eq!(stream.by_ref().count(), 7);
+    assert_eq!(stream.finish_reason(), Some(FinishReason::TokenLimit));
+    let (tokens, stats) = model.generate_with_stats(&prompt[..3], &config).unwrap();
+    assert_eq!((tokens.len(), stats.forwards), (9, 9));
+    let tokens = model.generate(
+        &prompt,
+        &GenerationConfig {
+            max_total_tokens: Some(5),
+            ..config.clone()
+        },
+    );
+    assert!(tokens.unwrap().is_empty());
+    // a prompt past max_seq_len fails up front
+    let long = vec![300; model.max_seq_len() + 1];
+    let err = model.generate(&long, &config).unwrap_err();
+    assert_eq!(
+        err,
+        GenerateError::PromptTooLong {
+            len: 513,
+            max_position_embeddings: 512
+        }
+    );
+    assert!(err.to_string().contains("513") && err.to_string().contains("512"));
+    assert!(model.generate_stream(&long, &config).is_err());
+}
+
+#[test]
+fn test_generate_n() {
+    let model = story_model();
+    let prompt = [1, 300, 25, 700, 40, 98, 1000, 12];
+    let config = GenerationConfig::builder()
+        .max_len(24)
+        .top_k(50)
+        .seed(21)
+        .build()
+        .unwrap();
+    let samples = model.generate_n(&prompt, 3, &config).unwrap();
+    assert_eq!(samples, model.generate_n(&prompt, 3, &config).unwrap());
+    // every sample is what generate gives with its seed
+    for (i, sample) in samples.iter().enumerate() {
+        let config = GenerationConfig {
+            seed: Some(21 + i as u64),
+            ..config.clone()
+        };
+        let (tokens, stats) = model.generate_with_stats(&prompt, &config).unwrap();
+        assert_eq!(sample.tokens, tokens);
+        assert_eq!(stats.prefill_tokens, prompt.len());
+        assert_eq!(sample.stats.tokens, tokens.len());
+    }
+    assert!(samples[0].tokens != samples[1].tokens || samples[1].tokens != samples[2].tokens);
+    // the prompt went through the model once
+    let prefilled: Vec<usize> = samples.iter().map(|s| s.stats.prefill_tokens).collect();
+    assert_eq!(prefilled, [prompt.len(), 0, 0]);
+    assert_eq!(samples[1].stats.forwards, samples[1].tokens.len() - 1);
+}
+
+#[test]
+fn test_custom_logits_processor() {
+    use crate::processor::LogitsProcessor;
+    struct BanEven;
+    impl LogitsProcessor for BanEven {
+        fn process(&mut self, _: &GenerationContext, logits: &mut Tensor<f32>) {
+            let data = unsafe { logits.data_mut() };
+            data.iter_mut()
+                .step_by(2)
+                .for_each(|l| *l = f32::NEG_INFINITY);
+        }
+    }
+    let model = story_model();
+    let prompt = [1, 300, 25, 700, 40];
+    let config = GenerationConfig::builder()
+        .max_len(40)
+        .top_k(50)
+        .repetition_penalty(1.2, 16)
+        .seed(8)
+        .build()
+        .unwrap();
+    let pipeline = LogitsPipeline::from_config(&config, model.bos_token_id);
+    let tokens = model
+        .generate_with_pipeline(&prompt, &config, pipeline)
+        .unwrap();
+    assert_eq!(tokens, model.generate(&prompt, &config).unwrap());
+    let mut pipeline = LogitsPipeline::from_config(&config, model.bos_token_id);
+    pipeline.push(BanEven);
+    let tokens = model
+        .generate_with_pipeline(&prompt, &config, pipeline)
+        .unwrap();
+    assert_eq!(tokens.len(), 40);
+    assert!(tokens.iter().all(|t| t % 2 == 1), "{tokens:?}");
+}
+
+#[test]
+fn test_contrastive_search() {
+    let model = story_model();
+    let prompt = [1, 300, 25, 700, 40];
+    let expected = model.generate(&prompt, &greedy(16)).unwrap();
+    // alpha 0 and k 1 are greedy decoding, whatever the rollbacks
+    for (k, alpha) in [(4, 0.), (1, 0.6)] {
+        let config = GenerationConfig {
+            contrastive: Some(ContrastiveSearch { k, alpha }),
+            ..GenerationConfig::default()
+        };
+        let config = GenerationConfig {
+            max_len: 16,
+            ..config
+        };
+        assert_eq!(model.generate(&prompt, &config).unwrap(), expected);
+    }
+    let config = GenerationConfig::builder()
+        .max_len(12)
+        .contrastive(3, 0.6)
+        .build()
+        .unwrap();
+    let (tokens, stats) = model.generate_with_stats(&prompt, &config).unwrap();
+    assert!(stats.forwards > tokens.len());
+    // every pick again from the whole context, with fresh caches
+    let mut context = prompt.to_vec();
+    for &token in &tokens {
+        let input = Tensor::new(context.clone(), &vec![context.len()]);
+        let (logits, hidden) = model
+            .forward_with_hidden(&input, &mut model.new_cache())
+            .unwrap();
+        let data = logits.data();
+        let mut candidates: Vec<u32> = (0..model.vocab as u32).collect();
+        candidates.sort_by(|&a, &b| data[b as usize].total_cmp(&data[a as usize]));
+        candidates.truncate(3);
+        let total: f32 = data
+            .iter()
+            .map(|l| (l - data[candidates[0] as usize]).exp())
+            .sum();
+        let mut probs = vec![];
+        let mut candidate_hidden = vec![];
+        for &c in &candidates {
+            probs.push((data[c as usize] - data[candidates[0] as usize]).exp() / total);
+            let mut extended = context.clone();
+            extended.push(c);
+            let input = Tensor::new(extended, &vec![context.len() + 1]);
+            let (_, h) = model
+                .forward_with_hidden(&input, &mut model.new_cache())
+                .unwrap();
+            candidate_hidden.extend_from_slice(&h.data()[context.len() * model.d..]);
+        }
+        let pick = contrastive_pick(&probs, &candidate_hidden, hidden.data(), model.d, 0.6);
+        assert_eq!(token, candidates[pick], "after {context:?}");
+        context.push(token);
+    }
+    assert_ne!(tokens, expected[..12]);
+}
+
+#[test]
+fn test_eos_token_ids() {
+    let mut model = story_model();
+    assert_eq!(model.eos_token_ids(), [2]);
+    assert_eq!(model.turn_terminator(), 2);
+    let config = GenerationConfig {
+        max_len: 32,
+        top_p: 0.9,
+        top_k: 50,
+        penalty_last_n: 0,
+        seed: Some(3),
+        ..Default::default()
+    };
+    let prompt = [1, 300, 25, 700, 40];
+    let full = model.generate(&prompt, &config).unwrap();
+    // a second terminator, a token that shows up for the first time at position at
+    let at = (5..full.len())
+        .find(|&i| !full[..i].contains(&full[i]))
+        .unwrap();
+    model.eos_token_ids.push(full[at]);
+    assert_eq!(model.turn_terminator(), full[at]);
+    assert_eq!(model.generate(&prompt, &config).unwrap(), full[..=at]);
+}
+
+#[test]
+fn test_no_repeat_ngram_size() {
+    let model = story_model();
+    // greedy decoding of this prompt repeats trigrams on its own
+    let prompt = [1, 300, 25, 700, 40];
+    let greedy = GenerationConfig {
+        max_len: 120,
+        top_p: 1.,
+        top_k: 1,
+        penalty_last_n: 0,
+        ..Default::default()
+    };
+    let trigrams = |output: &[u32]| {
+        let tokens = [&prompt[..], output].concat();
+        let all = tokens.windows(3).count();
+        let unique = tokens.windows(3).collect::<std::collections::HashSet<_>>();
+        (all, unique.len())
+    };
+    let (all, unique) = trigrams(&model.generate(&prompt, &greedy).unwrap());
+    assert!(unique < all);
+    let config = GenerationConfig {
+        no_repeat_ngram_size: 3,
+        ..greedy
+    };
+    let (all, unique) = trigrams(&model.generate(&prompt, &config).unwrap());
+    assert_eq!(unique, all);
+}
+
+#[test]
+fn test_logit_bias() {
+    use std::collections::HashMap;
+    let model = story_model();
+    let prompt = [1, 300, 25, 700, 40];
+    let greedy = GenerationConfig {
+        max_len: 8,
+        top_p: 1.,
+        top_k: 1,
+        penalty_last_n: 0,
+        ..Default::default()
+    };
+    let unbiased = model.generate(&prompt, &greedy).unwrap();
+    // banning the greedy choice picks another one
+    let config = GenerationConfig {
+        logit_bias: HashMap::from([(unbiased[0], -100.)]),
+        ..greedy.clone()
+    };
+    let banned = model.generate(&prompt, &config).unwrap();
+    assert!(!banned.contains(&unbiased[0]));
+    // a large bias forces a token every step
+    let config = GenerationConfig {
+        logit_bias: HashMap::from([(500, 100.)]),
+        ..greedy.clone()
+    };
+    assert_eq!(model.generate(&prompt, &config).unwrap(), [500; 8]);
+    // ids past the vocab are rejected up front
+    let config = GenerationConfig {
+        logit_bias: HashMap::from([(2048, 1.)]),
+        ..greedy.clone()
+    };
+    assert_eq!(
+        config.check(model.vocab),
+        Err("logit_bias token 2048 is out of range for a vocab of 2048".to_string())
+    );
+
+    // banned_tokens makes greedy decoding pick the runner-up
+    let mut cache = model.new_cache();
+    let mut logits = model
+        .forward(&Tensor::new(prompt.to_vec(), &vec![5]), &mut cache)
+        .unwrap();
+    let best = OP::argmax(&logits).data()[0];
+    OP::ban_tokens(&mut logits, &[best]);
+    let runner_up = OP::argmax(&logits).data()[0];
+    let config = GenerationConfig {
+        max_len: 1,
+        banned_tokens: vec![best],
+        ..greedy.clone()
+    };
+    assert_eq!(model.generate(&prompt, &config).unwrap(), [runner_up]);
+    // forcing bos through logit_bias loses to suppress_special
+    let config = GenerationConfig {
+        logit_bias: HashMap::from([(model.bos_token_id, 100.)]),
+        suppress_special: true,
+        ..greedy
+    };
+    assert!(!model
+        .generate(&prompt, &config)
+        .unwrap()
+        .contains(&model.bos_token_id));
+}
+
+#[test]
+fn test_attention_sink() {
+    let model = story_model();
+    let sink = AttentionSink {
+        n_sink: 4,
+        window: 60,
+        absolute_positions: false,
+    };
+    // as long as nothing is evicted the sinks change nothing
+    let prompt = Tensor::<u32>::new(vec![1, 300, 25, 700, 40], &vec![5]);
+    let mut cache = model.new_cache();
+    let expected = model.forward(&prompt, &mut cache).unwrap();
+    let mut cache = model.new_cache();
+    let logits = model
+        .forward_with_sink(&prompt, &mut cache, Some(sink))
+        .unwrap();
+    assert_eq!(logits.data(), expected.data());
+
+    // decode well past max_seq_len, feeding back the greedy token
+    let mut input = Tensor::<u32>::new(vec![OP::argmax(&logits).data()[0]], &vec![1]);
+    for _ in 0..model.max_seq_len + 64 {
+        model.evict_for_sink(&mut cache, sink, input.size());
+        let logits = model
+            .forward_with_sink(&input, &mut cache, Some(sink))
+            .unwrap();
+        assert!(cache.len() <= sink.n_sink + sink.window);
+        assert!(logits.data().iter().all(|x| x.is_finite()));
+        input = Tensor::new(vec![OP::argmax(&logits).data()[0]], &vec![1]);
+    }
+    assert_eq!(cache.len(), sink.n_sink + sink.window);
+
+    // a prompt longer than n_sink + window is prefilled with the sink mask, then evicted
+    let long: Vec<u32> = (0..100).map(|i| (i * 37 % 2000) as u32 + 3).collect();
+    let config = GenerationConfig {
+        max_len: 8,
+        penalty_last_n: 0,
+        attention_sink: Some(sink),
+        ..Default::default()
+    };
+    let output = model.generate(&long, &config).unwrap();
+    assert!(!output.is_empty() && output.len() <= 8);
+}
+
+#[test]
+fn test_sliding_window() {
+    let model = story_model();
+    let tokenizer = story_tokenizer();
+    let config = GenerationConfig::builder()
+        .sliding_window(32, 4)
+        .build()
+        .unwrap();
+    let sink = config.sink().unwrap();
+    assert!(sink.absolute_positions);
+    // decode 4 windows, feeding back the greedy token: the cache never holds more than the
+    // window and the kept tokens, while the positions go on with the text
+    let prompt = Tensor::<u32>::new(vec![1, 300, 25, 700, 40], &vec![5]);
+    let mut cache = model.new_cache();
+    let logits = model
+        .forward_with_sink(&prompt, &mut cache, Some(sink))
+        .unwrap();
+    let mut input = Tensor::<u32>::new(vec![OP::argmax(&logits).data()[0]], &vec![1]);
+    for fed in prompt.size()..prompt.size() + 4 * 32 {
+        model.evict_for_sink(&mut cache, sink, input.size());
+        let logits = model
+            .forward_with_sink(&input, &mut cache, Some(sink))
+            .unwrap();
+        assert!(cache.len() <= 32 + 4);
+        assert_eq!(cache.position(cache.len()), fed + 1);
+        assert!(logits.data().iter().all(|x| x.is_finite()));
+        input = Tensor::new(vec![OP::argmax(&logits).data()[0]], &vec![1]);
+    }
+    assert_eq!(cache.gap(), (4, prompt.size() + 4 * 32 - 36));
+
+    // generate goes on past max_seq_len, which stops it without a window
+    let config = GenerationConfig::builder()
+        .temperature(0.)
+        .max_len(4 * 128)
+        .ban(model.eos_token_ids[0])
+        .sliding_window(128, 4)
+        .build()
+        .unwrap();
+    let mut cache = model.new_cache();
+    let output = model
+        .generate_in(&mut cache, prompt.data(), &config)
+        .unwrap();
+    assert_eq!(output.len(), 4 * 128);
+    assert!(cache.len() <= 128 + 4);
+    assert_eq!(
+        cache.position(cache.len()),
+        prompt.size() + output.len() - 1
+    );
+
+    // the log perplexity of a text fed token by token through a window of a fraction of it
+    let text = "Once upon a time, there was a little boy named Tim. Tim liked to play with his \
+        red ball in the park. One day, the ball rolled into the pond and Tim was sad. His \
+        mom helped him get the ball out, and they went home to eat some cake together.";
+    let tokens = tokenizer.encode(text, true).unwrap().get_ids().to_vec();
+    let log_perplexity = |sink: Option<AttentionSink>| {
+        let mut cache = model.new_cache();
+        let nll: f32 = tokens
+            .windows(2)
+            .map(|pair| {
+                let input = Tensor::new(vec![pair[0]], &vec![1]);
+                if let Some(sink) = sink {
+                    model.evict_for_sink(&mut cache, sink, 1);
+                }
+                let logits = model.forward_with_sink(&input, &mut cache, sink).unwrap();
+                OP::cross_entropy(&logits, &Tensor::new(vec![pair[1]], &vec![1]))
+            })
+            .sum();
+        nll / (tokens.len() - 1) as f32
+    };
+    let full = log_perplexity(None);
+    let windowed = log_perplexity(Some(AttentionSink {
+        n_sink: 4,
+        window: 12,
+        absolute_positions: true,
+    }));
+    // most of the text is evicted before its end, and it still reads about as well
+    assert!(tokens.len() > 2 * (4 + 12));
+    assert!(windowed < full + 0.25, "{full} {windowed}");
+}
+
+#[test]
+fn test_fused_attention() {
+    // GQA with 2 query heads per kv head, decoding 3 new tokens on top of 4 cached ones
+    let (seq_len, total_seq_len, n_kv_h, n_groups, dqkv) = (3, 7, 2, 2, 8);
+    let n_q_h = n_kv_h * n_groups;
+    let q = Tensor::<f32>::random(&vec![seq_len, n_q_h * dqkv]);
+    let k = Tensor::<f32>::random(&vec![total_seq_len, n_kv_h * dqkv]);
+    let v = Tensor::<f32>::random(&vec![total_seq_len, n_kv_h * dqkv]);
+
+    let mut expected = Tensor::<f32>::default(&vec![seq_len, n_q_h * dqkv]);
+    let mut att_scores = Tensor::<f32>::default(&vec![n_kv_h, n_groups, seq_len, total_seq_len]);
+    self_attention(
+        &mut expected,
+        &mut att_scores,
+        &q,
+        &k,
+        &v,
+        n_kv_h,
+        n_groups,
+        seq_len,
+        total_seq_len,
+        dqkv,
+        1. / (dqkv as f32).sqrt(),
+        None,
+    );
+
+    let mut out = Tensor::<f32>::default(&vec![seq_len, n_q_h, dqkv]);
+    OP::attention(
+        &mut out,
+        &q.slice(0, &vec![seq_len, n_q_h, dqkv]),
+        &k.slice(0, &vec![total_seq_len, n_kv_h, dqkv]),
+        &v.slice(0, &vec![total_seq_len, n_kv_h, dqkv]),
+        1. / (dqkv as f32).sqrt(),
+    );
+    out.reshape(&vec![seq_len, n_q_h * dqkv]);
+    assert!(out.close_to(&expected, 1e-4));
+}
+
+#[test]
+fn test_self_attention_gqa() {
+    // 4 query heads on 2 kv heads against the same attention with the kv heads repeated
+    let (seq_len, total_seq_len, n_kv_h, n_groups, dqkv) = (2, 5, 2, 2, 4);
+    let n_q_h = n_kv_h * n_groups;
+    let q = Tensor::<f32>::random(&vec![seq_len, n_q_h * dqkv]);
+    let k = Tensor::<f32>::random(&vec![total_seq_len, n_kv_h * dqkv]);
+    let v = Tensor::<f32>::random(&vec![total_seq_len, n_kv_h * dqkv]);
+    let repeat = |t: &Tensor<f32>| {
+        let data = (0..total_seq_len * n_q_h)
+            .flat_map(|r| {
+                let (j, h) = (r / n_q_h, r % n_q_h);
+                t.data()[(j * n_kv_h + h / n_groups) * dqkv..][..dqkv].to_vec()
+            })
+            .collect();
+        Tensor::new(data, &vec![total_seq_len, n_q_h * dqkv])
+    };
+    let scale = 1. / (dqkv as f32).sqrt();
+
+    let mut out = Tensor::<f32>::default(&vec![seq_len, n_q_h * dqkv]);
+    let mut att_scores = Tensor::<f32>::default(&vec![n_kv_h, n_groups, seq_len, total_seq_len]);
+    self_attention(
+        &mut out,
+        &mut att_scores,
+        &q,
+        &k,
+        &v,
+        n_kv_h,
+        n_groups,
+        seq_len,
+        total_seq_len,
+        dqkv,
+        scale,
+        None,
+    );
+    let mut expected = Tensor::<f32>::default(&vec![seq_len, n_q_h * dqkv]);
+    let mut att_scores = Tensor::<f32>::default(&vec![n_q_h, 1, seq_len, total_seq_len]);
+    self_attention(
+        &mut expected,
+        &mut att_scores,
+        &q,
+        &repeat(&k),
+        &repeat(&v),
+        n_q_h,
+        1,
+        seq_len,
+        total_seq_len,
+        dqkv,
+        scale,
+        None,
+    );
+    assert_eq!(out.data(), expected.data());
+}
+
+// Times a prefill attention both ways and prints the temporaries each one allocates
+// cargo test --release bench_fused_attention -- --ignored --nocapture
+#[test]
+#[ignore]
+fn bench_fused_attention() {
+    let (seq_len, n_kv_h, n_groups, dqkv) = (2048, 4, 2, 16);
+    let n_q_h = n_kv_h * n_groups;
+    let q = Tensor::<f32>::random(&vec![seq_len, n_q_h * dqkv]);
+    let k = Tensor::<f32>::random(&vec![seq_len, n_kv_h * dqkv]);
+    let v = Tensor::<f32>::random(&vec![seq_len, n_kv_h * dqkv]);
+    let mib = |floats: usize| (floats * std::mem::size_of::<f32>()) as f64 / (1 << 20) as f64;
+
+    let start = std::time::Instant::now();
+    let mut hidden_states = Tensor::<f32>::default(&vec![seq_len, n_q_h * dqkv]);
+    let mut att_scores = Tensor::<f32>::default(&vec![n_kv_h, n_groups, seq_len, seq_len]);
+    self_attention(
+        &mut hidden_states,
+        &mut att_scores,
+        &q,
+        &k,
+        &v,
+        n_kv_h,
+        n_groups,
+        seq_len,
+        seq_len,
+        dqkv,
+        1. / (dqkv as f32).sqrt(),
+        None,
+    );
+    // scores plus the head-major copies of q, k, v and the output
+    let three_step = att_scores.size() + 2 * q.size() + 2 * k.size();
+    println!(
+        "three-step attention: {:.3}s, {:.1} MiB of temporaries",
+        start.elapsed().as_secs_f64(),
+        mib(three_step)
+    );
+
+    let start = std::time::Instant::now();
+    let mut out = Tensor::<f32>::default(&vec![seq_len, n_q_h, dqkv]);
+    OP::attention(
+        &mut out,
+        &q.slice(0, &vec![seq_len, n_q_h, dqkv]),
+        &k.slice(0, &vec![seq_len, n_kv_h, dqkv]),
+        &v.slice(0, &vec![seq_len, n_kv_h, dqkv]),
+        1. / (dqkv as f32).sqrt(),
+    );
+    println!(
+        "fused attention: {:.3}s, no temporaries besides the {:.1} MiB output",
+        start.elapsed().as_secs_f64(),
+        mib(out.size())
+    );
+}
+
+// cargo test --release bench_decode -- --ignored --nocapture
+#[test]
+#[ignore]
+fn bench_decode() {
+    let model = story_model();
+    let mut cache = model.new_cache();
+    model
+        .forward(&Tensor::<u32>::new(vec![1], &vec![1]), &mut cache)
+        .unwrap();
+    let steps = 256;
+    let start = std::time::Instant::now();
+    for i in 0..steps {
+        model
+            .forward(
+                &Tensor::<u32>::new(vec![i as u32 + 2], &vec![1]),
+                &mut cache,
+            )
+            .unwrap();
+    }
+    let secs = start.elapsed().as_secs_f64();
+    println!(
+        "decode ({} matmul): {steps} tokens in {secs:.3}s, {:.1} tokens/s",
+        OP::MATMUL_BACKEND,
+        steps as f64 / secs
+    );
+}
+
+// cargo test --release bench_greedy_generate -- --ignored --nocapture
+#[test]
+#[ignore]
+fn bench_greedy_generate() {
+    let model = story_model();
+    let config = GenerationConfig {
+        banned_tokens: model.eos_token_ids.to_vec(),
+        ..greedy(500)
+    };
+    let start = std::time::Instant::now();
+    let tokens = model.generate(&[1], &config).unwrap();
+    let secs = start.elapsed().as_secs_f64();
+    println!(
+        "greedy generate: {} tokens in {secs:.3}s, {:.1} tokens/s",
+        tokens.len(),
+        tokens.len() as f64 / secs
+    );
+}
+
+#[test]
+fn test_paged_cache() {
+    let model = story_model();
+    let pool = PagePool::new(16, model.n_kv_h * model.dqkv);
+    let paged = story_model().with_page_pool(pool.clone());
+    let prompt = [1, 300, 25, 700, 40, 26, 410];
+    let config = GenerationConfig {
+        max_len: 40,
+        seed: Some(3),
+        ..Default::default()
+    };
+    assert_eq!(
+        paged.generate(&prompt, &config).unwrap(),
+        model.generate(&prompt, &config).unwrap()
+    );
+    // the sequence of 47 entries took 3 pages of each layer, all back in the pool now
+    assert_eq!((pool.allocated(), pool.in_use()), (3 * model.n_layers, 0));
+    // and evicting moves the entries within the pages the same way
+    let config = GenerationConfig {
+        attention_sink: Some(AttentionSink {
+            n_sink: 4,
+            window: 20,
+            absolute_positions: false,
+        }),
+        ..config
+    };
+    assert_eq!(
+        paged.generate(&prompt, &config).unwrap(),
+        model.generate(&prompt, &config).unwrap()
+    );
+    assert_eq!((pool.allocated(), pool.in_use()), (3 * model.n_layers, 0));
+    let mut cache = paged.new_cache();
+    paged
+        .forward(
+            &Tensor::new(prompt.to_vec(), &vec![prompt.len()]),
+            &mut cache,
+        )
+        .unwrap();
+    assert_eq!(cache.memory_bytes(), model.n_layers * pool.page_bytes());
+}
+
+#[test]
+fn test_prefix_cache() {
+    use crate::backend::CountingBackend;
+    let model = story_model();
+    let counting = Arc::new(CountingBackend::default());
+    let counted = story_model().with_backend(counting.clone());
+    let config = GenerationConfig {
+        max_len: 12,
+        seed: Some(4),
+        ..Default::default()
+    };
+    let mut history = vec![1, 300, 25, 700, 40, 26, 410];
+    let mut cache = counted.new_cache();
+    let first = counted.generate_in(&mut cache, &history, &config).unwrap();
+    // the next request repeats the whole chat so far and adds a turn
+    history.extend_from_slice(&first);
+    history.extend_from_slice(&[13, 26, 300]);
+    counting.gathered.lock().unwrap().clear();
+    let (second, stats) = counted
+        .generate_in_with_stats(&mut cache, &history, &config)
+        .unwrap();
+    // the cache held all of it but the last generated token, only the rest is fed
+    let new = history.len() - (7 + first.len() - 1);
+    assert_eq!(stats.cached_prompt_tokens, history.len() - new);
+    assert_eq!(
+        (stats.prefill_tokens, counting.gathered.lock().unwrap()[0]),
+        (new, new)
+    );
+    assert_eq!(second, model.generate(&history, &config).unwrap());
+    // a request that differs from the cache partway keeps the entries up to there
+    history.truncate(5);
+    history.push(13);
+    let (third, stats) = counted
+        .generate_in_with_stats(&mut cache, &history, &config)
+        .unwrap();
+    assert_eq!((stats.cached_prompt_tokens, stats.prefill_tokens), (5, 1));
+    assert_eq!(third, model.generate(&history, &config).unwrap());
+    // and without prefix_cache everything is fed again
+    let config = GenerationConfig {
+        prefix_cache: false,
+        ..config
+    };
+    counting.gathered.lock().unwrap().clear();
+    let (again, stats) = counted
+        .generate_in_with_stats(&mut cache, &history, &config)
+        .unwrap();
+    assert_eq!(stats.cached_prompt_tokens, 0);
+    assert_eq!(counting.gathered.lock().unwrap()[0], history.len());
+    assert_eq!(again, third);
+}
+
+#[test]
+fn test_reused_cache() {
+    let model = story_model();
+    let config = GenerationConfig {
+        max_len: 16,
+        seed: Some(3),
+        ..Default::default()
+    };
+    let prompts = [
+        vec![1, 300, 25, 700],
+        vec![1, 410, 26],
+        vec![1, 300, 25, 13, 40],
+    ];
+    let mut cache = model.new_cache();
+    let data = cache.k_cache(0, 0).data().as_ptr();
+    // back to back generations in one cache, cleared in between, are those of new ones
+    for prompt in &prompts {
+        cache.clear();
+        let tokens = model.generate_in(&mut cache, prompt, &config).unwrap();
+        assert_eq!(tokens, model.generate(prompt, &config).unwrap());
+    }
+    // streamed ones too, which give the cache back when done
+    for prompt in &prompts {
+        cache.clear();
+        let stream = model
+            .generate_stream_in(&mut cache, prompt, &config)
+            .unwrap();
+        let tokens: Vec<u32> = stream.map(Result::unwrap).collect();
+        assert_eq!(tokens, model.generate(prompt, &config).unwrap());
+        assert_eq!(cache.tokens()[..prompt.len()], prompt[..]);
+    }
+    assert_eq!(cache.k_cache(0, 0).data().as_ptr(), data);
+}
+
+#[test]
+fn test_forward_batch() {
+    let model = story_model();
+    let prompts: [&[u32]; 2] = [&[1, 300, 25, 700, 40, 26, 410], &[1, 410, 26]];
+    let forward_alone = |prompt: &[u32], cache: &mut KVCache<f32>| {
+        let input = Tensor::<u32>::new(prompt.to_vec(), &vec![prompt.len()]);
+        model.forward(&input, cache).unwrap()
+    };
+    let mut alone: Vec<KVCache<f32>> = prompts.iter().map(|_| model.new_cache()).collect();
+    let mut batched: Vec<KVCache<f32>> = prompts.iter().map(|_| model.new_cache()).collect();
+    let expected: Vec<_> = prompts
+        .iter()
+        .zip(&mut alone)
+        .map(|(prompt, cache)| forward_alone(prompt, cache))
+        .collect();
+    // the sequences attend to their own entries only, at their own positions
+    let mut caches: Vec<&mut KVCache<f32>> = batched.iter_mut().collect();
+    let logits = model.forward_batch(&prompts, &mut caches).unwrap();
+    for (logits, expected) in logits.iter().zip(&expected) {
+        assert!(logits.max_abs_diff(expected) < 1e-5);
+    }
+    // and decode on from caches of different lengths
+    let next: [&[u32]; 2] = [&[13], &[300, 25]];
+    let logits = model.forward_batch(&next, &mut caches).unwrap();
+    for (i, logits) in logits.iter().enumerate() {
+        let expected = forward_alone(next[i], &mut alone[i]);
+        assert!(logits.max_abs_diff(&expected) < 1e-5);
+        assert_eq!(caches[i].tokens(), alone[i].tokens());
+    }
+}
+
+#[test]
+fn test_forward_batch_overflow() {
+    let mut model = story_model();
+    // dynamic NTK past 8 positions, so that the batch runs one forward per sequence
+    let scaling = OP::RopeScaling::DynamicNtk {
+        factor: 2.,
+        max_position_embeddings: 8,
+    };
+    model.rope = OP::RopeCache::new(model.max_seq_len, model.dqkv, model.rope.theta(), scaling);
+    let prompts: [&[u32]; 2] = [&[1, 300, 25, 700, 40, 26, 410, 13, 300, 25], &[1, 300, 25]];
+    let mut roomy = model.new_cache();
+    let mut small = KVCache::new(model.n_layers, 2, model.n_kv_h * model.dqkv, 0);
+    let result = model.forward_batch(&prompts, &mut [&mut roomy, &mut small]);
+    assert!(matches!(
+        result,
+        Err(OP::OperatorError::ContextOverflow { needed: 3, max: 2 })
+    ));
+    // the first sequence did not run either
+    assert_eq!((roomy.len(), roomy.tokens().len()), (0, 0));
+}
+
+#[test]
+fn test_head_major_cache() {
+    let model = story_model();
+    let heads = story_model().with_kv_layout(KvLayout::HeadMajor);
+    let (mut cache, mut head_cache) = (model.new_cache(), heads.new_cache());
+    let prompt = Tensor::<u32>::new(vec![1, 300, 25, 700, 40, 26, 410], &vec![7]);
+    // the logits cross zero, and the per-head matmuls sum in another order than the batched
+    // ones of the simd kernels, so they are compared absolutely
+    let mut logits = model.forward(&prompt, &mut cache).unwrap();
+    let head_logits = heads.forward(&prompt, &mut head_cache).unwrap();
+    assert!(head_logits.max_abs_diff(&logits) < 1e-3);
+    // decoding reads the history of every head in place, and gives the logits of the
+    // token-major layout
+    for _ in 0..24 {
+        let next = Tensor::<u32>::new(vec![OP::argmax(&logits).data()[0]], &vec![1]);
+        logits = model.forward(&next, &mut cache).unwrap();
+        let head_logits = heads.forward(&next, &mut head_cache).unwrap();
+        assert!(head_logits.max_abs_diff(&logits) < 1e-3);
+    }
+    // truncating goes back to the same entries
+    cache.truncate(10);
+    head_cache.truncate(10);
+    let next = Tensor::<u32>::new(vec![13], &vec![1]);
+    let logits = model.forward(&next, &mut cache).unwrap();
+    let head_logits = heads.forward(&next, &mut head_cache).unwrap();
+    assert!(head_logits.max_abs_diff(&logits) < 1e-3);
+    // and evicting for a sink too
+    let config = GenerationConfig {
+        max_len: 40,
+        attention_sink: Some(AttentionSink {
+            n_sink: 4,
+            window: 20,
+            absolute_positions: false,
+        }),
+        seed: Some(2),
+        ..Default::default()
+    };
+    assert_eq!(
+        heads.generate(prompt.data(), &config).unwrap(),
+        model.generate(prompt.data(), &config).unwrap()
+    );
+}
+
+#[cfg(feature = "parallel")]
+#[test]
+fn test_attend_heads_parallel() {
+    // 8 query heads on 2 kv heads, decoding 3 tokens after 9 cached ones, both layouts
+    let (seq_len, total_seq_len, n_kv_h, n_groups, dqkv) = (3, 12, 2, 4, 8);
+    let n_q_h = n_kv_h * n_groups;
+    let q_heads = Tensor::<f32>::random(&vec![n_q_h, seq_len, dqkv]);
+    let k_heads = Tensor::<f32>::random(&vec![n_kv_h, total_seq_len, dqkv]);
+    let v_heads = Tensor::<f32>::random(&vec![n_kv_h, total_seq_len, dqkv]);
+    for head_major in [false, true] {
+        let outputs: Vec<_> = [false, true]
+            .map(|parallel| {
+                let mut scores = Tensor::<f32>::default(&vec![n_q_h, seq_len, total_seq_len]);
+                let mut out = Tensor::<f32>::default(&vec![n_q_h, seq_len, dqkv]);
+                attend_heads(
+                    &CpuBackend,
+                    &mut scores,
+                    &mut out,
+                    &q_heads,
+                    &k_heads,
+                    &v_heads,
+                    head_major,
+                    n_groups,
+                    dqkv,
+                    0.3,
+                    Some(20.),
+                    None,
+                    parallel,
+                );
+                (scores, out)
+            })
+            .into();
+        // every head does the same arithmetic on whichever thread it runs
+        assert_eq!(outputs[0].0.data(), outputs[1].0.data());
+        assert_eq!(outputs[0].1.data(), outputs[1].1.data());
+    }
+}
+
+// Times one decode step of 32 query heads on 8 kv heads over 1024 cached tokens, the heads
+// one after another and on the rayon pool
+// cargo test --release --features parallel bench_parallel_attention -- --ignored --nocapture
+#[test]
+#[ignore]
+fn bench_parallel_attention() {
+    let (total_seq_len, n_kv_h, n_groups, dqkv) = (1024, 8, 4, 64);
+    let n_q_h = n_kv_h * n_groups;
+    let q_heads = Tensor::<f32>::random(&vec![n_q_h, 1, dqkv]);
+    let k_heads = Tensor::<f32>::random(&vec![n_kv_h, total_seq_len, dqkv]);
+    let v_heads = Tensor::<f32>::random(&vec![n_kv_h, dqkv, total_seq_len]);
+    let steps = 100;
+    for parallel in [false, true] {
+        let mut scores = Tensor::<f32>::default(&vec![n_q_h, 1, total_seq_len]);
+        let mut out = Tensor::<f32>::default(&vec![n_q_h, 1, dqkv]);
+        let start = std::time::Instant::now();
+        for _ in 0..steps {
+            attend_heads(
+                &CpuBackend,
+                &mut scores,
+                &mut out,
+                &q_heads,
+                &k_heads,
+                &v_heads,
+                false,
+                n_groups,
+                dqkv,
+                1. / (dqkv as f32).sqrt(),
+                None,
+                None,
+                parallel,
+            );
+        }
+        println!(
+            "{n_q_h} heads, parallel {parallel}: {:.1} us per step",
+            start.elapsed().as_secs_f64() * 1e6 / steps as f64
+        );
+    }
+}
+
+// Times decode attention over a 2048-token cache of either layout
+// cargo test --release bench_head_major_attention -- --ignored --nocapture
+#[test]
+#[ignore]
+fn bench_head_major_attention() {
+    let (total_seq_len, n_kv_h, n_groups, dqkv) = (2048, 4, 2, 16);
+    let n_q_h = n_kv_h * n_groups;
+    let q = Tensor::<f32>::random(&vec![1, n_q_h * dqkv]);
+    let k = Tensor::<f32>::random(&vec![total_seq_len, n_kv_h * dqkv]);
+    let v = Tensor::<f32>::random(&vec![total_seq_len, n_kv_h * dqkv]);
+    // the same entries laid out head-major
+    let to_heads = |t: &Tensor<f32>| {
+        let mut heads = Tensor::<f32>::default(&vec![n_kv_h, total_seq_len, dqkv]);
+        let data = unsafe { heads.data_mut() };
+        for (j, row) in t.data().chunks(n_kv_h * dqkv).enumerate() {
+            for (h, x) in row.chunks(dqkv).enumerate() {
+                data[(h * total_seq_len + j) * dqkv..][..dqkv].copy_from_slice(x);
+            }
+        }
+        heads
+    };
+    let (k_heads, v_heads) = (to_heads(&k), to_heads(&v));
+    let steps = 200;
+    let mut outputs = vec![];
+    for (layout, k, v) in [("token-major", &k, &v), ("head-major", &k_heads, &v_heads)] {
+        let mut hidden_states = Tensor::<f32>::default(&vec![1, n_q_h * dqkv]);
+        let mut att_scores = Tensor::<f32>::default(&vec![n_kv_h, n_groups, 1, total_seq_len]);
+        let mut buffers = AttentionBuffers::default();
+        let start = std::time::Instant::now();
+        for _ in 0..steps {
+            self_attention_on(
+                &CpuBackend,
+                &mut buffers,
+                &mut hidden_states,
+                &mut att_scores,
+                &q,
+                match layout {
+                    "head-major" => KvSource::HeadMajor(k, v),
+                    _ => KvSource::Rows(k, v),
+                },
+                n_kv_h,
+                n_groups,
+                1,
+                total_seq_len,
+                total_seq_len,
+                dqkv,
+                1. / (dqkv as f32).sqrt(),
+                None,
+                None,
+            );
+        }
+        let secs = start.elapsed().as_secs_f64();
+        println!(
+            "{layout} decode attention over {total_seq_len} tokens: {:.1} us per step",
+            secs * 1e6 / steps as f64
+        );
+        outputs.push(hidden_states);
+    }
+    assert!(outputs[1].close_to(&outputs[0], 1e-4));
+}
+
+#[test]
+fn test_forward_allocations() {
+    let mut model = story_model();
+    let allocations = || ALLOCATIONS.with(|n| n.get());
+    let mut logits = Tensor::<f32>::default(&[1, model.vocab]);
+    let prompt = Tensor::<u32>::new(vec![1, 300, 25, 700], &[4]);
+    // the weights of rms_norm as they are, and with the unit offset of Gemma
+    for unit_offset in [false, true] {
+        model.norm_unit_offset = unit_offset;
+        let mut cache = model.new_cache();
+        model
+            .forward_into(&prompt, &mut cache, None, &mut logits)
+            .unwrap();
+        // the first step grows the workspace to what decoding needs
+        let mut next = Tensor::<u32>::new(vec![13], &[1]);
+        model
+            .forward_into(&next, &mut cache, None, &mut logits)
+            .unwrap();
+        let before = allocations();
+        for _ in 0..32 {
+            let token = OP::argmax_row(logits.data());
+            unsafe { next.data_mut()[0] = token };
+            model
+                .forward_into(&next, &mut cache, None, &mut logits)
+                .unwrap();
+        }
+        // matrixmultiply packs the operands of gemm in buffers of its own, and rayon can
+        // allocate handing out work
+        if cfg!(not(any(feature = "gemm-backend", feature = "parallel"))) {
+            assert_eq!(allocations() - before, 0, "unit offset {unit_offset}");
+        }
+    }
+    // while another call has the workspace, forward works in buffers of its own
+    let busy = model.workspace.lock().unwrap();
+    let mut other = model.new_cache();
+    let mut other_logits = Tensor::<f32>::default(&[1, model.vocab]);
+    model
+        .forward_into(&prompt, &mut other, None, &mut other_logits)
+        .unwrap();
+    drop(busy);
+    let mut again = model.new_cache();
+    model
+        .forward_into(&prompt, &mut again, None, &mut logits)
+        .unwrap();
+    assert_eq!(logits.data(), other_logits.data());
+}
+
+#[test]
+fn test_forward_batch_allocations() {
+    let mut model = story_model();
+    let allocations = || ALLOCATIONS.with(|n| n.get());
+    // what a decode step of two sequences allocates with the layers of the model, and with
+    // only the first of them
+    let mut per_step = vec![];
+    for n_layers in [model.n_layers, 1] {
+        model.n_layers = n_layers;
+        let (mut a, mut b) = (model.new_cache(), model.new_cache());
+        let prompts: [&[u32]; 2] = [&[1, 300, 25, 700], &[1, 410]];
+        model
+            .forward_batch(&prompts, &mut [&mut a, &mut b])
+            .unwrap();
+        model
+            .forward_batch(&[&[13], &[13]], &mut [&mut a, &mut b])
+            .unwrap();
+        let before = allocations();
+        model
+            .forward_batch(&[&[25], &[26]], &mut [&mut a, &mut b])
+            .unwrap();
+        per_step.push(allocations() - before);
+    }
+    // the layers work in the workspace, and only the packed input and the logits returned are
+    // allocated
+    if cfg!(not(any(feature = "gemm-backend", feature = "parallel"))) {
+        assert_eq!(per_step[0], per_step[1]);
+    }
 }
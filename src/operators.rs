@@ -1,255 +1,4208 @@
-use crate::tensor::Tensor;
+use crate::tensor::{unpack_q4, I8Tensor, Q4Tensor, QuantizedTensor, Tensor, Q4_BLOCK, Q8_BLOCK};
+use half::{bf16, f16};
+use smallvec::SmallVec;
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum GatherError {
+    // indices[pos] = index is not a row of a table with `rows` rows
+    IndexOutOfRange { pos: usize, index: u32, rows: usize },
+}
+
+impl std::fmt::Display for GatherError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GatherError::IndexOutOfRange { pos, index, rows } => write!(
+                f,
+                "gather: index {index} at position {pos} is out of range for a table of {rows} rows"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for GatherError {}
+
+// Shape errors reported by the checked_ operators instead of an assertion failure
+#[derive(Clone, Debug, PartialEq)]
+pub enum OperatorError {
+    ShapeMismatch {
+        op: &'static str,
+        expected: Vec<usize>,
+        got: Vec<usize>,
+    },
+    RankMismatch {
+        op: &'static str,
+        expected: usize,
+        got: usize,
+    },
+    Gather(GatherError),
+    // forward of tokens that would take the cache to needed entries, past the max it holds
+    ContextOverflow {
+        needed: usize,
+        max: usize,
+    },
+    // the error of the decoder layer `layer`
+    InLayer {
+        layer: usize,
+        source: Box<OperatorError>,
+    },
+}
+
+impl OperatorError {
+    pub fn in_layer(self, layer: usize) -> Self {
+        OperatorError::InLayer {
+            layer,
+            source: Box::new(self),
+        }
+    }
+}
+
+impl std::fmt::Display for OperatorError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OperatorError::ShapeMismatch { op, expected, got } => {
+                write!(f, "{op}: expected shape {expected:?}, got {got:?}")
+            }
+            OperatorError::RankMismatch { op, expected, got } => {
+                write!(f, "{op}: expected a {expected}D tensor, got {got}D")
+            }
+            OperatorError::Gather(e) => e.fmt(f),
+            OperatorError::ContextOverflow { needed, max } => {
+                write!(f, "{needed} tokens do not fit into a context of {max}")
+            }
+            OperatorError::InLayer { layer, source } => write!(f, "layer {layer}: {source}"),
+        }
+    }
+}
+
+impl std::error::Error for OperatorError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            OperatorError::Gather(e) => Some(e),
+            OperatorError::InLayer { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<GatherError> for OperatorError {
+    fn from(e: GatherError) -> Self {
+        OperatorError::Gather(e)
+    }
+}
+
+fn check_rank(op: &'static str, t: &Tensor<f32>, rank: usize) -> Result<(), OperatorError> {
+    match t.shape().len() {
+        got if got == rank => Ok(()),
+        got => Err(OperatorError::RankMismatch {
+            op,
+            expected: rank,
+            got,
+        }),
+    }
+}
+
+fn check_shape(op: &'static str, expected: &[usize], t: &Tensor<f32>) -> Result<(), OperatorError> {
+    if t.shape() == expected {
+        Ok(())
+    } else {
+        Err(OperatorError::ShapeMismatch {
+            op,
+            expected: expected.to_vec(),
+            got: t.shape().to_vec(),
+        })
+    }
+}
 
 // get (row) vectors from a 2D table given a list of indices 从一个二维表中根据索引列表获取行向量
-pub fn gather(y: &mut Tensor<f32>, indices: &Tensor<u32>, table: &Tensor<f32>) {
+// Every index is checked against the table before anything is copied, an out-of-range one
+// (e.g. an id the vocab does not have) is reported instead of panicking or reading a wrong row.
+// The table may be f16/bf16, only the gathered rows are converted to f32.
+#[allow(unused)]
+pub fn gather<T: Copy + Default + Into<f32>>(
+    y: &mut Tensor<f32>,
+    indices: &Tensor<u32>,
+    table: &Tensor<T>,
+) -> Result<(), GatherError> {
+    gather_scaled(y, indices, table, 1.)
+}
+
+// gather multiplying every copied row by scale on the way, as Gemma does with sqrt(hidden_size).
+// A scale of 1 is the plain copy of gather.
+pub fn gather_scaled<T: Copy + Default + Into<f32>>(
+    y: &mut Tensor<f32>,
+    indices: &Tensor<u32>,
+    table: &Tensor<T>,
+    scale: f32,
+) -> Result<(), GatherError> {
     // y为输出张量，indices为索引列表，table为二维表
-    let length = indices.size();    // 索引列表的长度
-    let table_shape = table.shape();    // 二维表的形状
-    assert!(table_shape.len() == 2);                 // 确保是二维的
-    let dim = table_shape[1];                 // 二维表的列数
-    assert!(y.size() == length * dim);               // 确保输出张量的大小是索引列表长度乘以二维表的列数
-    for i in 0..length {                      // 遍历索引列表，获取对应的行向量
+    let length = indices.size(); // 索引列表的长度
+    let table_shape = table.shape(); // 二维表的形状
+    assert!(table_shape.len() == 2); // 确保是二维的
+    let (rows, dim) = (table_shape[0], table_shape[1]); // 二维表的行数和列数
+    assert!(y.size() == length * dim); // 确保输出张量的大小是索引列表长度乘以二维表的列数
+    if let Some((pos, &index)) = indices
+        .data()
+        .iter()
+        .enumerate()
+        .find(|(_, &index)| index as usize >= rows)
+    {
+        return Err(GatherError::IndexOutOfRange { pos, index, rows });
+    }
+    for i in 0..length {
+        // 遍历索引列表，获取对应的行向量
         let src = &table.data()[indices.data()[i] as usize * dim..][..dim]; // 获取二维表中的一行
-        let dst = &mut unsafe { y.data_mut() }[i * dim..][..dim];       // 获取输出张量中的一行
-        dst.copy_from_slice(src);
+        let dst = &mut unsafe { y.data_mut() }[i * dim..][..dim]; // 获取输出张量中的一行
+        if scale == 1. {
+            dst.iter_mut().zip(src).for_each(|(d, &s)| *d = s.into());
+        } else {
+            dst.iter_mut()
+                .zip(src)
+                .for_each(|(d, &s)| *d = s.into() * scale);
+        }
+    }
+    Ok(())
+}
+
+// Which elements of a head form the rotated pairs, checkpoints use either convention and
+// loading one with the other gives wrong outputs without any error
+#[allow(unused)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RopeLayout {
+    Neox,        // element i with element i + rotary_dim / 2 (Llama, GPT-NeoX)
+    Interleaved, // element 2i with element 2i + 1 (GPT-J)
+}
+
+impl RopeLayout {
+    // indices of pair i within a head
+    #[inline]
+    fn pair(self, i: usize, rotary_dim: usize) -> (usize, usize) {
+        match self {
+            RopeLayout::Neox => (i, i + rotary_dim / 2),
+            RopeLayout::Interleaved => (2 * i, 2 * i + 1),
+        }
+    }
+}
+
+// Context extension applied to the rope frequencies
+#[allow(unused)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RopeScaling {
+    None,
+    // position interpolation: positions are divided by factor, so factor times the trained
+    // context maps onto the trained range of angles
+    Linear {
+        factor: f32,
+    },
+    // dynamic NTK: once a sequence is longer than the trained context theta is raised with
+    // its length, shorter sequences are rotated exactly like unscaled rope
+    DynamicNtk {
+        factor: f32,
+        max_position_embeddings: usize,
+    },
+    // YaRN: high frequencies (many rotations over the original context) are kept, low ones
+    // are interpolated by factor and a linear ramp in between, the two cutoffs being the
+    // pairs that turn beta_fast and beta_slow times over original_max_position_embeddings.
+    // Attention logits are meant to be multiplied by attention_factor^2.
+    Yarn {
+        factor: f32,
+        original_max_position_embeddings: usize,
+        beta_fast: f32,
+        beta_slow: f32,
+        attention_factor: f32,
+    },
+}
+
+impl RopeScaling {
+    // angle of pair i at position pos, pos / theta^(2i / rotary_dim) when unscaled
+    #[inline]
+    fn angle(self, pos: usize, i: usize, rotary_dim: usize, theta: f32) -> f32 {
+        let wavelength = theta.powf((i * 2) as f32 / rotary_dim as f32);
+        match self {
+            // dynamic NTK only changes theta, see effective_theta
+            RopeScaling::None | RopeScaling::DynamicNtk { .. } => pos as f32 / wavelength,
+            RopeScaling::Linear { factor } => pos as f32 / factor / wavelength,
+            RopeScaling::Yarn { .. } => {
+                pos as f32 / wavelength * self.frequency_multiplier(i, rotary_dim, theta)
+            }
+        }
+    }
+
+    // what the frequency of pair i is multiplied by, between 1 (extrapolated as is) and
+    // 1 / factor (interpolated); only YaRN makes this depend on i
+    pub fn frequency_multiplier(self, i: usize, rotary_dim: usize, theta: f32) -> f32 {
+        match self {
+            RopeScaling::None | RopeScaling::DynamicNtk { .. } => 1.,
+            RopeScaling::Linear { factor } => 1. / factor,
+            RopeScaling::Yarn {
+                factor,
+                original_max_position_embeddings,
+                beta_fast,
+                beta_slow,
+                ..
+            } => {
+                let d = rotary_dim as f32;
+                // the pair that completes `rotations` turns over the original context
+                let correction_dim = |rotations: f32| {
+                    let wavelengths = original_max_position_embeddings as f32
+                        / (rotations * 2. * std::f32::consts::PI);
+                    d * wavelengths.ln() / (2. * theta.ln())
+                };
+                let low = correction_dim(beta_fast).floor().max(0.);
+                let high = correction_dim(beta_slow).ceil().min(d - 1.);
+                let high = if low == high { high + 0.001 } else { high };
+                let ramp = ((i as f32 - low) / (high - low)).clamp(0., 1.);
+                ramp / factor + (1. - ramp)
+            }
+        }
+    }
+
+    // scale of q and k implied by the scaling, applied to the attention scores squared
+    pub fn attention_factor(self) -> f32 {
+        match self {
+            RopeScaling::Yarn {
+                attention_factor, ..
+            } => attention_factor,
+            _ => 1.,
+        }
+    }
+
+    // theta to rotate a sequence of seq_len tokens with,
+    // theta * (factor * seq_len / max_position_embeddings - (factor - 1))^(d / (d - 2))
+    // for dynamic NTK past the trained context, theta itself otherwise
+    pub fn effective_theta(self, theta: f32, seq_len: usize, rotary_dim: usize) -> f32 {
+        match self {
+            RopeScaling::DynamicNtk {
+                factor,
+                max_position_embeddings,
+            } if seq_len > max_position_embeddings => {
+                let alpha =
+                    factor * seq_len as f32 / max_position_embeddings as f32 - (factor - 1.);
+                let d = rotary_dim as f32;
+                theta * alpha.powf(d / (d - 2.))
+            }
+            _ => theta,
+        }
+    }
+
+    // how many positions the scaled rope covers for a model trained on max_position_embeddings
+    pub fn max_positions(self, max_position_embeddings: usize) -> usize {
+        match self {
+            RopeScaling::None => max_position_embeddings,
+            RopeScaling::Linear { factor } | RopeScaling::DynamicNtk { factor, .. } => {
+                (factor * max_position_embeddings as f32) as usize
+            }
+            // fine-tunes usually already list the extended context in max_position_embeddings
+            RopeScaling::Yarn {
+                factor,
+                original_max_position_embeddings,
+                ..
+            } => max_position_embeddings
+                .max((factor * original_max_position_embeddings as f32) as usize),
+        }
+    }
+}
+
+// Concatenate inputs along axis into out, all other dimensions have to agree.
+// Every input contributes a block of shape[axis..] elements per index of the leading axes,
+// so axis 0 reduces to one memcpy per input and inner axes interleave the blocks.
+#[allow(unused)]
+pub fn concat(out: &mut Tensor<f32>, inputs: &[&Tensor<f32>], axis: usize) {
+    assert!(!inputs.is_empty());
+    let shape = out.shape().to_vec();
+    assert!(axis < shape.len());
+    let mut concat_len = 0;
+    for x in inputs {
+        let s = x.shape();
+        assert!(
+            s.len() == shape.len(),
+            "rank {} != {}",
+            s.len(),
+            shape.len()
+        );
+        for (i, (a, b)) in s.iter().zip(&shape).enumerate() {
+            assert!(
+                i == axis || a == b,
+                "shape {s:?} does not fit {shape:?} on axis {i}"
+            );
+        }
+        concat_len += s[axis];
+    }
+    assert!(concat_len == shape[axis]);
+
+    let outer: usize = shape[..axis].iter().product();
+    let blocks: Vec<usize> = inputs
+        .iter()
+        .map(|x| x.shape()[axis..].iter().product())
+        .collect();
+    let _out = unsafe { out.data_mut() };
+    let mut dst = 0;
+    for o in 0..outer {
+        for (x, &block) in inputs.iter().zip(&blocks) {
+            _out[dst..][..block].copy_from_slice(&x.data()[o * block..][..block]);
+            dst += block;
+        }
+    }
+}
+
+// (m, n) -> (n, m) as a new contiguous tensor, copied in TRANSPOSE_BLOCK x TRANSPOSE_BLOCK
+// tiles so that neither the reads nor the writes stride through the whole matrix
+#[allow(unused)]
+pub fn transpose2(x: &Tensor<f32>) -> Tensor<f32> {
+    let shape = x.shape();
+    assert!(shape.len() == 2, "transpose2 of a {}D tensor", shape.len());
+    let (m, n) = (shape[0], shape[1]);
+    let src = x.data();
+    let mut data = vec![0.; m * n];
+    for i0 in (0..m).step_by(TRANSPOSE_BLOCK) {
+        for j0 in (0..n).step_by(TRANSPOSE_BLOCK) {
+            for i in i0..(i0 + TRANSPOSE_BLOCK).min(m) {
+                for j in j0..(j0 + TRANSPOSE_BLOCK).min(n) {
+                    data[j * m + i] = src[i * n + j];
+                }
+            }
+        }
+    }
+    Tensor::new(data, &vec![n, m])
+}
+
+const TRANSPOSE_BLOCK: usize = 32;
+
+// A new contiguous tensor with the axes of x reordered, axis i of the result is axis perm[i]
+// of x. Up to 4D, a 2D swap goes through transpose2.
+#[allow(unused)]
+pub fn permute(x: &Tensor<f32>, perm: &[usize]) -> Tensor<f32> {
+    let shape = x.shape();
+    let rank = shape.len();
+    assert!((1..=4).contains(&rank), "permute of a {rank}D tensor");
+    let mut seen = [false; 4];
+    for &p in perm {
+        assert!(
+            perm.len() == rank && p < rank && !seen[p],
+            "{perm:?} is not a permutation of {rank} axes"
+        );
+        seen[p] = true;
+    }
+    if perm == [1, 0] {
+        return transpose2(x);
+    }
+    let mut strides = vec![1; rank];
+    for i in (0..rank - 1).rev() {
+        strides[i] = strides[i + 1] * shape[i + 1];
+    }
+    // the output axes padded to 4 with leading axes of length 1, each with the stride of the
+    // input axis it walks along
+    let mut out_shape = [1; 4];
+    let mut in_strides = [0; 4];
+    for (i, &p) in perm.iter().enumerate() {
+        out_shape[4 - rank + i] = shape[p];
+        in_strides[4 - rank + i] = strides[p];
+    }
+    let src = x.data();
+    let mut data = Vec::with_capacity(x.size());
+    for a in 0..out_shape[0] {
+        for b in 0..out_shape[1] {
+            for c in 0..out_shape[2] {
+                let base = a * in_strides[0] + b * in_strides[1] + c * in_strides[2];
+                data.extend((0..out_shape[3]).map(|d| src[base + d * in_strides[3]]));
+            }
+        }
     }
+    Tensor::new(data, &perm.iter().map(|&p| shape[p]).collect::<Vec<_>>())
 }
 
 // RoPE: Rotary Positional Embedding 实现旋转位置编码
-pub fn rope(y: &mut Tensor<f32>, start_pos: usize, theta: f32) {
-    let shape = y.shape();  // 获取张量的形状
-    assert!(shape.len() == 3);  // 确保是三维的
-    let seq_len = shape[0];    // 序列长度
-    let n_heads = shape[1];   // 头数
-    let d = shape[2];       // 维度
+#[allow(unused)]
+pub fn rope(y: &mut Tensor<f32>, start_pos: usize, theta: f32, layout: RopeLayout) {
+    let d = y.shape()[2];
+    rope_partial(y, start_pos, theta, d, layout, RopeScaling::None);
+}
+
+// rope that only rotates the first rotary_dim elements of every head and passes the rest
+// through (GPT-NeoX, Phi), the pairs and frequencies are formed within that sub-range
+#[allow(unused)]
+#[allow(clippy::too_many_arguments)]
+pub fn rope_partial(
+    y: &mut Tensor<f32>,
+    start_pos: usize,
+    theta: f32,
+    rotary_dim: usize,
+    layout: RopeLayout,
+    scaling: RopeScaling,
+) {
+    let shape = y.shape(); // 获取张量的形状
+    assert!(shape.len() == 3); // 确保是三维的
+    let seq_len = shape[0]; // 序列长度
+    let n_heads = shape[1]; // 头数
+    let d = shape[2]; // 维度
+    assert!(rotary_dim <= d && rotary_dim.is_multiple_of(2));
+    let r = rotary_dim;
     let data = unsafe { y.data_mut() };
-    for tok in 0..seq_len { 
+    for tok in 0..seq_len {
         let pos = start_pos + tok;
         for head in 0..n_heads {
-            for i in 0..d / 2 {
-                let a = data[tok * n_heads * d + head * d + i];
-                let b = data[tok * n_heads * d + head * d + i + d / 2];
-                let freq = pos as f32 / theta.powf((i * 2) as f32 / d as f32);
+            for i in 0..r / 2 {
+                let (ia, ib) = layout.pair(i, r);
+                let a = data[tok * n_heads * d + head * d + ia];
+                let b = data[tok * n_heads * d + head * d + ib];
+                let freq = scaling.angle(pos, i, r, theta);
                 let (sin, cos) = freq.sin_cos();
-                data[tok * n_heads * d + head * d + i] = a * cos - b * sin;
-                data[tok * n_heads * d + head * d + i + d / 2] = b * cos + a * sin;
+                data[tok * n_heads * d + head * d + ia] = a * cos - b * sin;
+                data[tok * n_heads * d + head * d + ib] = b * cos + a * sin;
             }
         }
     }
 }
 
-// softmax(x) = exp(x - max) / sum(exp(x - max))
-// y = softmax(mask(x)) 实现带掩码的 softmax
-pub fn masked_softmax(y: &mut Tensor<f32>) {
-    let ndim = y.shape().len(); // 获取张量的维度
-    assert!(ndim >= 2);
-    let seq_len = y.shape()[ndim - 2];  // 序列长度
-    let total_seq_len = y.shape()[ndim - 1];
-    let batch = y.size() / (seq_len * total_seq_len);   // 批次大小
-    let data = unsafe { y.data_mut() };
-    // 对每个批次的每个序列进行 softmax
-    for b in 0..batch {
-        let base = b * seq_len * total_seq_len;
-        for i in 0..seq_len {
-            let offset = base + i * total_seq_len;
-            let boundary = total_seq_len - seq_len + i + 1;
-
-            let max = data[offset..offset + boundary]
-                .iter()
-                .fold(data[offset], |a, b| a.max(*b));
+// sin/cos of pos / theta^(2i / rotary_dim) (after scaling) for every position and pair i,
+// so that rope_cached does not have to call powf and sin_cos per element. Positions past
+// the precomputed range extend the tables on demand, which is why they sit behind a lock.
+pub struct RopeCache {
+    rotary_dim: usize,
+    theta: f32,
+    scaling: RopeScaling,
+    tables: RwLock<RopeTables>,
+}
 
-            let sum = (0..boundary)
-                .map(|j| {
-                    let e = (data[offset + j] - max).exp();
-                    data[offset + j] = e;
-                    e
-                })
-                .sum::<f32>();
+#[derive(Default)]
+struct RopeTables {
+    sin: Vec<f32>, // (positions, rotary_dim / 2)
+    cos: Vec<f32>, // (positions, rotary_dim / 2)
+}
 
-            (0..boundary).for_each(|j| data[offset + j] /= sum);
-            (boundary..total_seq_len).for_each(|j| data[offset + j] = 0.0);
-        }
+impl RopeCache {
+    pub fn new(max_seq_len: usize, rotary_dim: usize, theta: f32, scaling: RopeScaling) -> Self {
+        assert!(rotary_dim > 0 && rotary_dim.is_multiple_of(2));
+        let cache = RopeCache {
+            rotary_dim,
+            theta,
+            scaling,
+            tables: RwLock::default(),
+        };
+        cache.ensure(max_seq_len);
+        cache
     }
-}
 
-pub fn rms_norm(y: &mut Tensor<f32>, x: &Tensor<f32>, w: &Tensor<f32>, epsilon: f32) {
-    let len = y.size();
-    assert!(len == x.size());
-    assert!(len == w.size());
-    let _y = unsafe { y.data_mut() };
-    let _x = x.data();
-    let _w = w.data();
-    let mut sum = 0.0;
-    for i in 0..len {
-        sum += _x[i] * _x[i];
+    // Tables for a whole sequence of seq_len tokens when the scaling makes them depend on it
+    // (dynamic NTK past the trained context), None when this cache already applies
+    pub fn for_seq_len(&self, seq_len: usize) -> Option<RopeCache> {
+        let theta = self
+            .scaling
+            .effective_theta(self.theta, seq_len, self.rotary_dim);
+        (theta != self.theta)
+            .then(|| RopeCache::new(seq_len, self.rotary_dim, theta, RopeScaling::None))
     }
-    let rms = ((sum / len as f32) + epsilon).sqrt();
-    for i in 0..len {
-        _y[i] = _w[i] * _x[i] / rms;
+
+    pub fn theta(&self) -> f32 {
+        self.theta
     }
-    // todo!("实现 rms_norm，计算前做一些必要的检查会帮助你后续调试")
-}
 
-// y = silu(x) * y
-// hint: this is an element-wise operation
-pub fn swiglu(y: &mut Tensor<f32>, x: &Tensor<f32>) {
-    let len = y.size();
-    assert!(len == x.size());
+    #[allow(unused)]
+    pub fn rotary_dim(&self) -> usize {
+        self.rotary_dim
+    }
 
-    let _y = unsafe { y.data_mut() };
-    let _x = x.data();
+    // copies of the sin and cos tables of the first positions positions,
+    // (positions, rotary_dim / 2) each
+    #[allow(unused)]
+    pub fn tables(&self, positions: usize) -> (Vec<f32>, Vec<f32>) {
+        self.ensure(positions);
+        let tables = self.tables.read().unwrap();
+        let len = positions * (self.rotary_dim / 2);
+        (tables.sin[..len].to_vec(), tables.cos[..len].to_vec())
+    }
 
-    for i in 0..len {
-        _y[i] *= _x[i] / (1. + (-_x[i]).exp());
+    // number of positions currently in the tables
+    pub fn len(&self) -> usize {
+        self.tables.read().unwrap().sin.len() / (self.rotary_dim / 2)
     }
 
-    // todo!("实现 silu，这里给了一些前期准备工作的提示，你可以参考")
+    fn ensure(&self, positions: usize) {
+        if positions > self.len() {
+            // grow at least twofold so that decoding past the end does not extend every step
+            let target = positions.max(2 * self.len());
+            let mut tables = self.tables.write().unwrap();
+            tables.extend(target, self.rotary_dim, self.theta, self.scaling);
+        }
+    }
 }
 
-// C = beta * C + alpha * A @ B^T
-// hint: You don't need to do an explicit transpose of B
-pub fn matmul_transb(c: &mut Tensor<f32>, beta: f32, a: &Tensor<f32>, b: &Tensor<f32>, alpha: f32) {
-    let c_shape = c.shape();
-    let a_shape = a.shape();
-    let b_shape = b.shape();
-    assert!(c_shape.len() == 2);
-    assert!(a_shape.len() == 2);
-    assert!(b_shape.len() == 2);
-    assert!(c_shape[0] == a_shape[0]);
-    assert!(c_shape[1] == b_shape[0]);
-    assert!(a_shape[1] == b_shape[1]);
-    let m = c_shape[0];
-    let n = c_shape[1];
-    let k = a_shape[1];
-    let _c = unsafe { c.data_mut() };
-    let _a = a.data();
-    let _b = b.data();
-    for i in 0..m {
-        for j in 0..n {
-            let mut sum = 0.0;
-            for l in 0..k {
-                sum += _a[i * k + l] * _b[j * k + l];
+impl RopeTables {
+    fn extend(&mut self, positions: usize, d: usize, theta: f32, scaling: RopeScaling) {
+        let half = d / 2;
+        for pos in self.sin.len() / half..positions {
+            for i in 0..half {
+                // same expression as rope_partial so that both give identical results
+                let freq = scaling.angle(pos, i, d, theta);
+                let (sin, cos) = freq.sin_cos();
+                self.sin.push(sin);
+                self.cos.push(cos);
             }
-            _c[i * n + j] = beta * _c[i * n + j] + alpha * sum;
         }
     }
-    // todo!("实现 matmul_transb，计算前做一些必要的检查会帮助你后续调试");
 }
 
-// Dot product of two tensors (treated as vectors)
-#[allow(unused)]
-pub fn dot(x: &Tensor<f32>, y: &Tensor<f32>) -> f32 {
-    let len = x.size();
-    assert!(len == y.size());
-    let x_ = x.data();
-    let y_ = y.data();
-    let mut sum = 0.0;
-    for i in 0..len {
-        sum += x_[i] * y_[i];
+// rope_partial with the sin/cos tables looked up in a RopeCache, which also decides how many
+// elements of every head are rotated
+pub fn rope_cached(y: &mut Tensor<f32>, start_pos: usize, cache: &RopeCache, layout: RopeLayout) {
+    let shape = y.shape();
+    assert!(shape.len() == 3);
+    let seq_len = shape[0];
+    let n_heads = shape[1];
+    let d = shape[2];
+    assert!(cache.rotary_dim <= d);
+    cache.ensure(start_pos + seq_len);
+    let tables = cache.tables.read().unwrap();
+    let half = cache.rotary_dim / 2;
+    let data = unsafe { y.data_mut() };
+    for (tok, tok_data) in data.chunks_mut(n_heads * d).enumerate() {
+        let sin = &tables.sin[(start_pos + tok) * half..][..half];
+        let cos = &tables.cos[(start_pos + tok) * half..][..half];
+        for head in tok_data.chunks_mut(d) {
+            for i in 0..half {
+                let (ia, ib) = layout.pair(i, cache.rotary_dim);
+                let (a, b) = (head[ia], head[ib]);
+                head[ia] = a * cos[i] - b * sin[i];
+                head[ib] = b * cos[i] + a * sin[i];
+            }
+        }
     }
-    sum
 }
 
-// Sample a index from a tensor (treated as a probability vector)
-pub fn random_sample(x: &Tensor<f32>, top_p: f32, top_k: u32, temperature: f32) -> u32 {
-    assert!(x.shape()[x.shape().len() - 1] == x.size());
-    if temperature <= 0. || top_k < 2 || top_p <= 0. {
-        return x
-            .data()
-            .iter()
-            .enumerate()
-            .max_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap())
-            .unwrap()
-            .0 as _;
+// rope_cached returning an error for y that is not (seq, n_heads, d) with d >= rotary_dim
+pub fn checked_rope_cached(
+    y: &mut Tensor<f32>,
+    start_pos: usize,
+    cache: &RopeCache,
+    layout: RopeLayout,
+) -> Result<(), OperatorError> {
+    check_rank("rope", y, 3)?;
+    let shape = y.shape();
+    if shape[2] < cache.rotary_dim {
+        return Err(OperatorError::ShapeMismatch {
+            op: "rope",
+            expected: vec![shape[0], shape[1], cache.rotary_dim],
+            got: shape.to_vec(),
+        });
     }
+    rope_cached(y, start_pos, cache, layout);
+    Ok(())
+}
 
-    #[derive(Clone, Copy, PartialEq, Debug)]
-    struct Probability {
-        val: f32,
-        tok: u32,
+// Turn the rotation of y, rotated by rope_cached with from, into the one to would give,
+// which is how keys already in the kv cache follow a change of the rope tables
+pub fn rope_rerotate(
+    y: &mut Tensor<f32>,
+    start_pos: usize,
+    from: &RopeCache,
+    to: &RopeCache,
+    layout: RopeLayout,
+) {
+    let shape = y.shape();
+    assert!(shape.len() == 3);
+    let seq_len = shape[0];
+    let n_heads = shape[1];
+    let d = shape[2];
+    assert!(from.rotary_dim == to.rotary_dim && to.rotary_dim <= d);
+    from.ensure(start_pos + seq_len);
+    to.ensure(start_pos + seq_len);
+    let (from, to, r) = (
+        from.tables.read().unwrap(),
+        to.tables.read().unwrap(),
+        to.rotary_dim,
+    );
+    let half = r / 2;
+    let data = unsafe { y.data_mut() };
+    for (tok, tok_data) in data.chunks_mut(n_heads * d).enumerate() {
+        let row = (start_pos + tok) * half..(start_pos + tok + 1) * half;
+        let (sin_f, cos_f) = (&from.sin[row.clone()], &from.cos[row.clone()]);
+        let (sin_t, cos_t) = (&to.sin[row.clone()], &to.cos[row]);
+        for head in tok_data.chunks_mut(d) {
+            for i in 0..half {
+                let (ia, ib) = layout.pair(i, r);
+                // undo the old rotation, then apply the new one
+                let a = head[ia] * cos_f[i] + head[ib] * sin_f[i];
+                let b = head[ib] * cos_f[i] - head[ia] * sin_f[i];
+                head[ia] = a * cos_t[i] - b * sin_t[i];
+                head[ib] = b * cos_t[i] + a * sin_t[i];
+            }
+        }
     }
-    impl Eq for Probability {}
-    impl PartialOrd for Probability {
-        #[inline]
-        fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-            Some(self.cmp(other))
+}
+
+// Move keys that rope_cached rotated to positions from_pos.. over to positions to_pos..,
+// which keeps the positions in the kv cache contiguous after entries are evicted from it
+pub fn rope_reposition(
+    y: &mut Tensor<f32>,
+    from_pos: usize,
+    to_pos: usize,
+    cache: &RopeCache,
+    layout: RopeLayout,
+) {
+    let shape = y.shape();
+    assert!(shape.len() == 3);
+    let seq_len = shape[0];
+    let n_heads = shape[1];
+    let d = shape[2];
+    assert!(cache.rotary_dim <= d);
+    cache.ensure(from_pos.max(to_pos) + seq_len);
+    let tables = cache.tables.read().unwrap();
+    let half = cache.rotary_dim / 2;
+    let data = unsafe { y.data_mut() };
+    for (tok, tok_data) in data.chunks_mut(n_heads * d).enumerate() {
+        let (from, to) = ((from_pos + tok) * half, (to_pos + tok) * half);
+        let (sin_f, cos_f) = (&tables.sin[from..][..half], &tables.cos[from..][..half]);
+        let (sin_t, cos_t) = (&tables.sin[to..][..half], &tables.cos[to..][..half]);
+        for head in tok_data.chunks_mut(d) {
+            for i in 0..half {
+                let (ia, ib) = layout.pair(i, cache.rotary_dim);
+                // undo the rotation at the old position, then apply the one at the new
+                let a = head[ia] * cos_f[i] + head[ib] * sin_f[i];
+                let b = head[ib] * cos_f[i] - head[ia] * sin_f[i];
+                head[ia] = a * cos_t[i] - b * sin_t[i];
+                head[ib] = b * cos_t[i] + a * sin_t[i];
+            }
         }
     }
-    impl Ord for Probability {
-        #[inline]
-        fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-            match self.val.total_cmp(&other.val) {
-                std::cmp::Ordering::Equal => self.tok.cmp(&other.tok),
-                ord => ord.reverse(),
+}
+
+// softmax(x) = exp(x - max) / sum(exp(x - max))
+// y = softmax(mask(x)) 实现带掩码的 softmax
+pub fn masked_softmax(y: &mut Tensor<f32>) {
+    masked_softmax_impl(y, None, None);
+}
+
+// Causal masked_softmax where query position p (= total_seq_len - seq_len + i) only sees
+// keys in [p + 1 - window, p], as in Mistral-style sliding-window attention
+#[allow(unused)]
+pub fn masked_softmax_window(y: &mut Tensor<f32>, window: usize) {
+    assert!(window > 0);
+    masked_softmax_impl(y, None, Some((0, window)));
+}
+
+// masked_softmax_window that also keeps the first n_sink keys visible to every query,
+// the attention sinks of StreamingLLM
+pub fn masked_softmax_sink(y: &mut Tensor<f32>, n_sink: usize, window: usize) {
+    assert!(window > 0);
+    masked_softmax_impl(y, None, Some((n_sink, window)));
+}
+
+// An explicit attention mask of shape (seq_len, total_seq_len), shared by every batch, or
+// (batch, seq_len, total_seq_len)
+#[allow(unused)]
+pub enum AttentionMask<'a> {
+    Binary(&'a Tensor<f32>),   // 1 attends, 0 is masked out
+    Additive(&'a Tensor<f32>), // added onto the scores, -inf is masked out
+}
+
+// masked_softmax with an explicit mask instead of the implicit causal one, which lets
+// padding and bidirectional prefixes be expressed. None keeps the causal mask.
+// A row that is masked out entirely comes out as all zeros.
+#[allow(unused)]
+pub fn masked_softmax_with_mask(y: &mut Tensor<f32>, mask: Option<&AttentionMask>) {
+    masked_softmax_impl(y, mask, None);
+}
+
+// window is (n_sink, window): the first n_sink keys plus the window keys up to the query
+fn masked_softmax_impl(
+    y: &mut Tensor<f32>,
+    mask: Option<&AttentionMask>,
+    window: Option<(usize, usize)>,
+) {
+    let ndim = y.shape().len(); // 获取张量的维度
+    assert!(ndim >= 2);
+    let seq_len = y.shape()[ndim - 2]; // 序列长度
+    let total_seq_len = y.shape()[ndim - 1];
+    // the causal boundary total_seq_len - seq_len + i would underflow
+    assert!(
+        seq_len <= total_seq_len,
+        "masked_softmax: seq_len {seq_len} > total_seq_len {total_seq_len}"
+    );
+    let batch = y.size() / (seq_len * total_seq_len); // 批次大小
+    let mask = mask.map(|m| {
+        let t = match m {
+            AttentionMask::Binary(t) | AttentionMask::Additive(t) => t,
+        };
+        assert!(t.size() == seq_len * total_seq_len || t.size() == y.size());
+        (t.data(), matches!(m, AttentionMask::Binary(_)))
+    });
+    let data = unsafe { y.data_mut() };
+    // 对每个批次的每个序列进行 softmax, row r is query i = r % seq_len of batch r / seq_len
+    // so that every row finds its boundary on its own, in whatever order rows are run
+    let row = |(r, row): (usize, &mut [f32])| {
+        let i = r % seq_len;
+        match mask {
+            None => {
+                let boundary = total_seq_len - seq_len + i + 1;
+                match window {
+                    None => softmax_row(row, 0..boundary),
+                    Some((n_sink, w)) => {
+                        let start = boundary.saturating_sub(w);
+                        let sinks = n_sink.min(start);
+                        // the keys between the sinks and the window are masked out
+                        row[sinks..start]
+                            .iter_mut()
+                            .for_each(|v| *v = f32::NEG_INFINITY);
+                        softmax_row(row, 0..boundary);
+                    }
+                }
+            }
+            Some((mask, binary)) => {
+                let offset = r * total_seq_len;
+                let m = &mask[offset % mask.len()..][..total_seq_len];
+                for (v, m) in row.iter_mut().zip(m) {
+                    if binary {
+                        if *m == 0. {
+                            *v = f32::NEG_INFINITY;
+                        }
+                    } else {
+                        *v += m;
+                    }
+                }
+                softmax_row(row, 0..total_seq_len);
             }
         }
+    };
+    let data = &mut data[..batch * seq_len * total_seq_len];
+    #[cfg(feature = "parallel")]
+    {
+        use rayon::prelude::*;
+        data.par_chunks_mut(total_seq_len).enumerate().for_each(row);
     }
-    impl From<(usize, &f32)> for Probability {
-        #[inline]
-        fn from((i, p): (usize, &f32)) -> Self {
-            Self {
-                val: p.clone(),
-                tok: i as _,
+    #[cfg(not(feature = "parallel"))]
+    data.chunks_mut(total_seq_len).enumerate().for_each(row);
+}
+
+// ALiBi slopes, the geometric sequence 2^(-8/n), 2^(-16/n), ... for n heads. When n is
+// not a power of two the first 2^floor(log2 n) heads use it and the rest take every other
+// slope of the sequence for twice as many heads.
+#[allow(unused)]
+pub fn alibi_slopes(n_heads: usize) -> Tensor<f32> {
+    let pow2 = 1 << n_heads.ilog2();
+    let geometric = |n: usize| {
+        let base = 2f32.powf(-8. / n as f32);
+        (1..=n).map(move |i| base.powi(i as i32))
+    };
+    let mut slopes: Vec<f32> = geometric(pow2).collect();
+    slopes.extend(geometric(2 * pow2).step_by(2).take(n_heads - pow2));
+    Tensor::new(slopes, &vec![n_heads])
+}
+
+// scores[h, i, j] -= slope_h * (pos_i - j), where pos_i = total_seq_len - seq_len + i is
+// the absolute position of query i, so the bias grows linearly with the distance to key j
+#[allow(unused)]
+pub fn alibi_bias(scores: &mut Tensor<f32>, slopes: &Tensor<f32>) {
+    let shape = scores.shape();
+    assert!(shape.len() == 3);
+    let (n_heads, seq_len, total_seq_len) = (shape[0], shape[1], shape[2]);
+    assert!(slopes.size() == n_heads);
+    assert!(seq_len <= total_seq_len);
+    let data = unsafe { scores.data_mut() };
+    for (h, slope) in slopes.data().iter().enumerate() {
+        for i in 0..seq_len {
+            let pos = total_seq_len - seq_len + i;
+            let row = &mut data[(h * seq_len + i) * total_seq_len..][..total_seq_len];
+            for (j, v) in row.iter_mut().enumerate() {
+                *v -= slope * (pos as f32 - j as f32);
             }
         }
     }
+}
 
-    // sort
-    let mut logits = x
-        .data()
-        .iter()
-        .enumerate()
-        .map(Probability::from)
-        .collect::<Vec<_>>();
-    logits.sort_unstable();
-    let max = core::mem::replace(&mut logits[0].val, 1.);
-    // softmax & sum
-    for i in 1..logits.len() {
-        logits[i].val = logits[i - 1].val + ((logits[i].val - max) / temperature).exp();
-    }
-    // topk & topp & random
-    let pk = logits[(top_k as usize).min(logits.len()) - 1].val;
-    let pp = logits[logits.len() - 1].val * top_p;
-    let plimit = rand::random::<f32>() * f32::min(pk, pp);
-    // sample
-    logits.iter().find(|p| p.val >= plimit).unwrap().tok
+// y = cap * tanh(y / cap), Gemma-2 style soft-capping of attention scores and logits
+pub fn softcap(y: &mut Tensor<f32>, cap: f32) {
+    assert!(cap > 0.);
+    let _y = unsafe { y.data_mut() };
+    for y_i in _y.iter_mut() {
+        *y_i = cap * (*y_i / cap).tanh();
+    }
 }
 
-// Your implementation should at least pass the following tests:
-#[test]
-fn test_silu() {
-    let mut y = Tensor::<f32>::new(vec![2., 3., 4.], &vec![1, 3]);
-    let x = Tensor::<f32>::new(vec![1., 2., 3.], &vec![1, 3]);
-    swiglu(&mut y, &x);
-    assert!(y.close_to(
-        &Tensor::<f32>::new(vec![1.4621172, 5.2847824, 11.43089], &vec![1, 3]),
-        1e-3
-    ));
+// softmax over the last axis without any mask
+#[allow(unused)]
+pub fn softmax(y: &mut Tensor<f32>) {
+    let ndim = y.shape().len();
+    assert!(ndim >= 1);
+    let n = y.shape()[ndim - 1];
+    let data = unsafe { y.data_mut() };
+    for row in data.chunks_mut(n) {
+        softmax_row(row, 0..n);
+    }
 }
 
-#[test]
-fn test_rms_norm() {
-    let mut y = Tensor::<f32>::new(vec![1., 2., 3., 4.], &vec![2, 2]);
-    let x = Tensor::<f32>::new(vec![1., 2., 3., 4.], &vec![2, 2]);
-    let w = Tensor::<f32>::new(vec![1., 2.], &vec![2]);
-    rms_norm(&mut y, &x, &w, 1e-6);
-    assert!(y.close_to(
-        &Tensor::<f32>::new(
-            vec![0.6324554, 2.5298216, 0.8485281, 2.2627416],
-            &vec![2, 2]
-        ),
-        1e-3
-    ));
+// y = x - max - ln(sum(exp(x - max))) over the last axis, the max-shifted log-sum-exp so
+// that large logits neither overflow nor lose the small log-probabilities
+#[allow(unused)]
+pub fn log_softmax(y: &mut Tensor<f32>) {
+    let n = *y.shape().last().unwrap();
+    let data = unsafe { y.data_mut() };
+    for row in data.chunks_mut(n) {
+        let lse = log_sum_exp(row);
+        row.iter_mut().for_each(|v| *v -= lse);
+    }
 }
 
-#[test]
-fn test_matmul_transb() {
-    let mut c = Tensor::<f32>::new(vec![1., 2., 3., 4.], &vec![2, 2]);
-    let a = Tensor::<f32>::new(vec![1., 2., 3., 4., 5., 6.], &vec![2, 3]);
-    let b = Tensor::<f32>::new(vec![1., 2., 3., 4., 5., 6.], &vec![2, 3]);
-    matmul_transb(&mut c, 1., &a, &b, 1.);
-    assert!(c.close_to(
-        &Tensor::<f32>::new(vec![15., 34., 35., 81.], &vec![2, 2]),
+#[inline]
+fn log_sum_exp(row: &[f32]) -> f32 {
+    let max = row.iter().fold(f32::NEG_INFINITY, |a, b| a.max(*b));
+    max + row.iter().map(|v| (v - max).exp()).sum::<f32>().ln()
+}
+
+// -ln softmax(logits)[target] of every row of (seq_len, vocab) logits
+#[allow(unused)]
+pub fn cross_entropy_per_token(logits: &Tensor<f32>, targets: &Tensor<u32>) -> Vec<f32> {
+    let n = *logits.shape().last().unwrap();
+    assert!(logits.size() == targets.size() * n);
+    logits
+        .data()
+        .chunks(n)
+        .zip(targets.data())
+        .map(|(row, &t)| log_sum_exp(row) - row[t as usize])
+        .collect()
+}
+
+// mean negative log-likelihood of targets, ln of the perplexity
+#[allow(unused)]
+pub fn cross_entropy(logits: &Tensor<f32>, targets: &Tensor<u32>) -> f32 {
+    let nll = cross_entropy_per_token(logits, targets);
+    nll.iter().sum::<f32>() / nll.len() as f32
+}
+
+// Normalize row[visible] in place and zero everything outside of it.
+// A row without anything to normalize (empty range, all -inf, or a NaN that ends up in the
+// denominator) comes out as all zeros rather than NaN, so it cannot poison the layers after.
+fn softmax_row(row: &mut [f32], visible: std::ops::Range<usize>) {
+    let (start, end) = (visible.start, visible.end);
+    let max = row[visible.clone()]
+        .iter()
+        .fold(f32::NEG_INFINITY, |a, b| a.max(*b));
+    if max == f32::NEG_INFINITY {
+        // nothing is visible, exp(-inf - -inf) would fill the row with NaN
+        row.iter_mut().for_each(|v| *v = 0.0);
+        return;
+    }
+
+    let sum = row[visible.clone()]
+        .iter_mut()
+        .map(|v| {
+            *v = (*v - max).exp();
+            *v
+        })
+        .sum::<f32>();
+    if !(sum.is_finite() && sum > 0.) {
+        row.iter_mut().for_each(|v| *v = 0.0);
+        return;
+    }
+
+    row[visible].iter_mut().for_each(|v| *v /= sum);
+    row[..start].iter_mut().for_each(|v| *v = 0.0);
+    row[end..].iter_mut().for_each(|v| *v = 0.0);
+}
+
+// Fused causal attention: out = softmax(q @ k.T * scale) @ v without materializing the
+// scores. q and out are (seq_len, n_q_h, dqkv), k and v are (total_seq_len, n_kv_h, dqkv);
+// query head h reads kv head h / (n_q_h / n_kv_h). Every query row streams over its
+// visible keys keeping a running max and sum (online softmax), rescaling the partial
+// output whenever the max grows, so the only state is the output row itself.
+#[allow(unused)]
+pub fn attention(
+    out: &mut Tensor<f32>,
+    q: &Tensor<f32>,
+    k: &Tensor<f32>,
+    v: &Tensor<f32>,
+    scale: f32,
+) {
+    assert!(q.shape().len() == 3 && k.shape().len() == 3);
+    let (seq_len, n_q_h, dqkv) = (q.shape()[0], q.shape()[1], q.shape()[2]);
+    let (total_seq_len, n_kv_h) = (k.shape()[0], k.shape()[1]);
+    assert!(k.shape()[2] == dqkv);
+    assert!(v.shape() == k.shape());
+    assert!(out.shape() == q.shape());
+    assert!(n_kv_h > 0 && n_q_h.is_multiple_of(n_kv_h));
+    assert!(seq_len <= total_seq_len);
+    let n_groups = n_q_h / n_kv_h;
+
+    let _q = q.data();
+    let _k = k.data();
+    let _v = v.data();
+    let row = |(r, o): (usize, &mut [f32])| {
+        let (i, h) = (r / n_q_h, r % n_q_h);
+        let q_row = &_q[r * dqkv..][..dqkv];
+        let kv_h = h / n_groups;
+        let boundary = total_seq_len - seq_len + i + 1;
+        let mut max = f32::NEG_INFINITY;
+        let mut sum = 0f32;
+        o.iter_mut().for_each(|x| *x = 0.);
+        for j in 0..boundary {
+            let kv = (j * n_kv_h + kv_h) * dqkv;
+            let s = dot_unrolled(q_row, &_k[kv..][..dqkv]) * scale;
+            if s > max {
+                // exp(-inf) == 0 on the first key, where there is nothing to rescale
+                let correction = (max - s).exp();
+                sum *= correction;
+                o.iter_mut().for_each(|x| *x *= correction);
+                max = s;
+            }
+            let p = (s - max).exp();
+            sum += p;
+            o.iter_mut()
+                .zip(&_v[kv..][..dqkv])
+                .for_each(|(x, v)| *x += p * v);
+        }
+        o.iter_mut().for_each(|x| *x /= sum);
+    };
+    let _out = unsafe { out.data_mut() };
+    #[cfg(feature = "parallel")]
+    {
+        use rayon::prelude::*;
+        _out.par_chunks_mut(dqkv).enumerate().for_each(row);
+    }
+    #[cfg(not(feature = "parallel"))]
+    _out.chunks_mut(dqkv).enumerate().for_each(row);
+}
+
+#[allow(unused)]
+pub fn rms_norm(y: &mut Tensor<f32>, x: &Tensor<f32>, w: &Tensor<f32>, epsilon: f32) {
+    rms_norm_offset(y, x, w, epsilon, 0.);
+}
+
+// Gemma stores its norm weights as deltas: y = x / sqrt(mean(x^2) + epsilon) * (1 + w),
+// epsilon inside the sqrt as in the reference implementation
+#[allow(unused)]
+pub fn rms_norm_gemma(y: &mut Tensor<f32>, x: &Tensor<f32>, w: &Tensor<f32>, epsilon: f32) {
+    rms_norm_offset(y, x, w, epsilon, 1.);
+}
+
+// rms_norm / rms_norm_gemma (unit_offset) returning an error on mismatched shapes
+pub fn checked_rms_norm(
+    y: &mut Tensor<f32>,
+    x: &Tensor<f32>,
+    w: &Tensor<f32>,
+    epsilon: f32,
+    unit_offset: bool,
+) -> Result<(), OperatorError> {
+    // y only needs as many elements as x, the final norm writes a (1, d) row from a (d,) one
+    if y.size() != x.size() {
+        return Err(OperatorError::ShapeMismatch {
+            op: "rms_norm",
+            expected: x.shape().to_vec(),
+            got: y.shape().to_vec(),
+        });
+    }
+    check_shape("rms_norm", &x.shape()[x.shape().len().max(1) - 1..], w)?;
+    rms_norm_offset(y, x, w, epsilon, if unit_offset { 1. } else { 0. });
+    Ok(())
+}
+
+fn rms_norm_offset(
+    y: &mut Tensor<f32>,
+    x: &Tensor<f32>,
+    w: &Tensor<f32>,
+    epsilon: f32,
+    offset: f32,
+) {
+    let len = y.size();
+    assert!(len == x.size());
+    let n = w.size(); // normalize over the last axis, one vector of length n at a time
+    assert!(x.shape().last() == Some(&n) && len.is_multiple_of(n));
+    let _y = unsafe { y.data_mut() };
+    let _x = x.data();
+    let _w = w.data();
+    let row = |(y_row, x_row): (&mut [f32], &[f32])| {
+        let sum = sum_squares(x_row);
+        let rms = ((sum / n as f32) + epsilon).sqrt();
+        for ((y_i, x_i), w_i) in y_row.iter_mut().zip(x_row).zip(_w) {
+            *y_i = (offset + w_i) * x_i / rms;
+        }
+    };
+    // rows go to the global rayon pool, the same one the matmuls use
+    #[cfg(feature = "parallel")]
+    {
+        use rayon::prelude::*;
+        _y.par_chunks_mut(n).zip(_x.par_chunks(n)).for_each(row);
+    }
+    #[cfg(not(feature = "parallel"))]
+    _y.chunks_mut(n).zip(_x.chunks(n)).for_each(row);
+}
+
+// In-place rms_norm of every (token, head) vector of a (seq, n_heads, d_head) tensor with a
+// weight of length d_head shared by all heads, used on q and k before rope (Qwen3)
+pub fn qk_rms_norm(y: &mut Tensor<f32>, w: &Tensor<f32>, epsilon: f32) {
+    let d_head = *y.shape().last().unwrap();
+    assert!(w.size() == d_head);
+    let _y = unsafe { y.data_mut() };
+    let _w = w.data();
+    for row in _y.chunks_mut(d_head) {
+        let sum = sum_squares(row);
+        let rms = ((sum / d_head as f32) + epsilon).sqrt();
+        for (y_i, w_i) in row.iter_mut().zip(_w) {
+            *y_i = w_i * *y_i / rms;
+        }
+    }
+}
+
+pub fn checked_qk_rms_norm(
+    y: &mut Tensor<f32>,
+    w: &Tensor<f32>,
+    epsilon: f32,
+) -> Result<(), OperatorError> {
+    check_rank("qk_rms_norm", y, 3)?;
+    check_shape("qk_rms_norm", &y.shape()[2..], w)?;
+    qk_rms_norm(y, w, epsilon);
+    Ok(())
+}
+
+// y = (x - mean) / sqrt(var + epsilon) * w + b, over the last axis like rms_norm
+#[allow(unused)]
+pub fn layer_norm(
+    y: &mut Tensor<f32>,
+    x: &Tensor<f32>,
+    w: &Tensor<f32>,
+    b: &Tensor<f32>,
+    epsilon: f32,
+) {
+    let len = y.size();
+    assert!(len == x.size());
+    let n = w.size();
+    assert!(n == b.size());
+    assert!(len.is_multiple_of(n));
+    let _y = unsafe { y.data_mut() };
+    let _x = x.data();
+    let _w = w.data();
+    let _b = b.data();
+    for (y_row, x_row) in _y.chunks_mut(n).zip(_x.chunks(n)) {
+        let mean = x_row.iter().sum::<f32>() / n as f32;
+        let var = x_row.iter().map(|v| (v - mean) * (v - mean)).sum::<f32>() / n as f32;
+        let std = (var + epsilon).sqrt();
+        for (((y_i, x_i), w_i), b_i) in y_row.iter_mut().zip(x_row).zip(_w).zip(_b) {
+            *y_i = (x_i - mean) / std * w_i + b_i;
+        }
+    }
+}
+
+// Elements per rayon task of the parallel element-wise operators, enough to amortize a task
+#[cfg(feature = "parallel")]
+const ELEMENTWISE_CHUNK: usize = 16384;
+
+// y = silu(x) * y
+// hint: this is an element-wise operation
+pub fn swiglu(y: &mut Tensor<f32>, x: &Tensor<f32>) {
+    let len = y.size();
+    assert!(len == x.size());
+
+    let _y = unsafe { y.data_mut() };
+    let _x = x.data();
+
+    let chunk = |(y_chunk, x_chunk): (&mut [f32], &[f32])| {
+        for (y_i, x_i) in y_chunk.iter_mut().zip(x_chunk) {
+            *y_i *= silu_scalar(*x_i);
+        }
+    };
+    #[cfg(feature = "parallel")]
+    {
+        use rayon::prelude::*;
+        _y.par_chunks_mut(ELEMENTWISE_CHUNK)
+            .zip(_x.par_chunks(ELEMENTWISE_CHUNK))
+            .for_each(chunk);
+    }
+    #[cfg(not(feature = "parallel"))]
+    chunk((_y, _x));
+}
+
+// y = silu(y) = y / (1 + e^-y), in place
+#[allow(unused)]
+pub fn silu(y: &mut Tensor<f32>) {
+    let _y = unsafe { y.data_mut() };
+    for y_i in _y.iter_mut() {
+        *y_i = silu_scalar(*y_i);
+    }
+}
+
+// for very negative x e^-x overflows to inf and the result is a clean (negative) zero
+#[inline]
+fn silu_scalar(x: f32) -> f32 {
+    x / (1. + (-x).exp())
+}
+
+// y += x, element-wise
+#[allow(unused)]
+pub fn add(y: &mut Tensor<f32>, x: &Tensor<f32>) {
+    add_scaled(y, x, 1.);
+}
+
+// y += alpha * x, element-wise
+#[allow(unused)]
+pub fn add_scaled(y: &mut Tensor<f32>, x: &Tensor<f32>, alpha: f32) {
+    let len = y.size();
+    assert!(len == x.size());
+    let _y = unsafe { y.data_mut() };
+    let _x = x.data();
+    for (y_i, x_i) in _y.iter_mut().zip(_x) {
+        *y_i += alpha * x_i;
+    }
+}
+
+// y *= x, element-wise (Hadamard product)
+#[allow(unused)]
+pub fn mul(y: &mut Tensor<f32>, x: &Tensor<f32>) {
+    let len = y.size();
+    assert!(len == x.size());
+    let _y = unsafe { y.data_mut() };
+    let _x = x.data();
+    for (y_i, x_i) in _y.iter_mut().zip(_x) {
+        *y_i *= x_i;
+    }
+}
+
+// y *= s
+pub fn scale(y: &mut Tensor<f32>, s: f32) {
+    let _y = unsafe { y.data_mut() };
+    for y_i in _y.iter_mut() {
+        *y_i *= s;
+    }
+}
+
+// Which implementation matmul_transb runs on, reported by the benchmarks
+#[allow(unused)]
+pub const MATMUL_BACKEND: &str = if cfg!(feature = "gemm-backend") {
+    "matrixmultiply"
+} else {
+    "native"
+};
+
+// Tile sizes of the blocked matmul_transb kernel
+const MM_TILE_M: usize = 64;
+const MM_TILE_N: usize = 64;
+const MM_TILE_K: usize = 256;
+
+// Check the shapes of C = A @ B^T and return (m, n, k)
+fn matmul_transb_dims(c: &Tensor<f32>, a: &Tensor<f32>, b: &Tensor<f32>) -> (usize, usize, usize) {
+    let c_shape = c.shape();
+    let a_shape = a.shape();
+    let b_shape = b.shape();
+    assert!(c_shape.len() == 2);
+    assert!(a_shape.len() == 2);
+    assert!(b_shape.len() == 2);
+    assert!(c_shape[0] == a_shape[0]);
+    assert!(c_shape[1] == b_shape[0]);
+    assert!(a_shape[1] == b_shape[1]);
+    (c_shape[0], c_shape[1], a_shape[1])
+}
+
+// Activation applied to the gate projection in the MLP
+#[allow(unused)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Activation {
+    Silu,     // x * sigmoid(x), the Llama SwiGLU
+    Gelu,     // exact GELU, x * Phi(x)
+    GeluTanh, // tanh approximation of GELU
+    Relu,     // max(0, x)
+    Relu2,    // max(0, x)^2, squared ReLU (Primer)
+}
+
+impl Activation {
+    #[inline]
+    fn apply(self, x: f32) -> f32 {
+        match self {
+            Activation::Silu => silu_scalar(x),
+            Activation::Gelu => gelu_scalar(x, false),
+            Activation::GeluTanh => gelu_scalar(x, true),
+            Activation::Relu => x.max(0.),
+            Activation::Relu2 => x.max(0.) * x.max(0.),
+        }
+    }
+}
+
+// y = max(0, y), in place
+#[allow(unused)]
+pub fn relu(y: &mut Tensor<f32>) {
+    let _y = unsafe { y.data_mut() };
+    for y_i in _y.iter_mut() {
+        *y_i = Activation::Relu.apply(*y_i);
+    }
+}
+
+// y = max(0, y)^2, in place
+#[allow(unused)]
+pub fn relu2(y: &mut Tensor<f32>) {
+    let _y = unsafe { y.data_mut() };
+    for y_i in _y.iter_mut() {
+        *y_i = Activation::Relu2.apply(*y_i);
+    }
+}
+
+// gelu(x) = 0.5 * x * (1 + erf(x / sqrt(2)))
+// or with the tanh approximation 0.5 * x * (1 + tanh(sqrt(2 / pi) * (x + 0.044715 * x^3)))
+#[allow(unused)]
+pub fn gelu(y: &mut Tensor<f32>, approximate: bool) {
+    let _y = unsafe { y.data_mut() };
+    for y_i in _y.iter_mut() {
+        *y_i = gelu_scalar(*y_i, approximate);
+    }
+}
+
+#[inline]
+fn gelu_scalar(x: f32, approximate: bool) -> f32 {
+    if approximate {
+        const SQRT_2_OVER_PI: f32 = 0.797_884_6;
+        0.5 * x * (1. + (SQRT_2_OVER_PI * (x + 0.044715 * x * x * x)).tanh())
+    } else {
+        0.5 * x * (1. + erf(x / std::f32::consts::SQRT_2))
+    }
+}
+
+// Abramowitz & Stegun 7.1.26, absolute error below 1.5e-7
+fn erf(x: f32) -> f32 {
+    let t = 1. / (1. + 0.327_591_1 * x.abs());
+    let poly = t
+        * (0.254_829_6
+            + t * (-0.284_496_74 + t * (1.421_413_7 + t * (-1.453_152_1 + t * 1.061_405_4))));
+    let r = 1. - poly * (-x * x).exp();
+    if x < 0. {
+        -r
+    } else {
+        r
+    }
+}
+
+// y = act(x) * y, the gated activation of the MLP
+pub fn gated_activation(y: &mut Tensor<f32>, x: &Tensor<f32>, act: Activation) {
+    match act {
+        Activation::Silu => swiglu(y, x),
+        _ => {
+            let len = y.size();
+            assert!(len == x.size());
+            let _y = unsafe { y.data_mut() };
+            for (y_i, x_i) in _y.iter_mut().zip(x.data()) {
+                *y_i *= act.apply(*x_i);
+            }
+        }
+    }
+}
+
+// C = beta * C + alpha * A @ B^T
+// hint: You don't need to do an explicit transpose of B
+pub fn matmul_transb(c: &mut Tensor<f32>, beta: f32, a: &Tensor<f32>, b: &Tensor<f32>, alpha: f32) {
+    matmul_transb_bias(c, beta, a, b, alpha, None);
+}
+
+// matmul_transb returning an error unless A is (m, k), B is (n, k) and C is (m, n).
+// A mismatch is reported against the shape A or B should have had given C and A.
+#[allow(unused)]
+pub fn checked_matmul_transb(
+    c: &mut Tensor<f32>,
+    beta: f32,
+    a: &Tensor<f32>,
+    b: &Tensor<f32>,
+    alpha: f32,
+) -> Result<(), OperatorError> {
+    check_matmul_transb(c, a, b.shape())?;
+    matmul_transb(c, beta, a, b, alpha);
+    Ok(())
+}
+
+// The checks of checked_matmul_transb with B given by its shape, for quantized weights
+pub fn check_matmul_transb(
+    c: &Tensor<f32>,
+    a: &Tensor<f32>,
+    b_shape: &[usize],
+) -> Result<(), OperatorError> {
+    const OP: &str = "matmul_transb";
+    check_rank(OP, c, 2)?;
+    check_rank(OP, a, 2)?;
+    if b_shape.len() != 2 {
+        return Err(OperatorError::RankMismatch {
+            op: OP,
+            expected: 2,
+            got: b_shape.len(),
+        });
+    }
+    let (m, n, k) = (c.shape()[0], c.shape()[1], a.shape()[1]);
+    check_shape(OP, &[m, k], a)?;
+    if b_shape != [n, k] {
+        return Err(OperatorError::ShapeMismatch {
+            op: OP,
+            expected: vec![n, k],
+            got: b_shape.to_vec(),
+        });
+    }
+    Ok(())
+}
+
+// C = beta * C + alpha * A @ B^T + bias
+// The length-n bias is added to every row of C in the same pass, None is plain matmul_transb.
+pub fn matmul_transb_bias(
+    c: &mut Tensor<f32>,
+    beta: f32,
+    a: &Tensor<f32>,
+    b: &Tensor<f32>,
+    alpha: f32,
+    bias: Option<&Tensor<f32>>,
+) {
+    let (_, n, k) = matmul_transb_dims(c, a, b);
+    if let Some(bias) = bias {
+        assert!(bias.size() == n);
+    }
+    let bias = bias.map(|t| t.data());
+    if c.shape()[0] == 1 && !cfg!(feature = "gemm-backend") {
+        // single-token decode: every projection is a matrix-vector product
+        matvec_transb_kernel(
+            unsafe { c.data_mut() },
+            beta,
+            a.data(),
+            b.data(),
+            alpha,
+            bias,
+            k,
+        );
+        return;
+    }
+    matmul_transb_kernel(
+        unsafe { c.data_mut() },
+        beta,
+        a.data(),
+        b.data(),
+        alpha,
+        bias,
+        n,
+        k,
+    );
+}
+
+// C[i] = beta * C[i] + alpha * A[i] @ B[i / (batch / batch_b)]^T
+// A is (batch, m, k), B is (batch_b, n, k) and C is (batch, m, n). B is broadcast when
+// batch_b divides batch, so consecutive A batches share one B batch as query heads share a
+// kv head in grouped-query attention.
+pub fn matmul_transb_batched(
+    c: &mut Tensor<f32>,
+    beta: f32,
+    a: &Tensor<f32>,
+    b: &Tensor<f32>,
+    alpha: f32,
+) {
+    let c_shape = c.shape();
+    let a_shape = a.shape();
+    let b_shape = b.shape();
+    assert!(c_shape.len() == 3);
+    assert!(a_shape.len() == 3);
+    assert!(b_shape.len() == 3);
+    let (batch, m, n, k) = (a_shape[0], a_shape[1], b_shape[1], a_shape[2]);
+    let batch_b = b_shape[0];
+    assert!(c_shape[0] == batch);
+    assert!(batch_b > 0 && batch % batch_b == 0);
+    assert!(c_shape[1] == m);
+    assert!(c_shape[2] == n);
+    assert!(b_shape[2] == k);
+    let group = batch / batch_b;
+    let _c = unsafe { c.data_mut() };
+    let _a = a.data();
+    let _b = b.data();
+    for (i, c_i) in _c.chunks_mut(m * n).enumerate() {
+        let a_i = &_a[i * m * k..][..m * k];
+        let b_i = &_b[(i / group) * n * k..][..n * k];
+        matmul_transb_kernel(c_i, beta, a_i, b_i, alpha, None, n, k);
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn matmul_transb_kernel(
+    c: &mut [f32],
+    beta: f32,
+    a: &[f32],
+    b: &[f32],
+    alpha: f32,
+    bias: Option<&[f32]>,
+    n: usize,
+    k: usize,
+) {
+    #[cfg(feature = "gemm-backend")]
+    matmul_transb_gemm(c, beta, a, b, alpha, bias, n, k);
+    #[cfg(not(feature = "gemm-backend"))]
+    matmul_transb_tiled(c, beta, a, b, alpha, bias, n, k);
+}
+
+// Blocked over (M, N, K) tiles: each tile of C is accumulated in a stack buffer and written
+// back once. Without the "simd" feature every element still sums over k in order, so the
+// result is bit-identical to matmul_transb_naive. Row blocks of C are independent, so with
+// the "parallel" feature they are split across threads.
+#[allow(clippy::too_many_arguments)]
+#[cfg_attr(feature = "gemm-backend", allow(unused))]
+fn matmul_transb_tiled(
+    c: &mut [f32],
+    beta: f32,
+    a: &[f32],
+    b: &[f32],
+    alpha: f32,
+    bias: Option<&[f32]>,
+    n: usize,
+    k: usize,
+) {
+    let row_block = |(bi, c_block): (usize, &mut [f32])| {
+        let i0 = bi * MM_TILE_M;
+        let rows = c_block.len() / n;
+        let mut acc = [[0f32; MM_TILE_N]; MM_TILE_M];
+        for j0 in (0..n).step_by(MM_TILE_N) {
+            let cols = MM_TILE_N.min(n - j0);
+            acc[..rows].iter_mut().for_each(|r| r[..cols].fill(0.));
+            for k0 in (0..k).step_by(MM_TILE_K) {
+                let k1 = (k0 + MM_TILE_K).min(k);
+                for (ii, acc_row) in acc[..rows].iter_mut().enumerate() {
+                    let a_row = &a[(i0 + ii) * k..][k0..k1];
+                    for (jj, acc_ij) in acc_row[..cols].iter_mut().enumerate() {
+                        let b_row = &b[(j0 + jj) * k..][k0..k1];
+                        *acc_ij = dot_acc(*acc_ij, a_row, b_row);
+                    }
+                }
+            }
+            for (c_row, acc_row) in c_block.chunks_mut(n).zip(&acc[..rows]) {
+                let c_tile = c_row[j0..j0 + cols].iter_mut().zip(&acc_row[..cols]);
+                match bias {
+                    Some(bias) => {
+                        for ((c_ij, sum), bias_j) in c_tile.zip(&bias[j0..j0 + cols]) {
+                            *c_ij = beta * *c_ij + alpha * sum + bias_j;
+                        }
+                    }
+                    None => {
+                        for (c_ij, sum) in c_tile {
+                            *c_ij = beta * *c_ij + alpha * sum;
+                        }
+                    }
+                }
+            }
+        }
+    };
+    #[cfg(feature = "parallel")]
+    {
+        use rayon::prelude::*;
+        c.par_chunks_mut(MM_TILE_M * n)
+            .enumerate()
+            .for_each(row_block);
+    }
+    #[cfg(not(feature = "parallel"))]
+    c.chunks_mut(MM_TILE_M * n).enumerate().for_each(row_block);
+}
+
+// C = beta * C + alpha * A @ B
+// A is (m, k) and B is (k, n) in the usual row-major orientation. k runs in the outer loop
+// and j in the inner one, so rows of B are read contiguously.
+#[allow(unused)]
+pub fn matmul(c: &mut Tensor<f32>, beta: f32, a: &Tensor<f32>, b: &Tensor<f32>, alpha: f32) {
+    let c_shape = c.shape();
+    let a_shape = a.shape();
+    let b_shape = b.shape();
+    assert!(c_shape.len() == 2);
+    assert!(a_shape.len() == 2);
+    assert!(b_shape.len() == 2);
+    assert!(c_shape[0] == a_shape[0]);
+    assert!(c_shape[1] == b_shape[1]);
+    assert!(a_shape[1] == b_shape[0]);
+    let n = c_shape[1];
+    let k = a_shape[1];
+    let _c = unsafe { c.data_mut() };
+    let _a = a.data();
+    let _b = b.data();
+    let mut acc = vec![0f32; n];
+    for (c_row, a_row) in _c.chunks_mut(n).zip(_a.chunks(k)) {
+        acc.fill(0.);
+        for (a_il, b_row) in a_row.iter().zip(_b.chunks(n)) {
+            for (acc_j, b_lj) in acc.iter_mut().zip(b_row) {
+                *acc_j += a_il * b_lj;
+            }
+        }
+        for (c_ij, sum) in c_row.iter_mut().zip(&acc) {
+            *c_ij = beta * *c_ij + alpha * sum;
+        }
+    }
+}
+
+// matmul_transb routed through matrixmultiply::sgemm. B^T is passed as a (k, n) view of the
+// row-major (n, k) B by swapping its strides, no copy is made. sgemm has the same
+// C = alpha * A @ B + beta * C convention, except that it never reads C when beta is zero.
+#[cfg(feature = "gemm-backend")]
+#[allow(clippy::too_many_arguments)]
+fn matmul_transb_gemm(
+    c: &mut [f32],
+    beta: f32,
+    a: &[f32],
+    b: &[f32],
+    alpha: f32,
+    bias: Option<&[f32]>,
+    n: usize,
+    k: usize,
+) {
+    let m = c.len() / n;
+    let (k_s, n_s) = (k as isize, n as isize);
+    unsafe {
+        matrixmultiply::sgemm(
+            m,
+            k,
+            n,
+            alpha,
+            a.as_ptr(),
+            k_s,
+            1,
+            b.as_ptr(),
+            1,
+            k_s,
+            beta,
+            c.as_mut_ptr(),
+            n_s,
+            1,
+        );
+    }
+    if let Some(bias) = bias {
+        for c_row in c.chunks_mut(n) {
+            for (c_ij, bias_j) in c_row.iter_mut().zip(bias) {
+                *c_ij += bias_j;
+            }
+        }
+    }
+}
+
+// c = beta * c + alpha * B @ a (+ bias) for a single row a, walking the rows of B
+fn matvec_transb_kernel(
+    c: &mut [f32],
+    beta: f32,
+    a: &[f32],
+    b: &[f32],
+    alpha: f32,
+    bias: Option<&[f32]>,
+    k: usize,
+) {
+    let chunk = |(ci, c_chunk): (usize, &mut [f32])| {
+        let j0 = ci * MM_TILE_N;
+        for (jj, c_j) in c_chunk.iter_mut().enumerate() {
+            let sum = dot_unrolled(a, &b[(j0 + jj) * k..][..k]);
+            *c_j = match bias {
+                Some(bias) => beta * *c_j + alpha * sum + bias[j0 + jj],
+                None => beta * *c_j + alpha * sum,
+            };
+        }
+    };
+    #[cfg(feature = "parallel")]
+    {
+        use rayon::prelude::*;
+        c.par_chunks_mut(MM_TILE_N).enumerate().for_each(chunk);
+    }
+    #[cfg(not(feature = "parallel"))]
+    c.chunks_mut(MM_TILE_N).enumerate().for_each(chunk);
+}
+
+// C = beta * C + alpha * A @ B^T with B stored as Q8_0 blocks, every block of B is scaled
+// inside the inner loop so no dequantized copy of B is ever made
+#[allow(unused)]
+pub fn matmul_transb_q8(
+    c: &mut Tensor<f32>,
+    beta: f32,
+    a: &Tensor<f32>,
+    b_q: &QuantizedTensor,
+    alpha: f32,
+) {
+    let (c_shape, a_shape, b_shape) = (c.shape(), a.shape(), b_q.shape());
+    assert!(c_shape.len() == 2 && a_shape.len() == 2 && b_shape.len() == 2);
+    assert!(c_shape[0] == a_shape[0] && c_shape[1] == b_shape[0] && a_shape[1] == b_shape[1]);
+    let (n, k) = (c_shape[1], a_shape[1]);
+    let a = a.data();
+    matmul_transb_rows(unsafe { c.data_mut() }, beta, alpha, n, |i, j| {
+        let (scales, values) = b_q.row(j);
+        dot_q8(&a[i * k..][..k], scales, values)
+    });
+}
+
+// sum over the blocks of scale * (x . q)
+fn dot_q8(x: &[f32], scales: &[f32], values: &[i8]) -> f32 {
+    x.chunks(Q8_BLOCK)
+        .zip(values.chunks(Q8_BLOCK))
+        .zip(scales)
+        .map(|((x, q), s)| s * x.iter().zip(q).map(|(x, &q)| x * q as f32).sum::<f32>())
+        .sum()
+}
+
+// C = beta * C + alpha * A @ B^T with B stored as Q4_0, nibbles are unpacked in the inner loop
+#[allow(unused)]
+pub fn matmul_transb_q4(
+    c: &mut Tensor<f32>,
+    beta: f32,
+    a: &Tensor<f32>,
+    b_q: &Q4Tensor,
+    alpha: f32,
+) {
+    let (c_shape, a_shape, b_shape) = (c.shape(), a.shape(), b_q.shape());
+    assert!(c_shape.len() == 2 && a_shape.len() == 2 && b_shape.len() == 2);
+    assert!(c_shape[0] == a_shape[0] && c_shape[1] == b_shape[0] && a_shape[1] == b_shape[1]);
+    let (n, k) = (c_shape[1], a_shape[1]);
+    let a = a.data();
+    matmul_transb_rows(unsafe { c.data_mut() }, beta, alpha, n, |i, j| {
+        let (scales, packed) = b_q.row(j);
+        dot_q4(&a[i * k..][..k], scales, packed)
+    });
+}
+
+// dot_q8 for nibbles, x pairs up with the bytes two values at a time
+fn dot_q4(x: &[f32], scales: &[f32], packed: &[u8]) -> f32 {
+    x.chunks(Q4_BLOCK)
+        .zip(packed.chunks(Q4_BLOCK / 2))
+        .zip(scales)
+        .map(|((x, q), s)| {
+            let mut sum = 0.;
+            for (x, &byte) in x.chunks(2).zip(q) {
+                let (lo, hi) = unpack_q4(byte);
+                sum += x[0] * lo + x.get(1).map_or(0., |x| x * hi);
+            }
+            s * sum
+        })
+        .sum()
+}
+
+// C = beta * C + alpha * A @ B^T with B per-row int8, the row scale is applied once per dot
+pub fn matmul_transb_i8(
+    c: &mut Tensor<f32>,
+    beta: f32,
+    a: &Tensor<f32>,
+    b_q: &I8Tensor,
+    alpha: f32,
+) {
+    let (c_shape, a_shape, b_shape) = (c.shape(), a.shape(), b_q.shape());
+    assert!(c_shape.len() == 2 && a_shape.len() == 2 && b_shape.len() == 2);
+    assert!(c_shape[0] == a_shape[0] && c_shape[1] == b_shape[0] && a_shape[1] == b_shape[1]);
+    let (n, k) = (c_shape[1], a_shape[1]);
+    let a = a.data();
+    matmul_transb_rows(unsafe { c.data_mut() }, beta, alpha, n, |i, j| {
+        let (scale, values) = b_q.row(j);
+        let x = &a[i * k..][..k];
+        scale
+            * x.iter()
+                .zip(values)
+                .map(|(x, &q)| x * q as f32)
+                .sum::<f32>()
+    });
+}
+
+// C = beta * C + alpha * A @ B^T with B stored as f16, every element of B is converted to f32
+// as it is used and the accumulation is done in f32
+pub fn matmul_transb_f16w(
+    c: &mut Tensor<f32>,
+    beta: f32,
+    a: &Tensor<f32>,
+    b: &Tensor<f16>,
+    alpha: f32,
+) {
+    matmul_transb_halfw(c, beta, a, b, alpha);
+}
+
+// matmul_transb_f16w for bf16 weights
+pub fn matmul_transb_bf16w(
+    c: &mut Tensor<f32>,
+    beta: f32,
+    a: &Tensor<f32>,
+    b: &Tensor<bf16>,
+    alpha: f32,
+) {
+    matmul_transb_halfw(c, beta, a, b, alpha);
+}
+
+fn matmul_transb_halfw<T: Copy + Default + Into<f32> + Sync>(
+    c: &mut Tensor<f32>,
+    beta: f32,
+    a: &Tensor<f32>,
+    b: &Tensor<T>,
+    alpha: f32,
+) {
+    let (c_shape, a_shape, b_shape) = (c.shape(), a.shape(), b.shape());
+    assert!(c_shape.len() == 2 && a_shape.len() == 2 && b_shape.len() == 2);
+    assert!(c_shape[0] == a_shape[0] && c_shape[1] == b_shape[0] && a_shape[1] == b_shape[1]);
+    let (n, k) = (c_shape[1], a_shape[1]);
+    let (a, b) = (a.data(), b.data());
+    matmul_transb_rows(unsafe { c.data_mut() }, beta, alpha, n, |i, j| {
+        let (x, w) = (&a[i * k..][..k], &b[j * k..][..k]);
+        x.iter().zip(w).map(|(x, &w)| x * w.into()).sum()
+    });
+}
+
+// C[i][j] = beta * C[i][j] + alpha * row_dot(i, j), shared by the kernels whose B is not a
+// plain f32 matrix. Work is split over MM_TILE_N entries of C at a time so that a single
+// decode row is spread over the threads as well.
+fn matmul_transb_rows(
+    c: &mut [f32],
+    beta: f32,
+    alpha: f32,
+    n: usize,
+    row_dot: impl Fn(usize, usize) -> f32 + Sync,
+) {
+    let chunk = |(ci, c_chunk): (usize, &mut [f32])| {
+        for (jj, c_ij) in c_chunk.iter_mut().enumerate() {
+            let idx = ci * MM_TILE_N + jj;
+            *c_ij = beta * *c_ij + alpha * row_dot(idx / n, idx % n);
+        }
+    };
+    #[cfg(feature = "parallel")]
+    {
+        use rayon::prelude::*;
+        c.par_chunks_mut(MM_TILE_N).enumerate().for_each(chunk);
+    }
+    #[cfg(not(feature = "parallel"))]
+    c.chunks_mut(MM_TILE_N).enumerate().for_each(chunk);
+}
+
+// C = alpha * rms_norm(X) @ W^T without the normalized X ever being stored: the rms of every
+// row of X is computed first and x_ik * norm_w_k / rms_i is fed straight into the dot products
+#[allow(unused)]
+pub fn rms_norm_matmul_transb(
+    c: &mut Tensor<f32>,
+    x: &Tensor<f32>,
+    norm_w: &Tensor<f32>,
+    proj_w: &Tensor<f32>,
+    epsilon: f32,
+    alpha: f32,
+) {
+    rms_norm_offset_matmul_transb(c, x, norm_w, proj_w, epsilon, 0., alpha);
+}
+
+// rms_norm_matmul_transb, normalizing as rms_norm_gemma with unit_offset, returning an error
+// on mismatched shapes
+pub fn checked_rms_norm_matmul_transb(
+    c: &mut Tensor<f32>,
+    x: &Tensor<f32>,
+    norm_w: &Tensor<f32>,
+    proj_w: &Tensor<f32>,
+    epsilon: f32,
+    unit_offset: bool,
+    alpha: f32,
+) -> Result<(), OperatorError> {
+    check_matmul_transb(c, x, proj_w.shape())?;
+    check_shape("rms_norm", &x.shape()[1..], norm_w)?;
+    let offset = if unit_offset { 1. } else { 0. };
+    rms_norm_offset_matmul_transb(c, x, norm_w, proj_w, epsilon, offset, alpha);
+    Ok(())
+}
+
+fn rms_norm_offset_matmul_transb(
+    c: &mut Tensor<f32>,
+    x: &Tensor<f32>,
+    norm_w: &Tensor<f32>,
+    proj_w: &Tensor<f32>,
+    epsilon: f32,
+    offset: f32,
+    alpha: f32,
+) {
+    let (_, n, k) = matmul_transb_dims(c, x, proj_w);
+    assert!(norm_w.size() == k);
+    let _x = x.data();
+    let _b = proj_w.data();
+    let w = norm_w.data();
+    // on the stack for the few rows of a decode step
+    let inv_rms: SmallVec<[f32; 16]> = _x
+        .chunks(k)
+        .map(|row| 1. / ((sum_squares(row) / k as f32) + epsilon).sqrt())
+        .collect();
+    matmul_transb_rows(unsafe { c.data_mut() }, 0., alpha, n, |i, j| {
+        inv_rms[i] * dot3(&_x[i * k..][..k], w, offset, &_b[j * k..][..k])
+    });
+}
+
+// sum of x_i * (offset + w_i) * y_i, vectorized and compensated with the same features as
+// dot_unrolled
+#[inline]
+fn dot3(x: &[f32], w: &[f32], offset: f32, y: &[f32]) -> f32 {
+    #[cfg(feature = "accurate-sum")]
+    {
+        let mut sum = 0f32;
+        let mut compensation = 0f32;
+        for ((a, w), b) in x.iter().zip(w).zip(y) {
+            let term = a * (offset + w) * b - compensation;
+            let next = sum + term;
+            compensation = (next - sum) - term;
+            sum = next;
+        }
+        sum
+    }
+    #[cfg(all(feature = "simd", not(feature = "accurate-sum")))]
+    {
+        use wide::f32x8;
+        let (xs, ws, ys) = (x.chunks_exact(8), w.chunks_exact(8), y.chunks_exact(8));
+        let tail: f32 = (xs
+            .remainder()
+            .iter()
+            .zip(ws.remainder())
+            .zip(ys.remainder()))
+        .map(|((a, w), b)| a * (offset + w) * b)
+        .sum();
+        let lane = |v: &[f32]| f32x8::from(<[f32; 8]>::try_from(v).unwrap());
+        let offsets = f32x8::splat(offset);
+        let mut lanes = f32x8::ZERO;
+        for ((a, w), b) in xs.zip(ws).zip(ys) {
+            lanes = (lane(a) * (offsets + lane(w))).mul_add(lane(b), lanes);
+        }
+        lanes.reduce_add() + tail
+    }
+    #[cfg(not(any(feature = "simd", feature = "accurate-sum")))]
+    {
+        let (xs, ws, ys) = (x.chunks_exact(4), w.chunks_exact(4), y.chunks_exact(4));
+        let tail: f32 = (xs
+            .remainder()
+            .iter()
+            .zip(ws.remainder())
+            .zip(ys.remainder()))
+        .map(|((a, w), b)| a * (offset + w) * b)
+        .sum();
+        let mut acc = [0f32; 4];
+        for ((a, w), b) in xs.zip(ws).zip(ys) {
+            for l in 0..4 {
+                acc[l] += a[l] * (offset + w[l]) * b[l];
+            }
+        }
+        (acc[0] + acc[1]) + (acc[2] + acc[3]) + tail
+    }
+}
+
+// Reference implementation of matmul_transb, a plain triple loop
+#[allow(unused)]
+pub fn matmul_transb_naive(
+    c: &mut Tensor<f32>,
+    beta: f32,
+    a: &Tensor<f32>,
+    b: &Tensor<f32>,
+    alpha: f32,
+) {
+    let (m, n, k) = matmul_transb_dims(c, a, b);
+    let _c = unsafe { c.data_mut() };
+    let _a = a.data();
+    let _b = b.data();
+    for i in 0..m {
+        for j in 0..n {
+            let mut sum = 0.0;
+            for l in 0..k {
+                sum += _a[i * k + l] * _b[j * k + l];
+            }
+            _c[i * n + j] = beta * _c[i * n + j] + alpha * sum;
+        }
+    }
+}
+
+// acc + x . y, summed in index order so it matches the plain loops exactly
+#[cfg_attr(feature = "accurate-sum", allow(unused))]
+#[inline]
+fn dot_acc_scalar(acc: f32, x: &[f32], y: &[f32]) -> f32 {
+    x.iter().zip(y).fold(acc, |sum, (a, b)| sum + a * b)
+}
+
+// acc + x . y with Kahan compensation: the rounding error of every addition is carried
+// into the next one, so long sums stay close to the exact result (see test_dot_kahan)
+#[cfg_attr(not(feature = "accurate-sum"), allow(unused))]
+#[inline]
+fn dot_acc_kahan(acc: f32, x: &[f32], y: &[f32]) -> f32 {
+    let mut sum = acc;
+    let mut compensation = 0f32;
+    for (a, b) in x.iter().zip(y) {
+        let term = a * b - compensation;
+        let next = sum + term;
+        compensation = (next - sum) - term;
+        sum = next;
+    }
+    sum
+}
+
+// sum of squares of x, the statistic of rms_norm
+#[inline]
+fn sum_squares(x: &[f32]) -> f32 {
+    #[cfg(feature = "accurate-sum")]
+    return dot_acc_kahan(0., x, x);
+    #[cfg(not(feature = "accurate-sum"))]
+    x.iter().map(|v| v * v).sum()
+}
+
+// acc + x . y, 8 lanes at a time with a scalar tail for the remainder
+#[cfg(feature = "simd")]
+#[cfg_attr(feature = "accurate-sum", allow(unused))]
+#[inline]
+fn dot_acc_simd(acc: f32, x: &[f32], y: &[f32]) -> f32 {
+    use wide::f32x8;
+    let xs = x.chunks_exact(8);
+    let ys = y.chunks_exact(8);
+    let tail = dot_acc_scalar(0., xs.remainder(), ys.remainder());
+    let mut lanes = f32x8::ZERO;
+    for (a, b) in xs.zip(ys) {
+        let a = f32x8::from(<[f32; 8]>::try_from(a).unwrap());
+        let b = f32x8::from(<[f32; 8]>::try_from(b).unwrap());
+        lanes = a.mul_add(b, lanes);
+    }
+    acc + lanes.reduce_add() + tail
+}
+
+// Inner loop shared by dot and matmul_transb, vectorized with the "simd" feature and
+// compensated with "accurate-sum", which wins over "simd" (but not over the gemm backend)
+#[inline]
+fn dot_acc(acc: f32, x: &[f32], y: &[f32]) -> f32 {
+    #[cfg(feature = "accurate-sum")]
+    return dot_acc_kahan(acc, x, y);
+    #[cfg(all(feature = "simd", not(feature = "accurate-sum")))]
+    return dot_acc_simd(acc, x, y);
+    #[cfg(not(any(feature = "simd", feature = "accurate-sum")))]
+    dot_acc_scalar(acc, x, y)
+}
+
+// x . y with four independent accumulators so the additions can overlap
+#[inline]
+fn dot_unrolled(x: &[f32], y: &[f32]) -> f32 {
+    #[cfg(feature = "accurate-sum")]
+    return dot_acc_kahan(0., x, y);
+    #[cfg(all(feature = "simd", not(feature = "accurate-sum")))]
+    return dot_acc_simd(0., x, y);
+    #[cfg(not(any(feature = "simd", feature = "accurate-sum")))]
+    {
+        let xs = x.chunks_exact(4);
+        let ys = y.chunks_exact(4);
+        let tail = dot_acc_scalar(0., xs.remainder(), ys.remainder());
+        let mut acc = [0f32; 4];
+        for (a, b) in xs.zip(ys) {
+            acc[0] += a[0] * b[0];
+            acc[1] += a[1] * b[1];
+            acc[2] += a[2] * b[2];
+            acc[3] += a[3] * b[3];
+        }
+        (acc[0] + acc[1]) + (acc[2] + acc[3]) + tail
+    }
+}
+
+// Dot product of two tensors (treated as vectors)
+#[allow(unused)]
+pub fn dot(x: &Tensor<f32>, y: &Tensor<f32>) -> f32 {
+    let len = x.size();
+    assert!(len == y.size());
+    dot_acc(0., x.data(), y.data())
+}
+
+// A token and its logit, ordered by descending value with ties broken by the lower token id
+#[derive(Clone, Copy, PartialEq, Debug)]
+struct Probability {
+    val: f32,
+    tok: u32,
+}
+impl Eq for Probability {}
+impl PartialOrd for Probability {
+    #[inline]
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for Probability {
+    #[inline]
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        match self.val.total_cmp(&other.val) {
+            std::cmp::Ordering::Equal => self.tok.cmp(&other.tok),
+            ord => ord.reverse(),
+        }
+    }
+}
+impl From<(usize, &f32)> for Probability {
+    #[inline]
+    fn from((i, p): (usize, &f32)) -> Self {
+        Self {
+            val: *p,
+            tok: i as _,
+        }
+    }
+}
+
+// The k largest values of x and their indices, in the order defined by Probability.
+// Uses a partial selection so only the k winners get sorted, not the whole vocab.
+#[allow(unused)]
+pub fn topk(x: &Tensor<f32>, k: usize) -> (Vec<f32>, Vec<u32>) {
+    let mut candidates = Vec::new();
+    topk_row(x.data(), k, &mut candidates);
+    candidates.iter().map(|p| (p.val, p.tok)).unzip()
+}
+
+// Leaves the sorted top k of row in candidates, reusing its allocation
+#[allow(unused)]
+fn topk_row(row: &[f32], k: usize, candidates: &mut Vec<Probability>) {
+    candidates.clear();
+    if k == 0 {
+        return;
+    }
+    candidates.extend(row.iter().enumerate().map(Probability::from));
+    if k < candidates.len() {
+        candidates.select_nth_unstable(k - 1);
+        candidates.truncate(k);
+    }
+    candidates.sort_unstable();
+}
+
+// Mixture-of-experts routing of (seq_len, n_experts) logits: the top k experts of every
+// token (by topk, so ties go to the lower expert) and their softmax weights renormalized
+// over the chosen ones, both (seq_len, k). Like topk, a k beyond n_experts routes every
+// token to all experts, giving (seq_len, n_experts).
+#[allow(unused)]
+pub fn moe_router(logits: &Tensor<f32>, k: usize) -> (Tensor<u32>, Tensor<f32>) {
+    let shape = logits.shape();
+    assert!(shape.len() == 2 && k > 0);
+    let (seq_len, n_experts) = (shape[0], shape[1]);
+    let k = k.min(n_experts);
+    let mut indices = Vec::with_capacity(seq_len * k);
+    let mut weights = Vec::with_capacity(seq_len * k);
+    let mut candidates = Vec::with_capacity(n_experts);
+    for row in logits.data().chunks_exact(n_experts) {
+        topk_row(row, k, &mut candidates);
+        indices.extend(candidates.iter().map(|p| p.tok));
+        weights.extend(candidates.iter().map(|p| p.val));
+        // softmax over all experts, then dividing by the mass of the top k, is the
+        // softmax of the top k logits alone
+        let start = weights.len() - k;
+        softmax_row(&mut weights[start..], 0..k);
+    }
+    (
+        Tensor::new(indices, &vec![seq_len, k]),
+        Tensor::new(weights, &vec![seq_len, k]),
+    )
+}
+
+// Index of the maximum of every row over the last axis, shaped like x without that axis
+// (a single element for 1D x). NaN is skipped, ties go to the lower index and a row with
+// nothing but NaN gives 0.
+#[allow(unused)]
+pub fn argmax(x: &Tensor<f32>) -> Tensor<u32> {
+    let shape = x.shape();
+    let n = *shape.last().unwrap();
+    assert!(n > 0);
+    let mut out_shape = shape[..shape.len() - 1].to_vec();
+    if out_shape.is_empty() {
+        out_shape.push(1);
+    }
+    let indices = x.data().chunks(n).map(argmax_row).collect();
+    Tensor::new(indices, &out_shape)
+}
+
+// argmax of a single row without allocating, the first of equal maxima and 0 if all are NaN
+pub fn argmax_row(row: &[f32]) -> u32 {
+    let mut best: Option<(usize, f32)> = None;
+    for (i, &v) in row.iter().enumerate() {
+        if !v.is_nan() && best.is_none_or(|(_, b)| v > b) {
+            best = Some((i, v));
+        }
+    }
+    best.map_or(0, |(i, _)| i as u32)
+}
+
+// CTRL repetition penalty: the logit of every token in recent (once, however often it
+// occurs) is divided by penalty when positive and multiplied by it otherwise, so a penalty
+// above 1 always makes it less likely. Ids outside of the logits are ignored.
+pub fn repetition_penalty(logits: &mut Tensor<f32>, recent: &[u32], penalty: f32) {
+    assert!(penalty > 0.);
+    let mut ids = recent.to_vec();
+    ids.sort_unstable();
+    ids.dedup();
+    let data = unsafe { logits.data_mut() };
+    for id in ids {
+        if let Some(v) = data.get_mut(id as usize) {
+            *v = if *v > 0. { *v / penalty } else { *v * penalty };
+        }
+    }
+}
+
+// OpenAI frequency and presence penalties, logit[t] -= frequency_penalty * count[t] +
+// presence_penalty * (count[t] > 0) for the counts of the tokens generated so far.
+// Ids outside of the logits are ignored.
+pub fn frequency_presence_penalty(
+    logits: &mut Tensor<f32>,
+    counts: &HashMap<u32, usize>,
+    frequency_penalty: f32,
+    presence_penalty: f32,
+) {
+    let data = unsafe { logits.data_mut() };
+    for (&id, &count) in counts {
+        if let Some(v) = data.get_mut(id as usize) {
+            if count > 0 {
+                *v -= frequency_penalty * count as f32 + presence_penalty;
+            }
+        }
+    }
+}
+
+// logits[t] += bias[t], where a bias of -100 or below bans t outright (-inf) as in the
+// OpenAI API. Ids outside of the logits are ignored.
+pub fn logit_bias(logits: &mut Tensor<f32>, bias: &HashMap<u32, f32>) {
+    let data = unsafe { logits.data_mut() };
+    for (&id, &b) in bias {
+        if let Some(v) = data.get_mut(id as usize) {
+            *v = if b <= -100. {
+                f32::NEG_INFINITY
+            } else {
+                *v + b
+            };
+        }
+    }
+}
+
+// Set the logits of banned to -inf, which the samplers never pick while anything else is
+// left. Ids outside of the logits are ignored.
+pub fn ban_tokens(logits: &mut Tensor<f32>, banned: &[u32]) {
+    let data = unsafe { logits.data_mut() };
+    for &id in banned {
+        if let Some(v) = data.get_mut(id as usize) {
+            *v = f32::NEG_INFINITY;
+        }
+    }
+}
+
+// sets every logit to -inf whose token is not allowed
+pub fn mask_tokens(logits: &mut Tensor<f32>, allowed: &[bool]) {
+    let data = unsafe { logits.data_mut() };
+    assert!(allowed.len() == data.len());
+    for (v, &allowed) in data.iter_mut().zip(allowed) {
+        if !allowed {
+            *v = f32::NEG_INFINITY;
+        }
+    }
+}
+
+// Bans every token that would repeat an n-gram of the tokens pushed so far. The
+// continuations of every (n - 1)-gram are kept in a map, so banning only looks up the last
+// n - 1 tokens instead of scanning the whole history.
+#[derive(Clone)]
+pub struct NoRepeatNgram {
+    n: usize,
+    tail: Vec<u32>, // the last n - 1 tokens
+    continuations: HashMap<Vec<u32>, Vec<u32>>,
+}
+
+impl NoRepeatNgram {
+    pub fn n(&self) -> usize {
+        self.n
+    }
+
+    pub fn new(n: usize) -> Self {
+        assert!(n > 0);
+        NoRepeatNgram {
+            n,
+            tail: Vec::with_capacity(n),
+            continuations: HashMap::new(),
+        }
+    }
+
+    pub fn push(&mut self, token: u32) {
+        if self.tail.len() == self.n - 1 {
+            let next = self.continuations.entry(self.tail.clone()).or_default();
+            if !next.contains(&token) {
+                next.push(token);
+            }
+            if !self.tail.is_empty() {
+                self.tail.remove(0);
+            }
+        }
+        if self.n > 1 {
+            self.tail.push(token);
+        }
+    }
+
+    // set the logits of the tokens that would complete a repeated n-gram to -inf
+    pub fn ban(&self, logits: &mut Tensor<f32>) {
+        if let Some(next) = self.continuations.get(&self.tail) {
+            let data = unsafe { logits.data_mut() };
+            for &id in next {
+                if let Some(v) = data.get_mut(id as usize) {
+                    *v = f32::NEG_INFINITY;
+                }
+            }
+        }
+    }
+}
+
+// log softmax of logits x at token, with the n most likely tokens and their log
+// probabilities, most likely first
+pub fn logprobs(x: &Tensor<f32>, token: u32, n: usize) -> (f32, Vec<(u32, f32)>) {
+    let data = x.data();
+    let max = data.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let log_sum = max + data.iter().map(|&v| (v - max).exp()).sum::<f32>().ln();
+    let mut ids = (0..data.len() as u32).collect::<Vec<_>>();
+    let by_logit = |a: &u32, b: &u32| {
+        data[*b as usize]
+            .total_cmp(&data[*a as usize])
+            .then(a.cmp(b))
+    };
+    let n = n.min(ids.len());
+    if n > 0 && n < ids.len() {
+        ids.select_nth_unstable_by(n - 1, by_logit);
+    }
+    ids.truncate(n);
+    ids.sort_unstable_by(by_logit);
+    let top = ids
+        .into_iter()
+        .map(|id| (id, data[id as usize] - log_sum))
+        .collect();
+    (data[token as usize] - log_sum, top)
+}
+
+// Sample an index from logits x: softmax(x / temperature), keep the top_k most likely
+// tokens, renormalize and keep the smallest prefix of them holding top_p of the
+// probability (always at least one), then draw from what is left, renormalized once more.
+// min_p > 0 also drops every token less likely than min_p times the most likely one and
+// typical_p < 1 keeps the locally typical tokens (see random_sample_with), the kept set
+// being what all of them keep. A temperature <= 0, top_k < 2 or top_p <= 0 is greedy.
+// rng is the only source of randomness, so a seeded rng reproduces the same samples.
+#[allow(unused)]
+#[allow(clippy::too_many_arguments)]
+pub fn random_sample(
+    x: &Tensor<f32>,
+    top_p: f32,
+    top_k: u32,
+    temperature: f32,
+    min_p: f32,
+    typical_p: f32,
+    rng: &mut impl rand::Rng,
+) -> u32 {
+    random_sample_with(
+        x,
+        top_p,
+        top_k,
+        temperature,
+        min_p,
+        typical_p,
+        rng,
+        &mut SampleScratch::default(),
+    )
+}
+
+// Candidate buffer of random_sample_with, kept by the caller so that sampling one token
+// after another does not allocate a vocab sized Vec every time
+#[derive(Default)]
+pub struct SampleScratch {
+    candidates: Vec<Probability>,
+    typical: Vec<(f32, f32)>, // (|surprise - entropy|, probability) of every candidate
+}
+
+// number of candidates the nucleus search starts with, doubled until they hold top_p
+const NUCLEUS_CHUNK: usize = 64;
+
+// random_sample on a caller owned buffer. Only the top_k candidates are selected (not
+// sorted) and the nucleus is found by sorting growing chunks of the most likely ones, so
+// nothing ever sorts the whole vocab when top_k or top_p cut it down.
+// Typical sampling ranks the candidates by how far their surprise -ln p is from the
+// entropy of the distribution and keeps the smallest set of the closest ones holding
+// typical_p of the probability. Ties in that distance are all kept, and when the other
+// filters leave nothing typical the most likely token is kept, so a near-deterministic
+// distribution still samples its top token.
+#[allow(clippy::too_many_arguments)]
+pub fn random_sample_with(
+    x: &Tensor<f32>,
+    top_p: f32,
+    top_k: u32,
+    temperature: f32,
+    min_p: f32,
+    typical_p: f32,
+    rng: &mut impl rand::Rng,
+    scratch: &mut SampleScratch,
+) -> u32 {
+    if let Err(token) = kept_candidates(x, top_p, top_k, temperature, min_p, typical_p, scratch) {
+        return token;
+    }
+    let probs = &scratch.candidates;
+    let total = probs.iter().map(|p| p.val).sum::<f32>();
+    let mut r = rng.gen::<f32>() * total;
+    for p in probs.iter() {
+        if r < p.val {
+            return p.tok;
+        }
+        r -= p.val;
+    }
+    // r can only get here through rounding
+    probs[probs.len() - 1].tok
+}
+
+// the distribution random_sample_with draws from with these settings, as the probability of
+// every token in probs
+#[allow(unused)]
+#[allow(clippy::too_many_arguments)]
+pub fn sample_probs(
+    x: &Tensor<f32>,
+    top_p: f32,
+    top_k: u32,
+    temperature: f32,
+    min_p: f32,
+    typical_p: f32,
+    scratch: &mut SampleScratch,
+    probs: &mut [f32],
+) {
+    assert!(probs.len() == x.size());
+    probs.fill(0.);
+    match kept_candidates(x, top_p, top_k, temperature, min_p, typical_p, scratch) {
+        Err(token) => probs[token as usize] = 1.,
+        Ok(()) => {
+            let total = scratch.candidates.iter().map(|p| p.val).sum::<f32>();
+            for p in &scratch.candidates {
+                probs[p.tok as usize] = p.val / total;
+            }
+        }
+    }
+}
+
+// Leaves the candidates random_sample_with draws from in scratch.candidates, weighted but
+// not normalized, or returns the only token the settings or the logits leave.
+fn kept_candidates(
+    x: &Tensor<f32>,
+    top_p: f32,
+    top_k: u32,
+    temperature: f32,
+    min_p: f32,
+    typical_p: f32,
+    scratch: &mut SampleScratch,
+) -> Result<(), u32> {
+    assert!(x.shape()[x.shape().len() - 1] == x.size());
+    if temperature <= 0. || top_k < 2 || top_p <= 0. {
+        return Err(argmax_row(x.data()));
+    }
+
+    let SampleScratch {
+        candidates: probs,
+        typical,
+    } = scratch;
+    probs.clear();
+    probs.extend(x.data().iter().enumerate().map(Probability::from));
+    // top-k, the softmax normalization cancels out in the renormalization that follows
+    let k = (top_k as usize).min(probs.len());
+    if k < probs.len() {
+        probs.select_nth_unstable(k - 1);
+        probs.truncate(k);
+    }
+    let max = probs.iter().min().unwrap().val;
+    if max == f32::NEG_INFINITY {
+        // everything is banned, exp(-inf - -inf) would turn the weights into NaN
+        return Err(argmax_row(x.data()));
+    }
+    for p in probs.iter_mut() {
+        p.val = ((p.val - max) / temperature).exp();
+    }
+    let sum = probs.iter().map(|p| p.val).sum::<f32>();
+    let top = *probs.iter().min().unwrap();
+    // typical, on the top-k distribution like the filters below so that only the kept
+    // sets are intersected, the largest distance kept is applied after them
+    let ln_sum = sum.ln();
+    let entropy = if typical_p < 1. {
+        probs
+            .iter()
+            .filter(|p| p.val > 0.)
+            .map(|p| -p.val / sum * (p.val.ln() - ln_sum))
+            .sum::<f32>()
+    } else {
+        0.
+    };
+    let distance = |w: f32| (ln_sum - w.ln() - entropy).abs();
+    let max_distance = (typical_p < 1.).then(|| {
+        typical.clear();
+        typical.extend(probs.iter().map(|p| (distance(p.val), p.val / sum)));
+        typical.sort_unstable_by(|a, b| a.0.total_cmp(&b.0));
+        let mut cumulative = 0.;
+        let last = typical
+            .iter()
+            .position(|&(_, q)| {
+                cumulative += q;
+                cumulative >= typical_p
+            })
+            .unwrap_or(typical.len() - 1);
+        typical[last].0
+    });
+    // min-p, the most likely token has a weight of exactly 1. Since it keeps a prefix of the
+    // sorted candidates, top-p below then keeps the intersection of both as long as it
+    // normalizes by the sum over all top-k candidates.
+    if min_p > 0. {
+        probs.retain(|p| p.val >= min_p);
+    }
+    // top-p on the normalized probabilities, sorting the most likely candidates chunk by
+    // chunk until they add up to it
+    if top_p < 1. {
+        let (mut sorted, mut cumulative) = (0, 0.);
+        let mut nucleus = None;
+        while nucleus.is_none() && sorted < probs.len() {
+            let end = (sorted.max(NUCLEUS_CHUNK / 2) * 2).min(probs.len());
+            let rest = &mut probs[sorted..];
+            if end - sorted < rest.len() {
+                rest.select_nth_unstable(end - sorted - 1);
+            }
+            rest[..end - sorted].sort_unstable();
+            nucleus = probs[sorted..end]
+                .iter()
+                .position(|p| {
+                    cumulative += p.val / sum;
+                    cumulative >= top_p
+                })
+                .map(|i| sorted + i + 1);
+            sorted = end;
+        }
+        probs.truncate(nucleus.unwrap_or(probs.len()));
+    }
+    if let Some(max_distance) = max_distance {
+        probs.retain(|p| distance(p.val) <= max_distance);
+        if probs.is_empty() {
+            probs.push(top);
+        }
+    }
+    Ok(())
+}
+
+// Mirostat v2: tau is the surprise (-log2 p, in bits) the samples should have on average,
+// eta how fast mu follows the surprise actually observed
+#[allow(unused)]
+#[derive(Clone, Copy, Debug, PartialEq, serde::Deserialize)]
+pub struct MirostatParams {
+    pub tau: f32,
+    pub eta: f32,
+}
+
+// the truncation threshold mirostat_sample adapts from one token to the next
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MirostatState {
+    pub mu: f32,
+}
+
+impl MirostatState {
+    pub fn new(params: MirostatParams) -> Self {
+        MirostatState {
+            mu: 2. * params.tau,
+        }
+    }
+}
+
+// Sample from softmax(x / temperature) truncated to the tokens with a surprise of at most
+// mu (always keeping the most likely one), renormalized. The observed surprise of the
+// sampled token under that distribution then moves mu by eta * (tau - surprise).
+// A temperature <= 0 is greedy and leaves mu alone.
+pub fn mirostat_sample(
+    x: &Tensor<f32>,
+    temperature: f32,
+    params: MirostatParams,
+    state: &mut MirostatState,
+    rng: &mut impl rand::Rng,
+    scratch: &mut SampleScratch,
+) -> u32 {
+    assert!(x.shape()[x.shape().len() - 1] == x.size());
+    if temperature <= 0. {
+        return argmax_row(x.data());
+    }
+
+    let probs = &mut scratch.candidates;
+    probs.clear();
+    probs.extend(x.data().iter().enumerate().map(Probability::from));
+    let top = *probs.iter().min().unwrap();
+    if top.val == f32::NEG_INFINITY {
+        // everything is banned, see random_sample_with
+        return argmax_row(x.data());
+    }
+    for p in probs.iter_mut() {
+        p.val = ((p.val - top.val) / temperature).exp();
+    }
+    let sum = probs.iter().map(|p| p.val).sum::<f32>();
+    // -log2(w / sum) <= mu, the most likely token has a weight of 1 and always stays
+    let min_weight = sum * (-state.mu).exp2();
+    probs.retain(|p| p.val >= min_weight || p.tok == top.tok);
+    // sample
+    let total = probs.iter().map(|p| p.val).sum::<f32>();
+    let mut r = rng.gen::<f32>() * total;
+    let sampled = probs
+        .iter()
+        .find(|p| {
+            r -= p.val;
+            r < 0.
+        })
+        // r can only miss through rounding
+        .unwrap_or(&probs[probs.len() - 1]);
+    let surprise = -(sampled.val / total).log2();
+    state.mu -= params.eta * (surprise - params.tau);
+    sampled.tok
+}
+
+#[test]
+fn test_gather() {
+    let table = Tensor::<f32>::new(vec![0., 1., 10., 11., 20., 21.], &vec![3, 2]);
+    let mut y = Tensor::<f32>::default(&vec![3, 2]);
+    let indices = Tensor::<u32>::new(vec![2, 0, 2], &vec![3]);
+    assert_eq!(gather(&mut y, &indices, &table), Ok(()));
+    assert_eq!(y.data(), &[20., 21., 0., 1., 20., 21.]);
+
+    // the row count itself is one past the end
+    let indices = Tensor::<u32>::new(vec![1, 3, 0], &vec![3]);
+    assert_eq!(
+        gather(&mut y, &indices, &table),
+        Err(GatherError::IndexOutOfRange {
+            pos: 1,
+            index: 3,
+            rows: 3
+        })
+    );
+}
+
+#[test]
+fn test_gather_scaled() {
+    let table = Tensor::<f32>::new(vec![0.5, -1., 3., 0.25], &vec![2, 2]);
+    let indices = Tensor::<u32>::new(vec![1, 0, 1], &vec![3]);
+    let mut y = Tensor::<f32>::default(&vec![3, 2]);
+    gather_scaled(&mut y, &indices, &table, 2.).unwrap();
+    assert_eq!(y.data(), &[6., 0.5, 1., -2., 6., 0.5]);
+    gather_scaled(&mut y, &indices, &table, 1.).unwrap();
+    assert_eq!(y.data(), &[3., 0.25, 0.5, -1., 3., 0.25]);
+}
+
+#[test]
+fn test_gather_half() {
+    use half::{bf16, f16};
+    let table = Tensor::<f32>::random(&vec![16, 8]);
+    let indices = Tensor::<u32>::new(vec![3, 15, 0, 3], &vec![4]);
+    let mut expected = Tensor::<f32>::default(&vec![4, 8]);
+    gather(&mut expected, &indices, &table).unwrap();
+
+    let table_f16 = Tensor::new(
+        table.data().iter().map(|&x| f16::from_f32(x)).collect(),
+        table.shape(),
+    );
+    let mut y = Tensor::<f32>::default(&vec![4, 8]);
+    gather(&mut y, &indices, &table_f16).unwrap();
+    // f16 keeps 11 significant bits, bf16 only 8
+    assert!(y.max_abs_diff(&expected) < 1e-3);
+
+    let table_bf16 = Tensor::new(
+        table.data().iter().map(|&x| bf16::from_f32(x)).collect(),
+        table.shape(),
+    );
+    gather(&mut y, &indices, &table_bf16).unwrap();
+    assert!(y.max_abs_diff(&expected) < 8e-3);
+}
+
+#[test]
+fn test_checked_operators() {
+    let mut c = Tensor::<f32>::default(&vec![2, 3]);
+    let a = Tensor::<f32>::random(&vec![2, 4]);
+    let b = Tensor::<f32>::random(&vec![3, 5]);
+    assert_eq!(
+        checked_matmul_transb(&mut c, 0., &a, &b, 1.),
+        Err(OperatorError::ShapeMismatch {
+            op: "matmul_transb",
+            expected: vec![3, 4],
+            got: vec![3, 5]
+        })
+    );
+    let b = Tensor::<f32>::random(&vec![3, 4]);
+    assert_eq!(checked_matmul_transb(&mut c, 0., &a, &b, 1.), Ok(()));
+
+    let cache = RopeCache::new(8, 4, 10000., RopeScaling::None);
+    let mut y = Tensor::<f32>::default(&vec![2, 8]);
+    assert_eq!(
+        checked_rope_cached(&mut y, 0, &cache, RopeLayout::Neox),
+        Err(OperatorError::RankMismatch {
+            op: "rope",
+            expected: 3,
+            got: 2
+        })
+    );
+    let e = OperatorError::RankMismatch {
+        op: "rope",
+        expected: 3,
+        got: 2,
+    };
+    assert_eq!(
+        e.in_layer(1).to_string(),
+        "layer 1: rope: expected a 3D tensor, got 2D"
+    );
+}
+
+// Your implementation should at least pass the following tests:
+#[test]
+fn test_silu() {
+    let mut y = Tensor::<f32>::new(vec![2., 3., 4.], &vec![1, 3]);
+    let x = Tensor::<f32>::new(vec![1., 2., 3.], &vec![1, 3]);
+    swiglu(&mut y, &x);
+    assert!(y.close_to(
+        &Tensor::<f32>::new(vec![1.4621172, 5.2847824, 11.43089], &vec![1, 3]),
+        1e-3
+    ));
+}
+
+#[test]
+fn test_silu_standalone() {
+    let mut y = Tensor::<f32>::new(vec![0., 1., -1., -100., -1000.], &vec![5]);
+    silu(&mut y);
+    let y = y.data();
+    assert_eq!(y[0], 0.);
+    assert!(crate::tensor::float_eq(&y[1], &0.7310586, 1e-6));
+    assert!(crate::tensor::float_eq(&y[2], &-0.26894143, 1e-6));
+    // e^100 and e^1000 overflow, which must not turn into NaN
+    assert!(y[3].abs() < 1e-40 && y[4] == 0.);
+}
+
+#[test]
+fn test_gelu() {
+    let x = vec![0., 1., -1., 2., -3.];
+    let mut y = Tensor::<f32>::new(x.clone(), &vec![5]);
+    gelu(&mut y, false);
+    assert!(y.data()[0] == 0.);
+    let exact = [0., 0.8413447, -0.15865526, 1.9544997, -0.0040496];
+    for (a, b) in y.data().iter().zip(exact) {
+        assert!((a - b).abs() < 1e-4);
+    }
+    let mut y = Tensor::<f32>::new(x, &vec![1, 5]);
+    gelu(&mut y, true);
+    let approx = [0., 0.841192, -0.158808, 1.9545977, -0.0036374];
+    for (a, b) in y.data().iter().zip(approx) {
+        assert!((a - b).abs() < 1e-4);
+    }
+}
+
+#[test]
+fn test_relu() {
+    let x = vec![-2., -0.5, 0., 0.5, 3.];
+    let mut y = Tensor::<f32>::new(x.clone(), &vec![5]);
+    relu(&mut y);
+    assert_eq!(y.data(), &[0., 0., 0., 0.5, 3.]);
+    let mut y = Tensor::<f32>::new(x, &vec![5]);
+    relu2(&mut y);
+    assert_eq!(y.data(), &[0., 0., 0., 0.25, 9.]);
+}
+
+#[test]
+fn test_gated_activation() {
+    let x = Tensor::<f32>::new(vec![1., 2., 3.], &vec![1, 3]);
+    let mut y = Tensor::<f32>::new(vec![2., 3., 4.], &vec![1, 3]);
+    let mut y_swiglu = Tensor::<f32>::new(vec![2., 3., 4.], &vec![1, 3]);
+    gated_activation(&mut y, &x, Activation::Silu);
+    swiglu(&mut y_swiglu, &x);
+    assert_eq!(y.data(), y_swiglu.data());
+
+    let mut y = Tensor::<f32>::new(vec![2., 3., 4.], &vec![1, 3]);
+    gated_activation(&mut y, &x, Activation::GeluTanh);
+    assert!(y.close_to(
+        &Tensor::<f32>::new(vec![1.682384, 5.863793, 11.985384], &vec![1, 3]),
+        1e-4
+    ));
+}
+
+#[test]
+fn test_add() {
+    let mut y = Tensor::<f32>::new(vec![1., 2., 3., 4.], &vec![2, 2]);
+    let x = Tensor::<f32>::new(vec![0.5, -2., 0., 10.], &vec![2, 2]);
+    add(&mut y, &x);
+    assert_eq!(y.data(), &[1.5, 0., 3., 14.]);
+    add_scaled(&mut y, &x, -2.);
+    assert_eq!(y.data(), &[0.5, 4., 3., -6.]);
+}
+
+#[test]
+#[should_panic]
+fn test_add_wrong_size() {
+    let mut y = Tensor::<f32>::default(&vec![2, 2]);
+    add(&mut y, &Tensor::<f32>::default(&vec![3]));
+}
+
+#[test]
+fn test_mul_scale() {
+    let mut y = Tensor::<f32>::new(vec![1., 2., 3., 4.], &vec![2, 2]);
+    let x = Tensor::<f32>::new(vec![0.5, -2., 0., 10.], &vec![2, 2]);
+    mul(&mut y, &x);
+    assert_eq!(y.data(), &[0.5, -4., 0., 40.]);
+    scale(&mut y, 0.25);
+    assert_eq!(y.data(), &[0.125, -1., 0., 10.]);
+}
+
+#[test]
+#[should_panic]
+fn test_mul_wrong_size() {
+    let mut y = Tensor::<f32>::default(&vec![4]);
+    mul(&mut y, &Tensor::<f32>::default(&vec![2, 3]));
+}
+
+#[test]
+fn test_softmax() {
+    let mut y = Tensor::<f32>::new(vec![1., 2., 3.], &vec![3]);
+    softmax(&mut y);
+    assert!(y.close_to(
+        &Tensor::<f32>::new(vec![0.09003057, 0.24472847, 0.66524096], &vec![3]),
+        1e-5
+    ));
+
+    let mut y = Tensor::<f32>::new(vec![0., 0., 1., 1., 3., 1.], &vec![2, 3]);
+    softmax(&mut y);
+    assert!(y.close_to(
+        &Tensor::<f32>::new(
+            vec![0.21194156, 0.21194156, 0.57611688, 0.10650698, 0.78698604, 0.10650698],
+            &vec![2, 3]
+        ),
+        1e-5
+    ));
+
+    // exp(x) underflows to zero for every element here without the max shift
+    let mut y = Tensor::<f32>::new(vec![-1000., -1001., -1002.], &vec![1, 3]);
+    softmax(&mut y);
+    assert!(y.close_to(
+        &Tensor::<f32>::new(vec![0.66524096, 0.24472847, 0.09003057], &vec![1, 3]),
+        1e-5
+    ));
+}
+
+#[test]
+fn test_log_softmax() {
+    let data = vec![1., 2., 3., 4., 5., -1., 0.5, 0., -3., 2.];
+    let mut y = Tensor::<f32>::new(data.clone(), &vec![2, 5]);
+    log_softmax(&mut y);
+    assert!(y.close_to(
+        &Tensor::<f32>::new(
+            vec![
+                -4.451914, -3.451914, -2.451914, -1.451914, -0.4519144, //
+                -3.347123, -1.847123, -2.347123, -5.347123, -0.3471228,
+            ],
+            &vec![2, 5]
+        ),
+        1e-5
+    ));
+
+    let logits = Tensor::<f32>::new(data, &vec![2, 5]);
+    let targets = Tensor::<u32>::new(vec![4, 1], &vec![2]);
+    let nll = cross_entropy_per_token(&logits, &targets);
+    assert!(crate::tensor::float_eq(&nll[0], &0.4519144, 1e-5));
+    assert!(crate::tensor::float_eq(&nll[1], &1.847123, 1e-5));
+    assert!(crate::tensor::float_eq(
+        &cross_entropy(&logits, &targets),
+        &1.149519,
+        1e-5
+    ));
+
+    // exp(1000) overflows, the shifted form does not
+    let mut y = Tensor::<f32>::new(vec![1000., 0.], &vec![2]);
+    log_softmax(&mut y);
+    assert_eq!(y.data(), &[0., -1000.]);
+}
+
+#[test]
+fn test_masked_softmax_padding_mask() {
+    // two prompts in a batch, the second one is padded at its last position
+    let mut y = Tensor::<f32>::new(vec![1., 2., 3., 1., 2., 3.], &vec![2, 1, 3]);
+    let mask = Tensor::<f32>::new(vec![1., 1., 1., 1., 1., 0.], &vec![2, 1, 3]);
+    masked_softmax_with_mask(&mut y, Some(&AttentionMask::Binary(&mask)));
+    assert!(y.close_to(
+        &Tensor::<f32>::new(
+            vec![0.09003057, 0.24472847, 0.66524096, 0.26894142, 0.7310586, 0.],
+            &vec![2, 1, 3]
+        ),
+        1e-5
+    ));
+
+    // the same padding as an additive mask shared by the whole batch
+    let mut y = Tensor::<f32>::new(vec![1., 2., 3., 3., 2., 1.], &vec![2, 1, 3]);
+    let mask = Tensor::<f32>::new(vec![0., 0., f32::NEG_INFINITY], &vec![1, 3]);
+    masked_softmax_with_mask(&mut y, Some(&AttentionMask::Additive(&mask)));
+    assert!(y.close_to(
+        &Tensor::<f32>::new(
+            vec![0.26894142, 0.7310586, 0., 0.7310586, 0.26894142, 0.],
+            &vec![2, 1, 3]
+        ),
+        1e-5
+    ));
+}
+
+#[test]
+fn test_masked_softmax_fully_masked_row() {
+    let mut y = Tensor::<f32>::new(vec![1., 2., 3., 4.], &vec![2, 2]);
+    let mask = Tensor::<f32>::new(vec![0., 0., 1., 0.], &vec![2, 2]);
+    masked_softmax_with_mask(&mut y, Some(&AttentionMask::Binary(&mask)));
+    assert_eq!(y.data(), &[0., 0., 1., 0.]);
+}
+
+#[test]
+fn test_masked_softmax_non_finite_rows() {
+    // a causal row whose visible part is all -inf, and one holding NaN
+    let inf = f32::NEG_INFINITY;
+    let mut y = Tensor::<f32>::new(vec![inf, inf, 7., 1., f32::NAN, 2.], &vec![2, 3]);
+    masked_softmax(&mut y);
+    assert_eq!(y.data(), &[0., 0., 0., 0., 0., 0.]);
+
+    let mut y = Tensor::<f32>::new(vec![inf, inf, 0., 0.], &vec![1, 4]);
+    masked_softmax(&mut y);
+    assert_eq!(y.data(), &[0., 0., 0.5, 0.5]);
+}
+
+#[test]
+#[should_panic(expected = "seq_len 3 > total_seq_len 2")]
+fn test_masked_softmax_seq_longer_than_total() {
+    let mut y = Tensor::<f32>::default(&vec![3, 2]);
+    masked_softmax(&mut y);
+}
+
+#[test]
+fn test_alibi_slopes() {
+    assert_eq!(
+        alibi_slopes(4).data(),
+        &[0.25, 0.0625, 0.015625, 0.00390625]
+    );
+    assert_eq!(
+        alibi_slopes(6).data(),
+        &[0.25, 0.0625, 0.015625, 0.00390625, 0.5, 0.125]
+    );
+    assert_eq!(alibi_slopes(1).data(), &[2f32.powi(-8)]);
+}
+
+#[test]
+fn test_alibi_bias() {
+    // one cached token and two new ones, so the queries sit at positions 1 and 2
+    let (n_heads, seq_len, total_seq_len) = (4, 2, 3);
+    let slopes = alibi_slopes(n_heads);
+    let mut scores = Tensor::<f32>::default(&vec![n_heads, seq_len, total_seq_len]);
+    alibi_bias(&mut scores, &slopes);
+    let s = slopes.data()[1];
+    assert_eq!(&scores.data()[6..12], &[-s, 0., s, -2. * s, -s, 0.]);
+
+    let mut scores = Tensor::<f32>::random(&vec![n_heads, seq_len, total_seq_len]);
+    alibi_bias(&mut scores, &slopes);
+    masked_softmax(&mut scores);
+    for row in scores.data().chunks(total_seq_len) {
+        assert!((row.iter().sum::<f32>() - 1.).abs() < 1e-6);
+    }
+}
+
+#[test]
+fn test_softcap() {
+    let mut y = Tensor::<f32>::new(vec![1e4, -1e4, 50., 0., 0.3], &vec![5]);
+    softcap(&mut y, 30.);
+    let y = y.data();
+    assert_eq!(y[0], 30.);
+    assert_eq!(y[1], -30.);
+    assert!(y[2] < 30. && y[2] > 27.);
+    assert_eq!(y[3], 0.);
+    // small values pass through almost unchanged
+    assert!((y[4] - 0.3).abs() < 1e-4);
+}
+
+#[test]
+fn test_concat() {
+    let a = Tensor::<f32>::new(vec![1., 2., 3., 4.], &vec![2, 2]);
+    let b = Tensor::<f32>::new(vec![5., 6.], &vec![1, 2]);
+    let mut out = Tensor::<f32>::default(&vec![3, 2]);
+    concat(&mut out, &[&a, &b], 0);
+    assert_eq!(out.data(), &[1., 2., 3., 4., 5., 6.]);
+
+    let b = Tensor::<f32>::new(vec![5., 6., 7., 8., 9., 10.], &vec![2, 3]);
+    let mut out = Tensor::<f32>::default(&vec![2, 5]);
+    concat(&mut out, &[&a, &b], 1);
+    assert_eq!(out.data(), &[1., 2., 5., 6., 7., 3., 4., 8., 9., 10.]);
+
+    // three (2, n, 2) tensors along the middle axis
+    let a = Tensor::<f32>::new((0..4).map(|v| v as f32).collect(), &vec![2, 1, 2]);
+    let b = Tensor::<f32>::new((10..18).map(|v| v as f32).collect(), &vec![2, 2, 2]);
+    let c = Tensor::<f32>::new((20..24).map(|v| v as f32).collect(), &vec![2, 1, 2]);
+    let mut out = Tensor::<f32>::default(&vec![2, 4, 2]);
+    concat(&mut out, &[&a, &b, &c], 1);
+    assert_eq!(
+        out.data(),
+        &[
+            0., 1., 10., 11., 12., 13., 20., 21., //
+            2., 3., 14., 15., 16., 17., 22., 23.,
+        ]
+    );
+}
+
+#[test]
+fn test_transpose2() {
+    // not square and not a multiple of the block size either way
+    let (m, n) = (37, 70);
+    let x = Tensor::<f32>::random(&vec![m, n]);
+    let t = transpose2(&x);
+    assert_eq!(t.shape(), &vec![n, m]);
+    for i in 0..m {
+        for j in 0..n {
+            assert_eq!(t.data()[j * m + i], x.data()[i * n + j]);
+        }
+    }
+    assert_eq!(permute(&x, &[1, 0]).data(), t.data());
+    assert_eq!(permute(&x, &[0, 1]).data(), x.data());
+}
+
+#[test]
+fn test_permute() {
+    let x = Tensor::<f32>::new((0..24).map(|v| v as f32).collect(), &vec![2, 3, 4]);
+    let y = permute(&x, &[2, 0, 1]);
+    assert_eq!(y.shape(), &vec![4, 2, 3]);
+    // y[k, i, j] = x[i, j, k]
+    assert_eq!(y.data()[(3 * 2 + 1) * 3 + 2], x.data()[(3 + 2) * 4 + 3]);
+    // permuting back with the inverse permutation gives the original
+    assert_eq!(permute(&y, &[1, 2, 0]).data(), x.data());
+
+    let x = Tensor::<f32>::random(&vec![2, 3, 4, 5]);
+    let perm = [3, 1, 0, 2];
+    let mut inverse = [0; 4];
+    for (i, &p) in perm.iter().enumerate() {
+        inverse[p] = i;
+    }
+    let y = permute(&x, &perm);
+    assert_eq!(y.shape(), &vec![5, 3, 2, 4]);
+    let back = permute(&y, &inverse);
+    assert_eq!(back.shape(), x.shape());
+    assert_eq!(back.data(), x.data());
+}
+
+#[test]
+#[should_panic(expected = "is not a permutation")]
+fn test_permute_invalid() {
+    permute(&Tensor::<f32>::default(&vec![2, 3]), &[1, 1]);
+}
+
+// cargo test --release bench_transpose2 -- --ignored --nocapture
+#[test]
+#[ignore]
+fn bench_transpose2() {
+    let x = Tensor::<f32>::random(&vec![4096, 4096]);
+    let start = std::time::Instant::now();
+    transpose2(&x);
+    println!("transpose2 4096x4096: {:?}", start.elapsed());
+}
+
+#[test]
+#[should_panic]
+fn test_concat_mismatched() {
+    let a = Tensor::<f32>::default(&vec![2, 2]);
+    let b = Tensor::<f32>::default(&vec![2, 3]);
+    let mut out = Tensor::<f32>::default(&vec![4, 2]);
+    concat(&mut out, &[&a, &b], 0);
+}
+
+#[test]
+fn test_rope_cached() {
+    // the cache only covers 4 positions up front, later start positions make it grow
+    let cache = RopeCache::new(4, 8, 10000., RopeScaling::None);
+    for start_pos in [0, 3, 17, 100] {
+        let x = Tensor::<f32>::random(&vec![5, 3, 8]);
+        let mut y = Tensor::<f32>::new(x.data().to_vec(), &vec![5, 3, 8]);
+        let mut y_cached = Tensor::<f32>::new(x.data().to_vec(), &vec![5, 3, 8]);
+        rope(&mut y, start_pos, 10000., RopeLayout::Neox);
+        rope_cached(&mut y_cached, start_pos, &cache, RopeLayout::Neox);
+        assert!(y_cached.close_to(&y, 1e-6));
+    }
+    assert!(cache.len() >= 105);
+}
+
+#[test]
+fn test_rope_partial() {
+    let (seq_len, n_heads, d, rotary_dim) = (4, 2, 8, 4);
+    let x = Tensor::<f32>::random(&vec![seq_len, n_heads, d]);
+    let mut y = Tensor::<f32>::new(x.data().to_vec(), &vec![seq_len, n_heads, d]);
+    let mut y_cached = Tensor::<f32>::new(x.data().to_vec(), &vec![seq_len, n_heads, d]);
+    rope_partial(
+        &mut y,
+        3,
+        10000.,
+        rotary_dim,
+        RopeLayout::Neox,
+        RopeScaling::None,
+    );
+    let cache = RopeCache::new(16, rotary_dim, 10000., RopeScaling::None);
+    rope_cached(&mut y_cached, 3, &cache, RopeLayout::Neox);
+    assert!(y_cached.close_to(&y, 1e-6));
+
+    // the rotated part is a full rope over a head of size rotary_dim
+    let mut rotated = Tensor::<f32>::new(
+        x.data()
+            .chunks(d)
+            .flat_map(|head| head[..rotary_dim].to_vec())
+            .collect(),
+        &vec![seq_len, n_heads, rotary_dim],
+    );
+    rope(&mut rotated, 3, 10000., RopeLayout::Neox);
+    for ((y_head, x_head), r_head) in y
+        .data()
+        .chunks(d)
+        .zip(x.data().chunks(d))
+        .zip(rotated.data().chunks(rotary_dim))
+    {
+        assert_eq!(&y_head[..rotary_dim], r_head);
+        // the pass-through part is left bitwise untouched
+        assert_eq!(
+            y_head[rotary_dim..]
+                .iter()
+                .map(|v| v.to_bits())
+                .collect::<Vec<_>>(),
+            x_head[rotary_dim..]
+                .iter()
+                .map(|v| v.to_bits())
+                .collect::<Vec<_>>()
+        );
+    }
+}
+
+#[test]
+fn test_rope_layout() {
+    // one head of size 4 at position 1, the only pair with a non-zero element rotates by
+    // 1 rad and lands in element 2 for neox but in element 1 when interleaved
+    let (sin, cos) = 1f32.sin_cos();
+    let mut neox = Tensor::<f32>::new(vec![1., 0., 0., 0.], &vec![1, 1, 4]);
+    let mut interleaved = Tensor::<f32>::new(vec![1., 0., 0., 0.], &vec![1, 1, 4]);
+    rope(&mut neox, 1, 10000., RopeLayout::Neox);
+    rope(&mut interleaved, 1, 10000., RopeLayout::Interleaved);
+    assert_eq!(neox.data(), &[cos, 0., sin, 0.]);
+    assert_eq!(interleaved.data(), &[cos, sin, 0., 0.]);
+
+    // rotating by -p is rotating by p between two reflections b -> -b, so this undoes rope
+    let cache = RopeCache::new(8, 8, 10000., RopeScaling::None);
+    for layout in [RopeLayout::Neox, RopeLayout::Interleaved] {
+        let reflect = |y: &mut Tensor<f32>| {
+            let data = unsafe { y.data_mut() };
+            for head in data.chunks_mut(8) {
+                for i in 0..4 {
+                    head[layout.pair(i, 8).1] *= -1.;
+                }
+            }
+        };
+        let x = Tensor::<f32>::random(&vec![3, 2, 8]);
+        let mut y = Tensor::<f32>::new(x.data().to_vec(), &vec![3, 2, 8]);
+        rope(&mut y, 5, 10000., layout);
+        reflect(&mut y);
+        rope(&mut y, 5, 10000., layout);
+        reflect(&mut y);
+        assert!(y.max_abs_diff(&x) < 1e-5);
+
+        let mut y = Tensor::<f32>::new(x.data().to_vec(), &vec![3, 2, 8]);
+        rope_cached(&mut y, 5, &cache, layout);
+        reflect(&mut y);
+        rope_cached(&mut y, 5, &cache, layout);
+        reflect(&mut y);
+        assert!(y.max_abs_diff(&x) < 1e-5);
+    }
+}
+
+#[test]
+fn test_rope_linear_scaling() {
+    // with factor 2 position 10 is rotated exactly like position 5 without scaling
+    let linear = RopeScaling::Linear { factor: 2. };
+    let x = Tensor::<f32>::random(&vec![1, 2, 8]);
+    let mut y = Tensor::<f32>::new(x.data().to_vec(), &vec![1, 2, 8]);
+    let mut y_scaled = Tensor::<f32>::new(x.data().to_vec(), &vec![1, 2, 8]);
+    let mut y_cached = Tensor::<f32>::new(x.data().to_vec(), &vec![1, 2, 8]);
+    rope_partial(&mut y, 5, 10000., 8, RopeLayout::Neox, RopeScaling::None);
+    rope_partial(&mut y_scaled, 10, 10000., 8, RopeLayout::Neox, linear);
+    rope_cached(
+        &mut y_cached,
+        10,
+        &RopeCache::new(16, 8, 10000., linear),
+        RopeLayout::Neox,
+    );
+    assert!(y_scaled.close_to(&y, 1e-6));
+    assert!(y_cached.close_to(&y, 1e-6));
+    assert_eq!(linear.max_positions(512), 1024);
+}
+
+#[test]
+fn test_rope_dynamic_ntk() {
+    let ntk = RopeScaling::DynamicNtk {
+        factor: 2.,
+        max_position_embeddings: 512,
+    };
+    // theta * (2 * seq_len / 512 - 1)^(8 / 6)
+    assert_eq!(ntk.effective_theta(10000., 100, 8), 10000.);
+    assert_eq!(ntk.effective_theta(10000., 512, 8), 10000.);
+    assert!(crate::tensor::float_eq(
+        &ntk.effective_theta(10000., 768, 8),
+        &25198.42,
+        1e-6
+    ));
+    assert!(crate::tensor::float_eq(
+        &ntk.effective_theta(10000., 1024, 8),
+        &43267.49,
+        1e-6
+    ));
+
+    // within the trained context nothing changes
+    let cache = RopeCache::new(16, 8, 10000., ntk);
+    assert!(cache.for_seq_len(512).is_none());
+    let x = Tensor::<f32>::random(&vec![4, 2, 8]);
+    let mut y = Tensor::<f32>::new(x.data().to_vec(), &vec![4, 2, 8]);
+    let mut y_ntk = Tensor::<f32>::new(x.data().to_vec(), &vec![4, 2, 8]);
+    rope(&mut y, 7, 10000., RopeLayout::Neox);
+    rope_cached(&mut y_ntk, 7, &cache, RopeLayout::Neox);
+    assert_eq!(y_ntk.data(), y.data());
+
+    // past it, re-rotating keys rotated with the old tables matches rotating them afresh
+    let long = cache.for_seq_len(768).unwrap();
+    let mut y_long = Tensor::<f32>::new(x.data().to_vec(), &vec![4, 2, 8]);
+    rope_cached(&mut y_long, 7, &long, RopeLayout::Neox);
+    rope_rerotate(&mut y_ntk, 7, &cache, &long, RopeLayout::Neox);
+    assert!(y_ntk.max_abs_diff(&y_long) < 1e-5);
+}
+
+#[test]
+fn test_rope_reposition() {
+    // keys rotated at 20.. and moved to 3.. match keys rotated at 3.. directly
+    let cache = RopeCache::new(8, 8, 10000., RopeScaling::None);
+    let x = Tensor::<f32>::random(&vec![4, 2, 8]);
+    for layout in [RopeLayout::Neox, RopeLayout::Interleaved] {
+        let mut y = Tensor::<f32>::new(x.data().to_vec(), &vec![4, 2, 8]);
+        let mut expected = Tensor::<f32>::new(x.data().to_vec(), &vec![4, 2, 8]);
+        rope_cached(&mut y, 20, &cache, layout);
+        rope_reposition(&mut y, 20, 3, &cache, layout);
+        rope_cached(&mut expected, 3, &cache, layout);
+        assert!(y.max_abs_diff(&expected) < 1e-5);
+    }
+}
+
+#[test]
+fn test_rope_yarn() {
+    let yarn = RopeScaling::Yarn {
+        factor: 4.,
+        original_max_position_embeddings: 2048,
+        beta_fast: 32.,
+        beta_slow: 1.,
+        attention_factor: 0.1 * 4f32.ln() + 1.,
+    };
+    // d_head 16: the cutoffs land on pairs 2 and 6, so pairs 0..=2 extrapolate, pairs 6
+    // and 7 interpolate by 1 / 4 and the ones in between follow the ramp
+    let expected = [1., 1., 1., 0.8125, 0.625, 0.4375, 0.25, 0.25];
+    for (i, m) in expected.iter().enumerate() {
+        assert!(crate::tensor::float_eq(
+            &yarn.frequency_multiplier(i, 16, 10000.),
+            m,
+            1e-6
+        ));
+    }
+    assert!(crate::tensor::float_eq(
+        &yarn.attention_factor(),
+        &1.1386294,
+        1e-6
+    ));
+    assert_eq!(yarn.max_positions(2048), 8192);
+
+    // the cached tables use the same frequencies
+    let x = Tensor::<f32>::random(&vec![3, 2, 16]);
+    let mut y = Tensor::<f32>::new(x.data().to_vec(), &vec![3, 2, 16]);
+    let mut y_cached = Tensor::<f32>::new(x.data().to_vec(), &vec![3, 2, 16]);
+    rope_partial(&mut y, 100, 10000., 16, RopeLayout::Neox, yarn);
+    rope_cached(
+        &mut y_cached,
+        100,
+        &RopeCache::new(8, 16, 10000., yarn),
+        RopeLayout::Neox,
+    );
+    assert_eq!(y_cached.data(), y.data());
+}
+
+#[test]
+fn test_masked_softmax_window() {
+    // prefill: seq_len == total_seq_len, each row sees at most the last 2 positions
+    let mut y = Tensor::<f32>::new(vec![0.; 9], &vec![3, 3]);
+    masked_softmax_window(&mut y, 2);
+    assert_eq!(y.data(), &[1., 0., 0., 0.5, 0.5, 0., 0., 0.5, 0.5]);
+
+    // decode: one query at position 5 of a 6 token cache with a window of 3
+    let mut y = Tensor::<f32>::new(vec![0.; 6], &vec![1, 6]);
+    masked_softmax_window(&mut y, 3);
+    let third = 1. / 3.;
+    assert_eq!(y.data(), &[0., 0., 0., third, third, third]);
+
+    // a window larger than the sequence is the plain causal mask
+    let x = Tensor::<f32>::random(&vec![2, 3, 4]);
+    let mut y = Tensor::<f32>::new(x.data().to_vec(), &vec![2, 3, 4]);
+    let mut y_causal = Tensor::<f32>::new(x.data().to_vec(), &vec![2, 3, 4]);
+    masked_softmax_window(&mut y, 16);
+    masked_softmax(&mut y_causal);
+    assert_eq!(y.data(), y_causal.data());
+}
+
+#[test]
+fn test_masked_softmax_sink() {
+    // decode at position 7 of an 8 token cache: 2 sinks plus a window of 2
+    let mut y = Tensor::<f32>::new(vec![0.; 8], &vec![1, 8]);
+    masked_softmax_sink(&mut y, 2, 2);
+    assert_eq!(y.data(), &[0.25, 0.25, 0., 0., 0., 0., 0.25, 0.25]);
+
+    // prefill: the sinks only show once the window has moved past them
+    let mut y = Tensor::<f32>::new(vec![0.; 16], &vec![4, 4]);
+    masked_softmax_sink(&mut y, 1, 2);
+    let third = 1. / 3.;
+    #[rustfmt::skip]
+    let expected = [
+        1., 0., 0., 0.,
+        0.5, 0.5, 0., 0.,
+        third, third, third, 0.,
+        third, 0., third, third,
+    ];
+    assert_eq!(y.data(), &expected);
+
+    // without sinks it is the sliding window
+    let x = Tensor::<f32>::random(&vec![2, 3, 6]);
+    let mut y = Tensor::<f32>::new(x.data().to_vec(), &vec![2, 3, 6]);
+    let mut y_window = Tensor::<f32>::new(x.data().to_vec(), &vec![2, 3, 6]);
+    masked_softmax_sink(&mut y, 0, 3);
+    masked_softmax_window(&mut y_window, 3);
+    assert_eq!(y.data(), y_window.data());
+}
+
+#[test]
+fn test_masked_softmax_matches_serial() {
+    // rows run in parallel with the parallel feature, the serial loop below has to give
+    // bit-identical results since the arithmetic of every row is the same
+    let (batch, seq_len, total_seq_len) = (8, 512, 512);
+    let x: Vec<f32> = (0..batch * seq_len * total_seq_len)
+        .map(|_| rand::random::<f32>() * 10.)
+        .collect();
+    let mut y = Tensor::new(x.clone(), &vec![batch, seq_len, total_seq_len]);
+    masked_softmax(&mut y);
+    let mut expected = x;
+    for (r, row) in expected.chunks_mut(total_seq_len).enumerate() {
+        softmax_row(row, 0..total_seq_len - seq_len + r % seq_len + 1);
+    }
+    assert_eq!(y.data(), &expected[..]);
+}
+
+#[test]
+fn test_rms_norm() {
+    let mut y = Tensor::<f32>::new(vec![1., 2., 3., 4.], &vec![2, 2]);
+    let x = Tensor::<f32>::new(vec![1., 2., 3., 4.], &vec![2, 2]);
+    let w = Tensor::<f32>::new(vec![1., 2.], &vec![2]);
+    rms_norm(&mut y, &x, &w, 1e-6);
+    assert!(y.close_to(
+        &Tensor::<f32>::new(
+            vec![0.6324554, 2.5298216, 0.8485281, 2.2627416],
+            &vec![2, 2]
+        ),
+        1e-3
+    ));
+}
+
+#[test]
+fn test_rms_norm_rows() {
+    // every row of a (2, 3) activation is normalized on its own
+    let mut y = Tensor::<f32>::default(&vec![2, 3]);
+    let x = Tensor::<f32>::new(vec![1., 2., 3., 4., 5., 6.], &vec![2, 3]);
+    let w = Tensor::<f32>::new(vec![1., 1., 2.], &vec![3]);
+    rms_norm(&mut y, &x, &w, 1e-6);
+    // rms of the rows: sqrt(14 / 3) and sqrt(77 / 3)
+    assert!(y.close_to(
+        &Tensor::<f32>::new(
+            vec![0.46291, 0.92582, 2.77746, 0.789542, 0.9869275, 2.368626],
+            &vec![2, 3]
+        ),
+        1e-5
+    ));
+}
+
+#[test]
+fn test_rms_norm_gemma() {
+    // a zero delta is a unit scale, where the plain rms_norm would output zeros
+    let x = Tensor::<f32>::new(vec![1., 2., 3., 4., 5., 6.], &vec![2, 3]);
+    let w = Tensor::<f32>::default(&vec![3]);
+    let mut y = Tensor::<f32>::default(&vec![2, 3]);
+    rms_norm_gemma(&mut y, &x, &w, 1e-6);
+    let rms = [(14f32 / 3. + 1e-6).sqrt(), (77f32 / 3. + 1e-6).sqrt()];
+    let expected: Vec<f32> = x
+        .data()
+        .chunks(3)
+        .zip(rms)
+        .flat_map(|(row, rms)| row.iter().map(move |v| v / rms))
+        .collect();
+    assert!(y.close_to(&Tensor::<f32>::new(expected, &vec![2, 3]), 1e-6));
+
+    rms_norm(&mut y, &x, &w, 1e-6);
+    assert_eq!(y.data(), &[0.; 6]);
+}
+
+#[test]
+fn test_qk_rms_norm() {
+    // (seq 2, heads 2, d_head 4), every head vector normalized with the same weight
+    let mut y = Tensor::<f32>::new(
+        vec![
+            1., 1., 1., 1., 2., 2., 2., 2., 1., -1., 1., -1., 3., 0., 0., 4.,
+        ],
+        &vec![2, 2, 4],
+    );
+    let w = Tensor::<f32>::new(vec![1., 2., 0.5, 1.], &vec![4]);
+    qk_rms_norm(&mut y, &w, 0.);
+    // rms of the four vectors: 1, 2, 1, sqrt(25 / 4) = 2.5
+    assert!(y.close_to(
+        &Tensor::<f32>::new(
+            vec![1., 2., 0.5, 1., 1., 2., 0.5, 1., 1., -2., 0.5, -1., 1.2, 0., 0., 1.6],
+            &vec![2, 2, 4]
+        ),
+        1e-6
+    ));
+}
+
+#[test]
+fn test_layer_norm() {
+    let mut y = Tensor::<f32>::default(&vec![2, 3]);
+    let x = Tensor::<f32>::new(vec![1., 2., 3., 4., 4., 4.], &vec![2, 3]);
+    let w = Tensor::<f32>::new(vec![1., 2., 0.5], &vec![3]);
+    let b = Tensor::<f32>::new(vec![0.5, 0., -1.], &vec![3]);
+    layer_norm(&mut y, &x, &w, &b, 1e-5);
+    assert!(y.close_to(
+        &Tensor::<f32>::new(vec![-0.724737, 0., -0.387632, 0.5, 0., -1.], &vec![2, 3]),
+        1e-3
+    ));
+
+    // with a zero-mean row and no bias layer_norm reduces to rms_norm
+    let x = Tensor::<f32>::new(vec![1., -2., 1.], &vec![1, 3]);
+    let w = Tensor::<f32>::new(vec![0.5, 1., 2.], &vec![3]);
+    let b = Tensor::<f32>::default(&vec![3]);
+    let mut y = Tensor::<f32>::default(&vec![1, 3]);
+    let mut y_rms = Tensor::<f32>::default(&vec![1, 3]);
+    layer_norm(&mut y, &x, &w, &b, 1e-6);
+    rms_norm(&mut y_rms, &x, &w, 1e-6);
+    assert!(y.close_to(&y_rms, 1e-5));
+}
+
+#[test]
+fn test_matmul_transb() {
+    let mut c = Tensor::<f32>::new(vec![1., 2., 3., 4.], &vec![2, 2]);
+    let a = Tensor::<f32>::new(vec![1., 2., 3., 4., 5., 6.], &vec![2, 3]);
+    let b = Tensor::<f32>::new(vec![1., 2., 3., 4., 5., 6.], &vec![2, 3]);
+    matmul_transb(&mut c, 1., &a, &b, 1.);
+    assert!(c.close_to(
+        &Tensor::<f32>::new(vec![15., 34., 35., 81.], &vec![2, 2]),
+        1e-3
+    ));
+}
+
+#[test]
+fn test_matmul_transb_bias() {
+    let mut c = Tensor::<f32>::new(vec![1., 2., 3., 4., 5., 6.], &vec![2, 3]);
+    let a = Tensor::<f32>::new(vec![1., 2., 3., 4.], &vec![2, 2]);
+    let b = Tensor::<f32>::new(vec![1., 0., 0., 1., 1., 1.], &vec![3, 2]);
+    let bias = Tensor::<f32>::new(vec![0.5, -1., 2.], &vec![3]);
+    matmul_transb_bias(&mut c, 1., &a, &b, 1., Some(&bias));
+    assert!(c.close_to(
+        &Tensor::<f32>::new(vec![2.5, 3., 8., 7.5, 8., 15.], &vec![2, 3]),
+        1e-6
+    ));
+
+    let a = Tensor::<f32>::random(&vec![4, 5]);
+    let b = Tensor::<f32>::random(&vec![3, 5]);
+    let mut c = Tensor::<f32>::default(&vec![4, 3]);
+    let mut c_ref = Tensor::<f32>::default(&vec![4, 3]);
+    matmul_transb_bias(&mut c, 0., &a, &b, 1., None);
+    matmul_transb(&mut c_ref, 0., &a, &b, 1.);
+    assert_eq!(c.data(), c_ref.data());
+}
+
+#[test]
+#[should_panic]
+fn test_matmul_transb_bias_wrong_length() {
+    let mut c = Tensor::<f32>::default(&vec![2, 3]);
+    let a = Tensor::<f32>::default(&vec![2, 2]);
+    let b = Tensor::<f32>::default(&vec![3, 2]);
+    let bias = Tensor::<f32>::default(&vec![2]);
+    matmul_transb_bias(&mut c, 0., &a, &b, 1., Some(&bias));
+}
+
+#[test]
+fn test_matmul() {
+    let mut c = Tensor::<f32>::new(vec![1., 2., 3., 4.], &vec![2, 2]);
+    let a = Tensor::<f32>::new(vec![1., 2., 3., 4., 5., 6.], &vec![2, 3]);
+    let b = Tensor::<f32>::new(vec![1., 4., 2., 5., 3., 6.], &vec![3, 2]);
+    matmul(&mut c, 1., &a, &b, 1.);
+    assert!(c.close_to(
+        &Tensor::<f32>::new(vec![15., 34., 35., 81.], &vec![2, 2]),
         1e-3
     ));
 }
+
+#[test]
+fn test_matmul_against_transb() {
+    let (m, n, k) = (5, 7, 9);
+    let a = Tensor::<f32>::random(&vec![m, k]);
+    let b = Tensor::<f32>::random(&vec![k, n]);
+    let mut b_t = Tensor::<f32>::default(&vec![n, k]);
+    {
+        let dst = unsafe { b_t.data_mut() };
+        for l in 0..k {
+            for j in 0..n {
+                dst[j * k + l] = b.data()[l * n + j];
+            }
+        }
+    }
+    let c_init = Tensor::<f32>::random(&vec![m, n]);
+    let mut c = Tensor::<f32>::new(c_init.data().to_vec(), &vec![m, n]);
+    let mut c_ref = Tensor::<f32>::new(c_init.data().to_vec(), &vec![m, n]);
+    matmul(&mut c, 0.5, &a, &b, 2.);
+    matmul_transb(&mut c_ref, 0.5, &a, &b_t, 2.);
+    assert!(c.close_to(&c_ref, 1e-5));
+}
+
+#[test]
+fn test_matvec_transb() {
+    for &(n, k) in &[(1, 1), (5, 3), (70, 33), (130, 256), (3, 1001)] {
+        let a = Tensor::<f32>::random(&vec![1, k]);
+        let b = Tensor::<f32>::random(&vec![n, k]);
+        let bias = Tensor::<f32>::random(&vec![n]);
+        let c_init = Tensor::<f32>::random(&vec![1, n]);
+        let mut c = Tensor::<f32>::new(c_init.data().to_vec(), &vec![1, n]);
+        let mut c_ref = Tensor::<f32>::new(c_init.data().to_vec(), &vec![1, n]);
+        matmul_transb_bias(&mut c, 0.5, &a, &b, 2., Some(&bias));
+        let mut c_ref_tiled = Tensor::<f32>::default(&vec![1, n]);
+        matmul_transb_kernel(
+            unsafe { c_ref_tiled.data_mut() },
+            0.,
+            a.data(),
+            b.data(),
+            1.,
+            None,
+            n,
+            k,
+        );
+        matmul_transb_naive(&mut c_ref, 0.5, &a, &b, 2.);
+        let expected: Vec<f32> = c_ref
+            .data()
+            .iter()
+            .zip(bias.data())
+            .map(|(c, b)| c + b)
+            .collect();
+        assert!(
+            c.close_to(&Tensor::new(expected, &vec![1, n]), 1e-5),
+            "shape {:?}",
+            (n, k)
+        );
+        let mut c_gemv = Tensor::<f32>::default(&vec![1, n]);
+        matmul_transb(&mut c_gemv, 0., &a, &b, 1.);
+        assert!(c_gemv.close_to(&c_ref_tiled, 1e-5), "shape {:?}", (n, k));
+    }
+}
+
+#[test]
+fn test_matmul_transb_tiled() {
+    // shapes that do not divide the tile sizes, plus ones spanning several tiles
+    for &(m, n, k) in &[
+        (7, 13, 31),
+        (1, 1, 1),
+        (65, 3, 257),
+        (3, 130, 5),
+        (70, 66, 600),
+    ] {
+        let a = Tensor::<f32>::random(&vec![m, k]);
+        let b = Tensor::<f32>::random(&vec![n, k]);
+        let c_init: Vec<f32> = (0..m * n).map(|_| rand::random()).collect();
+        let mut c = Tensor::<f32>::new(c_init.clone(), &vec![m, n]);
+        let mut c_ref = Tensor::<f32>::new(c_init, &vec![m, n]);
+        matmul_transb(&mut c, 0.5, &a, &b, 2.);
+        matmul_transb_naive(&mut c_ref, 0.5, &a, &b, 2.);
+        if cfg!(feature = "simd")
+            || cfg!(feature = "gemm-backend")
+            || cfg!(feature = "accurate-sum")
+        {
+            assert!(c.close_to(&c_ref, 1e-5), "shape {:?}", (m, n, k));
+        } else {
+            assert_eq!(c.data(), c_ref.data(), "shape {:?}", (m, n, k));
+        }
+    }
+}
+
+#[test]
+fn test_dot_kahan() {
+    // 1 followed by a million terms of 1e-8, each below half an ulp of the running sum
+    let n = 1_000_000;
+    let mut x = vec![1f32];
+    x.extend(std::iter::repeat_n(1e-8f32, n));
+    let ones = vec![1f32; x.len()];
+    let exact: f64 = x.iter().map(|&v| v as f64).sum();
+
+    let kahan = dot_acc_kahan(0., &x, &ones) as f64;
+    assert!((kahan - exact).abs() <= 1e-6 * exact, "{kahan} vs {exact}");
+    // the plain sum never moves off 1 and is off by the whole 1e-2
+    let naive = dot_acc_scalar(0., &x, &ones) as f64;
+    assert_eq!(naive, 1.);
+    assert!((naive - exact).abs() > 9e-3);
+}
+
+#[cfg(feature = "simd")]
+#[test]
+fn test_dot_simd() {
+    for len in [1, 7, 9, 31, 100, 1023] {
+        let x: Vec<f32> = (0..len).map(|_| rand::random::<f32>() - 0.5).collect();
+        let y: Vec<f32> = (0..len).map(|_| rand::random::<f32>() - 0.5).collect();
+        let simd = dot_acc_simd(0., &x, &y);
+        let scalar = dot_acc_scalar(0., &x, &y);
+        assert!(
+            (simd - scalar).abs() <= 1e-5 * (1. + scalar.abs()),
+            "len {len}"
+        );
+    }
+}
+
+#[test]
+fn test_matmul_transb_batched() {
+    let (m, n, k) = (3, 5, 4);
+    // (batch, batch_b): no broadcast, and two A batches per B batch as in grouped-query attention
+    for (batch, batch_b) in [(2, 2), (4, 2), (3, 1)] {
+        let a = Tensor::<f32>::random(&vec![batch, m, k]);
+        let b = Tensor::<f32>::random(&vec![batch_b, n, k]);
+        let c_init: Vec<f32> = (0..batch * m * n).map(|_| rand::random()).collect();
+        let mut c = Tensor::<f32>::new(c_init.clone(), &vec![batch, m, n]);
+        matmul_transb_batched(&mut c, 0.5, &a, &b, 2.);
+        for i in 0..batch {
+            let a_i = a.slice(i * m * k, &vec![m, k]);
+            let b_i = b.slice(i / (batch / batch_b) * n * k, &vec![n, k]);
+            let mut c_i = Tensor::<f32>::new(c_init[i * m * n..][..m * n].to_vec(), &vec![m, n]);
+            matmul_transb(&mut c_i, 0.5, &a_i, &b_i, 2.);
+            assert_eq!(c.slice(i * m * n, &vec![m, n]).data(), c_i.data());
+        }
+    }
+}
+
+#[test]
+#[should_panic]
+fn test_matmul_transb_batched_incompatible() {
+    let a = Tensor::<f32>::default(&vec![3, 2, 2]);
+    let b = Tensor::<f32>::default(&vec![2, 2, 2]);
+    let mut c = Tensor::<f32>::default(&vec![3, 2, 2]);
+    matmul_transb_batched(&mut c, 0., &a, &b, 1.);
+}
+
+// cargo test --release [--features parallel,simd|gemm-backend] bench_matmul_transb -- --ignored --nocapture
+#[test]
+#[ignore]
+fn bench_matmul_transb() {
+    let n = 2048;
+    let a = Tensor::<f32>::random(&vec![n, n]);
+    let b = Tensor::<f32>::random(&vec![n, n]);
+    let mut c = Tensor::<f32>::default(&vec![n, n]);
+    let start = std::time::Instant::now();
+    matmul_transb(&mut c, 0., &a, &b, 1.);
+    println!(
+        "matmul_transb {n}x{n}x{n} (backend: {MATMUL_BACKEND}, parallel: {}, simd: {}): {:?}",
+        cfg!(feature = "parallel"),
+        cfg!(feature = "simd"),
+        start.elapsed()
+    );
+}
+
+#[test]
+fn test_rms_norm_matmul_transb() {
+    let x = Tensor::<f32>::random(&vec![5, 130]);
+    let norm_w = Tensor::<f32>::random(&vec![130]);
+    let proj_w = Tensor::<f32>::random(&vec![70, 130]);
+    for unit_offset in [false, true] {
+        let mut normed = Tensor::<f32>::default(&vec![5, 130]);
+        let mut expected = Tensor::<f32>::default(&vec![5, 70]);
+        checked_rms_norm(&mut normed, &x, &norm_w, 1e-6, unit_offset).unwrap();
+        matmul_transb(&mut expected, 0., &normed, &proj_w, 0.5);
+        let mut c = Tensor::<f32>::random(&vec![5, 70]);
+        checked_rms_norm_matmul_transb(&mut c, &x, &norm_w, &proj_w, 1e-6, unit_offset, 0.5)
+            .unwrap();
+        assert!(c.close_to(&expected, 1e-5));
+    }
+    let mut c = Tensor::<f32>::default(&vec![5, 70]);
+    assert!(matches!(
+        checked_rms_norm_matmul_transb(&mut c, &x, &proj_w, &proj_w, 1e-6, false, 1.),
+        Err(OperatorError::ShapeMismatch { op: "rms_norm", .. })
+    ));
+}
+
+#[test]
+fn test_rms_norm_swiglu_rows() {
+    // fewer rows than threads, and odd counts, give the same results as a serial loop
+    for rows in [1, 3, 5, 7] {
+        let x = Tensor::<f32>::random(&vec![rows, 37]);
+        let w = Tensor::<f32>::random(&vec![37]);
+        let mut y = Tensor::<f32>::default(&vec![rows, 37]);
+        rms_norm(&mut y, &x, &w, 1e-6);
+        let mut expected = vec![0.; rows * 37];
+        for (y_row, x_row) in expected.chunks_mut(37).zip(x.data().chunks(37)) {
+            let rms = (sum_squares(x_row) / 37. + 1e-6).sqrt();
+            for ((y_i, x_i), w_i) in y_row.iter_mut().zip(x_row).zip(w.data()) {
+                *y_i = w_i * x_i / rms;
+            }
+        }
+        assert_eq!(y.data(), &expected[..]);
+
+        // spans several ELEMENTWISE_CHUNKs without filling the last one
+        let len = rows * 10007;
+        let x = Tensor::<f32>::random(&vec![len]);
+        let up = Tensor::<f32>::random(&vec![len]);
+        let mut y = Tensor::new(up.data().to_vec(), &vec![len]);
+        swiglu(&mut y, &x);
+        let expected: Vec<f32> = up
+            .data()
+            .iter()
+            .zip(x.data())
+            .map(|(y_i, x_i)| y_i * silu_scalar(*x_i))
+            .collect();
+        assert_eq!(y.data(), &expected[..]);
+    }
+}
+
+// cargo test --release --features parallel bench_rms_norm_swiglu -- --ignored --nocapture
+#[test]
+#[ignore]
+fn bench_rms_norm_swiglu() {
+    let (rows, n) = (2048, 4096);
+    let x = Tensor::<f32>::random(&vec![rows, n]);
+    let w = Tensor::<f32>::random(&vec![n]);
+    let mut y = Tensor::<f32>::random(&vec![rows, n]);
+    // the first call also starts the rayon pool
+    rms_norm(&mut y, &x, &w, 1e-6);
+    let start = std::time::Instant::now();
+    rms_norm(&mut y, &x, &w, 1e-6);
+    let rms_norm_time = start.elapsed();
+    let start = std::time::Instant::now();
+    swiglu(&mut y, &x);
+    println!(
+        "{rows}x{n} (parallel: {}): rms_norm {rms_norm_time:?}, swiglu {:?}",
+        cfg!(feature = "parallel"),
+        start.elapsed()
+    );
+}
+
+#[test]
+fn test_topk() {
+    let x = Tensor::<f32>::new(vec![0.5, 2., -1., 2., 3., 0.5, 2.], &vec![7]);
+    // the three 2s tie and come out by token id
+    assert_eq!(topk(&x, 4), (vec![3., 2., 2., 2.], vec![4, 1, 3, 6]));
+    assert_eq!(topk(&x, 1), (vec![3.], vec![4]));
+    assert_eq!(topk(&x, 0), (vec![], vec![]));
+    // k beyond the length returns everything, sorted
+    assert_eq!(
+        topk(&x, 100),
+        (
+            vec![3., 2., 2., 2., 0.5, 0.5, -1.],
+            vec![4, 1, 3, 6, 0, 5, 2]
+        )
+    );
+}
+
+#[test]
+fn test_random_sample_distribution() {
+    use rand::SeedableRng;
+    let p = [0.1f32, 0.2, 0.3, 0.4];
+    let x = Tensor::new(p.iter().map(|p| p.ln()).collect(), &vec![4]);
+    let normalize = |w: [f32; 4]| w.map(|v| v / w.iter().sum::<f32>());
+    let sq = p.map(|p| p * p);
+    // (top_k, top_p, temperature) and the resulting distribution
+    let cases = [
+        (4, 1., 1., p),
+        (2, 1., 1., normalize([0., 0., 0.3, 0.4])),
+        // 0.4 + 0.3 < 0.75 <= 0.4 + 0.3 + 0.2
+        (4, 0.75, 1., normalize([0., 0.2, 0.3, 0.4])),
+        (4, 1., 2., normalize(p.map(f32::sqrt))),
+        // after top-3 at temperature 0.5 probabilities are (0.04, 0.09, 0.16) / 0.29,
+        // the first two reach 0.8 only together
+        (3, 0.8, 0.5, normalize([0., 0., sq[2], sq[3]])),
+    ];
+    let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+    let draws = 100_000;
+    for (top_k, top_p, temperature, expected) in cases {
+        let mut counts = [0usize; 4];
+        for _ in 0..draws {
+            counts[random_sample(&x, top_p, top_k, temperature, 0., 1., &mut rng) as usize] += 1;
+        }
+        for (count, expected) in counts.iter().zip(expected) {
+            let freq = *count as f32 / draws as f32;
+            assert!(
+                (freq - expected).abs() < 0.01,
+                "top_k {top_k}, top_p {top_p}, temperature {temperature}: {counts:?}"
+            );
+        }
+    }
+}
+
+#[test]
+fn test_random_sample_large_vocab() {
+    // the nucleus of 150 tokens takes three rounds of the chunked search, the scratch
+    // buffer is reused across all draws
+    use rand::SeedableRng;
+    let n = 400;
+    // token i has weight 1 for i < 150 and 1e-3 otherwise, so the 150 hold 0.9983 and
+    // 149 of them 0.9917 of the probability
+    let x = Tensor::new(
+        (0..n)
+            .map(|i| if i < 150 { 0. } else { -(1e3f32).ln() })
+            .collect(),
+        &vec![n],
+    );
+    let mut rng = rand::rngs::StdRng::seed_from_u64(1);
+    let mut scratch = SampleScratch::default();
+    let draws = 20_000;
+    let mut counts = [0usize; 3];
+    for _ in 0..draws {
+        let tok = random_sample_with(&x, 0.995, n as u32, 1., 0., 1., &mut rng, &mut scratch);
+        assert!(tok < 150);
+        counts[tok as usize / 50] += 1;
+    }
+    for count in counts {
+        assert!(
+            (count as f32 / draws as f32 - 1. / 3.).abs() < 0.015,
+            "{counts:?}"
+        );
+    }
+    // top_k alone never sorts, it has to keep the same distribution
+    let p = [0.1f32, 0.2, 0.3, 0.4];
+    let x = Tensor::new(p.iter().map(|p| p.ln()).collect(), &vec![4]);
+    let mut counts = [0usize; 4];
+    for _ in 0..draws {
+        counts[random_sample_with(&x, 1., 3, 1., 0., 1., &mut rng, &mut scratch) as usize] += 1;
+    }
+    assert_eq!(counts[0], 0);
+    for (count, p) in counts.iter().zip(p).skip(1) {
+        assert!(
+            (*count as f32 / draws as f32 - p / 0.9).abs() < 0.015,
+            "{counts:?}"
+        );
+    }
+}
+
+#[test]
+fn test_random_sample_min_p() {
+    use rand::SeedableRng;
+    // the top token has probability 0.5, min_p 0.1 keeps what has at least 0.05
+    let p = [0.5f32, 0.2, 0.1, 0.06, 0.049, 0.051, 0.04];
+    let x = Tensor::new(p.iter().map(|p| p.ln()).collect(), &vec![7]);
+    let mut rng = rand::rngs::StdRng::seed_from_u64(2);
+    let mut seen = [false; 7];
+    for _ in 0..10_000 {
+        seen[random_sample(&x, 1., 7, 1., 0.1, 1., &mut rng) as usize] = true;
+    }
+    assert_eq!(seen, [true, true, true, true, false, true, false]);
+    // intersected with top_p: 0.5 + 0.2 + 0.1 reach 0.75
+    let mut seen = [false; 7];
+    for _ in 0..10_000 {
+        seen[random_sample(&x, 0.75, 7, 1., 0.1, 1., &mut rng) as usize] = true;
+    }
+    assert_eq!(seen, [true, true, true, false, false, false, false]);
+    // min_p 1 only keeps the most likely token
+    for _ in 0..1000 {
+        assert_eq!(random_sample(&x, 1., 7, 1.5, 1., 1., &mut rng), 0);
+    }
+}
+
+#[test]
+fn test_repetition_penalty() {
+    let mut logits = Tensor::<f32>::new(vec![2., -1., 3., 0.5], &vec![4]);
+    let mut probs = Tensor::new(logits.data().to_vec(), &vec![4]);
+    softmax(&mut probs);
+    // token 2 fills the context, token 1 is in it once, 9 is not a token
+    repetition_penalty(&mut logits, &[2, 2, 1, 2, 2, 9, 2], 2.);
+    assert_eq!(logits.data(), &[2., -2., 1.5, 0.5]);
+    let mut penalized = Tensor::new(logits.data().to_vec(), &vec![4]);
+    softmax(&mut penalized);
+    assert!(penalized.data()[2] < probs.data()[2] / 2.);
+    assert!(penalized.data()[1] < probs.data()[1]);
+    assert!(penalized.data()[0] > probs.data()[0]);
+}
+
+#[test]
+fn test_frequency_presence_penalty() {
+    // tokens 0, 1 and 2 were generated 0, 1 and 5 times
+    let counts = HashMap::from([(0, 0), (1, 1), (2, 5), (7, 2)]);
+    let mut logits = Tensor::<f32>::new(vec![1., 1., 1., 1.], &vec![4]);
+    frequency_presence_penalty(&mut logits, &counts, 0.5, 0.25);
+    assert_eq!(logits.data(), &[1., 0.25, -1.75, 1.]);
+    // either one alone
+    let mut logits = Tensor::<f32>::new(vec![1., 1., 1., 1.], &vec![4]);
+    frequency_presence_penalty(&mut logits, &counts, 0., 0.25);
+    assert_eq!(logits.data(), &[1., 0.75, 0.75, 1.]);
+    let mut logits = Tensor::<f32>::new(vec![1., 1., 1., 1.], &vec![4]);
+    frequency_presence_penalty(&mut logits, &counts, 0.5, 0.);
+    assert_eq!(logits.data(), &[1., 0.5, -1.5, 1.]);
+    // after the multiplicative repetition penalty, as generate applies them
+    let mut logits = Tensor::<f32>::new(vec![2., 2., -2., 1.], &vec![4]);
+    repetition_penalty(&mut logits, &[1, 2], 2.);
+    frequency_presence_penalty(&mut logits, &counts, 0.5, 0.25);
+    assert_eq!(logits.data(), &[2., 0.25, -6.75, 1.]);
+}
+
+#[test]
+fn test_no_repeat_ngram() {
+    // 1 2 3 1 2 is about to repeat the trigram 1 2 3
+    let mut ngrams = NoRepeatNgram::new(3);
+    for token in [1, 2, 3, 1, 2] {
+        ngrams.push(token);
+    }
+    let mut logits = Tensor::<f32>::new(vec![0., 1., 2., 5., 4.], &vec![5]);
+    ngrams.ban(&mut logits);
+    assert_eq!(logits.data()[3], f32::NEG_INFINITY);
+    assert_eq!(argmax(&logits).data(), &[4]);
+    // 2 4 has never been followed by anything
+    ngrams.push(4);
+    let mut logits = Tensor::<f32>::new(vec![0., 1., 2., 5., 4.], &vec![5]);
+    ngrams.ban(&mut logits);
+    assert_eq!(argmax(&logits).data(), &[3]);
+
+    // unigrams ban everything seen
+    let mut ngrams = NoRepeatNgram::new(1);
+    ngrams.push(3);
+    ngrams.push(0);
+    let mut logits = Tensor::<f32>::new(vec![0., 1., 2., 5., 4.], &vec![5]);
+    ngrams.ban(&mut logits);
+    assert_eq!(
+        logits.data(),
+        &[f32::NEG_INFINITY, 1., 2., f32::NEG_INFINITY, 4.]
+    );
+}
+
+#[test]
+fn test_logprobs() {
+    let x = Tensor::new(vec![1., 3., f32::NEG_INFINITY, 2., 3.], &vec![5]);
+    let (logprob, top) = logprobs(&x, 3, 3);
+    let expected = |v: f32| v - (1f32.exp() + 2. * 3f32.exp() + 2f32.exp()).ln();
+    assert!((logprob - expected(2.)).abs() < 1e-6);
+    // ties in id order
+    assert_eq!(top.iter().map(|t| t.0).collect::<Vec<_>>(), [1, 4, 3]);
+    assert!((top[0].1 - expected(3.)).abs() < 1e-6);
+    // the probabilities of the whole vocab sum to 1, a banned token has none
+    let (_, all) = logprobs(&x, 0, 10);
+    assert_eq!(all.len(), 5);
+    assert!((all.iter().map(|t| t.1.exp()).sum::<f32>() - 1.).abs() < 1e-6);
+    assert_eq!(logprobs(&x, 2, 0), (f32::NEG_INFINITY, vec![]));
+}
+
+#[test]
+fn test_sample_probs() {
+    use rand::SeedableRng;
+    let mut rng = rand::rngs::StdRng::seed_from_u64(9);
+    let mut scratch = SampleScratch::default();
+    let x = Tensor::new(vec![2., 0.5, 1., -1., 1.5, f32::NEG_INFINITY], &vec![6]);
+    let mut probs = vec![0.; 6];
+    // top-k 3 at temperature 0.5, the weights being exp((x - 2) / 0.5)
+    sample_probs(&x, 1., 3, 0.5, 0., 1., &mut scratch, &mut probs);
+    let w = [1., (-2f32).exp(), (-1f32).exp()];
+    let sum: f32 = w.iter().sum();
+    let expected = [w[0] / sum, 0., w[1] / sum, 0., w[2] / sum, 0.];
+    assert!(
+        probs
+            .iter()
+            .zip(expected)
+            .all(|(p, e)| (p - e).abs() < 1e-6),
+        "{probs:?}"
+    );
+    // which is what random_sample_with draws from
+    let mut counts = [0usize; 6];
+    let n = 20000;
+    for _ in 0..n {
+        counts[random_sample_with(&x, 1., 3, 0.5, 0., 1., &mut rng, &mut scratch) as usize] += 1;
+    }
+    for (c, e) in counts.iter().zip(expected) {
+        assert!((*c as f32 / n as f32 - e).abs() < 0.01, "{counts:?}");
+    }
+    // greedy is a single token
+    sample_probs(&x, 0.9, 1, 1., 0., 1., &mut scratch, &mut probs);
+    assert_eq!(probs, [1., 0., 0., 0., 0., 0.]);
+}
+
+#[test]
+fn test_ban_tokens() {
+    use rand::SeedableRng;
+    let mut rng = rand::rngs::StdRng::seed_from_u64(5);
+    let mut scratch = SampleScratch::default();
+    let x = Tensor::<f32>::random(&vec![1000]);
+    // all but three tokens banned
+    let mut logits = Tensor::new(x.data().to_vec(), &vec![1000]);
+    let banned = (0..1000)
+        .filter(|i| ![7, 300, 999].contains(i))
+        .collect::<Vec<_>>();
+    ban_tokens(&mut logits, &banned);
+    for _ in 0..100 {
+        let tok = random_sample_with(&logits, 0.9, 50, 1., 0.05, 0.9, &mut rng, &mut scratch);
+        assert!([7, 300, 999].contains(&tok));
+        let params = MirostatParams { tau: 3., eta: 0.1 };
+        let mut state = MirostatState::new(params);
+        let tok = mirostat_sample(&logits, 1., params, &mut state, &mut rng, &mut scratch);
+        assert!([7, 300, 999].contains(&tok));
+        assert!(state.mu.is_finite());
+    }
+    // everything banned still gives a token rather than NaN weights
+    ban_tokens(&mut logits, &[7, 300, 999]);
+    assert_eq!(
+        random_sample_with(&logits, 0.9, 50, 1., 0., 1., &mut rng, &mut scratch),
+        0
+    );
+}
+
+#[test]
+fn test_mirostat() {
+    use rand::SeedableRng;
+    // a stationary Zipf distribution over 100 tokens, whose untruncated samples are
+    // much more surprising (5.3 bits on average) than the target
+    let x = Tensor::new(
+        (0..100).map(|i| -((i + 1) as f32).ln()).collect(),
+        &vec![100],
+    );
+    let params = MirostatParams { tau: 3., eta: 0.1 };
+    let mut state = MirostatState::new(params);
+    let mut rng = rand::rngs::StdRng::seed_from_u64(4);
+    let mut scratch = SampleScratch::default();
+    let mut surprises = vec![];
+    for _ in 0..200 {
+        let mu = state.mu;
+        mirostat_sample(&x, 1., params, &mut state, &mut rng, &mut scratch);
+        // mu -= eta * (surprise - tau)
+        surprises.push(params.tau + (mu - state.mu) / params.eta);
+    }
+    let average = surprises[100..].iter().sum::<f32>() / 100.;
+    assert!((average - params.tau).abs() < 0.3, "{average}");
+    // with temperature 0 it is greedy and mu stays
+    let mu = state.mu;
+    assert_eq!(
+        mirostat_sample(&x, 0., params, &mut state, &mut rng, &mut scratch),
+        0
+    );
+    assert_eq!(state.mu, mu);
+}
+
+#[test]
+fn test_random_sample_typical() {
+    use rand::SeedableRng;
+    // the entropy is 1.5255, so the distances |-ln p - H| are
+    // (0.609, 0.139, 0.372, 0.777, 1.288, 1.693) and rank the tokens 1, 2, 0, 3, 4, 5
+    let p = [0.4f32, 0.25, 0.15, 0.1, 0.06, 0.04];
+    let x = Tensor::new(p.iter().map(|p| p.ln()).collect(), &vec![6]);
+    let mut rng = rand::rngs::StdRng::seed_from_u64(3);
+    let mut sample = |top_p, typical_p| {
+        let mut counts = [0usize; 6];
+        for _ in 0..10_000 {
+            counts[random_sample(&x, top_p, 6, 1., 0., typical_p, &mut rng) as usize] += 1;
+        }
+        counts
+    };
+    // 0.25 + 0.15 reach 0.3, even though the most likely token is not among them
+    let counts = sample(1., 0.3);
+    assert_eq!(
+        counts.map(|c| c > 0),
+        [false, true, true, false, false, false]
+    );
+    assert!((counts[1] as f32 / 10_000. - 0.25 / 0.4).abs() < 0.02);
+    // 0.25 + 0.15 + 0.4 reach 0.5
+    let counts = sample(1., 0.5);
+    assert_eq!(
+        counts.map(|c| c > 0),
+        [true, true, true, false, false, false]
+    );
+    // 1 disables the filter
+    assert!(sample(1., 1.).iter().all(|c| *c > 0));
+    // top_p 0.3 only keeps token 0, which is not typical, so that is what is left
+    assert_eq!(sample(0.3, 0.3), [10_000, 0, 0, 0, 0, 0]);
+
+    // a near-deterministic distribution keeps its top token
+    let x = Tensor::new(vec![10., -10., -10.], &vec![3]);
+    for typical_p in [0.01, 0.5, 0.99] {
+        assert_eq!(random_sample(&x, 1., 3, 1., 0., typical_p, &mut rng), 0);
+    }
+}
+
+// cargo test --release bench_random_sample -- --ignored --nocapture
+#[test]
+#[ignore]
+fn bench_random_sample() {
+    use rand::SeedableRng;
+    let x = Tensor::<f32>::random(&vec![128 * 1024]);
+    let x = Tensor::new(x.data().iter().map(|v| v * 20.).collect(), x.shape());
+    let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+    let mut scratch = SampleScratch::default();
+    for (top_p, top_k) in [(0.9, 50), (0.9, 128 * 1024), (1., 128 * 1024)] {
+        let start = std::time::Instant::now();
+        for _ in 0..1000 {
+            random_sample_with(&x, top_p, top_k, 0.8, 0., 1., &mut rng, &mut scratch);
+        }
+        println!(
+            "1000 x random_sample 128k, top_p {top_p}, top_k {top_k}: {:?}",
+            start.elapsed()
+        );
+    }
+    // what a full sort of the vocab alone costs
+    let start = std::time::Instant::now();
+    for _ in 0..1000 {
+        let mut probs = x
+            .data()
+            .iter()
+            .enumerate()
+            .map(Probability::from)
+            .collect::<Vec<_>>();
+        probs.sort_unstable();
+    }
+    println!("1000 x full sort 128k: {:?}", start.elapsed());
+}
+
+#[test]
+fn test_moe_router() {
+    #[rustfmt::skip]
+    let logits = Tensor::<f32>::new(
+        vec![
+            1., 2., 3., 4., // experts 3 and 2, weights 1 : e^-1
+            0., 3f32.ln(), 0., 0., // expert 1, then the tie of 0, 2 and 3 goes to 0
+            5., 5., 5., 5., // all tie
+        ],
+        &vec![3, 4],
+    );
+    let (experts, weights) = moe_router(&logits, 2);
+    assert_eq!(experts.shape(), &vec![3, 2]);
+    assert_eq!(experts.data(), &[3, 2, 1, 0, 0, 1]);
+    let e = (-1f32).exp();
+    let expected = Tensor::new(
+        vec![1. / (1. + e), e / (1. + e), 0.75, 0.25, 0.5, 0.5],
+        &vec![3, 2],
+    );
+    assert!(weights.close_to(&expected, 1e-6));
+
+    // k beyond n_experts routes to every expert, in order
+    let (experts, weights) = moe_router(&logits, 9);
+    assert_eq!(experts.shape(), &vec![3, 4]);
+    assert_eq!(&experts.data()[..4], &[3, 2, 1, 0]);
+    for row in weights.data().chunks(4) {
+        assert!((row.iter().sum::<f32>() - 1.).abs() < 1e-6);
+    }
+}
+
+#[test]
+fn test_matmul_transb_q8() {
+    use crate::tensor::quantize_q8;
+    // 70 columns leave a padded last block in every row
+    let a = Tensor::<f32>::random(&vec![5, 70]);
+    let b = Tensor::<f32>::random(&vec![9, 70]);
+    let b_q = quantize_q8(&b);
+    assert_eq!(b_q.blocks_per_row(), 3);
+    assert!(b_q.dequantize().max_abs_diff(&b) <= 0.5 / 127.);
+
+    let mut c = Tensor::<f32>::random(&vec![5, 9]);
+    let mut expected = Tensor::new(c.data().to_vec(), c.shape());
+    matmul_transb(&mut expected, 0.5, &a, &b, 2.);
+    matmul_transb_q8(&mut c, 0.5, &a, &b_q, 2.);
+    assert!(c.close_to(&expected, 1e-2));
+
+    // single-row decode
+    let a = a.slice(0, &vec![1, 70]);
+    let mut c = Tensor::<f32>::default(&vec![1, 9]);
+    let mut expected = Tensor::<f32>::default(&vec![1, 9]);
+    matmul_transb(&mut expected, 0., &a, &b, 1.);
+    matmul_transb_q8(&mut c, 0., &a, &b_q, 1.);
+    assert!(c.close_to(&expected, 1e-2));
+}
+
+#[test]
+fn test_matmul_transb_q4() {
+    use crate::tensor::quantize_q4;
+    // 45 columns: a padded last group and an odd row length
+    let b = Tensor::<f32>::new(
+        (0..3 * 45)
+            .map(|i| rand::random::<f32>() - 0.5 + (i % 7) as f32 * 0.1)
+            .collect(),
+        &vec![3, 45],
+    );
+    let b_q = quantize_q4(&b);
+    assert_eq!(b_q.blocks_per_row(), 2);
+    assert_eq!(b_q.size_in_bytes(), 3 * 2 * (16 + 4));
+    // round-trip error is at most half a step of every group
+    let deq = b_q.dequantize();
+    for (row, deq_row) in b.data().chunks(45).zip(deq.data().chunks(45)) {
+        for (x, y) in row.chunks(Q4_BLOCK).zip(deq_row.chunks(Q4_BLOCK)) {
+            let step = x.iter().fold(0f32, |m, x| m.max(x.abs())) / 7.;
+            assert!(x
+                .iter()
+                .zip(y)
+                .all(|(x, y)| (x - y).abs() <= step / 2. + 1e-6));
+        }
+    }
+    // zeros survive the round trip exactly
+    let zeros = quantize_q4(&Tensor::<f32>::default(&vec![2, 40]));
+    assert!(zeros.dequantize().data().iter().all(|&x| x == 0.));
+
+    // the kernel matches a matmul with the dequantized weight
+    let a = Tensor::<f32>::random(&vec![4, 45]);
+    let mut c = Tensor::<f32>::random(&vec![4, 3]);
+    let mut expected = Tensor::new(c.data().to_vec(), c.shape());
+    matmul_transb(&mut expected, 1., &a, &deq, 0.5);
+    matmul_transb_q4(&mut c, 1., &a, &b_q, 0.5);
+    assert!(c.max_abs_diff(&expected) < 1e-5);
+}
+
+#[test]
+fn test_matmul_transb_i8() {
+    use crate::tensor::quantize_i8;
+    let b = Tensor::<f32>::new(
+        (0..6 * 20)
+            .map(|i| rand::random::<f32>() * (i / 20 + 1) as f32 - 1.)
+            .collect(),
+        &vec![6, 20],
+    );
+    let b_q = quantize_i8(&b);
+    assert_eq!(b_q.size_in_bytes(), 6 * 20 + 6 * 4);
+    let deq = b_q.dequantize();
+    for (x, y) in b.data().chunks(20).zip(deq.data().chunks(20)) {
+        let step = x.iter().fold(0f32, |m, x| m.max(x.abs())) / 127.;
+        assert!(x
+            .iter()
+            .zip(y)
+            .all(|(x, y)| (x - y).abs() <= step / 2. + 1e-6));
+    }
+
+    let a = Tensor::<f32>::random(&vec![3, 20]);
+    let mut c = Tensor::<f32>::random(&vec![3, 6]);
+    let mut expected = Tensor::new(c.data().to_vec(), c.shape());
+    matmul_transb(&mut expected, 1., &a, &deq, 1.);
+    matmul_transb_i8(&mut c, 1., &a, &b_q, 1.);
+    assert!(c.max_abs_diff(&expected) < 1e-5);
+}
+
+#[test]
+fn test_matmul_transb_f16w() {
+    let a = Tensor::<f32>::random(&vec![3, 40]);
+    let b = Tensor::<f32>::random(&vec![7, 40]);
+    let mut c = Tensor::<f32>::random(&vec![3, 7]);
+    let mut expected = Tensor::new(c.data().to_vec(), c.shape());
+    matmul_transb(&mut expected, 0.5, &a, &b, 1.);
+    matmul_transb_f16w(&mut c, 0.5, &a, &b.to_f16(), 1.);
+    assert!(c.close_to(&expected, 1e-2));
+
+    let mut c = Tensor::<f32>::default(&vec![3, 7]);
+    matmul_transb(&mut expected, 0., &a, &b, 1.);
+    matmul_transb_bf16w(&mut c, 0., &a, &b.to_bf16(), 1.);
+    assert!(c.close_to(&expected, 2e-2));
+}
+
+#[test]
+fn test_bf16_conversion() {
+    use crate::tensor::{bf16_to_f32, f32_to_bf16};
+    assert_eq!(bf16_to_f32(0x3f80), 1.);
+    assert_eq!(bf16_to_f32(0xc040), -3.);
+    assert_eq!(bf16_to_f32(0x7f80), f32::INFINITY);
+    // the smallest subnormal is 2^-133
+    assert_eq!(bf16_to_f32(0x0001).to_bits(), 0x0001_0000);
+    assert_eq!(bf16_to_f32(0x0001), 2f64.powi(-133) as f32);
+    // NaN payloads pass through unchanged
+    assert_eq!(bf16_to_f32(0x7fc5).to_bits(), 0x7fc5_0000);
+    assert_eq!(bf16_to_f32(0xff81).to_bits(), 0xff81_0000);
+
+    assert_eq!(f32_to_bf16(1.), 0x3f80);
+    assert_eq!(f32_to_bf16(2f64.powi(-133) as f32), 0x0001);
+    // ties go to even, both ways
+    assert_eq!(f32_to_bf16(f32::from_bits(0x3f80_8000)), 0x3f80);
+    assert_eq!(f32_to_bf16(f32::from_bits(0x3f81_8000)), 0x3f82);
+    assert_eq!(f32_to_bf16(f32::from_bits(0x3f80_8001)), 0x3f81);
+    // rounding up the largest finite bf16 overflows to inf, NaN stays NaN
+    assert_eq!(f32_to_bf16(f32::MAX), 0x7f80);
+    assert_eq!(f32_to_bf16(f32::from_bits(0x7f80_0001)), 0x7fc0);
+    assert_eq!(f32_to_bf16(f32::from_bits(0x7fc5_0000)), 0x7fc5);
+    // every bf16 survives the round trip
+    for bits in (0..=u16::MAX).filter(|&b| !bf16_to_f32(b).is_nan()) {
+        assert_eq!(f32_to_bf16(bf16_to_f32(bits)), bits);
+    }
+}
+
+#[test]
+fn test_argmax() {
+    let x = Tensor::<f32>::new(vec![0.5, 2., -1., 2.], &vec![4]);
+    let idx = argmax(&x);
+    assert_eq!((idx.shape().to_vec(), idx.data()), (vec![1], &[1][..]));
+
+    // one index per row, NaN never wins and -inf still beats nothing
+    let x = Tensor::<f32>::new(
+        vec![
+            1.,
+            5.,
+            3., //
+            f32::NAN,
+            -2.,
+            f32::NEG_INFINITY,
+            f32::NEG_INFINITY,
+            f32::NAN,
+            f32::NEG_INFINITY,
+            f32::NAN,
+            f32::NAN,
+            f32::NAN,
+        ],
+        &vec![2, 2, 3],
+    );
+    let idx = argmax(&x);
+    assert_eq!(idx.shape(), &vec![2, 2]);
+    assert_eq!(idx.data(), &[1, 1, 0, 0]);
+    let mut rng = rand::thread_rng();
+    assert_eq!(
+        random_sample(&x.slice(3, &vec![3]), 0.9, 1, 1., 0., 1., &mut rng),
+        1
+    );
+}
@@ -0,0 +1,270 @@
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+
+// Pages of page_size positions of K and V of one layer, which the paged caches of every
+// sequence take from and give back to. A page freed by one sequence goes to the next one
+// that grows rather than back to the allocator, and all pages are the same size, so the
+// memory of many sequences coming and going does not fragment.
+pub struct PagePool {
+    page_size: usize,
+    dim: usize,
+    pages: Mutex<Pages>,
+}
+
+#[derive(Default)]
+struct Pages {
+    free: Vec<Page>,
+    allocated: usize, // the pages ever made, free or not
+}
+
+// K of page_size positions, then V of them, dim values each
+type Page = Box<[f32]>;
+
+#[allow(unused)]
+impl PagePool {
+    pub fn new(page_size: usize, dim: usize) -> Arc<Self> {
+        assert!(page_size > 0);
+        Arc::new(PagePool {
+            page_size,
+            dim,
+            pages: Mutex::default(),
+        })
+    }
+
+    pub fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    pub fn dim(&self) -> usize {
+        self.dim
+    }
+
+    pub fn allocated(&self) -> usize {
+        self.pages.lock().unwrap().allocated
+    }
+
+    pub fn free(&self) -> usize {
+        self.pages.lock().unwrap().free.len()
+    }
+
+    // the pages the block tables of the caches hold
+    pub fn in_use(&self) -> usize {
+        let pages = self.pages.lock().unwrap();
+        pages.allocated - pages.free.len()
+    }
+
+    pub fn page_bytes(&self) -> usize {
+        2 * self.page_size * self.dim * size_of::<f32>()
+    }
+
+    // a free page, or a new one if there is none; what it holds is whatever the sequence
+    // that had it last left there
+    fn take(&self) -> Page {
+        let mut pages = self.pages.lock().unwrap();
+        pages.free.pop().unwrap_or_else(|| {
+            pages.allocated += 1;
+            vec![0.; 2 * self.page_size * self.dim].into_boxed_slice()
+        })
+    }
+
+    fn give_back(&self, freed: impl IntoIterator<Item = Page>) {
+        self.pages.lock().unwrap().free.extend(freed);
+    }
+}
+
+// The entries of one layer of a sequence, position p in row p % page_size of page
+// block_table[p / page_size]. Writing past the last page takes more from the pool, and
+// dropping the layer gives them all back.
+pub struct PagedLayer {
+    pool: Arc<PagePool>,
+    block_table: Vec<Page>,
+}
+
+impl PagedLayer {
+    pub fn new(pool: Arc<PagePool>) -> Self {
+        PagedLayer {
+            pool,
+            block_table: Vec::new(),
+        }
+    }
+
+    // an empty layer of the same pool
+    pub fn empty_like(&self) -> Self {
+        PagedLayer::new(self.pool.clone())
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.block_table.len() * self.pool.page_bytes()
+    }
+
+    // the positions the pages of the block table have room for
+    pub fn capacity(&self) -> usize {
+        self.block_table.len() * self.pool.page_size
+    }
+
+    // gives back the pages past the first rows positions
+    pub fn truncate(&mut self, rows: usize) {
+        let pages = rows.div_ceil(self.pool.page_size);
+        if pages < self.block_table.len() {
+            self.pool.give_back(self.block_table.drain(pages..));
+        }
+    }
+
+    // calls f with every page and the rows within it of the positions of rows, along with
+    // the index within rows of the first of them
+    fn walk(&self, rows: Range<usize>, mut f: impl FnMut(&Page, Range<usize>, usize)) {
+        let n = self.pool.page_size;
+        let mut pos = rows.start;
+        while pos < rows.end {
+            let (page, row) = (pos / n, pos % n);
+            let end = n.min(row + rows.end - pos);
+            f(&self.block_table[page], row..end, pos - rows.start);
+            pos += end - row;
+        }
+    }
+
+    // K and V of the positions of rows, (rows.len(), dim) each; those past the pages, such
+    // as the ones forward is about to write, leave k and v as they are
+    pub fn read_rows(&self, rows: Range<usize>, k: &mut [f32], v: &mut [f32]) {
+        let (n, dim) = (self.pool.page_size, self.pool.dim);
+        let rows = rows.start..rows.end.min(self.capacity());
+        self.walk(rows, |page, within, at| {
+            let len = within.len() * dim;
+            let start = within.start * dim;
+            k[at * dim..][..len].copy_from_slice(&page[start..start + len]);
+            v[at * dim..][..len].copy_from_slice(&page[n * dim + start..][..len]);
+        });
+    }
+
+    // K and V of the first rows positions where they are, page by page
+    pub fn pages(&self, rows: usize) -> PageRows<'_> {
+        assert!(rows <= self.capacity());
+        PageRows {
+            pages: self.block_table.iter(),
+            rows,
+            page_size: self.pool.page_size,
+            dim: self.pool.dim,
+        }
+    }
+
+    // takes the pages for the first rows positions that the block table has no room for yet
+    pub fn reserve(&mut self, rows: usize) {
+        while self.capacity() < rows {
+            self.block_table.push(self.pool.take());
+        }
+    }
+
+    pub fn write_rows(&mut self, rows: Range<usize>, k: &[f32], v: &[f32]) {
+        let (n, dim) = (self.pool.page_size, self.pool.dim);
+        self.reserve(rows.end);
+        let mut pos = rows.start;
+        while pos < rows.end {
+            let (page, row) = (pos / n, pos % n);
+            let count = (n - row).min(rows.end - pos);
+            let (src, start) = ((pos - rows.start) * dim, row * dim);
+            let page = &mut self.block_table[page];
+            page[start..][..count * dim].copy_from_slice(&k[src..][..count * dim]);
+            page[n * dim + start..][..count * dim].copy_from_slice(&v[src..][..count * dim]);
+            pos += count;
+        }
+    }
+
+    // copies the positions of src from src_start on to the ones from dst on, returning the
+    // bytes copied
+    pub fn copy_rows(
+        &mut self,
+        src: &PagedLayer,
+        src_start: usize,
+        rows: usize,
+        dst: usize,
+    ) -> usize {
+        let values = rows * self.pool.dim;
+        let (mut k, mut v) = (vec![0.; values], vec![0.; values]);
+        src.read_rows(src_start..src_start + rows, &mut k, &mut v);
+        self.write_rows(dst..dst + rows, &k, &v);
+        2 * values * size_of::<f32>()
+    }
+
+    // moves the positions of src to the ones from dst on, returning the bytes copied
+    pub fn copy_within(&mut self, src: Range<usize>, dst: usize) -> usize {
+        let values = src.len() * self.pool.dim;
+        let (mut k, mut v) = (vec![0.; values], vec![0.; values]);
+        self.read_rows(src.clone(), &mut k, &mut v);
+        self.write_rows(dst..dst + src.len(), &k, &v);
+        2 * values * size_of::<f32>()
+    }
+}
+
+// The K and V of the positions of PagedLayer::pages, (rows within the page, dim) each
+pub struct PageRows<'a> {
+    pages: std::slice::Iter<'a, Page>,
+    rows: usize, // left of the ones asked for
+    page_size: usize,
+    dim: usize,
+}
+
+impl<'a> Iterator for PageRows<'a> {
+    type Item = (&'a [f32], &'a [f32]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.rows == 0 {
+            return None;
+        }
+        let page = self.pages.next()?;
+        let rows = self.rows.min(self.page_size);
+        self.rows -= rows;
+        let len = rows * self.dim;
+        Some((&page[..len], &page[self.page_size * self.dim..][..len]))
+    }
+}
+
+impl Drop for PagedLayer {
+    fn drop(&mut self) {
+        self.pool.give_back(self.block_table.drain(..));
+    }
+}
+
+#[test]
+fn test_page_pool_churn() {
+    use rand::{Rng, SeedableRng};
+    let pool = PagePool::new(16, 4);
+    let mut rng = rand::rngs::StdRng::seed_from_u64(7);
+    let mut live: Vec<(PagedLayer, usize)> = Vec::new();
+    let mut peak = 0;
+    // 100 sequences of up to 100 positions, freed in random order as new ones come
+    for seq in 0..100 {
+        let mut layer = PagedLayer::new(pool.clone());
+        let len = rng.gen_range(2..100);
+        for pos in 0..len {
+            let x = (seq * 1000 + pos) as f32;
+            layer.write_rows(pos..pos + 1, &[x; 4], &[-x; 4]);
+        }
+        assert_eq!(layer.block_table.len(), len.div_ceil(16));
+        live.push((layer, len));
+        let in_use = |live: &[(PagedLayer, usize)]| -> usize {
+            live.iter().map(|(l, _)| l.block_table.len()).sum()
+        };
+        peak = peak.max(in_use(&live));
+        if rng.gen_bool(0.5) {
+            live.swap_remove(rng.gen_range(0..live.len()));
+        }
+        assert_eq!(pool.in_use(), in_use(&live));
+    }
+    // the pages of the live sequences still hold their entries, read out or in place
+    for (layer, len) in &live {
+        let (mut k, mut v) = (vec![0.; len * 4], vec![0.; len * 4]);
+        layer.read_rows(0..*len, &mut k, &mut v);
+        assert!(k.iter().zip(&v).all(|(k, v)| *k == -v));
+        assert_eq!(k[4..8], [k[0] + 1.; 4]);
+        let (mut in_place_k, mut in_place_v) = (vec![], vec![]);
+        for (page_k, page_v) in layer.pages(*len) {
+            in_place_k.extend_from_slice(page_k);
+            in_place_v.extend_from_slice(page_v);
+        }
+        assert_eq!((in_place_k, in_place_v), (k, v));
+    }
+    // the pool only made a page when none was free, and has them all back in the end
+    assert_eq!(pool.allocated(), peak);
+    drop(live);
+    assert_eq!((pool.in_use(), pool.free()), (0, pool.allocated()));
+}
@@ -1,35 +1,306 @@
 use crate::config::LlamaConfigJson;
-use crate::tensor::Tensor;
-use safetensors::SafeTensors;
+use crate::operators::{self as OP, GatherError, OperatorError};
+use crate::tensor::{
+    bf16_to_f32, quantize_i8, quantize_q4, I8Tensor, Q4Tensor, QuantizedTensor, Tensor,
+};
+use half::{bf16, f16};
+use safetensors::tensor::TensorView;
+use safetensors::{Dtype, SafeTensors};
+
+// The embedding table is only ever gathered from, so a half precision checkpoint keeps it
+// in half precision and the looked up rows are converted to f32 per step.
+pub enum EmbeddingTable<T> {
+    Full(Tensor<T>),
+    F16(Tensor<f16>),
+    BF16(Tensor<bf16>),
+}
+
+impl EmbeddingTable<f32> {
+    pub fn gather(&self, y: &mut Tensor<f32>, indices: &Tensor<u32>) -> Result<(), GatherError> {
+        self.gather_scaled(y, indices, 1.)
+    }
+
+    // gather with the rows multiplied by scale, see OP::gather_scaled
+    pub fn gather_scaled(
+        &self,
+        y: &mut Tensor<f32>,
+        indices: &Tensor<u32>,
+        scale: f32,
+    ) -> Result<(), GatherError> {
+        match self {
+            EmbeddingTable::Full(table) => OP::gather_scaled(y, indices, table, scale),
+            EmbeddingTable::F16(table) => OP::gather_scaled(y, indices, table, scale),
+            EmbeddingTable::BF16(table) => OP::gather_scaled(y, indices, table, scale),
+        }
+    }
+
+    pub fn size_in_bytes(&self) -> usize {
+        match self {
+            EmbeddingTable::Full(t) => t.size() * std::mem::size_of::<f32>(),
+            EmbeddingTable::F16(t) => t.size() * std::mem::size_of::<f16>(),
+            EmbeddingTable::BF16(t) => t.size() * std::mem::size_of::<bf16>(),
+        }
+    }
+
+    // the whole table upcast to f32
+    #[allow(unused)]
+    pub fn to_f32(&self) -> Tensor<f32> {
+        let (rows, dim) = match self {
+            EmbeddingTable::Full(t) => (t.shape()[0], t.shape()[1]),
+            EmbeddingTable::F16(t) => (t.shape()[0], t.shape()[1]),
+            EmbeddingTable::BF16(t) => (t.shape()[0], t.shape()[1]),
+        };
+        let mut y = Tensor::default(&vec![rows, dim]);
+        let indices = Tensor::new((0..rows as u32).collect(), &vec![rows]);
+        self.gather(&mut y, &indices).unwrap();
+        y
+    }
+}
+
+// A projection matrix, used as B in C = A @ B^T, in f32 or quantized at load time
+pub enum Weight<T> {
+    Full(Tensor<T>),
+    I8(I8Tensor),
+    F16(Tensor<f16>),
+    BF16(Tensor<bf16>),
+    #[allow(unused)]
+    Q8(QuantizedTensor),
+    Q4(Q4Tensor),
+}
+
+impl Weight<f32> {
+    pub fn shape(&self) -> &[usize] {
+        match self {
+            Weight::Full(t) => t.shape(),
+            Weight::I8(t) => t.shape(),
+            Weight::F16(t) => t.shape(),
+            Weight::BF16(t) => t.shape(),
+            Weight::Q8(t) => t.shape(),
+            Weight::Q4(t) => t.shape(),
+        }
+    }
+
+    // C = beta * C + alpha * A @ self^T, with the shape checks of OP::checked_matmul_transb
+    pub fn matmul_transb(
+        &self,
+        c: &mut Tensor<f32>,
+        beta: f32,
+        a: &Tensor<f32>,
+        alpha: f32,
+    ) -> Result<(), OperatorError> {
+        OP::check_matmul_transb(c, a, self.shape())?;
+        match self {
+            Weight::Full(b) => OP::matmul_transb(c, beta, a, b, alpha),
+            Weight::I8(b) => OP::matmul_transb_i8(c, beta, a, b, alpha),
+            Weight::F16(b) => OP::matmul_transb_f16w(c, beta, a, b, alpha),
+            Weight::BF16(b) => OP::matmul_transb_bf16w(c, beta, a, b, alpha),
+            Weight::Q8(b) => OP::matmul_transb_q8(c, beta, a, b, alpha),
+            Weight::Q4(b) => OP::matmul_transb_q4(c, beta, a, b, alpha),
+        }
+        Ok(())
+    }
+
+    // the f32 values, shared with the weight unless it's quantized
+    #[allow(unused)]
+    pub fn to_f32(&self) -> Tensor<f32> {
+        match self {
+            Weight::Full(t) => t.slice(0, t.shape()),
+            Weight::I8(t) => t.dequantize(),
+            Weight::F16(t) => t.to_f32(),
+            Weight::BF16(t) => t.to_f32(),
+            Weight::Q8(t) => t.dequantize(),
+            Weight::Q4(t) => t.dequantize(),
+        }
+    }
+
+    #[allow(unused)]
+    pub fn size_in_bytes(&self) -> usize {
+        match self {
+            Weight::Full(t) => t.size() * std::mem::size_of::<f32>(),
+            Weight::I8(t) => t.size_in_bytes(),
+            Weight::F16(t) => t.size() * std::mem::size_of::<f16>(),
+            Weight::BF16(t) => t.size() * std::mem::size_of::<bf16>(),
+            Weight::Q8(t) => t.size_in_bytes(),
+            Weight::Q4(t) => t.size_in_bytes(),
+        }
+    }
+}
+
+// Which projection matrices from_safetensors_with quantizes to per-row int8. The others are
+// kept in f16/bf16 if the checkpoint is (or in f16 if f16 is set) and upcast to f32 otherwise.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LoadOptions {
+    pub i8_attention: bool, // wq, wk, wv and wo
+    pub i8_mlp: bool,       // w_up, w_gate and w_down
+    pub f16: bool,          // store f32 projection matrices as f16 too
+}
+
+// decode the raw little-endian bytes of a tensor view into `T`
+fn decode<T>(view: &TensorView, width: usize, f: impl Fn(&[u8]) -> T) -> Vec<T> {
+    view.data().chunks_exact(width).map(f).collect()
+}
+
 pub struct LLamaParams<T> {
     // token_id to embedding lookup table
-    pub embedding_table: Tensor<T>, // (vocab_size, dim)
+    pub embedding_table: EmbeddingTable<T>, // (vocab_size, dim)
     // decoder layer
     pub rms_att_w: Vec<Tensor<T>>, // (hidden_size, ) x layers
-    pub wq: Vec<Tensor<T>>,        // (n_heads * head_size, hidden_size) x layers
-    pub wk: Vec<Tensor<T>>,        // (n_kv_heads * head_size, hidden_size) x layers
-    pub wv: Vec<Tensor<T>>,        // (n_kv_heads * head_size, hidden_size) x layers
-    pub wo: Vec<Tensor<T>>,        // (hidden_size, n_heads * head_size) x layers
+    pub wq: Vec<Weight<T>>,        // (n_heads * head_size, hidden_size) x layers
+    pub wk: Vec<Weight<T>>,        // (n_kv_heads * head_size, hidden_size) x layers
+    pub wv: Vec<Weight<T>>,        // (n_kv_heads * head_size, hidden_size) x layers
+    pub wo: Vec<Weight<T>>,        // (hidden_size, n_heads * head_size) x layers
+    // per-head rms_norm of q and k before rope, only in some checkpoints (Qwen3)
+    pub q_norm: Option<Vec<Tensor<T>>>, // (head_size, ) x layers
+    pub k_norm: Option<Vec<Tensor<T>>>, // (head_size, ) x layers
     // ffn layer
     pub rms_ffn_w: Vec<Tensor<T>>, // (hidden_size, ) x layers
-    pub w_up: Vec<Tensor<T>>,      // (intermediate_size, hidden_size) x layers
-    pub w_gate: Vec<Tensor<T>>,    // (intermediate_size, hidden_size) x layers
-    pub w_down: Vec<Tensor<T>>,    // (hidden_size, intermediate_size) x layers
+    pub w_up: Vec<Weight<T>>,      // (intermediate_size, hidden_size) x layers
+    pub w_gate: Vec<Weight<T>>,    // (intermediate_size, hidden_size) x layers
+    pub w_down: Vec<Weight<T>>,    // (hidden_size, intermediate_size) x layers
     // output
     pub rms_out_w: Tensor<T>, // (hidden_size, )
     pub lm_head: Tensor<T>,   // (vocab_size, dim)
+    // quantized copy of lm_head used instead of it when set
+    pub lm_head_quantized: Option<Weight<T>>,
 }
 
 impl LLamaParams<f32> {
+    #[allow(unused)]
     pub fn from_safetensors(safetensor: &SafeTensors, config: &LlamaConfigJson) -> Self {
-        todo!("实现从safetensors文件的模型参数加载");
-        // let get_tensor: impl Fn(&str) -> Tensor<f32> = |name: &str| {
-        // ...    
-        // };
-        
-        // LLamaParams {
-        //     embedding_table: get_tensor(...),
-        //     ...
-        // }
+        Self::from_safetensors_with(safetensor, config, LoadOptions::default())
+    }
+
+    pub fn from_safetensors_with(
+        safetensor: &SafeTensors,
+        config: &LlamaConfigJson,
+        options: LoadOptions,
+    ) -> Self {
+        let get_view = |name: &str| -> TensorView {
+            safetensor
+                .tensor(name)
+                .unwrap_or_else(|e| panic!("failed to load tensor {name}: {e}"))
+        };
+        // safetensors stores raw little-endian bytes, reinterpret them as f32
+        // (f16/bf16 checkpoints are upcast here)
+        let get_tensor = |name: &str| -> Tensor<f32> {
+            let view = get_view(name);
+            let data = match view.dtype() {
+                Dtype::F32 => decode(&view, 4, |b| f32::from_le_bytes([b[0], b[1], b[2], b[3]])),
+                Dtype::F16 => decode(&view, 2, |b| f16::from_le_bytes([b[0], b[1]]).to_f32()),
+                Dtype::BF16 => decode(&view, 2, |b| bf16_to_f32(u16::from_le_bytes([b[0], b[1]]))),
+                dtype => panic!("unsupported dtype {dtype:?} for tensor {name}"),
+            };
+            Tensor::new(data, &view.shape().to_vec())
+        };
+        // the embedding table stays in the checkpoint's precision
+        let get_embedding = |name: &str| -> EmbeddingTable<f32> {
+            let view = get_view(name);
+            let shape = view.shape().to_vec();
+            match view.dtype() {
+                Dtype::F16 => EmbeddingTable::F16(Tensor::new(
+                    decode(&view, 2, |b| f16::from_le_bytes([b[0], b[1]])),
+                    &shape,
+                )),
+                Dtype::BF16 => EmbeddingTable::BF16(Tensor::new(
+                    decode(&view, 2, |b| bf16::from_le_bytes([b[0], b[1]])),
+                    &shape,
+                )),
+                _ => EmbeddingTable::Full(get_tensor(name)),
+            }
+        };
+        let layer_tensors = |suffix: &str| -> Vec<Tensor<f32>> {
+            (0..config.num_hidden_layers)
+                .map(|i| get_tensor(&format!("model.layers.{i}.{suffix}")))
+                .collect()
+        };
+        let get_weight = |name: &str, i8: bool| -> Weight<f32> {
+            let view = get_view(name);
+            match view.dtype() {
+                _ if i8 => Weight::I8(quantize_i8(&get_tensor(name))),
+                Dtype::F16 => Weight::F16(Tensor::new(
+                    decode(&view, 2, |b| f16::from_le_bytes([b[0], b[1]])),
+                    &view.shape().to_vec(),
+                )),
+                Dtype::BF16 => Weight::BF16(Tensor::new(
+                    decode(&view, 2, |b| bf16::from_le_bytes([b[0], b[1]])),
+                    &view.shape().to_vec(),
+                )),
+                _ if options.f16 => Weight::F16(get_tensor(name).to_f16()),
+                _ => Weight::Full(get_tensor(name)),
+            }
+        };
+        let layer_weights = |suffix: &str, i8: bool| -> Vec<Weight<f32>> {
+            (0..config.num_hidden_layers)
+                .map(|i| get_weight(&format!("model.layers.{i}.{suffix}"), i8))
+                .collect()
+        };
+        let optional_layer_tensors = |suffix: &str| -> Option<Vec<Tensor<f32>>> {
+            let first = format!("model.layers.0.{suffix}");
+            safetensor
+                .tensor(&first)
+                .is_ok()
+                .then(|| layer_tensors(suffix))
+        };
+
+        // with tied embeddings only lm_head.weight is stored
+        let lm_head = get_tensor("lm_head.weight");
+        let embedding_table = if config.tie_word_embeddings {
+            get_embedding("lm_head.weight")
+        } else {
+            get_embedding("model.embed_tokens.weight")
+        };
+
+        LLamaParams {
+            embedding_table,
+            rms_att_w: layer_tensors("input_layernorm.weight"),
+            wq: layer_weights("self_attn.q_proj.weight", options.i8_attention),
+            wk: layer_weights("self_attn.k_proj.weight", options.i8_attention),
+            wv: layer_weights("self_attn.v_proj.weight", options.i8_attention),
+            wo: layer_weights("self_attn.o_proj.weight", options.i8_attention),
+            q_norm: optional_layer_tensors("self_attn.q_norm.weight"),
+            k_norm: optional_layer_tensors("self_attn.k_norm.weight"),
+            rms_ffn_w: layer_tensors("post_attention_layernorm.weight"),
+            w_up: layer_weights("mlp.up_proj.weight", options.i8_mlp),
+            w_gate: layer_weights("mlp.gate_proj.weight", options.i8_mlp),
+            w_down: layer_weights("mlp.down_proj.weight", options.i8_mlp),
+            rms_out_w: get_tensor("model.norm.weight"),
+            lm_head,
+            lm_head_quantized: None,
+        }
+    }
+
+    // bytes taken by every tensor, the embedding table of tied embeddings being a copy of
+    // lm_head
+    pub fn size_in_bytes(&self) -> usize {
+        let tensor = |t: &Tensor<f32>| t.size() * std::mem::size_of::<f32>();
+        // the norms, and lm_head as the one other f32 tensor
+        let norms = [&self.rms_att_w, &self.rms_ffn_w]
+            .into_iter()
+            .chain(&self.q_norm)
+            .chain(&self.k_norm)
+            .flatten()
+            .chain([&self.rms_out_w, &self.lm_head])
+            .map(tensor)
+            .sum::<usize>();
+        let weights = [
+            &self.wq,
+            &self.wk,
+            &self.wv,
+            &self.wo,
+            &self.w_up,
+            &self.w_gate,
+            &self.w_down,
+        ]
+        .into_iter()
+        .flatten()
+        .chain(&self.lm_head_quantized)
+        .map(Weight::size_in_bytes)
+        .sum::<usize>();
+        self.embedding_table.size_in_bytes() + norms + weights
+    }
+
+    #[allow(unused)]
+    pub fn quantize_lm_head_q4(&mut self) {
+        self.lm_head_quantized = Some(Weight::Q4(quantize_q4(&self.lm_head)));
     }
 }
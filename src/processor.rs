@@ -0,0 +1,357 @@
+use crate::generation::{DryParams, GenerationConfig};
+use crate::operators as OP;
+use crate::tensor::Tensor;
+use std::collections::HashMap;
+
+// what a LogitsProcessor sees of the generation besides the logits
+pub struct GenerationContext<'a> {
+    pub tokens: &'a [u32], // the prompt, then the tokens generated so far
+    pub prompt_len: usize,
+}
+
+impl GenerationContext<'_> {
+    #[allow(unused)]
+    pub fn generated(&self) -> &[u32] {
+        &self.tokens[self.prompt_len..]
+    }
+}
+
+// Changes the logits of the next token in place before sampling. Whatever state a
+// processor keeps should follow from the context alone, as a fork of a generation (see
+// Llama::generate_n) starts its processors over from the config.
+pub trait LogitsProcessor {
+    fn process(&mut self, ctx: &GenerationContext, logits: &mut Tensor<f32>);
+}
+
+// CTRL repetition penalty of the last last_n tokens of the context
+pub struct RepetitionPenalty {
+    pub penalty: f32,
+    pub last_n: usize,
+}
+
+impl LogitsProcessor for RepetitionPenalty {
+    fn process(&mut self, ctx: &GenerationContext, logits: &mut Tensor<f32>) {
+        let recent = &ctx.tokens[ctx.tokens.len().saturating_sub(self.last_n)..];
+        OP::repetition_penalty(logits, recent, self.penalty);
+    }
+}
+
+// OpenAI style penalties of the generated tokens, counted as they come
+pub struct FrequencyPresencePenalty {
+    pub frequency: f32,
+    pub presence: f32,
+    counts: HashMap<u32, usize>,
+    counted: usize, // how many of the generated tokens
+}
+
+impl FrequencyPresencePenalty {
+    pub fn new(frequency: f32, presence: f32) -> Self {
+        FrequencyPresencePenalty {
+            frequency,
+            presence,
+            counts: HashMap::new(),
+            counted: 0,
+        }
+    }
+}
+
+impl LogitsProcessor for FrequencyPresencePenalty {
+    fn process(&mut self, ctx: &GenerationContext, logits: &mut Tensor<f32>) {
+        let generated = ctx.generated();
+        // a context that went back starts the counts over
+        if generated.len() < self.counted {
+            self.counts.clear();
+            self.counted = 0;
+        }
+        for &token in &generated[self.counted..] {
+            *self.counts.entry(token).or_default() += 1;
+        }
+        self.counted = generated.len();
+        OP::frequency_presence_penalty(logits, &self.counts, self.frequency, self.presence);
+    }
+}
+
+// DRY: for every earlier position it keeps how long the text ending there matches the end
+// of the context, updated with every token, and penalizes the token that followed each
+// match of allowed_length or more
+pub struct Dry {
+    params: DryParams,
+    matches: Vec<usize>, // for every position of the context but the last
+    pushed: usize,       // how many of the context tokens
+}
+
+impl Dry {
+    pub fn new(params: DryParams) -> Self {
+        Dry {
+            params,
+            matches: Vec::new(),
+            pushed: 0,
+        }
+    }
+
+    fn push(&mut self, tokens: &[u32]) {
+        let (last, token) = (tokens.len() - 1, tokens[tokens.len() - 1]);
+        let breaker = self.params.sequence_breakers.contains(&token);
+        // position last - 1 gets a continuation, and every match grows by token or ends
+        if last > 0 {
+            self.matches.push(0);
+        }
+        for i in (0..last).rev() {
+            self.matches[i] = if !breaker && tokens[i] == token {
+                1 + i.checked_sub(1).map_or(0, |j| self.matches[j])
+            } else {
+                0
+            };
+        }
+    }
+}
+
+impl LogitsProcessor for Dry {
+    fn process(&mut self, ctx: &GenerationContext, logits: &mut Tensor<f32>) {
+        if ctx.tokens.len() < self.pushed {
+            *self = Dry::new(self.params.clone());
+        }
+        for len in self.pushed + 1..=ctx.tokens.len() {
+            self.push(&ctx.tokens[..len]);
+        }
+        self.pushed = ctx.tokens.len();
+        let params = &self.params;
+        let data = unsafe { logits.data_mut() };
+        // the longest match a token would extend, which the ones before do not shadow
+        let mut longest: HashMap<u32, usize> = HashMap::new();
+        for (i, &n) in self.matches.iter().enumerate() {
+            if n >= params.allowed_length.max(1) {
+                let next = longest.entry(ctx.tokens[i + 1]).or_default();
+                *next = (*next).max(n);
+            }
+        }
+        for (token, n) in longest {
+            if let Some(l) = data.get_mut(token as usize) {
+                *l -= params.multiplier * params.base.powi((n - params.allowed_length) as i32);
+            }
+        }
+    }
+}
+
+pub struct LogitBias(pub HashMap<u32, f32>);
+
+impl LogitsProcessor for LogitBias {
+    fn process(&mut self, _: &GenerationContext, logits: &mut Tensor<f32>) {
+        OP::logit_bias(logits, &self.0);
+    }
+}
+
+pub struct BanTokens(pub Vec<u32>);
+
+impl LogitsProcessor for BanTokens {
+    fn process(&mut self, _: &GenerationContext, logits: &mut Tensor<f32>) {
+        OP::ban_tokens(logits, &self.0);
+    }
+}
+
+// bans every token that would repeat an n-gram of the context
+pub struct NoRepeatNgram {
+    ngrams: OP::NoRepeatNgram,
+    pushed: usize, // how many of the context tokens
+}
+
+impl NoRepeatNgram {
+    pub fn new(n: usize) -> Self {
+        NoRepeatNgram {
+            ngrams: OP::NoRepeatNgram::new(n),
+            pushed: 0,
+        }
+    }
+}
+
+impl LogitsProcessor for NoRepeatNgram {
+    fn process(&mut self, ctx: &GenerationContext, logits: &mut Tensor<f32>) {
+        if ctx.tokens.len() < self.pushed {
+            *self = NoRepeatNgram::new(self.ngrams.n());
+        }
+        for &token in &ctx.tokens[self.pushed..] {
+            self.ngrams.push(token);
+        }
+        self.pushed = ctx.tokens.len();
+        self.ngrams.ban(logits);
+    }
+}
+
+// cap * tanh(logit / cap); the models with final_logit_softcapping have it applied by
+// forward already
+#[allow(unused)]
+pub struct Softcap(pub f32);
+
+impl LogitsProcessor for Softcap {
+    fn process(&mut self, _: &GenerationContext, logits: &mut Tensor<f32>) {
+        OP::softcap(logits, self.0);
+    }
+}
+
+// The processors generate runs on the logits of every token, in the order they were
+// pushed. from_config pushes, of those the config turns on:
+// 1. RepetitionPenalty
+// 2. FrequencyPresencePenalty
+// 3. Dry
+// 4. LogitBias
+// 5. BanTokens, of banned_tokens and the bos token under suppress_special
+// 6. NoRepeatNgram, so that no penalty or bias brings a banned n-gram back
+// and the ones pushed after it come last.
+#[derive(Default)]
+pub struct LogitsPipeline {
+    processors: Vec<Box<dyn LogitsProcessor>>,
+}
+
+#[allow(unused)]
+impl LogitsPipeline {
+    pub fn from_config(config: &GenerationConfig, bos_token_id: u32) -> Self {
+        let mut pipeline = LogitsPipeline::default();
+        if config.repetition_penalty != 1. {
+            pipeline.push(RepetitionPenalty {
+                penalty: config.repetition_penalty,
+                last_n: config.penalty_last_n,
+            });
+        }
+        if config.frequency_penalty != 0. || config.presence_penalty != 0. {
+            pipeline.push(FrequencyPresencePenalty::new(
+                config.frequency_penalty,
+                config.presence_penalty,
+            ));
+        }
+        if let Some(dry) = &config.dry {
+            pipeline.push(Dry::new(dry.clone()));
+        }
+        if !config.logit_bias.is_empty() {
+            pipeline.push(LogitBias(config.logit_bias.clone()));
+        }
+        let mut banned = config.banned_tokens.clone();
+        if config.suppress_special {
+            banned.push(bos_token_id);
+        }
+        if !banned.is_empty() {
+            pipeline.push(BanTokens(banned));
+        }
+        if config.no_repeat_ngram_size > 0 {
+            pipeline.push(NoRepeatNgram::new(config.no_repeat_ngram_size));
+        }
+        pipeline
+    }
+
+    pub fn push(&mut self, processor: impl LogitsProcessor + 'static) {
+        self.processors.push(Box::new(processor));
+    }
+
+    pub fn len(&self) -> usize {
+        self.processors.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.processors.is_empty()
+    }
+
+    pub fn process(&mut self, ctx: &GenerationContext, logits: &mut Tensor<f32>) {
+        for processor in &mut self.processors {
+            processor.process(ctx, logits);
+        }
+    }
+}
+
+#[test]
+fn test_pipeline_matches_config() {
+    use rand::{Rng, SeedableRng};
+    let config = GenerationConfig::builder()
+        .repetition_penalty(1.3, 6)
+        .frequency_penalty(0.4)
+        .presence_penalty(0.2)
+        .logit_bias(3, 2.5)
+        .logit_bias(9, -1.)
+        .ban(5)
+        .suppress_special(true)
+        .no_repeat_ngram_size(2)
+        .build()
+        .unwrap();
+    let mut pipeline = LogitsPipeline::from_config(&config, 1);
+    assert_eq!(pipeline.len(), 5);
+    let mut rng = rand::rngs::StdRng::seed_from_u64(3);
+    let mut tokens = vec![1, 4, 7, 4];
+    let prompt_len = tokens.len();
+    for _ in 0..12 {
+        let data: Vec<f32> = (0..16).map(|_| rng.gen_range(-3.0..3.0)).collect();
+        let mut expected = Tensor::new(data.clone(), &vec![16]);
+        // every step the way generate used to do it, from the whole context
+        let recent = &tokens[tokens.len().saturating_sub(6)..];
+        OP::repetition_penalty(&mut expected, recent, 1.3);
+        let mut counts = HashMap::new();
+        for &t in &tokens[prompt_len..] {
+            *counts.entry(t).or_default() += 1;
+        }
+        OP::frequency_presence_penalty(&mut expected, &counts, 0.4, 0.2);
+        OP::logit_bias(&mut expected, &config.logit_bias);
+        OP::ban_tokens(&mut expected, &[5]);
+        OP::ban_tokens(&mut expected, &[1]);
+        let mut ngrams = OP::NoRepeatNgram::new(2);
+        tokens.iter().for_each(|&t| ngrams.push(t));
+        ngrams.ban(&mut expected);
+        let mut logits = Tensor::new(data, &vec![16]);
+        let ctx = GenerationContext {
+            tokens: &tokens,
+            prompt_len,
+        };
+        pipeline.process(&ctx, &mut logits);
+        assert_eq!(logits.data(), expected.data());
+        tokens.push(OP::argmax_row(logits.data()));
+    }
+}
+
+#[test]
+fn test_dry() {
+    let params = DryParams {
+        multiplier: 0.8,
+        base: 1.75,
+        allowed_length: 2,
+        sequence_breakers: vec![0],
+    };
+    let penalty = |tokens: &[u32]| {
+        let mut logits = Tensor::new(vec![0.; 16], &vec![16]);
+        let ctx = GenerationContext {
+            tokens,
+            prompt_len: 0,
+        };
+        Dry::new(params.clone()).process(&ctx, &mut logits);
+        -logits.data()[7]
+    };
+    // 5 6 came before, followed by 7
+    assert_eq!(penalty(&[5, 6, 7, 9, 5, 6]), 0.8);
+    assert_eq!(penalty(&[4, 5, 6, 7, 9, 4, 5, 6]), 0.8 * 1.75);
+    assert_eq!(
+        penalty(&[1, 2, 3, 4, 6, 7, 9, 1, 2, 3, 4, 6]),
+        0.8 * 1.75f32.powi(3)
+    );
+    // 6 alone is shorter than allowed_length, and a breaker cuts the match short
+    assert_eq!(penalty(&[6, 7, 9, 6]), 0.);
+    assert_eq!(penalty(&[4, 0, 6, 7, 9, 4, 0, 6]), 0.);
+    // the longest of several matches, the tokens others continue penalized too
+    let tokens = [4, 5, 6, 8, 3, 5, 6, 7, 9, 4, 5, 6];
+    assert_eq!(penalty(&tokens), 0.8);
+    let mut logits = Tensor::new(vec![0.; 16], &vec![16]);
+    let mut dry = Dry::new(params.clone());
+    let ctx = GenerationContext {
+        tokens: &tokens,
+        prompt_len: 0,
+    };
+    dry.process(&ctx, &mut logits);
+    assert_eq!(logits.data()[8], -0.8 * 1.75);
+    // fed token by token it is the same
+    let mut incremental = Dry::new(params);
+    for len in 1..=tokens.len() {
+        let mut logits = Tensor::new(vec![0.; 16], &vec![16]);
+        let ctx = GenerationContext {
+            tokens: &tokens[..len],
+            prompt_len: 0,
+        };
+        incremental.process(&ctx, &mut logits);
+        if len == tokens.len() {
+            assert_eq!(incremental.matches, dry.matches);
+        }
+    }
+}
@@ -0,0 +1,427 @@
+use crate::generation::{GenerateError, GenerationConfig};
+use crate::kvcache::{CacheFileError, KVCache, SharedPrefix};
+use crate::model::Llama;
+use crate::tensor::Tensor;
+#[cfg(test)]
+use crate::test_support::story_model;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+// A conversation with a model: its tokens so far and the cache of them, which every turn
+// goes on from so that only the new tokens are fed. A saved session keeps both, so that a
+// long system prompt is prefilled once rather than on every launch.
+pub struct ChatSession {
+    tokens: Vec<u32>,
+    cache: KVCache<f32>, // the entries of a prefix of tokens
+}
+
+const SESSION_MAGIC: &[u8; 8] = b"LMSES001";
+
+#[allow(unused)]
+impl ChatSession {
+    pub fn new(model: &Llama<f32>) -> Self {
+        ChatSession {
+            tokens: Vec::new(),
+            cache: model.new_cache(),
+        }
+    }
+
+    // a conversation going on from prefix, whose entries it shares with every other
+    // session of it rather than holding a copy
+    pub fn with_prefix(model: &Llama<f32>, prefix: &SharedPrefix) -> Self {
+        ChatSession {
+            tokens: prefix.tokens().to_vec(),
+            cache: model.cache_with_prefix(prefix),
+        }
+    }
+
+    pub fn tokens(&self) -> &[u32] {
+        &self.tokens
+    }
+
+    pub fn cache(&self) -> &KVCache<f32> {
+        &self.cache
+    }
+
+    // appends tokens and feeds them, such as the system prompt ahead of the first turn
+    pub fn prefill(&mut self, model: &Llama<f32>, tokens: &[u32]) -> Result<(), GenerateError> {
+        let len = self.tokens.len() + tokens.len();
+        if len > model.max_seq_len() {
+            return Err(GenerateError::PromptTooLong {
+                len,
+                max_position_embeddings: model.max_seq_len(),
+            });
+        }
+        let mut rest = self.tokens[self.cache.position(self.cache.len())..].to_vec();
+        rest.extend_from_slice(tokens);
+        if !rest.is_empty() {
+            model.forward(
+                &Tensor::new(rest.clone(), &vec![rest.len()]),
+                &mut self.cache,
+            )?;
+        }
+        self.tokens.extend_from_slice(tokens);
+        Ok(())
+    }
+
+    // appends input and then what the model generates after the whole conversation, which
+    // it returns; a failed generation leaves the session as it was
+    pub fn generate(
+        &mut self,
+        model: &Llama<f32>,
+        input: &[u32],
+        config: &GenerationConfig,
+    ) -> Result<Vec<u32>, GenerateError> {
+        let len = self.tokens.len();
+        self.tokens.extend_from_slice(input);
+        match model.generate_in(&mut self.cache, &self.tokens, config) {
+            Ok(generated) => {
+                self.tokens.extend_from_slice(&generated);
+                Ok(generated)
+            }
+            Err(e) => {
+                self.tokens.truncate(len);
+                self.cache.truncate_positions(len);
+                Err(e)
+            }
+        }
+    }
+
+    // the tokens, as a u64 count and u32s, then the cache, all little-endian
+    pub fn save(&self, path: impl AsRef<Path>, model: &Llama<f32>) -> io::Result<()> {
+        let mut w = io::BufWriter::new(std::fs::File::create(path)?);
+        w.write_all(SESSION_MAGIC)?;
+        w.write_all(&(self.tokens.len() as u64).to_le_bytes())?;
+        for token in &self.tokens {
+            w.write_all(&token.to_le_bytes())?;
+        }
+        self.cache.write_to(&mut w, &model.cache_spec())?;
+        w.flush()
+    }
+
+    // a session save wrote for a model of the same config
+    pub fn load(path: impl AsRef<Path>, model: &Llama<f32>) -> Result<Self, CacheFileError> {
+        let mut r = io::BufReader::new(std::fs::File::open(path)?);
+        let mut header = [0; 16];
+        r.read_exact(&mut header)
+            .map_err(|_| CacheFileError::NotACacheFile)?;
+        if &header[..8] != SESSION_MAGIC {
+            return Err(CacheFileError::NotACacheFile);
+        }
+        let n = u64::from_le_bytes(header[8..].try_into().unwrap()) as usize;
+        if n > model.max_seq_len() {
+            return Err(CacheFileError::NotACacheFile);
+        }
+        let mut bytes = vec![0; n * 4];
+        r.read_exact(&mut bytes)?;
+        let tokens: Vec<u32> = bytes
+            .chunks_exact(4)
+            .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+            .collect();
+        let cache = KVCache::read_from(&mut r, &model.cache_spec())?;
+        if cache.position(cache.len()) > tokens.len()
+            || tokens.iter().any(|&t| t as usize >= model.vocab())
+        {
+            return Err(CacheFileError::NotACacheFile);
+        }
+        Ok(ChatSession { tokens, cache })
+    }
+}
+
+pub type SessionId = u64;
+
+// The chat sessions of a server, whose caches stay within a memory budget. When a session
+// does not fit, the least recently used ones go, saved to dir first if there is one, from
+// which get_or_restore loads them back. Sessions are handed out as Arcs, and one that
+// anyone holds besides the manager, such as a request handler generating with it, is
+// never evicted. The manager itself goes behind a Mutex that the handlers share, and only
+// needs to be locked to hand a session out.
+pub struct SessionManager {
+    model: Arc<Llama<f32>>,
+    budget: usize, // bytes of the caches of the resident sessions
+    dir: Option<PathBuf>,
+    sessions: HashMap<SessionId, Resident>,
+    next_id: SessionId,
+    clock: u64, // counts the uses, for the least recently used
+}
+
+struct Resident {
+    session: Arc<Mutex<ChatSession>>,
+    bytes: usize, // the memory of its cache as of the last use
+    last_used: u64,
+}
+
+#[derive(Debug)]
+pub enum SessionError {
+    Unknown(SessionId), // neither resident nor saved
+    // the bytes still needed after evicting every session not in use
+    OverBudget { needed: usize, budget: usize },
+    Save(SessionId, io::Error),
+    Restore(SessionId, CacheFileError),
+}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SessionError::Unknown(id) => write!(f, "no session {id}"),
+            SessionError::OverBudget { needed, budget } => write!(
+                f,
+                "{needed} bytes do not fit into the session budget of {budget}, the other sessions being in use"
+            ),
+            SessionError::Save(id, e) => write!(f, "cannot save session {id}: {e}"),
+            SessionError::Restore(id, e) => write!(f, "cannot restore session {id}: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for SessionError {}
+
+#[allow(unused)]
+impl SessionManager {
+    pub fn new(model: Arc<Llama<f32>>, budget: usize) -> Self {
+        SessionManager {
+            model,
+            budget,
+            dir: None,
+            sessions: HashMap::new(),
+            next_id: 0,
+            clock: 0,
+        }
+    }
+
+    // save evicted sessions into dir rather than dropping them
+    pub fn with_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.dir = Some(dir.into());
+        self
+    }
+
+    fn path(&self, id: SessionId) -> Option<PathBuf> {
+        self.dir
+            .as_ref()
+            .map(|dir| dir.join(format!("{id}.session")))
+    }
+
+    // a new empty session, evicting others to make room for its cache
+    pub fn create_session(&mut self) -> Result<SessionId, SessionError> {
+        let session = ChatSession::new(&self.model);
+        self.evict_to_fit(session.cache().memory_bytes())?;
+        let id = self.next_id;
+        self.next_id += 1;
+        self.insert(id, session);
+        Ok(id)
+    }
+
+    fn insert(&mut self, id: SessionId, session: ChatSession) -> Arc<Mutex<ChatSession>> {
+        self.clock += 1;
+        let session = Arc::new(Mutex::new(session));
+        let bytes = session.lock().unwrap().cache().memory_bytes();
+        self.sessions.insert(
+            id,
+            Resident {
+                session: session.clone(),
+                bytes,
+                last_used: self.clock,
+            },
+        );
+        session
+    }
+
+    // the session, loaded back from dir if it was evicted
+    pub fn get_or_restore(
+        &mut self,
+        id: SessionId,
+    ) -> Result<Arc<Mutex<ChatSession>>, SessionError> {
+        if self.touch(id) {
+            return Ok(self.sessions[&id].session.clone());
+        }
+        let path = self
+            .path(id)
+            .filter(|path| path.exists())
+            .ok_or(SessionError::Unknown(id))?;
+        let session =
+            ChatSession::load(&path, &self.model).map_err(|e| SessionError::Restore(id, e))?;
+        self.evict_to_fit(session.cache().memory_bytes())?;
+        // it is resident again, and saved anew if it is evicted again
+        std::fs::remove_file(&path).map_err(|e| SessionError::Restore(id, e.into()))?;
+        Ok(self.insert(id, session))
+    }
+
+    // marks the session as just used, returning whether it is resident
+    pub fn touch(&mut self, id: SessionId) -> bool {
+        self.clock += 1;
+        let Some(resident) = self.sessions.get_mut(&id) else {
+            return false;
+        };
+        resident.last_used = self.clock;
+        // a paged cache may have grown since
+        if let Ok(session) = resident.session.try_lock() {
+            resident.bytes = session.cache().memory_bytes();
+        }
+        true
+    }
+
+    // Evict the least recently used sessions that are not in use until bytes more fit into
+    // the budget, saving them first if there is a dir.
+    pub fn evict_to_fit(&mut self, bytes: usize) -> Result<(), SessionError> {
+        while self.resident_bytes() + bytes > self.budget {
+            let idle = self
+                .sessions
+                .iter()
+                .filter(|(_, resident)| Arc::strong_count(&resident.session) == 1)
+                .min_by_key(|(_, resident)| resident.last_used)
+                .map(|(&id, _)| id);
+            let Some(id) = idle else {
+                return Err(SessionError::OverBudget {
+                    needed: self.resident_bytes() + bytes - self.budget,
+                    budget: self.budget,
+                });
+            };
+            if let Some(path) = self.path(id) {
+                let session = self.sessions[&id].session.lock().unwrap();
+                session
+                    .save(&path, &self.model)
+                    .map_err(|e| SessionError::Save(id, e))?;
+            }
+            self.sessions.remove(&id);
+        }
+        Ok(())
+    }
+
+    pub fn resident_bytes(&self) -> usize {
+        self.sessions.values().map(|resident| resident.bytes).sum()
+    }
+
+    pub fn is_resident(&self, id: SessionId) -> bool {
+        self.sessions.contains_key(&id)
+    }
+}
+
+#[test]
+fn test_session_save_restore() {
+    let model = story_model();
+    let config = GenerationConfig {
+        max_len: 24,
+        seed: Some(5),
+        ..Default::default()
+    };
+    let system = [1, 300, 25, 700, 40, 26, 410];
+    let mut session = ChatSession::new(&model);
+    session.prefill(&model, &system).unwrap();
+    assert_eq!(session.cache().len(), system.len());
+    let path = std::env::temp_dir().join(format!("session-{}", std::process::id()));
+    session.save(&path, &model).unwrap();
+    let first = session.generate(&model, &[26], &config).unwrap();
+    let second = session.generate(&model, &[13, 26], &config).unwrap();
+    // a fresh model instance goes on from the saved prefill exactly as the session did
+    let fresh = story_model();
+    let mut restored = ChatSession::load(&path, &fresh).unwrap();
+    assert_eq!(restored.tokens(), system);
+    assert_eq!(restored.generate(&fresh, &[26], &config).unwrap(), first);
+    assert_eq!(
+        restored.generate(&fresh, &[13, 26], &config).unwrap(),
+        second
+    );
+    assert_eq!(restored.tokens(), session.tokens());
+    assert_eq!(restored.cache().len(), session.tokens().len() - 1);
+    // and saving again keeps the whole conversation
+    restored.save(&path, &fresh).unwrap();
+    let reloaded = ChatSession::load(&path, &model).unwrap();
+    assert_eq!(reloaded.tokens(), session.tokens());
+    std::fs::write(&path, b"LMSES001 but short").unwrap();
+    assert!(ChatSession::load(&path, &model).is_err());
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_sessions_share_prefix() {
+    let model = story_model();
+    let config = GenerationConfig {
+        max_len: 16,
+        seed: Some(9),
+        ..Default::default()
+    };
+    let system = [1, 300, 25, 700, 40, 26, 410, 13, 26];
+    let prefix = model.shared_prefix(&system).unwrap();
+    for input in [[26, 300], [13, 40]] {
+        let mut own = ChatSession::new(&model);
+        own.prefill(&model, &system).unwrap();
+        let mut shared = ChatSession::with_prefix(&model, &prefix);
+        assert_eq!(shared.tokens(), own.tokens());
+        for _ in 0..2 {
+            assert_eq!(
+                shared.generate(&model, &input, &config).unwrap(),
+                own.generate(&model, &input, &config).unwrap()
+            );
+        }
+        // the session only holds storage for what comes after the prefix
+        let per_token = own.cache().memory_bytes() / model.max_seq_len();
+        assert_eq!(prefix.memory_bytes(), system.len() * per_token);
+        assert_eq!(
+            shared.cache().memory_bytes(),
+            (model.max_seq_len() - system.len()) * per_token
+        );
+    }
+}
+
+#[test]
+fn test_session_manager_lru() {
+    let model = Arc::new(story_model());
+    let config = GenerationConfig {
+        max_len: 12,
+        seed: Some(6),
+        ..Default::default()
+    };
+    let dir = std::env::temp_dir().join(format!("sessions-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let per_session = model.new_cache().memory_bytes();
+    let mut manager = SessionManager::new(model.clone(), 2 * per_session).with_dir(&dir);
+    let (a, b) = (
+        manager.create_session().unwrap(),
+        manager.create_session().unwrap(),
+    );
+    // b talks first, then a, so b is the least recently used one
+    let mut reference = ChatSession::new(&model);
+    let first = reference.generate(&model, &[1, 300, 25], &config).unwrap();
+    for id in [b, a] {
+        let session = manager.get_or_restore(id).unwrap();
+        let reply = session
+            .lock()
+            .unwrap()
+            .generate(&model, &[1, 300, 25], &config)
+            .unwrap();
+        assert_eq!(reply, first);
+    }
+    let c = manager.create_session().unwrap();
+    assert!(manager.is_resident(a) && !manager.is_resident(b) && manager.is_resident(c));
+    assert_eq!(manager.resident_bytes(), 2 * per_session);
+    // a session in use is never evicted: restoring b has to evict c rather than a
+    let in_use = manager.get_or_restore(a).unwrap();
+    manager.touch(c);
+    let restored = manager.get_or_restore(b).unwrap();
+    assert!(manager.is_resident(a) && !manager.is_resident(c));
+    // and b goes on from its saved history as if it had never left
+    let expected = reference.generate(&model, &[13, 26], &config).unwrap();
+    let mut restored = restored.lock().unwrap();
+    assert_eq!(restored.tokens(), &reference.tokens()[..3 + first.len()]);
+    assert_eq!(
+        restored.generate(&model, &[13, 26], &config).unwrap(),
+        expected
+    );
+    drop(restored);
+    // with a and b both in use nothing can make room for another
+    let _b = manager.get_or_restore(b).unwrap();
+    assert!(matches!(
+        manager.create_session(),
+        Err(SessionError::OverBudget { .. })
+    ));
+    drop(in_use);
+    assert!(manager.create_session().is_ok() && !manager.is_resident(a));
+    assert!(matches!(
+        SessionManager::new(model, per_session).get_or_restore(a),
+        Err(SessionError::Unknown(_))
+    ));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,69 @@
+// Causal softmax over the last axis of a (.., seq_len, total_seq_len) tensor, one workgroup
+// per row. Row i of every seq_len block sees total_seq_len - seq_len + i + 1 positions, the
+// rest is zeroed. Rows that cannot be normalized (all -inf or non-finite) are zeroed too.
+struct Params {
+    rows: u32,
+    seq_len: u32,
+    total_seq_len: u32,
+    rows_per_dispatch: u32,
+}
+
+const WG: u32 = 64u;
+
+@group(0) @binding(0) var<storage, read_write> y: array<f32>;
+@group(0) @binding(1) var<uniform> p: Params;
+
+var<workgroup> partial: array<f32, 64>;
+
+@compute @workgroup_size(64)
+fn main(
+    @builtin(workgroup_id) wid: vec3<u32>,
+    @builtin(local_invocation_id) lid: vec3<u32>,
+) {
+    let row = wid.y * p.rows_per_dispatch + wid.x;
+    if (row >= p.rows) {
+        return;
+    }
+    let base = row * p.total_seq_len;
+    let visible = p.total_seq_len - p.seq_len + row % p.seq_len + 1u;
+
+    var m = -3.4028235e38;
+    for (var j = lid.x; j < visible; j += WG) {
+        m = max(m, y[base + j]);
+    }
+    partial[lid.x] = m;
+    workgroupBarrier();
+    for (var stride = WG / 2u; stride > 0u; stride >>= 1u) {
+        if (lid.x < stride) {
+            partial[lid.x] = max(partial[lid.x], partial[lid.x + stride]);
+        }
+        workgroupBarrier();
+    }
+    m = partial[0];
+    workgroupBarrier();
+
+    var s = 0.0;
+    for (var j = lid.x; j < visible; j += WG) {
+        let e = exp(y[base + j] - m);
+        y[base + j] = e;
+        s += e;
+    }
+    partial[lid.x] = s;
+    workgroupBarrier();
+    for (var stride = WG / 2u; stride > 0u; stride >>= 1u) {
+        if (lid.x < stride) {
+            partial[lid.x] += partial[lid.x + stride];
+        }
+        workgroupBarrier();
+    }
+    s = partial[0];
+    // s != s is NaN
+    let ok = s > 0.0 && s <= 3.4028235e38 && s == s;
+    for (var j = lid.x; j < p.total_seq_len; j += WG) {
+        if (ok && j < visible) {
+            y[base + j] = y[base + j] / s;
+        } else {
+            y[base + j] = 0.0;
+        }
+    }
+}
@@ -0,0 +1,57 @@
+// C = beta * C + alpha * A @ B^T, A is (m, k), B is (n, k) and C is (m, n).
+// Every workgroup computes one TILE x TILE block of C, staging TILE-wide slices of the
+// rows of A and B in workgroup memory. Out-of-range rows, columns and k are loaded as
+// zeros, so no shape needs to be a multiple of TILE.
+struct Params {
+    m: u32,
+    n: u32,
+    k: u32,
+    beta: f32,
+    alpha: f32,
+    _pad0: u32,
+    _pad1: u32,
+    _pad2: u32,
+}
+
+const TILE: u32 = 16u;
+
+@group(0) @binding(0) var<storage, read> a: array<f32>;
+@group(0) @binding(1) var<storage, read> b: array<f32>;
+@group(0) @binding(2) var<storage, read_write> c: array<f32>;
+@group(0) @binding(3) var<uniform> p: Params;
+
+var<workgroup> tile_a: array<array<f32, 16>, 16>;
+var<workgroup> tile_b: array<array<f32, 16>, 16>;
+
+@compute @workgroup_size(16, 16)
+fn main(
+    @builtin(workgroup_id) wid: vec3<u32>,
+    @builtin(local_invocation_id) lid: vec3<u32>,
+) {
+    let row = wid.y * TILE + lid.y; // row of A and C
+    let col = wid.x * TILE + lid.x; // row of B, column of C
+    let b_row = wid.x * TILE + lid.y; // the row of B this invocation loads
+    var sum = 0.0;
+    for (var t = 0u; t < (p.k + TILE - 1u) / TILE; t++) {
+        let kk = t * TILE + lid.x;
+        if (row < p.m && kk < p.k) {
+            tile_a[lid.y][lid.x] = a[row * p.k + kk];
+        } else {
+            tile_a[lid.y][lid.x] = 0.0;
+        }
+        if (b_row < p.n && kk < p.k) {
+            tile_b[lid.y][lid.x] = b[b_row * p.k + kk];
+        } else {
+            tile_b[lid.y][lid.x] = 0.0;
+        }
+        workgroupBarrier();
+        for (var i = 0u; i < TILE; i++) {
+            sum += tile_a[lid.y][i] * tile_b[lid.x][i];
+        }
+        workgroupBarrier();
+    }
+    if (row < p.m && col < p.n) {
+        let idx = row * p.n + col;
+        c[idx] = p.beta * c[idx] + p.alpha * sum;
+    }
+}
@@ -0,0 +1,42 @@
+// y = x / sqrt(mean(x^2) + eps) * (offset + w) over rows of length n, one workgroup per row
+struct Params {
+    rows: u32,
+    n: u32,
+    eps: f32,
+    offset: f32,
+}
+
+const WG: u32 = 64u;
+
+@group(0) @binding(0) var<storage, read> x: array<f32>;
+@group(0) @binding(1) var<storage, read> w: array<f32>;
+@group(0) @binding(2) var<storage, read_write> y: array<f32>;
+@group(0) @binding(3) var<uniform> p: Params;
+
+var<workgroup> partial: array<f32, 64>;
+
+@compute @workgroup_size(64)
+fn main(
+    @builtin(workgroup_id) wid: vec3<u32>,
+    @builtin(local_invocation_id) lid: vec3<u32>,
+) {
+    let row = wid.x;
+    let base = row * p.n;
+    var s = 0.0;
+    for (var i = lid.x; i < p.n; i += WG) {
+        let v = x[base + i];
+        s += v * v;
+    }
+    partial[lid.x] = s;
+    workgroupBarrier();
+    for (var stride = WG / 2u; stride > 0u; stride >>= 1u) {
+        if (lid.x < stride) {
+            partial[lid.x] += partial[lid.x + stride];
+        }
+        workgroupBarrier();
+    }
+    let rms = sqrt(partial[0] / f32(p.n) + p.eps);
+    for (var i = lid.x; i < p.n; i += WG) {
+        y[base + i] = (p.offset + w[i]) * x[base + i] / rms;
+    }
+}
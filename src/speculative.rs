@@ -0,0 +1,298 @@
+use crate::generation::{GenerationConfig, GenerationStats};
+use crate::kvcache::KVCache;
+use crate::model::Llama;
+use crate::operators as OP;
+use crate::processor::{GenerationContext, LogitsPipeline};
+use crate::tensor::Tensor;
+#[cfg(test)]
+use crate::test_support::story_model;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+// Speculative sampling (Leviathan et al., Chen et al.): the draft model proposes up to k
+// tokens one by one, the target model scores all of them in a single forward, and draft
+// token x is kept with probability min(1, p(x) / q(x)), p and q being what the target and
+// the draft sample from. The first rejected one is replaced by a sample of
+// max(0, p - q), and when all are kept the target adds one more token, so the output is
+// distributed exactly as sampling the target alone. Both caches are truncated back to the
+// kept tokens after every round.
+pub struct SpeculativeGenerator {
+    target: Llama<f32>,
+    draft: Llama<f32>,
+    target_cache: KVCache<f32>,
+    draft_cache: KVCache<f32>,
+    k: usize,
+}
+
+#[allow(unused)]
+impl SpeculativeGenerator {
+    pub fn new(target: Llama<f32>, draft: Llama<f32>, k: usize) -> Self {
+        assert!(k > 0);
+        assert!(
+            target.vocab() == draft.vocab(),
+            "the draft model has a vocab of {} rather than {}",
+            draft.vocab(),
+            target.vocab()
+        );
+        SpeculativeGenerator {
+            target_cache: target.new_cache(),
+            draft_cache: draft.new_cache(),
+            target,
+            draft,
+            k,
+        }
+    }
+
+    // Llama::generate of the target model, which also stops at the end of the shorter
+    // max_seq_len of both. The penalties, no_repeat_ngram_size, mirostat and attention
+    // sinks are not supported, DRY is.
+    pub fn generate(
+        &mut self,
+        prompt: &[u32],
+        config: &GenerationConfig,
+    ) -> Result<(Vec<u32>, GenerationStats), OP::OperatorError> {
+        config
+            .validate_tokens()
+            .and_then(|_| config.check(self.target.vocab()))
+            .unwrap_or_else(|e| panic!("invalid generation config: {e}"));
+        assert!(
+            config.repetition_penalty == 1.
+                && config.frequency_penalty == 0.
+                && config.presence_penalty == 0.
+                && config.no_repeat_ngram_size == 0
+                && config.mirostat.is_none()
+                && config.sink().is_none(),
+            "speculative decoding only supports settings that do not depend on the history"
+        );
+        assert!(!prompt.is_empty());
+        let (vocab, bos) = (self.target.vocab(), self.target.bos_token_id());
+        let max_seq_len = self.target.max_seq_len().min(self.draft.max_seq_len());
+        // the draws of the samples, which with draft == target are the ones generate makes,
+        // and those deciding acceptance
+        let (mut rng, mut accept_rng) = match config.seed {
+            Some(seed) => (
+                StdRng::seed_from_u64(seed),
+                StdRng::seed_from_u64(seed ^ 0x5eed_5eed_5eed_5eed),
+            ),
+            None => (StdRng::from_entropy(), StdRng::from_entropy()),
+        };
+        let mut scratch = OP::SampleScratch::default();
+        let sample = |logits: &Tensor<f32>, rng: &mut StdRng, scratch: &mut OP::SampleScratch| {
+            OP::random_sample_with(
+                logits,
+                config.top_p,
+                config.top_k,
+                config.temperature,
+                config.min_p,
+                config.typical_p,
+                rng,
+                scratch,
+            )
+        };
+        let probs = |logits: &Tensor<f32>, scratch: &mut OP::SampleScratch, out: &mut [f32]| {
+            OP::sample_probs(
+                logits,
+                config.top_p,
+                config.top_k,
+                config.temperature,
+                config.min_p,
+                config.typical_p,
+                scratch,
+                out,
+            )
+        };
+        // of the processors only DRY looks at the history, which for every draft and every
+        // row of the target is the tokens before it, the drafts so far included
+        let mut pipeline = LogitsPipeline::from_config(config, bos);
+        let mut process = |tokens: &[u32], logits: &mut Tensor<f32>| {
+            let prompt_len = prompt.len();
+            pipeline.process(&GenerationContext { tokens, prompt_len }, logits)
+        };
+        let mut q = vec![vec![0f32; vocab]; self.k];
+        let mut p = vec![0f32; vocab];
+        let mut stats = GenerationStats::default();
+        self.target_cache = self.target.new_cache();
+        self.draft_cache = self.draft.new_cache();
+        // the caches hold a prefix of tokens, the tokens after it are fed in the next round
+        let mut tokens = prompt.to_vec();
+        let mut done = false;
+        while !done && tokens.len() - prompt.len() < config.max_len && tokens.len() <= max_seq_len {
+            let len = tokens.len();
+            let k = self
+                .k
+                .min(config.max_len - (len - prompt.len()) - 1)
+                .min(max_seq_len - len);
+            let mut history = tokens.clone();
+            for q in &mut q[..k] {
+                let input = if history.len() > len {
+                    vec![*history.last().unwrap()]
+                } else {
+                    tokens[self.draft_cache.len()..].to_vec()
+                };
+                let n = input.len();
+                let input = Tensor::new(input, &vec![n]);
+                let mut logits = self.draft.forward(&input, &mut self.draft_cache)?;
+                logits.reshape(&vec![vocab]);
+                process(&history, &mut logits);
+                probs(&logits, &mut scratch, q);
+                history.push(sample(&logits, &mut rng, &mut scratch));
+            }
+            let drafts = history.split_off(len);
+            // scoring the drafts and the token after them in one forward
+            let mut chunk = tokens[self.target_cache.len()..].to_vec();
+            chunk.extend_from_slice(&drafts);
+            let input = Tensor::new(chunk.clone(), &vec![chunk.len()]);
+            let logits = self.target.forward_all(&input, &mut self.target_cache)?;
+            stats.forwards += 1;
+            stats.drafted += k;
+            let first = chunk.len() - k - 1;
+            let mut next = None;
+            for (i, &token) in drafts.iter().enumerate() {
+                // tokens has the kept drafts[..i] pushed already
+                let mut row = logits.slice((first + i) * vocab, &vec![vocab]);
+                process(&tokens, &mut row);
+                probs(&row, &mut scratch, &mut p);
+                let t = token as usize;
+                if accept_rng.gen::<f32>() * q[i][t] < p[t] {
+                    stats.accepted += 1;
+                    tokens.push(token);
+                    if self.target.eos_token_ids().contains(&token) {
+                        done = true;
+                        break;
+                    }
+                    continue;
+                }
+                // rejected, p - q has some mass left wherever p exceeds q
+                for (p, q) in p.iter_mut().zip(&q[i]) {
+                    *p = (*p - q).max(0.);
+                }
+                let total = p.iter().sum::<f32>();
+                next = Some(if total > 0. {
+                    let mut r = rng.gen::<f32>() * total;
+                    p.iter()
+                        .position(|&w| {
+                            r -= w;
+                            r < 0. && w > 0.
+                        })
+                        .unwrap_or_else(|| p.iter().rposition(|&w| w > 0.).unwrap())
+                        as u32
+                } else {
+                    sample(&row, &mut rng, &mut scratch)
+                });
+                break;
+            }
+            if !done {
+                let next = next.unwrap_or_else(|| {
+                    let mut row = logits.slice((first + k) * vocab, &vec![vocab]);
+                    process(&tokens, &mut row);
+                    sample(&row, &mut rng, &mut scratch)
+                });
+                tokens.push(next);
+                done = self.target.eos_token_ids().contains(&next);
+            }
+            // keep what the kept tokens left in the caches, all but the last token
+            let kept = tokens.len() - 1;
+            self.target_cache
+                .truncate(self.target_cache.len().min(kept));
+            self.draft_cache.truncate(self.draft_cache.len().min(kept));
+        }
+        let output = tokens.split_off(prompt.len());
+        stats.tokens = output.len();
+        Ok((output, stats))
+    }
+}
+
+#[test]
+fn test_speculative_same_model() {
+    let mut generator = SpeculativeGenerator::new(story_model(), story_model(), 4);
+    let model = story_model();
+    let prompt = [1, 300, 25, 700, 40];
+    for seed in [1, 2, 3] {
+        let config = GenerationConfig {
+            max_len: 48,
+            top_p: 0.9,
+            top_k: 50,
+            seed: Some(seed),
+            ..Default::default()
+        };
+        let (tokens, stats) = generator.generate(&prompt, &config).unwrap();
+        // every draft is kept and the samples are those of plain sampling
+        assert_eq!(tokens, model.generate(&prompt, &config).unwrap());
+        assert!(stats.acceptance_rate() > 0.99, "{stats:?}");
+        assert_eq!(stats.tokens, tokens.len());
+        assert!(stats.tokens_per_forward() > 3., "{stats:?}");
+    }
+}
+
+#[test]
+fn test_speculative_dry() {
+    let mut generator = SpeculativeGenerator::new(story_model(), story_model(), 4);
+    let model = story_model();
+    let prompt = [1, 300, 25, 700, 40];
+    let plain = GenerationConfig {
+        max_len: 96,
+        top_k: 1,
+        ..Default::default()
+    };
+    let dry = GenerationConfig {
+        dry: Some(crate::generation::DryParams {
+            multiplier: 2.,
+            base: 1.75,
+            allowed_length: 2,
+            sequence_breakers: vec![],
+        }),
+        ..plain.clone()
+    };
+    // DRY changes what the target samples, and the drafts are penalized after the ones
+    // before them just like it
+    assert_ne!(
+        model.generate(&prompt, &dry).unwrap(),
+        model.generate(&prompt, &plain).unwrap()
+    );
+    for config in [
+        dry.clone(),
+        GenerationConfig {
+            top_k: 50,
+            top_p: 0.9,
+            seed: Some(3),
+            ..dry
+        },
+    ] {
+        let (tokens, stats) = generator.generate(&prompt, &config).unwrap();
+        assert_eq!(tokens, model.generate(&prompt, &config).unwrap());
+        assert!(stats.acceptance_rate() > 0.99, "{stats:?}");
+    }
+}
+
+#[test]
+fn test_speculative_other_draft() {
+    // a draft differing from the target, its lm_head quantized to 4 bits
+    let mut draft = story_model();
+    draft.quantize_lm_head_q4();
+    let mut generator = SpeculativeGenerator::new(story_model(), draft, 3);
+    let model = story_model();
+    let prompt = [1, 300, 25, 700, 40];
+    // greedy speculation stays exactly greedy decoding of the target, whatever is rejected
+    let greedy = GenerationConfig {
+        max_len: 64,
+        top_k: 1,
+        ..Default::default()
+    };
+    let (tokens, stats) = generator.generate(&prompt, &greedy).unwrap();
+    assert_eq!(tokens, model.generate(&prompt, &greedy).unwrap());
+    assert!(
+        stats.accepted > 0 && stats.forwards < tokens.len(),
+        "{stats:?}"
+    );
+    let config = GenerationConfig {
+        max_len: 64,
+        seed: Some(5),
+        ..Default::default()
+    };
+    let (tokens, stats) = generator.generate(&prompt, &config).unwrap();
+    assert!(tokens.len() <= 64 && tokens.iter().all(|&t| (t as usize) < model.vocab()));
+    assert!(
+        stats.acceptance_rate() > 0.2 && stats.acceptance_rate() <= 1.,
+        "{stats:?}"
+    );
+}
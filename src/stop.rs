@@ -0,0 +1,152 @@
+// finds stop strings in the generated bytes, holding back whatever may still begin one
+pub struct StopMatcher {
+    stops: Vec<Vec<u8>>,
+    pending: Vec<u8>, // bytes that end in a prefix of some stop string
+    stopped: bool,
+}
+
+impl StopMatcher {
+    // empty stop strings never match
+    pub fn new(stops: &[String]) -> Self {
+        StopMatcher {
+            stops: stops
+                .iter()
+                .filter(|s| !s.is_empty())
+                .map(|s| s.as_bytes().to_vec())
+                .collect(),
+            pending: Vec::new(),
+            stopped: false,
+        }
+    }
+
+    pub fn stopped(&self) -> bool {
+        self.stopped
+    }
+
+    // appends the bytes of a token and returns the bytes no stop string can start in any
+    // more; once a stop string shows up, whatever comes before it and then nothing
+    pub fn push(&mut self, bytes: &[u8]) -> Vec<u8> {
+        if self.stopped {
+            return Vec::new();
+        }
+        self.pending.extend_from_slice(bytes);
+        let first = self
+            .stops
+            .iter()
+            .filter_map(|stop| {
+                self.pending
+                    .windows(stop.len())
+                    .position(|w| w == &stop[..])
+            })
+            .min();
+        if let Some(start) = first {
+            self.stopped = true;
+            self.pending.truncate(start);
+            return std::mem::take(&mut self.pending);
+        }
+        // the longest tail that a stop string begins with
+        let held = self
+            .stops
+            .iter()
+            .filter_map(|stop| {
+                (1..stop.len().min(self.pending.len() + 1))
+                    .rev()
+                    .find(|&n| self.pending.ends_with(&stop[..n]))
+            })
+            .max()
+            .unwrap_or(0);
+        self.pending.drain(..self.pending.len() - held).collect()
+    }
+
+    // the held back bytes, for when generation ends before a stop string completes
+    pub fn finish(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.pending)
+    }
+}
+
+// turns a stream of bytes into text, holding back a character split across chunks
+#[derive(Default)]
+pub struct Utf8Stream {
+    pending: Vec<u8>,
+}
+
+impl Utf8Stream {
+    pub fn push(&mut self, bytes: &[u8]) -> String {
+        self.pending.extend_from_slice(bytes);
+        let mut text = String::new();
+        loop {
+            match std::str::from_utf8(&self.pending) {
+                Ok(s) => {
+                    text.push_str(s);
+                    self.pending.clear();
+                    return text;
+                }
+                Err(e) => {
+                    let valid = e.valid_up_to();
+                    text.push_str(std::str::from_utf8(&self.pending[..valid]).unwrap());
+                    match e.error_len() {
+                        // invalid bytes, which no later chunk repairs
+                        Some(n) => {
+                            text.push(char::REPLACEMENT_CHARACTER);
+                            self.pending.drain(..valid + n);
+                        }
+                        None => {
+                            self.pending.drain(..valid);
+                            return text;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn finish(&mut self) -> String {
+        let text = String::from_utf8_lossy(&self.pending).into_owned();
+        self.pending.clear();
+        text
+    }
+}
+
+#[test]
+fn test_stop_split_across_tokens() {
+    let mut stop = StopMatcher::new(&["<|user|>".to_string(), "\nUser:".to_string()]);
+    assert_eq!(stop.push(b"Hello <|us"), b"Hello ");
+    assert!(!stop.stopped());
+    assert_eq!(stop.push(b"er|> and more"), b"");
+    assert!(stop.stopped());
+    assert_eq!(stop.push(b"ignored"), b"");
+    // and a token holding the end of the text, a whole stop string and more
+    let mut stop = StopMatcher::new(&["\nUser:".to_string()]);
+    assert_eq!(stop.push(b"Hi"), b"Hi");
+    assert_eq!(stop.push(b"!\nUser: x"), b"!");
+    assert!(stop.stopped());
+}
+
+#[test]
+fn test_stop_prefix_never_completes() {
+    let mut stop = StopMatcher::new(&["<|user|>".to_string()]);
+    assert_eq!(stop.push(b"a <|u"), b"a ");
+    // the held back prefix is let go as soon as it cannot become the stop string
+    assert_eq!(stop.push(b"x"), b"<|ux");
+    assert_eq!(stop.push(b" <"), b" ");
+    assert_eq!(stop.finish(), b"<");
+    assert!(!stop.stopped());
+}
+
+#[test]
+fn test_stop_streaming() {
+    let stops = ["END".to_string(), "ENDING".to_string(), "ab".to_string()];
+    let chunks: [&[u8]; 7] = [b"x", b"E", b"N", b"\xc3", b"\xa9 EN", b"DING", b" rest"];
+    let mut stop = StopMatcher::new(&stops);
+    let mut utf8 = Utf8Stream::default();
+    let mut streamed = Vec::new();
+    for chunk in chunks {
+        streamed.push(utf8.push(&stop.push(chunk)));
+    }
+    streamed.push(utf8.push(&stop.finish()));
+    streamed.push(utf8.finish());
+    assert!(stop.stopped());
+    assert_eq!(streamed.concat(), "xEN\u{e9} ");
+    // "EN" waits until it cannot start a stop string, the split character until it is whole
+    assert_eq!(streamed[..5], ["x", "", "", "EN", "\u{e9} "]);
+}
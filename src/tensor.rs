@@ -1,24 +1,27 @@
-use std::{slice, sync::Arc, vec};
+use half::{bf16, f16};
+use smallvec::SmallVec;
+use std::{slice, sync::Arc};
 pub struct Tensor<T> {
     data: Arc<Box<[T]>>,
-    shape: Vec<usize>,
+    // inline up to 4 dims, so that slicing and reshaping in forward allocate nothing
+    shape: SmallVec<[usize; 4]>,
     offset: usize,
     length: usize,
 }
 
 impl<T: Copy + Clone + Default> Tensor<T> {
-    pub fn new(data: Vec<T>, shape: &Vec<usize>) -> Self {
+    pub fn new(data: Vec<T>, shape: &(impl AsRef<[usize]> + ?Sized)) -> Self {
         let length = data.len();
         Tensor {
-            data: Arc::new(data.into_boxed_slice().try_into().unwrap()),
-            shape: shape.clone(),
+            data: Arc::new(data.into_boxed_slice()),
+            shape: SmallVec::from_slice(shape.as_ref()),
             offset: 0,
-            length: length,
+            length,
         }
     }
 
-    pub fn default(shape: &Vec<usize>) -> Self {
-        let length = shape.iter().product();
+    pub fn default(shape: &(impl AsRef<[usize]> + ?Sized)) -> Self {
+        let length = shape.as_ref().iter().product();
         let data = vec![T::default(); length];
         Self::new(data, shape)
     }
@@ -32,7 +35,7 @@ impl<T: Copy + Clone + Default> Tensor<T> {
         slice::from_raw_parts_mut(ptr, self.length)
     }
 
-    pub fn shape(&self) -> &Vec<usize> {
+    pub fn shape(&self) -> &[usize] {
         &self.shape
     }
 
@@ -41,28 +44,28 @@ impl<T: Copy + Clone + Default> Tensor<T> {
     }
 
     // Reinterpret the tensor as a new shape while preserving total size.
-    pub fn reshape(&mut self, new_shape: &Vec<usize>) -> &mut Self {
+    pub fn reshape(&mut self, new_shape: &(impl AsRef<[usize]> + ?Sized)) -> &mut Self {
+        let new_shape = new_shape.as_ref();
         let new_length: usize = new_shape.iter().product();
         if new_length != self.length {
             let old_shape = self.shape.clone();
             panic!("New shape {new_shape:?} does not match tensor of {old_shape:?}");
         }
-        self.shape = new_shape.clone();
+        self.shape = SmallVec::from_slice(new_shape);
         self
     }
 
-    pub fn slice(&self, start: usize, shape: &Vec<usize>) -> Self {
+    pub fn slice(&self, start: usize, shape: &(impl AsRef<[usize]> + ?Sized)) -> Self {
+        let shape = shape.as_ref();
         let new_length: usize = shape.iter().product();
         assert!(self.offset + start + new_length <= self.length);
         Tensor {
             data: self.data.clone(),
-            shape: shape.clone(),
+            shape: SmallVec::from_slice(shape),
             offset: self.offset + start,
             length: new_length,
         }
     }
-
-
 }
 
 // Some helper functions for testing and debugging
@@ -74,12 +77,41 @@ impl Tensor<f32> {
         }
         let a = self.data();
         let b = other.data();
-        
-        return a.iter().zip(b).all(|(x, y)| float_eq(x, y, rel));
+
+        a.iter().zip(b).all(|(x, y)| float_eq(x, y, rel))
+    }
+    // absolute counterpart of close_to for data that crosses zero, where relative
+    // tolerances break down
+    #[allow(unused)]
+    pub fn max_abs_diff(&self, other: &Self) -> f32 {
+        assert!(self.shape() == other.shape());
+        let a = self.data();
+        let b = other.data();
+        a.iter().zip(b).fold(0f32, |m, (x, y)| m.max((x - y).abs()))
+    }
+    #[allow(unused)]
+    pub fn to_f16(&self) -> Tensor<f16> {
+        Tensor::new(
+            self.data().iter().map(|&x| f16::from_f32(x)).collect(),
+            &self.shape,
+        )
+    }
+    #[allow(unused)]
+    pub fn to_bf16(&self) -> Tensor<bf16> {
+        let data = self.data().iter().map(|&x| bf16::from_bits(f32_to_bf16(x)));
+        Tensor::new(data.collect(), &self.shape)
     }
     #[allow(unused)]
-    pub fn print(&self){
-        println!("shpae: {:?}, offset: {}, length: {}", self.shape, self.offset, self.length);
+    pub fn random(shape: &(impl AsRef<[usize]> + ?Sized)) -> Self {
+        let length = shape.as_ref().iter().product();
+        Self::new((0..length).map(|_| rand::random()).collect(), shape)
+    }
+    #[allow(unused)]
+    pub fn print(&self) {
+        println!(
+            "shpae: {:?}, offset: {}, length: {}",
+            self.shape, self.offset, self.length
+        );
         let dim = self.shape()[self.shape().len() - 1];
         let batch = self.length / dim;
         for i in 0..batch {
@@ -89,6 +121,269 @@ impl Tensor<f32> {
     }
 }
 
+// Q8_0 block quantization: every row is cut into blocks of Q8_BLOCK values sharing one f32
+// scale, value = scale * q with q an i8. The last block of a row whose length is not a
+// multiple of Q8_BLOCK is padded with zeros.
+pub const Q8_BLOCK: usize = 32;
+
+pub struct QuantizedTensor {
+    values: Vec<i8>,  // blocks_per_row * Q8_BLOCK per row
+    scales: Vec<f32>, // blocks_per_row per row
+    shape: Vec<usize>,
+}
+
+#[allow(unused)]
+impl QuantizedTensor {
+    pub fn shape(&self) -> &[usize] {
+        &self.shape
+    }
+
+    pub fn blocks_per_row(&self) -> usize {
+        self.shape.last().unwrap().div_ceil(Q8_BLOCK)
+    }
+
+    // the scales and (padded) values of row i
+    pub fn row(&self, i: usize) -> (&[f32], &[i8]) {
+        let blocks = self.blocks_per_row();
+        (
+            &self.scales[i * blocks..][..blocks],
+            &self.values[i * blocks * Q8_BLOCK..][..blocks * Q8_BLOCK],
+        )
+    }
+
+    // bytes taken by the values and scales
+    pub fn size_in_bytes(&self) -> usize {
+        self.values.len() + self.scales.len() * std::mem::size_of::<f32>()
+    }
+
+    pub fn dequantize(&self) -> Tensor<f32> {
+        let n = *self.shape.last().unwrap();
+        let rows = self.shape.iter().product::<usize>() / n;
+        let mut data = Vec::with_capacity(rows * n);
+        for i in 0..rows {
+            let (scales, values) = self.row(i);
+            for (q, s) in values.chunks(Q8_BLOCK).zip(scales) {
+                data.extend(q.iter().map(|&q| s * q as f32));
+            }
+            data.truncate((i + 1) * n);
+        }
+        Tensor::new(data, &self.shape)
+    }
+}
+
+// Quantize t to Q8_0 along its last axis, scale = max(abs(block)) / 127
+#[allow(unused)]
+pub fn quantize_q8(t: &Tensor<f32>) -> QuantizedTensor {
+    let n = *t.shape().last().unwrap();
+    let blocks = n.div_ceil(Q8_BLOCK);
+    let rows = t.size() / n;
+    let mut values = vec![0i8; rows * blocks * Q8_BLOCK];
+    let mut scales = Vec::with_capacity(rows * blocks);
+    for (row, q_row) in t.data().chunks(n).zip(values.chunks_mut(blocks * Q8_BLOCK)) {
+        for (x, q) in row.chunks(Q8_BLOCK).zip(q_row.chunks_mut(Q8_BLOCK)) {
+            let amax = x.iter().fold(0f32, |m, x| m.max(x.abs()));
+            let scale = amax / 127.;
+            if scale > 0. {
+                for (q, x) in q.iter_mut().zip(x) {
+                    *q = (x / scale).round() as i8;
+                }
+            }
+            scales.push(scale);
+        }
+    }
+    QuantizedTensor {
+        values,
+        scales,
+        shape: t.shape().to_vec(),
+    }
+}
+
+// Q4_0 group quantization: groups of Q4_BLOCK values of a row share one f32 scale, value =
+// scale * (q - 8) with q a nibble, two per byte (low nibble first). Symmetric, scale =
+// max(abs(group)) / 7, padded with zeros like Q8_0.
+pub const Q4_BLOCK: usize = 32;
+
+pub struct Q4Tensor {
+    packed: Vec<u8>,  // blocks_per_row * Q4_BLOCK / 2 per row
+    scales: Vec<f32>, // blocks_per_row per row
+    shape: Vec<usize>,
+}
+
+#[allow(unused)]
+impl Q4Tensor {
+    pub fn shape(&self) -> &[usize] {
+        &self.shape
+    }
+
+    pub fn blocks_per_row(&self) -> usize {
+        self.shape.last().unwrap().div_ceil(Q4_BLOCK)
+    }
+
+    // the scales and packed nibbles of row i
+    pub fn row(&self, i: usize) -> (&[f32], &[u8]) {
+        let blocks = self.blocks_per_row();
+        (
+            &self.scales[i * blocks..][..blocks],
+            &self.packed[i * blocks * Q4_BLOCK / 2..][..blocks * Q4_BLOCK / 2],
+        )
+    }
+
+    pub fn size_in_bytes(&self) -> usize {
+        self.packed.len() + self.scales.len() * std::mem::size_of::<f32>()
+    }
+
+    pub fn dequantize(&self) -> Tensor<f32> {
+        let n = *self.shape.last().unwrap();
+        let rows = self.shape.iter().product::<usize>() / n;
+        let mut data = Vec::with_capacity(rows * n);
+        for i in 0..rows {
+            let (scales, packed) = self.row(i);
+            for (q, s) in packed.chunks(Q4_BLOCK / 2).zip(scales) {
+                for &byte in q {
+                    let (lo, hi) = unpack_q4(byte);
+                    data.extend([s * lo, s * hi]);
+                }
+            }
+            data.truncate((i + 1) * n);
+        }
+        Tensor::new(data, &self.shape)
+    }
+}
+
+// the two values of a packed byte, still to be multiplied by the scale
+#[inline]
+pub fn unpack_q4(byte: u8) -> (f32, f32) {
+    ((byte & 0xf) as f32 - 8., (byte >> 4) as f32 - 8.)
+}
+
+// Quantize t to Q4_0 along its last axis
+#[allow(unused)]
+pub fn quantize_q4(t: &Tensor<f32>) -> Q4Tensor {
+    let n = *t.shape().last().unwrap();
+    let blocks = n.div_ceil(Q4_BLOCK);
+    let rows = t.size() / n;
+    // zero is stored as 8 in both nibbles
+    let mut packed = vec![0x88u8; rows * blocks * Q4_BLOCK / 2];
+    let mut scales = Vec::with_capacity(rows * blocks);
+    for (row, q_row) in t
+        .data()
+        .chunks(n)
+        .zip(packed.chunks_mut(blocks * Q4_BLOCK / 2))
+    {
+        for (x, q) in row.chunks(Q4_BLOCK).zip(q_row.chunks_mut(Q4_BLOCK / 2)) {
+            let amax = x.iter().fold(0f32, |m, x| m.max(x.abs()));
+            let scale = amax / 7.;
+            if scale > 0. {
+                let nibble = |x: f32| ((x / scale).round().clamp(-8., 7.) + 8.) as u8;
+                for (i, x) in x.iter().enumerate() {
+                    let shift = 4 * (i % 2);
+                    q[i / 2] = q[i / 2] & !(0xf << shift) | nibble(*x) << shift;
+                }
+            }
+            scales.push(scale);
+        }
+    }
+    Q4Tensor {
+        packed,
+        scales,
+        shape: t.shape().to_vec(),
+    }
+}
+
+// Per-row (per output channel) int8: value = scales[row] * q, scale = max(abs(row)) / 127
+pub struct I8Tensor {
+    data: Vec<i8>,
+    scales: Vec<f32>,
+    shape: Vec<usize>,
+}
+
+#[allow(unused)]
+impl I8Tensor {
+    pub fn shape(&self) -> &[usize] {
+        &self.shape
+    }
+
+    // the scale and values of row i
+    pub fn row(&self, i: usize) -> (f32, &[i8]) {
+        let n = *self.shape.last().unwrap();
+        (self.scales[i], &self.data[i * n..][..n])
+    }
+
+    pub fn size_in_bytes(&self) -> usize {
+        self.data.len() + self.scales.len() * std::mem::size_of::<f32>()
+    }
+
+    pub fn dequantize(&self) -> Tensor<f32> {
+        let n = *self.shape.last().unwrap();
+        let data = self
+            .data
+            .chunks(n)
+            .zip(&self.scales)
+            .flat_map(|(q, s)| q.iter().map(move |&q| s * q as f32))
+            .collect();
+        Tensor::new(data, &self.shape)
+    }
+}
+
+// Quantize every row of t (along its last axis) to int8 with its own scale
+#[allow(unused)]
+pub fn quantize_i8(t: &Tensor<f32>) -> I8Tensor {
+    let n = *t.shape().last().unwrap();
+    let mut data = vec![0i8; t.size()];
+    let mut scales = Vec::with_capacity(t.size() / n);
+    for (x, q) in t.data().chunks(n).zip(data.chunks_mut(n)) {
+        let scale = x.iter().fold(0f32, |m, x| m.max(x.abs())) / 127.;
+        if scale > 0. {
+            for (q, x) in q.iter_mut().zip(x) {
+                *q = (x / scale).round() as i8;
+            }
+        }
+        scales.push(scale);
+    }
+    I8Tensor {
+        data,
+        scales,
+        shape: t.shape().to_vec(),
+    }
+}
+
+impl Tensor<f16> {
+    #[allow(unused)]
+    pub fn to_f32(&self) -> Tensor<f32> {
+        Tensor::new(
+            self.data().iter().map(|x| x.to_f32()).collect(),
+            &self.shape,
+        )
+    }
+}
+
+impl Tensor<bf16> {
+    #[allow(unused)]
+    pub fn to_f32(&self) -> Tensor<f32> {
+        let data = self.data().iter().map(|x| bf16_to_f32(x.to_bits()));
+        Tensor::new(data.collect(), &self.shape)
+    }
+}
+
+// bf16 is the upper half of an f32, so widening is a shift and keeps subnormals and NaN
+// payloads as they are
+#[inline]
+pub fn bf16_to_f32(bits: u16) -> f32 {
+    f32::from_bits((bits as u32) << 16)
+}
+
+// Narrow to bf16 rounding to nearest even. NaNs are quieted rather than rounded, which
+// could otherwise carry them into inf.
+#[inline]
+pub fn f32_to_bf16(x: f32) -> u16 {
+    let bits = x.to_bits();
+    if x.is_nan() {
+        return (bits >> 16) as u16 | 0x40;
+    }
+    let round = 0x7fff + ((bits >> 16) & 1);
+    (bits.wrapping_add(round) >> 16) as u16
+}
+
 #[inline]
 pub fn float_eq(x: &f32, y: &f32, rel: f32) -> bool {
     (x - y).abs() <= rel * (x.abs() + y.abs()) / 2.0
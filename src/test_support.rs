@@ -0,0 +1,19 @@
+// What the tests of several modules share
+use crate::model::Llama;
+use std::path::PathBuf;
+use tokenizers::Tokenizer;
+
+// models/story, the model the tests run
+pub(crate) fn story_dir() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("models")
+        .join("story")
+}
+
+pub(crate) fn story_model() -> Llama<f32> {
+    Llama::from_safetensors(story_dir())
+}
+
+pub(crate) fn story_tokenizer() -> Tokenizer {
+    Tokenizer::from_file(story_dir().join("tokenizer.json")).unwrap()
+}
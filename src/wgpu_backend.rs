@@ -0,0 +1,388 @@
+use crate::backend::{Backend, CpuBackend};
+use crate::operators::{GatherError, OperatorError, RopeCache, RopeLayout};
+use crate::params::{EmbeddingTable, Weight};
+use crate::tensor::Tensor;
+#[cfg(test)]
+use crate::test_support::{story_dir, story_model};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use wgpu::util::DeviceExt;
+
+// Runs matmul_transb (f32 weights), rms_norm and masked_softmax as WGSL compute shaders and
+// everything else on the CPU. Activations are uploaded and read back around every operator,
+// weights are uploaded the first time they are used and kept on the GPU from then on.
+pub struct WgpuBackend {
+    device: wgpu::Device,
+    queue: wgpu::Queue,
+    matmul: wgpu::ComputePipeline,
+    rms_norm: wgpu::ComputePipeline,
+    softmax: wgpu::ComputePipeline,
+    weights: Mutex<WeightBuffers>,
+}
+
+// keyed by the address of the weight's data, the view kept next to the buffer holds the data
+// alive so that the address cannot be reused by another tensor
+type WeightBuffers = HashMap<usize, (Tensor<f32>, Arc<wgpu::Buffer>)>;
+
+const MATMUL_TILE: usize = 16;
+const MAX_WORKGROUPS: usize = 65535;
+
+impl WgpuBackend {
+    // None if there is no adapter
+    pub fn new() -> Option<Self> {
+        let instance = wgpu::Instance::default();
+        let adapter =
+            pollster::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions::default()))
+                .ok()?;
+        let (device, queue) =
+            pollster::block_on(adapter.request_device(&wgpu::DeviceDescriptor::default())).ok()?;
+        let pipeline = |source: &str, label: &str| {
+            let module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+                label: Some(label),
+                source: wgpu::ShaderSource::Wgsl(source.into()),
+            });
+            device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some(label),
+                layout: None,
+                module: &module,
+                entry_point: Some("main"),
+                compilation_options: Default::default(),
+                cache: None,
+            })
+        };
+        let matmul = pipeline(include_str!("shaders/matmul_transb.wgsl"), "matmul_transb");
+        let rms_norm = pipeline(include_str!("shaders/rms_norm.wgsl"), "rms_norm");
+        let softmax = pipeline(
+            include_str!("shaders/masked_softmax.wgsl"),
+            "masked_softmax",
+        );
+        Some(WgpuBackend {
+            device,
+            queue,
+            matmul,
+            rms_norm,
+            softmax,
+            weights: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn upload(&self, data: &[f32], usage: wgpu::BufferUsages) -> wgpu::Buffer {
+        self.device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: None,
+                contents: bytemuck::cast_slice(data),
+                usage,
+            })
+    }
+
+    fn uniform(&self, params: &[u32]) -> wgpu::Buffer {
+        self.device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: None,
+                contents: bytemuck::cast_slice(params),
+                usage: wgpu::BufferUsages::UNIFORM,
+            })
+    }
+
+    fn weight(&self, w: &Tensor<f32>) -> Arc<wgpu::Buffer> {
+        let mut weights = self.weights.lock().unwrap();
+        let (_, buffer) = weights
+            .entry(w.data().as_ptr() as usize)
+            .or_insert_with(|| {
+                let buffer = self.upload(w.data(), wgpu::BufferUsages::STORAGE);
+                (w.slice(0, w.shape()), Arc::new(buffer))
+            });
+        buffer.clone()
+    }
+
+    // Bind buffers to pipeline in order, dispatch and copy output back into out
+    fn run(
+        &self,
+        pipeline: &wgpu::ComputePipeline,
+        buffers: &[&wgpu::Buffer],
+        workgroups: (u32, u32),
+        output: &wgpu::Buffer,
+        out: &mut [f32],
+    ) {
+        let entries: Vec<_> = buffers
+            .iter()
+            .enumerate()
+            .map(|(i, b)| wgpu::BindGroupEntry {
+                binding: i as u32,
+                resource: b.as_entire_binding(),
+            })
+            .collect();
+        let bind_group = self.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.get_bind_group_layout(0),
+            entries: &entries,
+        });
+        let size = std::mem::size_of_val(out) as u64;
+        let staging = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: None,
+            size,
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let mut encoder = self.device.create_command_encoder(&Default::default());
+        {
+            let mut pass = encoder.begin_compute_pass(&Default::default());
+            pass.set_pipeline(pipeline);
+            pass.set_bind_group(0, &bind_group, &[]);
+            pass.dispatch_workgroups(workgroups.0, workgroups.1, 1);
+        }
+        encoder.copy_buffer_to_buffer(output, 0, &staging, 0, size);
+        self.queue.submit([encoder.finish()]);
+
+        let slice = staging.slice(..);
+        slice.map_async(wgpu::MapMode::Read, |r| {
+            r.expect("failed to map a wgpu buffer")
+        });
+        self.device
+            .poll(wgpu::PollType::wait_indefinitely())
+            .expect("wgpu device lost");
+        {
+            let view = slice
+                .get_mapped_range()
+                .expect("failed to read a wgpu buffer");
+            out.copy_from_slice(bytemuck::cast_slice(&view));
+        }
+        staging.unmap();
+    }
+}
+
+impl Backend for WgpuBackend {
+    fn matmul_transb(
+        &self,
+        c: &mut Tensor<f32>,
+        beta: f32,
+        a: &Tensor<f32>,
+        b: &Weight<f32>,
+        alpha: f32,
+    ) -> Result<(), OperatorError> {
+        let Weight::Full(w) = b else {
+            return CpuBackend.matmul_transb(c, beta, a, b, alpha);
+        };
+        crate::operators::check_matmul_transb(c, a, w.shape())?;
+        let (m, n, k) = (c.shape()[0], c.shape()[1], a.shape()[1]);
+        let a_buf = self.upload(a.data(), wgpu::BufferUsages::STORAGE);
+        let c_buf = self.upload(
+            c.data(),
+            wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+        );
+        let params = [
+            m as u32,
+            n as u32,
+            k as u32,
+            beta.to_bits(),
+            alpha.to_bits(),
+            0,
+            0,
+            0,
+        ];
+        let params = self.uniform(&params);
+        let w_buf = self.weight(w);
+        let groups = (
+            n.div_ceil(MATMUL_TILE) as u32,
+            m.div_ceil(MATMUL_TILE) as u32,
+        );
+        let out = unsafe { c.data_mut() };
+        self.run(
+            &self.matmul,
+            &[&a_buf, &w_buf, &c_buf, &params],
+            groups,
+            &c_buf,
+            out,
+        );
+        Ok(())
+    }
+
+    fn rms_norm(
+        &self,
+        y: &mut Tensor<f32>,
+        x: &Tensor<f32>,
+        w: &Tensor<f32>,
+        epsilon: f32,
+        unit_offset: bool,
+    ) -> Result<(), OperatorError> {
+        let n = w.size();
+        let rows = x.size() / n.max(1);
+        if y.size() != x.size() || x.shape().last() != Some(&n) || rows > MAX_WORKGROUPS {
+            // the CPU path reports the shape errors
+            return CpuBackend.rms_norm(y, x, w, epsilon, unit_offset);
+        }
+        let x_buf = self.upload(x.data(), wgpu::BufferUsages::STORAGE);
+        let w_buf = self.weight(w);
+        let y_buf = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: None,
+            size: std::mem::size_of_val(y.data()) as u64,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+        let offset: f32 = if unit_offset { 1. } else { 0. };
+        let params = self.uniform(&[rows as u32, n as u32, epsilon.to_bits(), offset.to_bits()]);
+        let out = unsafe { y.data_mut() };
+        self.run(
+            &self.rms_norm,
+            &[&x_buf, &w_buf, &y_buf, &params],
+            (rows as u32, 1),
+            &y_buf,
+            out,
+        );
+        Ok(())
+    }
+
+    fn rope(
+        &self,
+        y: &mut Tensor<f32>,
+        start_pos: usize,
+        cache: &RopeCache,
+        layout: RopeLayout,
+    ) -> Result<(), OperatorError> {
+        CpuBackend.rope(y, start_pos, cache, layout)
+    }
+
+    fn masked_softmax(&self, y: &mut Tensor<f32>) {
+        let shape = y.shape();
+        assert!(shape.len() >= 2);
+        let (seq_len, total_seq_len) = (shape[shape.len() - 2], shape[shape.len() - 1]);
+        assert!(
+            seq_len <= total_seq_len,
+            "masked_softmax: seq_len {} > total_seq_len {}",
+            seq_len,
+            total_seq_len
+        );
+        let rows = y.size() / total_seq_len;
+        let y_buf = self.upload(
+            y.data(),
+            wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+        );
+        // rows beyond the 65535 workgroups of one dimension go to the second one
+        let per_dispatch = rows.min(MAX_WORKGROUPS);
+        let params = [rows, seq_len, total_seq_len, per_dispatch].map(|x| x as u32);
+        let params = self.uniform(&params);
+        let groups = (per_dispatch as u32, rows.div_ceil(per_dispatch) as u32);
+        let out = unsafe { y.data_mut() };
+        self.run(&self.softmax, &[&y_buf, &params], groups, &y_buf, out);
+    }
+
+    fn swiglu(&self, y: &mut Tensor<f32>, x: &Tensor<f32>) {
+        CpuBackend.swiglu(y, x);
+    }
+
+    fn gather(
+        &self,
+        y: &mut Tensor<f32>,
+        indices: &Tensor<u32>,
+        table: &EmbeddingTable<f32>,
+        scale: f32,
+    ) -> Result<(), GatherError> {
+        CpuBackend.gather(y, indices, table, scale)
+    }
+}
+
+// WgpuBackend if there is an adapter, CpuBackend otherwise
+pub fn default_backend() -> Arc<dyn Backend> {
+    match WgpuBackend::new() {
+        Some(backend) => Arc::new(backend),
+        None => Arc::new(CpuBackend),
+    }
+}
+
+#[cfg(test)]
+fn gpu() -> Option<WgpuBackend> {
+    let gpu = WgpuBackend::new();
+    if gpu.is_none() {
+        eprintln!("no wgpu adapter, skipping");
+    }
+    gpu
+}
+
+#[test]
+fn test_wgpu_matmul_transb() {
+    let Some(gpu) = gpu() else { return };
+    // none of the sizes is a multiple of the 16 x 16 tile
+    for (m, n, k) in [(1, 37, 50), (19, 33, 7), (70, 5, 129)] {
+        let a = Tensor::<f32>::random(&vec![m, k]);
+        let b = Weight::Full(Tensor::<f32>::random(&vec![n, k]));
+        let mut c = Tensor::<f32>::random(&vec![m, n]);
+        let mut expected = Tensor::new(c.data().to_vec(), c.shape());
+        CpuBackend
+            .matmul_transb(&mut expected, 0.5, &a, &b, 2.)
+            .unwrap();
+        gpu.matmul_transb(&mut c, 0.5, &a, &b, 2.).unwrap();
+        assert!(c.max_abs_diff(&expected) < 1e-3);
+    }
+}
+
+#[test]
+fn test_wgpu_rms_norm() {
+    let Some(gpu) = gpu() else { return };
+    let x = Tensor::<f32>::random(&vec![5, 130]);
+    let w = Tensor::<f32>::random(&vec![130]);
+    for unit_offset in [false, true] {
+        let mut y = Tensor::<f32>::default(&vec![5, 130]);
+        let mut expected = Tensor::<f32>::default(&vec![5, 130]);
+        CpuBackend
+            .rms_norm(&mut expected, &x, &w, 1e-6, unit_offset)
+            .unwrap();
+        gpu.rms_norm(&mut y, &x, &w, 1e-6, unit_offset).unwrap();
+        assert!(y.max_abs_diff(&expected) < 1e-3);
+    }
+}
+
+#[test]
+fn test_wgpu_masked_softmax() {
+    let Some(gpu) = gpu() else { return };
+    let data: Vec<f32> = (0..3 * 7 * 100)
+        .map(|_| rand::random::<f32>() * 10.)
+        .collect();
+    let mut y = Tensor::new(data.clone(), &vec![3, 7, 100]);
+    let mut expected = Tensor::new(data, &vec![3, 7, 100]);
+    CpuBackend.masked_softmax(&mut expected);
+    gpu.masked_softmax(&mut y);
+    assert!(y.max_abs_diff(&expected) < 1e-3);
+}
+
+#[test]
+fn test_wgpu_forward() {
+    let Some(gpu) = gpu() else { return };
+    let model = story_model();
+    let on_gpu = story_model().with_backend(Arc::new(gpu));
+    let input = Tensor::<u32>::new(vec![1, 300, 25, 700, 40], &vec![5]);
+    let expected = model.forward(&input, &mut model.new_cache()).unwrap();
+    let logits = on_gpu.forward(&input, &mut on_gpu.new_cache()).unwrap();
+    assert!(logits.max_abs_diff(&expected) < 1e-3);
+}
+
+// cargo test --release --features wgpu bench_wgpu_prefill -- --ignored --nocapture
+// the chat model when it has been downloaded to models/chat, the story model otherwise
+#[test]
+#[ignore]
+fn bench_wgpu_prefill() {
+    let Some(gpu) = gpu() else { return };
+    use crate::model::Llama;
+    use std::path::PathBuf;
+    use std::time::Instant;
+    let project_dir = env!("CARGO_MANIFEST_DIR");
+    let chat_dir = PathBuf::from(project_dir).join("models").join("chat");
+    let model_dir = if chat_dir.join("model.safetensors").exists() {
+        chat_dir
+    } else {
+        story_dir()
+    };
+    let cpu = Llama::from_safetensors(&model_dir);
+    let on_gpu = Llama::from_safetensors(&model_dir).with_backend(Arc::new(gpu));
+    let input = Tensor::<u32>::new((0..256).map(|i| i % 100 + 3).collect(), &vec![256]);
+    // the first run uploads the weights
+    on_gpu.forward(&input, &mut on_gpu.new_cache()).unwrap();
+    for (name, model) in [("cpu", &cpu), ("wgpu", &on_gpu)] {
+        let start = Instant::now();
+        model.forward(&input, &mut model.new_cache()).unwrap();
+        println!(
+            "{}: {name} prefill of 256 tokens in {:?}",
+            model_dir.display(),
+            start.elapsed()
+        );
+    }
+}